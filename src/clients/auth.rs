@@ -1,6 +1,8 @@
 use serde_json::json;
+use std::time::Duration;
 use serde::Deserialize;
-use crate::{utils::http::post, utils::{context::RequestContext, errors::InternalError}};
+use super::Downstream;
+use crate::utils::{context::RequestContext, errors::InternalError};
 
 ///
 /// This is an example of how to make a downstream HTTP request via another service.
@@ -8,27 +10,82 @@ use crate::{utils::http::post, utils::{context::RequestContext, errors::Internal
 
 // Dummy example response body from an example request.
 #[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ClaimResponse {
-    claims: Vec<String>
+    claims: Vec<String>,
+    principal_id: Option<String>,
+}
+
+impl ClaimResponse {
+    ///
+    /// Who the caller is - for attributing changes. Without one (eg. an internal caller), the configured
+    /// system_identity is used.
+    ///
+    pub fn principal<'a>(&'a self, ctx: &'a RequestContext) -> &'a str {
+        self.principal_id.as_deref().unwrap_or(&ctx.config().system_identity)
+    }
 }
 
 ///
-/// Pass the session token to the remote auth service to check if the claim is assigned.
+/// The client for the remote auth service.
 ///
-/// This is just an example downstream HTTP request.
+pub struct AuthClient<'a> {
+    downstream: Downstream<'a>,
+}
+
+impl<'a> AuthClient<'a> {
+    pub fn new(ctx: &'a RequestContext) -> Self {
+        // The claim check holds up every guarded request - so a slow auth service fails fast.
+        let timeout = Duration::from_millis(ctx.config().auth_timeout);
+        AuthClient { downstream: Downstream::new(ctx, &ctx.config().auth_address).timeout(timeout) }
+    }
+
+    ///
+    /// Pass the session token to the remote auth service to check if the claim is assigned.
+    ///
+    /// An explicit refusal is an InvalidClaim (403) but failing to reach the auth service at all is an
+    /// AuthUnavailable (503) - the latter is worth the caller retrying, as is an AuthTimeout (504) if it takes
+    /// longer than the auth_timeout. Either way the claim is not granted.
+    ///
+    /// This is just an example downstream HTTP request.
+    ///
+    pub async fn check_claim(&self, claim: &str) -> Result<ClaimResponse, InternalError> {
+        let ctx = self.downstream.ctx();
+
+        // The caller's session token - if the authorization header is one of the context_headers.
+        let token = ctx.header("authorization").unwrap_or("eg session token from source request here");
+
+        let response = self.downstream.post("/auth/get-claims")
+            .header("content-type", "application/json")
+            .query_param("param1", "value1")
+            .json(&json!({ "token": token }))
+            .retry_unsafe() // A claims lookup has no side-effects so is safe to retry.
+            .send(ctx)
+            .await
+            .map_err(|err| unavailable(err, ctx))?;
+
+        match response.status() {
+            200 => {
+                let claims: ClaimResponse = response.json()?;
+                if let Some(principal) = &claims.principal_id {
+                    ctx.set_principal(principal);
+                }
+                Ok(claims)
+            },
+            403 => Err(InternalError::InvalidClaim { claim: claim.to_string() }),
+            any_other_status => Err(InternalError::RemoteRequestError { cause: format!("Bad response status {}", any_other_status), url: format!("{} {}", response.method(), response.url()) })
+        }
+    }
+}
+
+///
+/// Transport failures (and 50x responses once retries are exhausted) mean the auth service is down.
 ///
-pub async fn check_claim(claim: &str, ctx: &RequestContext) -> Result<ClaimResponse, InternalError> {
-
-    let response = post(format!("{}/auth/get-claims", ctx.config().auth_address))
-        .header("content-type", "application/json")
-        .query_param("param1", "value1")
-        .json(&json!({ "token": "eg session token from source request here" }))
-        .send(ctx)
-        .await?;
-
-    match response.status() {
-        200 => Ok(response.json()?),
-        403 => Err(InternalError::InvalidClaim { claim: claim.to_string() }),
-        any_other_status => Err(InternalError::RemoteRequestError { cause: format!("Bad response status {}", any_other_status), url: format!("{} {}", response.method(), response.url()) })
+fn unavailable(err: InternalError, ctx: &RequestContext) -> InternalError {
+    match err {
+        InternalError::DownstreamTimeout { url: _ } => InternalError::AuthTimeout { timeout: ctx.config().auth_timeout },
+        InternalError::SendRequestError { cause: _ } |
+        InternalError::RemoteRequestError { cause: _, url: _ } => InternalError::AuthUnavailable { cause: err.to_string() },
+        err => err,
     }
-}
\ No newline at end of file
+}
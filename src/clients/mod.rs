@@ -1 +1,75 @@
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+
+use std::time::Duration;
+use crate::utils::{context::RequestContext, http::{get, post, HttpRequest}};
+
+///
+/// Each downstream service gets a small typed client (eg. auth::AuthClient) constructed from the
+/// RequestContext. The client owns the paths and error mapping for its service and builds it's
+/// requests through this - which holds the base address and the retry/timeout policy.
+///
+/// Example: -
+///    pub struct BillingClient<'a> { downstream: Downstream<'a> }
+///
+///    impl<'a> BillingClient<'a> {
+///        pub fn new(ctx: &'a RequestContext) -> Self {
+///            BillingClient { downstream: Downstream::new(ctx, &ctx.config().billing_address).timeout(Duration::from_secs(5)) }
+///        }
+///    }
+///
+pub struct Downstream<'a> {
+    ctx: &'a RequestContext,
+    address: &'a str,
+    retry_limit: Option<u8>,     // Overrides the configured client_retry_limit.
+    timeout: Option<Duration>,   // Overrides the client's default timeout.
+}
+
+impl<'a> Downstream<'a> {
+    pub fn new(ctx: &'a RequestContext, address: &'a str) -> Self {
+        Downstream { ctx, address, retry_limit: None, timeout: None }
+    }
+
+    ///
+    /// Attempt requests to this downstream no more than this many times.
+    ///
+    pub fn _retry_limit(mut self, retry_limit: u8) -> Self {
+        self.retry_limit = Some(retry_limit);
+        self
+    }
+
+    ///
+    /// Give up on a request to this downstream after this long - it's not retried.
+    ///
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn ctx(&self) -> &'a RequestContext {
+        self.ctx
+    }
+
+    pub fn post(&self, path: &str) -> HttpRequest {
+        self.with_policy(post(self.url(path)))
+    }
+
+    pub fn _get(&self, path: &str) -> HttpRequest {
+        self.with_policy(get(self.url(path)))
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.address.trim_end_matches('/'), path)
+    }
+
+    fn with_policy(&self, mut request: HttpRequest) -> HttpRequest {
+        if let Some(retry_limit) = self.retry_limit {
+            request.retry_limit(retry_limit);
+        }
+
+        if let Some(timeout) = self.timeout {
+            request.timeout(timeout);
+        }
+
+        request
+    }
+}
@@ -4,20 +4,20 @@ mod routes;
 mod clients;
 mod middleware;
 
-use tracing::info;
-use dotenv::dotenv;
-use std::sync::Arc;
+use tracing::{error, info};
+use std::{future::Future, sync::Arc, thread::JoinHandle, time::Duration};
+use futures::{channel::mpsc::unbounded, future::{Either, select, try_join_all}};
 use crossbeam_channel::bounded;
 use actix_service::ServiceFactory;
 use opentelemetry_jaeger::Uninstall;
-use middleware::{request, response};
-use crate::routes::admin::tracer::USE_COLOUR;
+use middleware::{admin, cors, encoding, limits, request, response};
+use crate::routes::admin::{set_time::TimeProvider, tracer::USE_COLOUR};
 use actix_web_opentelemetry::RequestTracing as OpenTelemetryMiddleware;
 use opentelemetry::{global, sdk::{propagation::TraceContextPropagator,trace,trace::Sampler}};
 use tracing_subscriber::{prelude::__tracing_subscriber_SubscriberExt, Registry, util::SubscriberInitExt};
-use actix_web::{App, HttpServer, body::Body, dev::{ServiceRequest, ServiceResponse}, middleware::Condition, web, web::Data};
-use utils::{config::{Configuration, default_env}, context::{InitialisationContext, PartialRequestContext}, errors::{configure_json_extractor, InternalError}, mongo::{get_mongo_db, update_mongo}, rabbit::rabbit_publisher};
-use routes::{admin::{health, ping, set_time, settings, tracer}, create_account, get_account, get_account_profile, get_accounts, get_device_profile, update_account};
+use actix_web::{App, HttpServer, body::Body, dev::{Server, ServiceRequest, ServiceResponse}, middleware::{Condition, NormalizePath, normalize::TrailingSlash}, web, web::Data};
+use utils::{config::{resolve_uri, Configuration, default_env, load_dotenv}, context::{InitialisationContext, PartialRequestContext}, errors::{configure_json_extractor, configure_query_extractor, InternalError}, dead_letters::write_dead_letters, outbox::relay_outbox, mongo::{get_mongo_db, update_mongo}, rabbit::{await_connection, rabbit_publisher, stop_publisher, RabbitConnected}, self_test::{self, self_test, SelfTestReport}, shutdown};
+use routes::{admin::{chaos, correlation, dead_letters, health, inflight, maintenance, migrate, notification_stats, ping, purge, replay, set_time, settings, toggles, tracer}, account_events, account_export, account_history, account_notes, create_account, device_profiles, get_account, get_account_profile, get_accounts, get_created_stats, get_device_profile, get_effective_profile, openapi, patch_account, rotate_account_id, unknown_route, update_account};
 
 // TODO: Propagate span context into middleware so logged errors are within a span.
 //    This will require a newer actix_otel see https://github.com/OutThereLabs/actix-web-opentelemetry/pull/60/commits/66ce5b5b16b32004f1374263b60adf0f3141fe71
@@ -25,30 +25,86 @@ use routes::{admin::{health, ping, set_time, settings, tracer}, create_account,
 
 pub const APP_NAME: &'static str = "Nails"; // Keep in sync with cargo.toml
 
+const RABBIT_THREAD_NAME: &str = "rabbit-publisher";
+
 ///
-/// The HTTP endpoints are wired-in here.
+/// The admin/internal HTTP endpoints are wired-in here.
 ///
-fn configure_routes(cfg: &mut web::ServiceConfig) {
+fn configure_admin_routes(cfg: &mut web::ServiceConfig) {
     cfg
-        // Admin/internal
+        // Probes are left unguarded, everything else requires any configured admin token.
         .route("/ping", web::get().to(ping::handle))
         .route("/health", web::get().to(health::handle))
-        .route("/settings", web::get().to(settings::handle))
-        .route("/tracer/on", web::post().to(tracer::handle_on))
-        .route("/tracer/off", web::post().to(tracer::handle_off))
-        .route("/tracer-bullet", web::post().to(tracer::handle_bullet))
-        .route("/set_time/{fixed_time}", web::post().to(set_time::handle_set))
-        .route("/reset_time", web::post().to(set_time::handle_reset))
-
-        // Account
-        .route("/account/{account_id}", web::get().to(get_account::handle))
-        .route("/accounts", web::get().to(get_accounts::handle))
-        .route("/create-account", web::post().to(create_account::handle))
-        .route("/update-account-status", web::put().to(update_account::handle_status))
-
-        // Profiles
-        .route("/account-profile/{profile_id}", web::get().to(get_account_profile::handle))
-        .route("/device-profile/{profile_id}", web::get().to(get_device_profile::handle));
+        .service(web::resource("/settings").wrap(admin::Middleware).route(web::get().to(settings::handle)))
+        .service(web::resource("/settings/sources").wrap(admin::Middleware).route(web::get().to(settings::handle_sources)))
+        .service(web::resource("/endpoints/disabled").wrap(admin::Middleware).route(web::get().to(toggles::handle_get)))
+        .service(web::resource("/endpoints/{endpoint}/disable").wrap(admin::Middleware).route(web::post().to(toggles::handle_disable)))
+        .service(web::resource("/endpoints/{endpoint}/enable").wrap(admin::Middleware).route(web::post().to(toggles::handle_enable)))
+        .service(web::resource("/stats/inflight").wrap(admin::Middleware).route(web::get().to(inflight::handle)))
+        .service(web::resource("/stats/notifications").wrap(admin::Middleware).route(web::get().to(notification_stats::handle_get)))
+        .service(web::resource("/stats/notifications/reset").wrap(admin::Middleware).route(web::post().to(notification_stats::handle_reset)))
+        .service(web::resource("/trace/{correlation_id}").wrap(admin::Middleware).route(web::get().to(correlation::handle)))
+        .service(web::resource("/tracer/on").wrap(admin::Middleware).route(web::post().to(tracer::handle_on)))
+        .service(web::resource("/tracer/off").wrap(admin::Middleware).route(web::post().to(tracer::handle_off)))
+        .service(web::resource("/tracer-bullet").wrap(admin::Middleware).route(web::post().to(tracer::handle_bullet)))
+        .service(web::resource("/set_time/{fixed_time}").wrap(admin::Middleware).route(web::post().to(set_time::handle_set)))
+        .service(web::resource("/reset_time").wrap(admin::Middleware).route(web::post().to(set_time::handle_reset)))
+        .service(web::resource("/admin/maintenance").wrap(admin::Middleware).route(web::post().to(maintenance::handle)))
+        .service(web::resource("/admin/migrate").wrap(admin::Middleware).route(web::post().to(migrate::handle)))
+        .service(web::resource("/admin/recase-statuses").wrap(admin::Middleware).route(web::post().to(migrate::handle_recase)))
+        .service(web::resource("/admin/accounts").wrap(admin::Middleware).route(web::delete().to(purge::handle)))
+        .service(web::resource("/admin/chaos").wrap(admin::Middleware).route(web::get().to(chaos::handle_get)))
+        .service(web::resource("/admin/chaos/{endpoint}").wrap(admin::Middleware)
+            .route(web::put().to(chaos::handle_set))
+            .route(web::delete().to(chaos::handle_clear)))
+        .service(web::resource("/admin/dead-letters").wrap(admin::Middleware).route(web::get().to(dead_letters::handle_list)))
+        .service(web::resource("/admin/dead-letters/redrive").wrap(admin::Middleware).route(web::post().to(dead_letters::handle_redrive)))
+        .service(web::resource("/account/{account_id}/replay-events").wrap(admin::Middleware).route(web::post().to(replay::handle)));
+}
+
+///
+/// The business HTTP endpoints are wired-in here.
+///
+fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg
+        // Browsers may call these from the configured origins.
+        .service(web::scope("").wrap(cors::Middleware)
+            // Account
+            .route("/account/{account_id}", web::get().to(get_account::handle))
+            .route("/account/{account_id}", web::patch().to(patch_account::handle))
+            .route("/account/{account_id}/events", web::get().to(account_events::handle))
+            .route("/account/{account_id}/effective-profile", web::get().to(get_effective_profile::handle))
+            .route("/account/{account_id}/export", web::get().to(account_export::handle_export))
+            .route("/account/{account_id}/history", web::get().to(account_history::handle))
+            .route("/account/{account_id}/notes", web::get().to(account_notes::handle_get))
+            .route("/account/{account_id}/notes", web::post().to(account_notes::handle_add))
+            .route("/account/{account_id}/reactivate", web::post().to(update_account::handle_reactivate))
+            .route("/account/{account_id}/rotate-id", web::post().to(rotate_account_id::handle))
+            .route("/accounts", web::get().to(get_accounts::handle))
+            .route("/accounts/search", web::get().to(get_accounts::handle_search))
+            .route("/accounts/created-stats", web::get().to(get_created_stats::handle))
+            .route("/accounts/by-device/{device_id}", web::get().to(get_account::handle_by_device))
+            .route("/accounts/import", web::post().to(account_export::handle_import))
+            .route("/create-account", web::post().to(create_account::handle))
+            .route("/update-account-status", web::put().to(update_account::handle_status))
+            .route("/update-account-statuses", web::put().to(update_account::handle_statuses))
+
+            // Profiles
+            .route("/account-profile/{profile_id}", web::get().to(get_account_profile::handle))
+            .route("/device-profile/{profile_id}", web::get().to(get_device_profile::handle))
+            .route("/create-device-profile", web::post().to(device_profiles::handle_create))
+            .route("/update-device-profile", web::put().to(device_profiles::handle_update))
+
+            // Documentation
+            .route("/openapi.json", web::get().to(openapi::handle)));
+}
+
+///
+/// All the HTTP endpoints - used when the admin endpoints share the main port.
+///
+fn configure_all_routes(cfg: &mut web::ServiceConfig) {
+    configure_admin_routes(cfg);
+    configure_routes(cfg);
 }
 
 ///
@@ -59,7 +115,30 @@ fn configure_routes(cfg: &mut web::ServiceConfig) {
 /// with near-identical set-up as the runtime instance.
 ///
 pub async fn lib_main() -> Result<(), std::io::Error> {
-    let (ctx, _uninstall) = init_everything().await?;
+    // The self-test can be requested on the command line as well as in the environment.
+    let overrides: &[(&str, &str)] = match std::env::args().any(|arg| arg == "--self-test") {
+        true  => &[("self_test", "true")],
+        false => &[],
+    };
+
+    let (ctx, uninstall, publisher) = match init_everything_with(overrides).await {
+        Ok(init) => init,
+
+        // A self-test always reports - even if the service couldn't be initialised (.env is loaded by now).
+        Err(err) if self_test::requested() => {
+            println!("{}", serde_json::to_string_pretty(&SelfTestReport::initialisation_failed(&err))?);
+            std::process::exit(1);
+        },
+        Err(err) => return Err(err.into()),
+    };
+    init_panic_hook();
+
+    if ctx.config().self_test {
+        let report = self_test(&ctx).await;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        std::process::exit(if report.passed { 0 } else { 1 });
+    }
+
     let init_ctx = Arc::new(ctx);
     let server_cfg = init_ctx.config().clone();
 
@@ -73,17 +152,71 @@ pub async fn lib_main() -> Result<(), std::io::Error> {
     //     Some(exporter),
     // );
 
-    // Start the HTTP server now, spawning an App for each worker thread.
-    HttpServer::new(move || app(init_ctx.clone())
+    info!("Starting HTTP server with {} workers, {} max connections per worker and a backlog of {}",
+        server_cfg.workers,
+        server_cfg.max_connections,
+        server_cfg.backlog);
+
+    // Start the HTTP server now, spawning an App for each worker thread. Signals are handled below so
+    // the in-flight requests can be drained.
+    let app_ctx = init_ctx.clone();
+    let server = HttpServer::new(move || app(app_ctx.clone())
         // .wrap(request_metrics.clone()) // Prometheus metrics for each endpoint.
 
         // Add here not in app due to change in ServiceFactory signature.
         .wrap(response::Middleware))
+        .workers(server_cfg.workers)
+        .backlog(server_cfg.backlog) // Must be set before bind.
+        .max_connections(server_cfg.max_connections)
         .bind(format!("0.0.0.0:{}", server_cfg.port))?
         .keep_alive(server_cfg.keep_alive)
         .client_timeout(server_cfg.client_timeout)
-        .run()
-        .await
+        .shutdown_timeout(server_cfg.drain_timeout)
+        .disable_signals()
+        .run();
+
+    // If configured, the admin endpoints are served from their own port so they can be firewalled.
+    let mut servers = vec!(server);
+    if let Some(admin_port) = server_cfg.admin_port {
+        info!("Starting admin HTTP server on port {}", admin_port);
+
+        let admin_ctx = init_ctx.clone();
+        servers.push(HttpServer::new(move || admin_app(admin_ctx.clone())
+            .wrap(response::Middleware))
+            .workers(1)
+            .bind(format!("0.0.0.0:{}", admin_port))?
+            .keep_alive(server_cfg.keep_alive)
+            .client_timeout(server_cfg.client_timeout)
+            .shutdown_timeout(server_cfg.drain_timeout)
+            .disable_signals()
+            .run());
+    }
+
+    // Serve until asked to stop - then let the in-flight requests complete.
+    let result = serve_until(servers, shutdown::signalled(), Duration::from_secs(server_cfg.drain_timeout)).await;
+
+    // Only now are the final traces flushed and the queued notifications published.
+    drop(uninstall);
+    stop_publisher();
+    if publisher.join().is_err() {
+        error!("The RabbitMQ publisher thread panicked");
+    }
+
+    result
+}
+
+///
+/// Run the servers until the shutdown future resolves (a SIGTERM or ctrl-c from lib_main). They then stop
+/// accepting connections and the in-flight requests are given up to the drain timeout to complete.
+///
+pub async fn serve_until<F: Future>(servers: Vec<Server>, shutdown: F, drain_timeout: Duration) -> Result<(), std::io::Error> {
+    match select(try_join_all(servers.clone()), Box::pin(shutdown)).await {
+        Either::Left((result, _)) => result.map(|_| ()),
+        Either::Right(_) => {
+            shutdown::drain(&servers, drain_timeout).await;
+            Ok(())
+        },
+    }
 }
 
 ///
@@ -91,17 +224,30 @@ pub async fn lib_main() -> Result<(), std::io::Error> {
 ///
 /// Return a context object which can be passed into HTTP request handlers to access config,
 /// MongoDB, RabbitMQ (via publisher), a HTTP client, etc. Also return the Jaeger guard which,
-/// when dropped, will terminate the Jaeger tracing pipeline.
+/// when dropped, will terminate the Jaeger tracing pipeline - and the RabbitMQ publisher thread, so
+/// it can be joined on shutdown.
+///
+pub async fn init_everything() -> Result<(InitialisationContext, Option<Uninstall>, JoinHandle<()>), InternalError> {
+    init_everything_with(&[]).await
+}
+
 ///
-pub async fn init_everything() -> Result<(InitialisationContext, Option<Uninstall>), InternalError> {
+/// As init_everything but with some configuration settings explicitly overridden (by setting name).
+///
+pub async fn init_everything_with(overrides: &[(&str, &str)]) -> Result<(InitialisationContext, Option<Uninstall>, JoinHandle<()>), InternalError> {
     // Load any local dev settings as environment variables from a .env file.
-    dotenv().ok();
+    load_dotenv();
 
     // Default log level to INFO if it's not specified.
     default_env("RUST_LOG", "INFO");
 
     // Load the service configuration into struct and initialise any lazy statics.
-    let config = Configuration::from_env().expect("The service configuration is not correct");
+    let config = Configuration::from_env_with(overrides)?;
+
+    // Fail now if the URIs reference environment variables which aren't set - RabbitMQ only connects later,
+    // in it's own thread.
+    resolve_uri(&config.mongo_uri, config.mongo_credentials.as_deref())?;
+    resolve_uri(&config.rabbit_uri, config.rabbit_credentials.as_deref())?;
 
     // Initialise open-telemetry distributed tracing.
     let uninstall = init_tracing(&config);
@@ -111,21 +257,56 @@ pub async fn init_everything() -> Result<(InitialisationContext, Option<Uninstal
     // Create a MongoDB client and connect to it before proceeding.
     let db = get_mongo_db(APP_NAME, &config).await?;
 
-    // Ensure the schema is in sync with the code.
-    update_mongo(&db).await?;
+    // Ensure the schema is in sync with the code - a self-test only checks it, so nothing is written.
+    if !config.self_test {
+        let report = update_mongo(&db, &config).await?;
+        info!("Applied {} schema updates, skipped {}", report.applied.len(), report.skipped.len());
+    }
 
     // Notifications are done with RabbitMQ. The publisher of rabbit messages runs in it's own thread and we
     // use an internal channel (crossbeam) to send notifications from HTTP request handler threads to this
     // RabbitMQ thread - which in-turn, transmits the message over the wire. This means the handlers are not blocked
     // and can use a fire-and-forget approach to notifications.
+    //
+    // Any notifications the publisher fails to send are passed back to a task on this thread to be
+    // written to MongoDB (if dead_letters is configured).
     let rabbit_config = config.clone();
+    let clock = TimeProvider::shared();
+    let rabbit_clock = clock.clone();
+    let rabbit_connected = RabbitConnected::default();
+    let publisher_connected = rabbit_connected.clone();
     let (tx, rx) = bounded(config.notification_queue_size);
-    std::thread::spawn(move || rabbit_publisher(rx, APP_NAME, rabbit_config));
+    let (dead_letter_tx, dead_letter_rx) = unbounded();
+    actix_rt::spawn(write_dead_letters(db.clone(), dead_letter_rx));
+
+    // If configured, notifications staged in account outboxes are relayed to the publisher by a task on
+    // this thread - the publisher tells it which were sent, so they can be removed. Not during a self-test,
+    // which mustn't write.
+    let (outbox_tx, outbox_rx) = unbounded();
+    if config.transactional_outbox && !config.self_test {
+        actix_rt::spawn(relay_outbox(db.clone(), tx.clone(), outbox_rx, Duration::from_secs(config.outbox_poll_interval)));
+    }
+
+    let publisher = std::thread::Builder::new()
+        .name(RABBIT_THREAD_NAME.to_string())
+        .spawn(move || rabbit_publisher(rx, APP_NAME, rabbit_config, dead_letter_tx, outbox_tx, rabbit_clock, publisher_connected))
+        .expect("Unable to start the RabbitMQ publisher thread");
+
+    // If configured, don't accept any traffic until notifications can be published. A self-test reports on the
+    // connection instead.
+    if config.await_rabbit && !config.self_test {
+        let timeout = config.await_rabbit_timeout;
+        info!("Waiting up to {} seconds for RabbitMQ to connect", timeout);
+
+        if !await_connection(&rabbit_connected, Duration::from_secs(timeout)).await {
+            return Err(InternalError::RabbitMQError { cause: format!("Not connected within {} seconds", timeout) })
+        }
+    }
 
     // Create a context object that can be used as a parameter in any HTTP request handler.
     // Actix_web will wrap in a Data wrapper (essentially an Arc) and share it amongst each
     // worker thread.
-    Ok((InitialisationContext::new(db, config.clone(), tx.clone()), uninstall))
+    Ok((InitialisationContext::new(db, config.clone(), tx.clone(), rabbit_connected, clock), uninstall, publisher))
 }
 
 ///
@@ -170,6 +351,21 @@ fn init_tracing(config: &Configuration) -> Option<Uninstall> {
     };
 }
 
+///
+/// Route panics through tracing so they land in the same pipeline as all other logging, rather than
+/// the default hook's stderr.
+///
+/// Actix names its worker threads (actix-rt:worker:n) and we name our own, so the thread can be
+/// attributed. The error is logged within the current span - for instrumented handlers this includes
+/// the request's correlation id.
+///
+fn init_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let thread = std::thread::current();
+        error!("Thread '{}' {}", thread.name().unwrap_or("unnamed"), info);
+    }));
+}
+
 // Want to test capturing tracing span in middleware.
 // fn render_error<B>(mut res: actix_web::dev::ServiceResponse<B>) -> actix_web::Result<actix_web::middleware::errhandlers::ErrorHandlerResponse<B>> {
 //     error!("TEST");
@@ -182,6 +378,8 @@ fn init_tracing(config: &Configuration) -> Option<Uninstall> {
 ///
 /// Create a configured actix_web HttpServer App with configured middleware, data and routes.
 ///
+/// If an admin_port is configured the admin endpoints are not included - see admin_app.
+///
 pub fn app(ctx: Arc<InitialisationContext>) -> App<
     impl ServiceFactory<
         Request = ServiceRequest,
@@ -191,19 +389,71 @@ pub fn app(ctx: Arc<InitialisationContext>) -> App<
         InitError = ()>,
     Body> {
 
+    let routes = match ctx.config().admin_port {
+        None    => configure_all_routes,
+        Some(_) => configure_routes,
+    };
+
+    build_app(ctx, routes)
+}
+
+///
+/// Create an App with only the admin endpoints - used when an admin_port is configured.
+///
+pub fn admin_app(ctx: Arc<InitialisationContext>) -> App<
+    impl ServiceFactory<
+        Request = ServiceRequest,
+        Config = (),
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+        InitError = ()>,
+    Body> {
+
+    build_app(ctx, configure_admin_routes)
+}
+
+fn build_app(ctx: Arc<InitialisationContext>, routes: fn(&mut web::ServiceConfig)) -> App<
+    impl ServiceFactory<
+        Request = ServiceRequest,
+        Config = (),
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+        InitError = ()>,
+    Body> {
+
     App::new()
+        // Inject any faults configured for chaos testing - only ever possible in test environments.
+        .wrap(Condition::new(ctx.config().allow_test_endpoints, middleware::chaos::Middleware))
+
+        // Refuse requests whose handlers take longer than their endpoint's timeout - if configured.
+        .wrap(Condition::new(ctx.config().handler_timeouts_enabled(), middleware::timeout::Middleware::new(ctx.config())))
+
         .wrap(request::Middleware::new(Data::new(PartialRequestContext::from(ctx.clone()))))
 
+        // Decompress any compressed request bodies before they're traced or extracted.
+        .wrap(encoding::Middleware::new(ctx.config().request_encodings(), ctx.config().max_decompressed_bytes))
+
+        // Trim any trailing slashes (and merge repeated ones) so /accounts/ is routed as /accounts. This
+        // is wrapped outside of the request middleware so the handler name and tracing see the routed path.
+        .wrap(NormalizePath::new(TrailingSlash::Trim))
+
         // .wrap(ErrorHandlers::new().handler(StatusCode::BAD_REQUEST, render_error))
 
         // Enable open-telemetry tracing on incoming requests.
         .wrap(Condition::new(ctx.config().distributed_tracing, OpenTelemetryMiddleware::new()))
 
-        // Ensure all endpoints return detailed Json request parse errors.
+        // Refuse oversized URIs and headers before anything else parses or logs them.
+        .wrap(limits::Middleware::new(ctx.config().max_uri_bytes, ctx.config().max_header_bytes))
+
+        // Ensure all endpoints return detailed Json and query string parse errors.
         .app_data(configure_json_extractor())
+        .app_data(configure_query_extractor())
 
         // Add the routes to this root url path.
-        .service(web::scope(&ctx.config().base_url).configure(configure_routes))
+        .service(web::scope(&ctx.config().base_url).configure(routes))
+
+        // Anything else gets a JSON 404 like any other error.
+        .default_service(web::route().to(unknown_route::handle))
             // .wrap(actix_web_opentelemetry::RequestTracing::new())
 }
 
@@ -0,0 +1,83 @@
+use subtle::ConstantTimeEq;
+use std::task::{Context, Poll};
+use actix_service::{Service, Transform};
+use futures::future::{err, ok, Either, Ready};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error, HttpMessage};
+use crate::utils::{context::RequestContext, errors::InternalError};
+
+/// The header callers must provide the admin token in.
+pub const ADMIN_TOKEN_HEADER: &str = "x-admin-token";
+
+///
+/// This middleware guards the admin endpoints with a shared-secret header.
+///
+/// If an admin_token is configured, requests must provide the same value in the x-admin-token
+/// header or be rejected with a 401. If no admin_token is configured the guard is disabled.
+///
+/// It relies on the request middleware having already placed a RequestContext in the request.
+///
+pub struct Middleware;
+
+impl<S: 'static, B> Transform<S> for Middleware
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AdminMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AdminMiddleware { service })
+    }
+}
+
+pub struct AdminMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for AdminMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        match authorised(&req) {
+            true  => Either::Left(self.service.call(req)),
+            false => Either::Right(err(InternalError::InvalidAdminToken.into())),
+        }
+    }
+}
+
+///
+/// Check the request's admin token header matches the configured admin token (if there is one).
+///
+fn authorised(req: &ServiceRequest) -> bool {
+    let admin_token = match req.extensions().get::<RequestContext>() {
+        Some(ctx) => ctx.config().admin_token.clone(),
+        None => return false,
+    };
+
+    match admin_token {
+        None => true,
+        Some(admin_token) => match req.headers().get(ADMIN_TOKEN_HEADER) {
+            // Compared in constant time so the token can't be guessed a byte at a time from response times.
+            Some(header_value) => header_value.as_bytes().ct_eq(admin_token.as_bytes()).into(),
+            None => false,
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,91 @@
+use std::rc::Rc;
+use std::pin::Pin;
+use std::cell::RefCell;
+use std::time::Duration;
+use tracing::debug;
+use std::task::{Context, Poll};
+use actix_service::{Service, Transform};
+use futures::future::{ok, Future, Ready};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error, HttpMessage, ResponseError};
+use crate::utils::{context::RequestContext, endpoints, errors::InternalError};
+
+///
+/// This middleware injects the faults configured through the /admin/chaos endpoints - delaying requests
+/// to an endpoint and/or refusing a fraction of them with a 503. So callers' timeouts and retries can be
+/// exercised end-to-end.
+///
+/// It's only wrapped if allow_test_endpoints is configured and relies on the request middleware having
+/// already placed a RequestContext in the request.
+///
+pub struct Middleware;
+
+impl<S: 'static, B> Transform<S> for Middleware
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ChaosMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ChaosMiddleware { service: Rc::new(RefCell::new(service)) })
+    }
+}
+
+pub struct ChaosMiddleware<S> {
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for ChaosMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let mut svc = self.service.clone();
+
+        Box::pin(async move {
+            let (endpoint, delay, fail) = match fault(&req) {
+                Some(fault) => fault,
+                None => return svc.call(req).await,
+            };
+
+            debug!("Injecting a {}ms delay{} into endpoint {}", delay.as_millis(), if fail { " and failure" } else { "" }, endpoint);
+
+            if !delay.is_zero() {
+                actix_rt::time::delay_for(delay).await;
+            }
+
+            match fail {
+                true  => Ok(req.into_response(InternalError::FaultInjected { endpoint }.error_response().into_body())),
+                false => svc.call(req).await,
+            }
+        })
+    }
+}
+
+///
+/// The endpoint the request is for and the delay and failure to inject - if it has a fault.
+///
+fn fault(req: &ServiceRequest) -> Option<(String, Duration, bool)> {
+    let extensions = req.extensions();
+    let ctx = extensions.get::<RequestContext>()?;
+    let endpoint = endpoints::handler_name(req.method(), &req.match_pattern()?, &ctx.config().base_url)?;
+    let (delay, fail) = ctx.chaos()?.next(endpoint)?;
+    Some((endpoint.to_string(), delay, fail))
+}
@@ -0,0 +1,177 @@
+use std::rc::Rc;
+use std::pin::Pin;
+use std::cell::RefCell;
+use itertools::Itertools;
+use std::task::{Context, Poll};
+use actix_service::{Service, Transform};
+use futures::future::{ok, Future, Ready};
+use crate::utils::{config::Configuration, context::RequestContext};
+use actix_http::http::{header, HeaderMap, HeaderName, HeaderValue, Method};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error, HttpMessage, HttpResponse};
+use super::request::REQUEST_ID_HEADER;
+
+///
+/// This middleware allows browsers to call the business endpoints from the configured origins.
+///
+/// Preflight (OPTIONS) requests from an allowed origin are answered here - the handlers never see
+/// them. Other requests from an allowed origin are handled as normal with the CORS headers added to
+/// the response. Requests without an Origin (or from any other origin) are untouched, so if no
+/// origins are configured server-to-server callers see no difference.
+///
+/// It relies on the request middleware having already placed a RequestContext in the request.
+///
+pub struct Middleware;
+
+impl<S: 'static> Transform<S> for Middleware
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CorsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CorsMiddleware { service: Rc::new(RefCell::new(service)) })
+    }
+}
+
+pub struct CorsMiddleware<S> {
+    service: Rc<RefCell<S>>,
+}
+
+impl<S> Service for CorsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let mut svc = self.service.clone();
+
+        Box::pin(async move {
+            match cors(&req) {
+                None => svc.call(req).await,
+                Some(Cors::Preflight(response)) => Ok(req.into_response(response.into_body())),
+                Some(Cors::Actual(headers)) => {
+                    let mut res = svc.call(req).await?;
+                    for (name, value) in headers.iter() {
+                        res.headers_mut().append(name.clone(), value.clone());
+                    }
+                    Ok(res)
+                }
+            }
+        })
+    }
+}
+
+///
+/// How a request from an allowed origin is handled.
+///
+enum Cors {
+    Preflight(HttpResponse), // Answered without calling the handler.
+    Actual(HeaderMap),       // Handled as normal with these headers added to the response.
+}
+
+///
+/// If CORS is enabled and the request's origin is allowed, determine how to respond.
+///
+fn cors(req: &ServiceRequest) -> Option<Cors> {
+    let extensions = req.extensions();
+    let config = match extensions.get::<RequestContext>() {
+        Some(ctx) if ctx.config().cors_enabled() => ctx.config(),
+        _ => return None,
+    };
+
+    let origin = req.headers().get(header::ORIGIN)?;
+    if !config.cors_allows_origin(origin.to_str().ok()?) {
+        return None
+    }
+
+    if is_preflight(req) {
+        return Some(Cors::Preflight(preflight(req, config, origin)))
+    }
+
+    let mut headers = HeaderMap::new();
+    add_cors_headers(&mut headers, config, origin);
+    headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static(REQUEST_ID_HEADER));
+    Some(Cors::Actual(headers))
+}
+
+fn is_preflight(req: &ServiceRequest) -> bool {
+    req.method() == Method::OPTIONS && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+///
+/// Answer a preflight request. If the method or any of the headers requested aren't allowed then
+/// the response has no CORS headers and the browser won't make the actual request.
+///
+fn preflight(req: &ServiceRequest, config: &Configuration, origin: &HeaderValue) -> HttpResponse {
+    let method_allowed = req.headers()
+        .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+        .and_then(|method| Method::from_bytes(method.as_bytes()).ok())
+        .map(|method| config.cors_methods().contains(&method))
+        .unwrap_or(false);
+
+    let headers_allowed = req.headers()
+        .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+        .map(|headers| match headers.to_str() {
+            Ok(headers) => headers.split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .all(|name| match HeaderName::from_bytes(name.as_bytes()) {
+                    Ok(name) => config.cors_headers().contains(&name),
+                    Err(_) => false,
+                }),
+            Err(_) => false,
+        })
+        .unwrap_or(true);
+
+    if !method_allowed || !headers_allowed {
+        return HttpResponse::Forbidden().finish()
+    }
+
+    let mut response = HttpResponse::NoContent().finish();
+    let headers = response.headers_mut();
+    add_cors_headers(headers, config, origin);
+
+    if let Ok(methods) = HeaderValue::from_str(&config.cors_methods().iter().join(",")) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+    }
+
+    if let Ok(allowed) = HeaderValue::from_str(&config.cors_headers().iter().join(",")) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed);
+    }
+
+    headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(config.cors_max_age));
+    response
+}
+
+///
+/// The headers common to preflight and actual responses. A wildcard origin can't be used with
+/// credentials, so in that case the caller's origin is echoed back instead.
+///
+fn add_cors_headers(headers: &mut HeaderMap, config: &Configuration, origin: &HeaderValue) {
+    match config.cors_allows_any_origin() && !config.cors_allow_credentials {
+        true  => headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*")),
+        false => {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        },
+    };
+
+    if config.cors_allow_credentials {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+    }
+}
@@ -0,0 +1,141 @@
+use std::rc::Rc;
+use std::pin::Pin;
+use std::io::Read;
+use std::cell::RefCell;
+use std::task::{Context, Poll};
+use futures::stream::{self, StreamExt};
+use actix_service::{Service, Transform};
+use futures::future::{ok, ready, Future, Ready};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use actix_web::{dev::{Payload, ServiceRequest, ServiceResponse}, web::{Bytes, BytesMut}, Error, HttpMessage};
+use actix_http::http::{HeaderValue, header::{CONTENT_ENCODING, CONTENT_LENGTH}};
+use crate::utils::errors::InternalError;
+
+///
+/// This middleware decompresses gzip and deflate encoded request bodies so the JSON extractors (and the
+/// tracer) see plain JSON.
+///
+/// Only the configured encodings are accepted - any other gets a 415. A body which decompresses to more
+/// than max_bytes is refused with a 413 rather than being inflated, so a small zip-bomb can't exhaust
+/// the worker.
+///
+pub struct Middleware {
+    encodings: Rc<Vec<String>>,
+    max_bytes: usize,
+}
+
+impl Middleware {
+    pub fn new(encodings: &[String], max_bytes: usize) -> Self {
+        Middleware { encodings: Rc::new(encodings.to_vec()), max_bytes }
+    }
+}
+
+impl<S: 'static, B> Transform<S> for Middleware
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = EncodingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(EncodingMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            encodings: self.encodings.clone(),
+            max_bytes: self.max_bytes,
+        })
+    }
+}
+
+pub struct EncodingMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    encodings: Rc<Vec<String>>,
+    max_bytes: usize,
+}
+
+impl<S, B> Service for EncodingMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let mut svc = self.service.clone();
+        let encodings = self.encodings.clone();
+        let max_bytes = self.max_bytes;
+
+        Box::pin(async move {
+            let encoding = match req.headers().get(CONTENT_ENCODING) {
+                Some(value) => value.to_str().unwrap_or_default().trim().to_ascii_lowercase(),
+                None => return svc.call(req).await,
+            };
+
+            if encoding == "identity" {
+                return svc.call(req).await
+            }
+
+            if !encodings.contains(&encoding) {
+                return Err(InternalError::UnsupportedEncoding { encoding }.into())
+            }
+
+            // Inflate the body and pass it on as though it was sent uncompressed.
+            let body = read_body(&mut req, max_bytes).await?;
+            let body = decompress(&encoding, &body, max_bytes)?;
+
+            req.headers_mut().remove(CONTENT_ENCODING);
+            req.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+            req.set_payload(Payload::Stream(Box::pin(stream::once(ready(Ok(body))))));
+
+            svc.call(req).await
+        })
+    }
+}
+
+///
+/// Read the compressed body - which can't be larger than the decompressed limit either.
+///
+async fn read_body(req: &mut ServiceRequest, max_bytes: usize) -> Result<Bytes, InternalError> {
+    let mut body = BytesMut::new();
+    let mut stream = req.take_payload();
+
+    while let Some(chunk) = stream.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > max_bytes {
+            return Err(InternalError::BodyTooLarge { limit: max_bytes })
+        }
+    }
+
+    Ok(body.freeze())
+}
+
+fn decompress(encoding: &str, body: &[u8], max_bytes: usize) -> Result<Bytes, InternalError> {
+    let decoder: Box<dyn Read + '_> = match encoding {
+        "gzip"    => Box::new(GzDecoder::new(body)),
+        "deflate" => Box::new(ZlibDecoder::new(body)),
+        _         => return Err(InternalError::UnsupportedEncoding { encoding: encoding.to_string() }),
+    };
+
+    // Read no more than one byte over the limit - enough to know it's been exceeded.
+    let mut decompressed = Vec::new();
+    decoder.take(max_bytes as u64 + 1).read_to_end(&mut decompressed)
+        .map_err(|err| InternalError::RequestFormatError { reason: format!("Unable to decompress the {} body: {}", encoding, err) })?;
+
+    match decompressed.len() > max_bytes {
+        true  => Err(InternalError::BodyTooLarge { limit: max_bytes }),
+        false => Ok(Bytes::from(decompressed)),
+    }
+}
@@ -0,0 +1,84 @@
+use std::task::{Context, Poll};
+use actix_service::{Service, Transform};
+use futures::future::{err, ok, Either, Ready};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error};
+use crate::utils::errors::InternalError;
+
+///
+/// This middleware refuses requests whose URI (including the query string) or headers are larger than
+/// configured - before they're routed, traced or logged - with a 414 or 431.
+///
+pub struct Middleware {
+    max_uri_bytes: usize,
+    max_header_bytes: usize,
+}
+
+impl Middleware {
+    pub fn new(max_uri_bytes: usize, max_header_bytes: usize) -> Self {
+        Middleware { max_uri_bytes, max_header_bytes }
+    }
+}
+
+impl<S: 'static, B> Transform<S> for Middleware
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = LimitsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(LimitsMiddleware { service, max_uri_bytes: self.max_uri_bytes, max_header_bytes: self.max_header_bytes })
+    }
+}
+
+pub struct LimitsMiddleware<S> {
+    service: S,
+    max_uri_bytes: usize,
+    max_header_bytes: usize,
+}
+
+impl<S, B> Service for LimitsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        match check_limits(&req, self.max_uri_bytes, self.max_header_bytes) {
+            Ok(())   => Either::Left(self.service.call(req)),
+            Err(error) => Either::Right(err(error.into())),
+        }
+    }
+}
+
+fn check_limits(req: &ServiceRequest, max_uri_bytes: usize, max_header_bytes: usize) -> Result<(), InternalError> {
+    let uri_bytes = req.uri().path_and_query().map(|path| path.as_str().len()).unwrap_or_default();
+    if uri_bytes > max_uri_bytes {
+        return Err(InternalError::UriTooLong { length: uri_bytes, limit: max_uri_bytes })
+    }
+
+    let header_bytes: usize = req.headers().iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum();
+
+    if header_bytes > max_header_bytes {
+        return Err(InternalError::HeadersTooLarge { length: header_bytes, limit: max_header_bytes })
+    }
+
+    Ok(())
+}
@@ -1,2 +1,8 @@
+pub mod admin;
+pub mod chaos;
+pub mod cors;
+pub mod encoding;
+pub mod limits;
 pub mod request;
-pub mod response;
\ No newline at end of file
+pub mod response;
+pub mod timeout;
\ No newline at end of file
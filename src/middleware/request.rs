@@ -2,26 +2,36 @@ use uuid::Uuid;
 use std::rc::Rc;
 use std::pin::Pin;
 use std::cell::RefCell;
+use std::time::{Duration, Instant};
 use itertools::Itertools;
 use tracing::{info, trace};
 use std::task::{Context, Poll};
-use futures::stream::StreamExt;
+use futures::stream::{self, StreamExt};
 use actix_service::{Service, Transform};
-use futures::future::{ok, Future, Ready};
-use actix_web::web::{Bytes, BytesMut, Data};
-use actix_http::http::{HeaderName, HeaderValue};
-use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error, HttpMessage};
-use crate::{routes::admin::tracer::{prelude::*, tracer_on}, utils::context::{PartialRequestContext, RequestContext}};
+use futures::future::{ok, ready, Future, Ready};
+use actix_web::{dev::Payload, web::{Bytes, BytesMut, Data}};
+use actix_http::http::{HeaderName, HeaderValue, header::{ACCEPT_LANGUAGE, CONTENT_TYPE}};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error, HttpMessage, ResponseError};
+use crate::{model::account::ENUM_CASING, routes::admin::{inflight::InFlight, tracer::{self, prelude::*, tracer_on}}, utils::{audit, endpoints, context::{PartialRequestContext, RequestContext}, errors::{accept_languages, InternalError, ACCEPT_LANGUAGES}}};
 
 /// The header set by the middleware
 pub const REQUEST_ID_HEADER: &str = "x-correlation-id";
 
+/// The (optional) response header naming the handler which served the request.
+pub const HANDLER_HEADER: &str = "x-handler";
+
 ///
 /// This middleware servers a number of purposes.
 /// - It ensures a request has a unique request id.
 /// - It ensures the response contains the same request id.
+/// - It copies any configured echo headers from the request to the response.
 /// - It constructs a RequestContext used by HTTP handlers.
 /// - It traces the request with tracer if approriate.
+/// - It refuses requests to any endpoints which have been disabled.
+/// - It notes the client's languages so any error messages can be localised.
+/// - It names the handler in an X-Handler response header if configured.
+/// - It writes an audit event for any audited endpoints once they're handled.
+/// - It counts the request as in-flight until it's handled.
 ///
 pub struct Middleware {
     ctx: Data<PartialRequestContext>
@@ -80,23 +90,93 @@ where
         let ctx = self.ctx.clone();
 
         Box::pin(async move {
+            // Count the request as in-flight until this future completes (or is dropped).
+            let _inflight = InFlight::start();
+
             // Ensure the request has a request id - generate or use provided.
             let request_id = ensure_request_has_id(&mut req);
 
+            // Take a copy of any headers to echo back.
+            let echoes = echo_headers(&req, ctx.borrow().config().echo_headers());
+
+            // And any headers handlers can read from the context.
+            let headers = context_headers(&req, ctx.borrow().config().context_headers());
+
+            // Never trace the bodies of endpoints which handle secrets.
+            let endpoint = req.match_pattern().and_then(|pattern| endpoints::handler_name(req.method(), &pattern, &ctx.borrow().config().base_url));
+            if let Some(endpoint) = endpoint {
+                if ctx.borrow().config().untraced_bodies().iter().any(|untraced| untraced == endpoint) {
+                    tracer::untrace_body(&req);
+                }
+            }
+
             // Trace the request if appropriate
-            let tracer = trace(&mut req).await;
+            let (max_bytes, timeout) = {
+                let ctx = ctx.borrow();
+                (ctx.config().trace_max_body_bytes, Duration::from_secs(ctx.config().trace_body_timeout))
+            };
+            let tracer = trace(&mut req, max_bytes, timeout).await;
+
+            // Capture the body of an audited request - redacted ready to log once it's been handled.
+            let audited = audit::audited(&req, endpoint, ctx.borrow().config());
+            let audit_body = match audited {
+                true => {
+                    let (body, timed_out) = read_body(&mut req, max_bytes, timeout).await;
+                    let complete = !timed_out && body.len() < max_bytes;
+                    Some(audit::redact_body(&body, complete, ctx.borrow().config().audit_redacted_fields()))
+                },
+                false => None,
+            };
 
             // Create a RequestContext extractor for the request.
             req.extensions_mut().insert(RequestContext::from(
                 ctx.borrow_mut().clone(),
                 request_id.clone(),
-                tracer));
+                tracer,
+                headers));
+
+            // Forward the call now - unless the endpoint has been switched off.
+            let disabled = endpoint.filter(|endpoint| ctx.borrow().is_endpoint_disabled(endpoint));
 
-            // Forward the call now.
-            let mut res = svc.call(req).await?;
+            let languages = req.headers()
+                .get(ACCEPT_LANGUAGE)
+                .and_then(|value| value.to_str().ok())
+                .map(accept_languages)
+                .unwrap_or_default();
 
-            // Mirror the request id onto the response.
+            let casing = ctx.borrow().config().enum_casing;
+            let mut res = ACCEPT_LANGUAGES.scope(languages, ENUM_CASING.scope(casing, async move {
+                match disabled {
+                    Some(endpoint) => Ok(req.into_response(InternalError::EndpointDisabled { endpoint: endpoint.to_string() }.error_response().into_body())),
+                    None => svc.call(req).await,
+                }
+            })).await?;
+
+            // Mirror the request id and any echo headers onto the response.
             ensure_response_has_id(&mut res, &request_id);
+            for (name, value) in echoes {
+                res.headers_mut().insert(name, value);
+            }
+
+            // Name the handler on the response if configured.
+            let ctx = ctx.borrow();
+            if ctx.config().handler_header {
+                ensure_response_has_handler(&mut res, &ctx.config().base_url);
+            }
+
+            // Audit the request - attributed to whoever the handler's claim check identified.
+            if let Some(body) = audit_body {
+                let request = res.request();
+                let principal = request.extensions().get::<RequestContext>().and_then(|ctx| ctx.principal());
+                audit::audit(
+                    &request_id,
+                    principal.as_deref().unwrap_or(&ctx.config().system_identity),
+                    request.method().as_str(),
+                    &request.match_pattern().unwrap_or_default(),
+                    request.path(),
+                    res.status().as_u16(),
+                    &body);
+            }
 
             Ok(res)
         })
@@ -172,38 +252,106 @@ fn ensure_response_has_id<B>(res: &mut ServiceResponse<B>, request_id: &str) {
     }
 }
 
+///
+/// The configured headers which are present on the request - and their values.
+///
+fn echo_headers(req: &ServiceRequest, names: &[HeaderName]) -> Vec<(HeaderName, HeaderValue)> {
+    names.iter()
+        .filter_map(|name| req.headers().get(name).map(|value| (name.clone(), value.clone())))
+        .collect()
+}
+
+///
+/// The configured headers which are present on the request (with a text value) for the RequestContext.
+///
+fn context_headers(req: &ServiceRequest, names: &[HeaderName]) -> Vec<(HeaderName, String)> {
+    names.iter()
+        .filter_map(|name| req.headers().get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| (name.clone(), value.to_string())))
+        .collect()
+}
+
+///
+/// Put the name of the handler on the response.
+///
+fn ensure_response_has_handler<B>(res: &mut ServiceResponse<B>, base_url: &str) {
+    let handler = match res.request().match_pattern().and_then(|pattern| endpoints::handler_name(res.request().method(), &pattern, base_url)) {
+        Some(handler) => handler,
+        None => return, // No route matched.
+    };
+
+    match HeaderValue::from_str(handler) {
+        Ok(value) => { res.headers_mut().insert(HeaderName::from_static(HANDLER_HEADER), value); },
+        Err(err) => trace!("Unable to set header value for handler {} : {}", handler, err.to_string()),
+    };
+}
+
 ///
 /// To trace a payload we must read it from the stream then reconstruct it and set it back.
 ///
-/// If the the request qualifies for tracing, returns true.
+/// At most max_bytes of the body are read (and only for up to the timeout) so a huge or slow body
+/// can't exhaust the worker - whatever hasn't been read is left in the stream and passed on to the
+/// handler after the part which was.
 ///
-async fn trace(req: &mut ServiceRequest) -> bool {
-    if tracer_on(req.headers()) {
-        let mut body = BytesMut::new();
-        let mut stream = req.take_payload();
-        while let Some(chunk) = stream.next().await {
-            match chunk {
-                Ok(chunk) => body.extend_from_slice(&chunk),
-                Err(err) => trace!("Unable to read payload: {}", err.to_string()),
-            };
-        }
+/// If the the request qualifies for tracing, returns true. The body of a request marked as untraced
+/// isn't read or logged - only it's headers.
+///
+async fn trace(req: &mut ServiceRequest, max_bytes: usize, timeout: Duration) -> bool {
+    if !tracer_on(req) {
+        return false
+    }
+
+    let body = match tracer::body_untraced(req) {
+        true => format!("\n{}", UNTRACED_BODY),
+        false => {
+            let (body, timed_out) = read_body(req, max_bytes, timeout).await;
+
+            format!("{body}{cut}",
+                body = format_body(req, &body.slice(..body.len().min(max_bytes))),
+                cut  = match (timed_out, body.len() >= max_bytes) {
+                    (true, _) => format!("\n<body not received within {}s>", timeout.as_secs()),
+                    (_, true) => format!("\n<body truncated at {} bytes>", max_bytes),
+                    _         => String::new(),
+                })
+        },
+    };
 
-        let body = body.freeze();
+    info!("Request received from {addr}\n{in}{url}\n{headers}{body}\n",
+        addr = req.connection_info().realip_remote_addr().unwrap_or("unknown"),
+        in   = *IN,
+        url  = format_path(req),
+        headers = format_headers(req),
+        body = body);
 
-        info!("Request received from {addr}\n{in}{url}\n{headers}{body}\n",
-            addr = req.connection_info().realip_remote_addr().unwrap_or("unknown"),
-            in   = *IN,
-            url  = format_path(&req),
-            headers = format_headers(&req),
-            body = format_body(&body));
+    true
+}
+
+///
+/// Read at most max_bytes of the body (for up to the timeout) then rebuild the request's payload, so
+/// the handler still receives all of it. Returns what was read and if the timeout was reached.
+///
+async fn read_body(req: &mut ServiceRequest, max_bytes: usize, timeout: Duration) -> (Bytes, bool) {
+    let mut body = BytesMut::new();
+    let mut stream = req.take_payload();
+    let deadline = Instant::now() + timeout;
+    let mut timed_out = false;
 
-        // Rebuild the request as we've just consumed the stream.
-        let mut payload = actix_http::h1::Payload::empty();
-        payload.unread_data(body);
-        req.set_payload(payload.into());
-        return true
+    while body.len() < max_bytes {
+        match actix_rt::time::timeout(deadline.saturating_duration_since(Instant::now()), stream.next()).await {
+            Ok(Some(Ok(chunk))) => body.extend_from_slice(&chunk),
+            Ok(Some(Err(err))) => trace!("Unable to read payload: {}", err.to_string()),
+            Ok(None) => break,
+            Err(_) => { timed_out = true; break },
+        };
     }
-    false
+
+    // Rebuild the request as we've just consumed (some of) the stream.
+    let body = body.freeze();
+    let payload = stream::once(ready(Ok(body.clone()))).chain(stream);
+    req.set_payload(Payload::Stream(Box::pin(payload)));
+
+    (body, timed_out)
 }
 
 fn format_path(req: &ServiceRequest) -> String {
@@ -220,10 +368,11 @@ fn format_headers(req: &ServiceRequest) -> String {
         .join("\n")
 }
 
-fn format_body(body: &Bytes) -> String {
+fn format_body(req: &ServiceRequest, body: &Bytes) -> String {
     if body.is_empty() {
         return String::new();
     }
 
-    format!("\n{}", String::from_utf8(body.to_vec()).unwrap_or(String::from("cant read body")))
+    let content_type = req.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok());
+    format!("\n{}", tracer::format_body(content_type, body))
 }
\ No newline at end of file
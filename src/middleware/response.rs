@@ -1,17 +1,28 @@
-use tracing::info;
+use tracing::{debug, info};
 use std::pin::Pin;
+use std::rc::Rc;
+use std::cell::Cell;
 use std::future::Future;
 use itertools::Itertools;
+use std::time::Instant;
 use std::marker::PhantomData;
 use actix_http::ResponseHead;
 use std::task::{Context, Poll};
-use futures::future::{ok, Ready};
+use futures::{StreamExt, future::{ok, Ready}};
 use actix_web::web::{Bytes, BytesMut};
 use actix_service::{Service, Transform};
-use crate::routes::admin::tracer::{colour_status, prelude::*, tracer_on};
+use actix_http::http::header::CONTENT_TYPE;
+use crate::routes::admin::tracer::{body_untraced, colour_status, format_body, prelude::*, tracer_on};
 use actix_web::body::{BodySize, MessageBody, ResponseBody};
-use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error};
+use actix_web::{dev::Payload, dev::ServiceRequest, dev::ServiceResponse, Error, HttpMessage};
 
+///
+/// This middleware logs responses (and their bodies) if the tracer is on. The bodies of endpoints which
+/// handle secrets (see untraced_bodies) are never logged - the request middleware marks those requests.
+///
+/// Regardless of the tracer, it records the size of every request and response body and emits a
+/// structured event with these, the status and the duration once the response has been sent.
+///
 pub struct Middleware;
 
 impl<S: 'static, B> Transform<S> for Middleware
@@ -49,8 +60,10 @@ where
         self.service.poll_ready(cx)
     }
 
-    fn call(&mut self, req: ServiceRequest) -> Self::Future {
-        let partial_log = match tracer_on(req.headers()) {
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let metrics = RequestMetrics::new(&mut req);
+
+        let partial_log = match tracer_on(&req) {
             false => None,
             true => {
                 let remote_addr = req.connection_info().realip_remote_addr().unwrap_or("unknown").to_string();
@@ -64,6 +77,7 @@ where
 
         WrapperStream {
             partial_log,
+            metrics,
             fut: self.service.call(req),
             _t: PhantomData,
         }
@@ -78,6 +92,7 @@ where
 {
     #[pin]
     partial_log: Option<String>,
+    metrics: RequestMetrics,
     #[pin]
     fut: S::Future,
     _t: PhantomData<(B,)>,
@@ -93,9 +108,12 @@ where
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let projected = self.project();
         let partial_log = projected.partial_log.clone();
+        let metrics = projected.metrics.clone();
         let res = futures::ready!(projected.fut.poll(cx));
 
         Poll::Ready(res.map(|res| {
+            let log_body = partial_log.is_some() && !body_untraced(res.request());
+
             res.map_body(move |resp_head, body| {
                 let more_log = match partial_log {
                     None => None,
@@ -105,10 +123,19 @@ where
                         format_headers(resp_head))),
                 };
 
+                let content_type = resp_head.headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .map(String::from);
+
                 ResponseBody::Body(BodyLogger {
                     more_log,
+                    log_body,
+                    content_type,
+                    metrics: metrics.with_status(resp_head.status.as_u16()),
                     body,
                     body_accum: BytesMut::new(),
+                    resp_bytes: 0,
                 })
             })
         }))
@@ -118,21 +145,28 @@ where
 #[pin_project::pin_project(PinnedDrop)]
 pub struct BodyLogger<B> {
     more_log: Option<String>,
+    log_body: bool,
+    content_type: Option<String>,
+    metrics: RequestMetrics,
     #[pin]
     body: ResponseBody<B>,
     body_accum: BytesMut,
+    resp_bytes: usize,
 }
 
 #[pin_project::pinned_drop]
 impl<B> PinnedDrop for BodyLogger<B> {
     fn drop(self: Pin<&mut Self>) {
         if let Some(more_log) = &self.more_log {
-            let body = match self.body_accum.len() {
-                0 => String::default(),
-                _ => format!("\n{}", String::from_utf8(self.body_accum.to_vec()).unwrap_or(String::from("cant read body")))
+            let body = match (self.log_body, self.body_accum.len()) {
+                (false, _) => format!("\n{}", UNTRACED_BODY),
+                (true, 0)  => String::default(),
+                (true, _)  => format!("\n{}", format_body(self.content_type.as_deref(), &self.body_accum))
             };
             info!("{}{}\n", more_log, body);
         }
+
+        self.metrics.record(self.resp_bytes);
     }
 }
 
@@ -146,7 +180,12 @@ impl<B: MessageBody> MessageBody for BodyLogger<B> {
 
         match this.body.poll_next(cx) {
             Poll::Ready(Some(Ok(chunk))) => {
-                this.body_accum.extend_from_slice(&chunk);
+                *this.resp_bytes += chunk.len();
+
+                // Only accumulate the body if it's going to be logged.
+                if *this.log_body {
+                    this.body_accum.extend_from_slice(&chunk);
+                }
                 Poll::Ready(Some(Ok(chunk)))
             }
             Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
@@ -156,6 +195,60 @@ impl<B: MessageBody> MessageBody for BodyLogger<B> {
     }
 }
 
+///
+/// Per-request telemetry. The request body is counted as the handler consumes it, the response body
+/// as it's sent.
+///
+#[derive(Clone)]
+struct RequestMetrics {
+    started: Instant,
+    method: String,
+    path: String,
+    status: u16,
+    req_bytes: Rc<Cell<usize>>,
+}
+
+impl RequestMetrics {
+    ///
+    /// Start the clock and wrap the request's payload so the bytes read from it are counted.
+    ///
+    fn new(req: &mut ServiceRequest) -> Self {
+        let req_bytes = Rc::new(Cell::new(0));
+        let counter = req_bytes.clone();
+
+        let payload = req.take_payload().inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                counter.set(counter.get() + chunk.len());
+            }
+        });
+        req.set_payload(Payload::Stream(Box::pin(payload)));
+
+        RequestMetrics {
+            started: Instant::now(),
+            method: req.method().to_string(),
+            path: req.path().to_string(),
+            status: 0,
+            req_bytes,
+        }
+    }
+
+    fn with_status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    fn record(&self, resp_bytes: usize) {
+        debug!(
+            method = %self.method,
+            path = %self.path,
+            status = self.status,
+            req_bytes = self.req_bytes.get(),
+            resp_bytes,
+            duration_ms = self.started.elapsed().as_millis() as u64,
+            "Request completed");
+    }
+}
+
 fn format_headers(rsp: &ResponseHead) -> String {
     rsp.headers()
         .iter()
@@ -0,0 +1,88 @@
+use std::rc::Rc;
+use std::pin::Pin;
+use std::cell::RefCell;
+use tracing::warn;
+use std::task::{Context, Poll};
+use actix_service::{Service, Transform};
+use futures::future::{ok, Future, Ready};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error};
+use crate::utils::{config::Configuration, endpoints, errors::InternalError};
+
+///
+/// This middleware refuses a request with a 503 if its handler takes longer than the endpoint's timeout -
+/// the handler_timeout unless the endpoint has its own in endpoint_timeouts. So slow endpoints (eg.
+/// accounts_import) can be given longer without loosening the rest.
+///
+/// The handler's future is dropped at the timeout, so any work it had yet to do is abandoned.
+///
+pub struct Middleware {
+    config: Rc<Configuration>,
+}
+
+impl Middleware {
+    pub fn new(config: &Configuration) -> Self {
+        Middleware { config: Rc::new(config.clone()) }
+    }
+}
+
+impl<S: 'static, B> Transform<S> for Middleware
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TimeoutMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(TimeoutMiddleware { service: Rc::new(RefCell::new(service)), config: self.config.clone() })
+    }
+}
+
+pub struct TimeoutMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    config: Rc<Configuration>,
+}
+
+impl<S, B> Service for TimeoutMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let mut svc = self.service.clone();
+
+        // Unmatched routes have no endpoint, so only the default applies.
+        let endpoint = req.match_pattern().and_then(|pattern| endpoints::handler_name(req.method(), &pattern, &self.config.base_url)).unwrap_or_default();
+        let timeout = self.config.handler_timeout_for(endpoint);
+
+        Box::pin(async move {
+            let timeout = match timeout {
+                Some(timeout) => timeout,
+                None => return svc.call(req).await,
+            };
+
+            match actix_rt::time::timeout(timeout, svc.call(req)).await {
+                Ok(result) => result,
+                Err(_) => {
+                    warn!("Endpoint {} did not complete within {}ms", endpoint, timeout.as_millis());
+                    Err(InternalError::HandlerTimeout { endpoint: endpoint.to_string(), timeout: timeout.as_millis() }.into())
+                },
+            }
+        })
+    }
+}
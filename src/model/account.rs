@@ -1,9 +1,10 @@
-use crate::utils::mongo::{bson_date, optional_bson_date};
-use chrono::{DateTime, Utc};
-use mongodb::bson::Bson;
-use serde::{Deserialize, Serialize};
+use crate::utils::{errors::InternalError, mongo::{bson_date, optional_bson_date, optional_json_date_as_bson}};
+use url::form_urlencoded;
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use mongodb::{bson::{Bson, Document, doc}, options::FindOptions};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
 use serde_with::skip_serializing_none;
-use super::{device::{Device, NewDevice}, external_id::ExternalId};
+use super::{device::{Device, NewDevice, RejectedDevice}, external_id::ExternalId, profile::prelude::PROFILE_ID};
 use prelude::*;
 
 pub mod prelude {
@@ -17,19 +18,51 @@ pub mod prelude {
     pub const STATUS: &str          = "status";
     pub const CREATED: &str         = "created";
     pub const MODIFIED: &str        = "modified";
+    pub const CREATED_BY: &str      = "createdBy";
+    pub const MODIFIED_BY: &str     = "modifiedBy";
+    pub const VERSION: &str         = "version";
+    pub const LAST_ACCESSED_AT: &str = "lastAccessedAt";
     pub const CREDENTIALS: &str     = "credentials";
     pub const DEVICES: &str         = "devices";
+    pub const STATUS_HISTORY: &str  = "statusHistory";
+    pub const EXTERNAL_IDS: &str    = "externalIds";
+    pub const SALUTATION: &str      = "salutation";
+    pub const BILLING_ADDRESS: &str = "billingAddress";
+    pub const BILLING_DATE: &str    = "billingDate";
+
+    // Fields only present while an account's id is being rotated.
+    pub const ROTATED_TO: &str            = "rotatedTo";
+    pub const ROTATING_DEVICES: &str      = "rotatingDevices";
+    pub const ROTATING_EXTERNAL_IDS: &str = "rotatingExternalIds";
 
     // Account statuses.
     pub const STATUS_ACTIVE: &str = "ACTIVE";
 
-    #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+    // Serialised in the configured EnumCasing (see AccountStatus::as_str) - either casing is accepted.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[allow(clippy::upper_case_acronyms)]
     pub enum AccountStatus {
+        PENDING,  // Awaiting verification.
         ACTIVE,
         RESTRICTED,
         SUSPENDED,
         CANCELLED // Terminal - status can't change from this value.
     }
+
+    // How enum values are written to JSON and MongoDB, eg. ACTIVE or Active.
+    #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum EnumCasing {
+        Uppercase, // The original casing - so all existing data is uppercase.
+        Titlecase
+    }
+}
+
+tokio::task_local! {
+    /// The configured enum_casing. Enums are serialised (by serde) without any access to the configuration,
+    /// so the request middleware sets this for the duration of each request - apps with different casings can
+    /// run side-by-side. Outside of a request, the original uppercase is used.
+    pub static ENUM_CASING: EnumCasing;
 }
 
 ///
@@ -46,6 +79,9 @@ pub struct NewAccount {
     pub billing_address: Option<Vec<AddressLine>>,
     pub external_ids: Option<Vec<ExternalId>>,
     pub devices: Option<Vec<NewDevice>>,
+
+    #[serde(default, serialize_with = "optional_json_date_as_bson")]
+    pub billing_date: Option<DateTime<Utc>>,
 }
 
 ///
@@ -58,6 +94,44 @@ pub struct StatusModification {
     pub status: AccountStatus
 }
 
+///
+/// The API schema for reactivating a cancelled account - the reason is kept in the status history.
+///
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Reactivation {
+    pub reason: String
+}
+
+///
+/// An entry in an account's status history - recorded each time the status is changed.
+///
+#[skip_serializing_none]
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusChange {
+    pub old_status: AccountStatus,
+    pub new_status: AccountStatus,
+    pub modified_by: String,
+    pub reason: Option<String>,
+
+    #[serde(deserialize_with = "bson_date")]
+    pub modified: DateTime<Utc>,
+}
+
+///
+/// The API schema for the outcome of a single item in a batch status update.
+///
+#[skip_serializing_none]
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusModificationResult {
+    pub account_id: String,
+    pub updated: bool,
+    pub error_code: Option<u16>,
+    pub message: Option<String>,
+}
+
 ///
 /// This is the public schema for retrieving an Account.
 ///
@@ -75,22 +149,357 @@ pub struct Account {
     pub devices: Option<Vec<Device>>,
     pub external_ids: Option<Vec<ExternalId>>,
     pub billing_address: Option<Vec<AddressLine>>,
+    pub created_by: Option<String>,
+    pub modified_by: Option<String>,
+    pub status_history: Option<Vec<StatusChange>>,
 
     #[serde(deserialize_with = "bson_date")]
     pub created: DateTime<Utc>,
 
     #[serde(default, deserialize_with = "optional_bson_date")]
     pub modified: Option<DateTime<Utc>>,
+
+    #[serde(default, deserialize_with = "optional_bson_date")]
+    pub billing_date: Option<DateTime<Utc>>,
+
+    #[serde(default, deserialize_with = "optional_bson_date")]
+    pub last_accessed_at: Option<DateTime<Utc>>,
+
+    #[serde(default, skip_serializing)]
+    pub version: i64, // Incremented by every change to the account - internal, only exposed as the ETag.
+}
+
+///
+/// The fields of an account which a JSON-patch may change - read back from the account once the patch has been
+/// applied (see patch_account).
+///
+#[skip_serializing_none]
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountPatch {
+    pub profile_id: String,
+    pub salutation: Option<String>,
+    pub billing_address: Option<Vec<AddressLine>>,
+    pub external_ids: Option<Vec<ExternalId>>,
+
+    #[serde(default, serialize_with = "optional_json_date_as_bson")]
+    pub billing_date: Option<DateTime<Utc>>,
+}
+
+///
+/// The API schema for a newly created account - along with any devices skipped because of partialDevices.
+///
+#[skip_serializing_none]
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreatedAccount {
+    #[serde(flatten)]
+    pub account: Account,
+    pub rejected_devices: Option<Vec<RejectedDevice>>,
+}
+
+///
+/// The API schema for an exported account - everything needed to re-create it in another environment with
+/// POST /accounts/import. Server-generated audit fields (created, modified, statusHistory, etc.) are left out.
+///
+#[skip_serializing_none]
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountExport {
+    pub account_id: String,
+    pub status: AccountStatus,
+    pub profile_id: String,
+    pub salutation: Option<String>,
+    pub billing_address: Option<Vec<AddressLine>>,
+    pub billing_date: Option<DateTime<Utc>>,
+    pub external_ids: Option<Vec<ExternalId>>,
+    pub devices: Option<Vec<Device>>,
+}
+
+impl From<Account> for AccountExport {
+    fn from(account: Account) -> Self {
+        AccountExport {
+            account_id: account.account_id,
+            status: account.status,
+            profile_id: account.profile_id,
+            salutation: account.salutation,
+            billing_address: account.billing_address,
+            billing_date: account.billing_date,
+            external_ids: account.external_ids,
+            devices: account.devices,
+        }
+    }
+}
+
+///
+/// The query parameters for listing (and searching) accounts. Results are ordered by accountId and may be
+/// paged with either skip or cursor (the last accountId of the previous page) - not both. Every filter given
+/// must match.
+///
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountQuery {
+    pub limit: Option<i64>,
+    pub skip: Option<u64>,
+    pub cursor: Option<String>,
+    pub status: Option<AccountStatus>,
+    pub profile_id: Option<String>,
+    pub modified_since: Option<DateTime<Utc>>,
+    pub created_from: Option<DateTime<Utc>>, // Inclusive.
+    pub created_to: Option<DateTime<Utc>>,   // Inclusive.
+}
+
+impl AccountQuery {
+    pub fn validate(&self) -> Result<(), InternalError> {
+        if self.skip.is_some() && self.cursor.is_some() {
+            return Err(InternalError::RequestFormatError { reason: "skip and cursor cannot be used together".to_string() })
+        }
+
+        if let (Some(created_from), Some(created_to)) = (self.created_from, self.created_to) {
+            if created_from > created_to {
+                return Err(InternalError::RequestFormatError { reason: "createdFrom cannot be after createdTo".to_string() })
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// The MongoDB filter and options to find a page of (at most limit) accounts with.
+    ///
+    /// Whatever the filters, the accounts are read in idx_accountId order - which a cursor seeks straight to.
+    /// The other filters aren't indexed, so they're checked against each account as the index is walked.
+    /// Selective filters (eg. a rarely used profileId) over a large collection may read many accounts to
+    /// fill a page.
+    ///
+    pub fn to_find(&self, limit: i64) -> (Document, FindOptions) {
+        let mut filter = doc!{};
+
+        if let Some(cursor) = &self.cursor {
+            filter.insert(ACCOUNT_ID, doc!{ "$gt": cursor });
+        }
+
+        if let Some(status) = self.status {
+            filter.insert(STATUS, status.filter());
+        }
+
+        if let Some(profile_id) = &self.profile_id {
+            filter.insert(PROFILE_ID, profile_id);
+        }
+
+        if let Some(modified_since) = self.modified_since {
+            filter.insert(MODIFIED, doc!{ "$gte": modified_since });
+        }
+
+        let mut created = doc!{};
+        if let Some(created_from) = self.created_from {
+            created.insert("$gte", created_from);
+        }
+
+        if let Some(created_to) = self.created_to {
+            created.insert("$lte", created_to);
+        }
+
+        if !created.is_empty() {
+            filter.insert(CREATED, created);
+        }
+
+        let options = FindOptions::builder()
+            .sort(doc!{ ACCOUNT_ID: 1 })
+            .limit(limit)
+            .skip(self.skip.map(|skip| skip as i64))
+            .build();
+
+        (filter, options)
+    }
+
+    ///
+    /// The MongoDB filter and options to find the accountIds of the last account on the page (of at most
+    /// limit) and the account after it - if there is one.
+    ///
+    pub fn to_lookahead(&self, limit: i64) -> (Document, FindOptions) {
+        let (filter, _) = self.to_find(limit);
+
+        let options = FindOptions::builder()
+            .sort(doc!{ ACCOUNT_ID: 1 })
+            .projection(doc!{ ACCOUNT_ID: 1, "_id": 0 })
+            .skip(self.skip.unwrap_or(0) as i64 + limit - 1)
+            .limit(2)
+            .build();
+
+        (filter, options)
+    }
+
+    ///
+    /// The query string for another page of this query - the filters are kept, the paging replaced.
+    ///
+    pub fn to_query_string(&self, limit: i64, skip: Option<u64>, cursor: Option<&str>) -> String {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        query.append_pair("limit", &limit.to_string());
+
+        if let Some(skip) = skip {
+            query.append_pair("skip", &skip.to_string());
+        }
+
+        if let Some(cursor) = cursor {
+            query.append_pair("cursor", cursor);
+        }
+
+        if let Some(status) = self.status {
+            query.append_pair("status", status.as_str());
+        }
+
+        if let Some(profile_id) = &self.profile_id {
+            query.append_pair("profileId", profile_id);
+        }
+
+        let dates = [("modifiedSince", self.modified_since), ("createdFrom", self.created_from), ("createdTo", self.created_to)];
+        for (name, date) in &dates {
+            if let Some(date) = date {
+                query.append_pair(name, &date.to_rfc3339_opts(SecondsFormat::Millis, true));
+            }
+        }
+
+        query.finish()
+    }
+}
+
+///
+/// The query parameters for creating an account.
+///
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateAccountQuery {
+    #[serde(default)]
+    pub return_existing_on_duplicate: bool, // If the accountId is taken, return that account rather than an error.
+
+    #[serde(default)]
+    pub partial_devices: bool, // Skip (and report) any invalid devices rather than failing the create.
+}
+
+///
+/// The query parameters for account creation statistics. Both dates are inclusive.
+///
+#[derive(Debug, Deserialize)]
+pub struct CreatedStatsQuery {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+}
+
+///
+/// The number of accounts created on a day (yyyy-mm-dd).
+///
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CreatedCount {
+    pub date: String,
+    pub count: i64,
+}
+
+impl Account {
+    ///
+    /// The filter matching the account - but not an original whose accountId is being (or failed part-way
+    /// through being) rotated, as the copy with the new accountId has replaced it.
+    ///
+    pub fn id_filter(account_id: &str) -> Document {
+        doc!{ ACCOUNT_ID: account_id, ROTATED_TO: { "$exists": false } }
+    }
+
+    ///
+    /// An entity tag for the current version of the account - it changes whenever the account is
+    /// modified, however close together (or if the clock is fixed).
+    ///
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self.version)
+    }
+
+    ///
+    /// The filter matching this account only while it's still at this version - so a write with it
+    /// fails if another has changed the account since it was read.
+    ///
+    pub fn version_filter(&self) -> Document {
+        let mut filter = Account::id_filter(&self.account_id);
+        filter.insert(VERSION, self.version);
+        filter
+    }
+}
+
+impl StatusChange {
+    ///
+    /// The history entry to $push onto the account as it's status changes.
+    ///
+    pub fn to_doc(old_status: AccountStatus, new_status: AccountStatus, modified_by: &str, reason: Option<&str>, modified: DateTime<Utc>) -> Document {
+        let mut doc = doc!{ "oldStatus": old_status, "newStatus": new_status, MODIFIED_BY: modified_by, MODIFIED: modified };
+        if let Some(reason) = reason {
+            doc.insert("reason", reason);
+        }
+        doc
+    }
+}
+
+impl AccountStatus {
+    pub const ALL: [AccountStatus; 5] = [AccountStatus::PENDING, AccountStatus::ACTIVE, AccountStatus::RESTRICTED, AccountStatus::SUSPENDED, AccountStatus::CANCELLED];
+
+    const UPPERCASE: [&'static str; 5] = ["PENDING", "ACTIVE", "RESTRICTED", "SUSPENDED", "CANCELLED"];
+
+    pub fn uppercase(self) -> &'static str {
+        match self {
+            AccountStatus::PENDING    => "PENDING",
+            AccountStatus::ACTIVE     => "ACTIVE",
+            AccountStatus::RESTRICTED => "RESTRICTED",
+            AccountStatus::SUSPENDED  => "SUSPENDED",
+            AccountStatus::CANCELLED  => "CANCELLED",
+        }
+    }
+
+    pub fn titlecase(self) -> &'static str {
+        match self {
+            AccountStatus::PENDING    => "Pending",
+            AccountStatus::ACTIVE     => "Active",
+            AccountStatus::RESTRICTED => "Restricted",
+            AccountStatus::SUSPENDED  => "Suspended",
+            AccountStatus::CANCELLED  => "Cancelled",
+        }
+    }
+
+    ///
+    /// The status as written to JSON and MongoDB - in the configured casing.
+    ///
+    pub fn as_str(self) -> &'static str {
+        match ENUM_CASING.try_with(|casing| *casing).unwrap_or(EnumCasing::Uppercase) {
+            EnumCasing::Uppercase => self.uppercase(),
+            EnumCasing::Titlecase => self.titlecase(),
+        }
+    }
+
+    ///
+    /// A MongoDB filter matching the status in either casing - stored accounts may pre-date (or not yet be
+    /// migrated to) the configured casing.
+    ///
+    pub fn filter(self) -> Bson {
+        Bson::Document(doc!{ "$in": [self.uppercase(), self.titlecase()] })
+    }
 }
 
 impl From<AccountStatus> for Bson {
     fn from(status: AccountStatus) -> Self {
-        match status {
-            AccountStatus::ACTIVE     => Bson::String("ACTIVE".to_string()),
-            AccountStatus::RESTRICTED => Bson::String("RESTRICTED".to_string()),
-            AccountStatus::SUSPENDED  => Bson::String("SUSPENDED".to_string()),
-            AccountStatus::CANCELLED  => Bson::String("CANCELLED".to_string()),
-        }
+        Bson::String(status.as_str().to_string())
+    }
+}
+
+impl Serialize for AccountStatus {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for AccountStatus {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+
+        AccountStatus::ALL.iter()
+            .find(|status| status.uppercase().eq_ignore_ascii_case(&value))
+            .copied()
+            .ok_or_else(|| de::Error::unknown_variant(&value, &AccountStatus::UPPERCASE))
     }
 }
 
@@ -0,0 +1,56 @@
+use serde_json::Value;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use mongodb::bson::{self, Document, doc};
+use crate::utils::{errors::InternalError, mongo::bson_date};
+use super::account::prelude::{MODIFIED, MODIFIED_BY};
+
+pub mod prelude {
+    // Account fields.
+    pub const CHANGE_HISTORY: &str = "changeHistory";
+
+    // Change fields.
+    pub const PATH: &str      = "path";
+    pub const OLD_VALUE: &str = "oldValue";
+    pub const NEW_VALUE: &str = "newValue";
+}
+
+use prelude::*;
+
+///
+/// A change to one of an account's fields - kept in the account's change history. Like notes, the history
+/// is only exposed via it's own endpoint - never in the Account itself.
+///
+/// There's no old value for a field which was added, or new value for one which was removed.
+///
+#[skip_serializing_none]
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldChange {
+    pub path: String,
+    pub old_value: Option<Value>,
+    pub new_value: Option<Value>,
+    pub modified_by: String,
+
+    #[serde(deserialize_with = "bson_date")]
+    pub modified: DateTime<Utc>,
+}
+
+impl FieldChange {
+    ///
+    /// The history entry to $push onto the account as the field at the path changes.
+    ///
+    pub fn to_doc(path: &str, old_value: Option<&Value>, new_value: Option<&Value>, modified_by: &str, modified: DateTime<Utc>)
+        -> Result<Document, InternalError> {
+
+        let mut doc = doc!{ PATH: path, MODIFIED_BY: modified_by, MODIFIED: modified };
+        if let Some(old_value) = old_value {
+            doc.insert(OLD_VALUE, bson::to_bson(old_value)?);
+        }
+        if let Some(new_value) = new_value {
+            doc.insert(NEW_VALUE, bson::to_bson(new_value)?);
+        }
+        Ok(doc)
+    }
+}
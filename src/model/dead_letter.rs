@@ -0,0 +1,51 @@
+use serde_json::Value;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::utils::mongo::bson_date;
+
+pub mod prelude {
+    pub const DEAD_LETTERS: &str = "DeadLetters";
+
+    // Dead letter fields.
+    pub const DEAD_LETTER_ID: &str = "deadLetterId";
+    pub const TOPIC: &str          = "topic";
+    pub const ROUTING_KEY: &str    = "routingKey";
+    pub const HEADERS: &str        = "headers";
+    pub const BODY: &str           = "body";
+    pub const REASON: &str         = "reason";
+    pub const FAILED_AT: &str      = "failedAt";
+
+    // Header fields.
+    pub const VERSION: &str        = "version";
+    pub const CORRELATION_ID: &str = "correlationId";
+    pub const REPLAY: &str         = "replay";
+}
+
+///
+/// A notification which couldn't be published to RabbitMQ. It's kept in MongoDB so it can be re-driven
+/// once the broker is reachable again.
+///
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeadLetter {
+    pub dead_letter_id: String,
+    pub topic: String,
+    pub routing_key: Option<String>,
+    pub headers: DeadLetterHeaders,
+    pub body: Value,
+    pub reason: String,
+
+    #[serde(deserialize_with = "bson_date")]
+    pub failed_at: DateTime<Utc>,
+}
+
+///
+/// The notification headers needed to publish the notification as it would have been originally.
+///
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeadLetterHeaders {
+    pub version: u16,
+    pub correlation_id: String,
+    pub replay: bool,
+}
@@ -10,7 +10,7 @@ pub mod prelude {
     pub const DEVICE_ID: &str = "deviceId";
     pub const ENABLED: &str   = "enabled";
 
-    #[derive(Debug, Deserialize, Serialize)]
+    #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
     pub enum DeviceType {
         SMARTPHONE,
         PC,
@@ -38,4 +38,16 @@ pub struct Device {
     pub device_type: DeviceType,
     pub enabled: bool,
     pub external_ids: Option<Vec<ExternalId>>,
-}
\ No newline at end of file
+}
+///
+/// A device skipped when an account is created with partialDevices - rather than failing the whole create.
+///
+#[skip_serializing_none]
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RejectedDevice {
+    pub index: usize, // The device's position in the request.
+    pub device_id: Option<String>,
+    pub error_code: u16,
+    pub message: Option<String>,
+}
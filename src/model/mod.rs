@@ -1,4 +1,9 @@
 pub mod account;
 pub mod device;
 pub mod profile;
-pub mod external_id;
\ No newline at end of file
+pub mod external_id;
+pub mod note;
+pub mod change;
+pub mod dead_letter;
+pub mod outbox;
+pub mod patch;
\ No newline at end of file
@@ -0,0 +1,37 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::utils::mongo::bson_date;
+
+pub mod prelude {
+    // Account fields.
+    pub const NOTES: &str = "notes";
+
+    // Note fields.
+    pub const TEXT: &str   = "text";
+    pub const AUTHOR: &str = "author";
+    pub const AT: &str     = "at";
+}
+
+///
+/// The API schema for POSTing a note to an account.
+///
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewNote {
+    pub text: String,
+    pub author: String,
+}
+
+///
+/// A free-text, case-management note on an account. Notes are stored on the account but are only
+/// exposed via the account's notes endpoint - never in the Account itself.
+///
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Note {
+    pub text: String,
+    pub author: String,
+
+    #[serde(deserialize_with = "bson_date")]
+    pub at: DateTime<Utc>,
+}
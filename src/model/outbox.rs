@@ -0,0 +1,37 @@
+use serde_json::Value;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::utils::mongo::bson_date;
+
+pub mod prelude {
+    // The field on an account holding the notifications waiting to be published.
+    pub const OUTBOX: &str = "outbox";
+
+    // Outbox entry fields.
+    pub const OUTBOX_ID: &str      = "outboxId";
+    pub const TOPIC: &str          = "topic";
+    pub const ROUTING_KEY: &str    = "routingKey";
+    pub const CORRELATION_ID: &str = "correlationId";
+    pub const BODY: &str           = "body";
+    pub const STAGED: &str         = "staged";
+}
+
+///
+/// A notification written to an account's outbox in the same MongoDB write as the change it describes.
+/// It's removed once RabbitMQ confirms it's been published.
+///
+/// The MongoDB driver doesn't expose multi-document transactions, so rather than a separate collection
+/// the outbox is a field on the account - a write to a single document is atomic.
+///
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxEntry {
+    pub outbox_id: String,
+    pub topic: String,
+    pub routing_key: Option<String>,
+    pub correlation_id: String,
+    pub body: Value,
+
+    #[serde(deserialize_with = "bson_date")]
+    pub staged: DateTime<Utc>,
+}
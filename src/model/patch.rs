@@ -0,0 +1,95 @@
+use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use crate::utils::errors::InternalError;
+
+///
+/// A JSON-patch (RFC 6902) operation. Only add, remove and replace are supported - the path is a JSON
+/// pointer, eg. /billingAddress/0/value.
+///
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOperation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+}
+
+impl PatchOperation {
+    pub fn path(&self) -> &str {
+        match self {
+            PatchOperation::Add { path, value: _ }     => path,
+            PatchOperation::Remove { path }            => path,
+            PatchOperation::Replace { path, value: _ } => path,
+        }
+    }
+
+    ///
+    /// The top-level field the operation changes, eg. billingAddress for /billingAddress/0. Empty for the
+    /// whole document.
+    ///
+    pub fn field(&self) -> String {
+        let path = self.path().strip_prefix('/').unwrap_or_default();
+        unescape(path.split('/').next().unwrap_or_default())
+    }
+
+    ///
+    /// Apply the operation to the document. The path (or for add, it's parent) must already exist.
+    ///
+    pub fn apply(&self, document: &mut Value) -> Result<(), InternalError> {
+        let path = self.path();
+        let (parent, token) = match path.rfind('/') {
+            Some(idx) => (&path[..idx], unescape(&path[idx + 1..])),
+            None => return Err(InternalError::RequestFormatError { reason: format!("The path {} is not a JSON pointer", path) }),
+        };
+
+        match (self, document.pointer_mut(parent)) {
+            (PatchOperation::Add { path: _, value }, Some(Value::Object(object))) => {
+                object.insert(token, value.clone());
+            },
+            (PatchOperation::Add { path, value }, Some(Value::Array(array))) => {
+                let idx = match token.as_str() {
+                    "-" => array.len(),
+                    _   => index(&token, array.len() + 1, path)?,
+                };
+                array.insert(idx, value.clone());
+            },
+            (PatchOperation::Remove { path }, Some(Value::Object(object))) => {
+                object.remove(&token).ok_or_else(|| missing(path))?;
+            },
+            (PatchOperation::Remove { path }, Some(Value::Array(array))) => {
+                array.remove(index(&token, array.len(), path)?);
+            },
+            (PatchOperation::Replace { path, value }, Some(Value::Object(object))) => {
+                *object.get_mut(&token).ok_or_else(|| missing(path))? = value.clone();
+            },
+            (PatchOperation::Replace { path, value }, Some(Value::Array(array))) => {
+                let idx = index(&token, array.len(), path)?;
+                array[idx] = value.clone();
+            },
+            _ => return Err(missing(path)),
+        };
+
+        Ok(())
+    }
+}
+
+///
+/// Decode a JSON pointer reference token - ~1 is a '/' and ~0 is a '~'.
+///
+fn unescape(token: &str) -> String {
+    token.replace("~1", "/").replace("~0", "~")
+}
+
+///
+/// The array index the token refers to - which must be below the bound given.
+///
+fn index(token: &str, bound: usize, path: &str) -> Result<usize, InternalError> {
+    match token.parse::<usize>() {
+        Ok(idx) if idx < bound => Ok(idx),
+        _ => Err(missing(path)),
+    }
+}
+
+fn missing(path: &str) -> InternalError {
+    InternalError::RequestFormatError { reason: format!("The path {} does not exist", path) }
+}
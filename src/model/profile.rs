@@ -1,4 +1,6 @@
 use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use super::device::prelude::DeviceType;
 
 pub mod prelude {
     // Collection names
@@ -12,14 +14,46 @@ pub mod prelude {
     pub const DEFAULT: &str = "DEFAULT";
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+///
+/// The settings accounts in the profile are subject to. Unset settings apply no restriction.
+///
+#[skip_serializing_none]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AccountProfile {
-    pub profile_id: Option<String>
+    pub profile_id: Option<String>,
+    pub max_devices: Option<u32>, // The most devices an account in the profile may have.
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+///
+/// The settings devices in the profile are subject to. Unset settings apply no restriction.
+///
+#[skip_serializing_none]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DeviceProfile {
-    profile_id: Option<String>
+    pub profile_id: Option<String>,
+    pub allowed_device_types: Option<Vec<DeviceType>>, // The types of device which can use the profile.
+    pub max_sessions: Option<u32>,                     // The most concurrent sessions a device in the profile may have.
+}
+
+impl DeviceProfile {
+    pub fn allows(&self, device_type: DeviceType) -> bool {
+        match &self.allowed_device_types {
+            Some(allowed) => allowed.contains(&device_type),
+            None => true,
+        }
+    }
+}
+
+///
+/// The profiles an account is subject to - its own account profile and the distinct device profiles
+/// used by its devices.
+///
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EffectiveProfile {
+    pub account_id: String,
+    pub account_profile: AccountProfile,
+    pub device_profiles: Vec<DeviceProfile>,
 }
\ No newline at end of file
@@ -0,0 +1,90 @@
+use serde::Serialize;
+use chrono::{DateTime, Utc};
+use mongodb::{bson::{self, Bson, doc}, options::FindOneOptions};
+use actix_web::{HttpResponse, dev::HttpResponseBuilder, http::StatusCode, web::{Path, Query}};
+use crate::{model::{account::{prelude::*, Account}, outbox::{prelude::*, OutboxEntry}}, routes::admin::correlation::{self, Emitted}, utils::{context::RequestContext, errors::InternalError, paging::{page_size, PageQuery, PAGE_SIZE_HEADER}}};
+
+///
+/// A notification emitted (or waiting to be) about an account.
+///
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountEvent {
+    topic: String,
+    version: u16,
+    timestamp: DateTime<Utc>,
+    correlation_id: String,
+
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pending: bool, // Staged in the account's outbox but not yet published.
+}
+
+///
+/// Http handler for listing the notifications emitted about an account - oldest first. Only the most recent
+/// (up to the page size) are listed.
+///
+/// Published notifications are remembered in memory (the last emitted_history of them), so older ones and
+/// those published by other instances aren't listed. If transactional_outbox is configured, the notifications
+/// staged in the account's outbox which haven't been published yet are also listed as pending.
+///
+#[tracing::instrument(name="get_account_events", skip(ctx), level="info")]
+pub async fn handle(Path(account_id): Path<String>, Query(query): Query<PageQuery>, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+
+    let limit = page_size(query.limit, ctx.config())?;
+
+    let mut events: Vec<AccountEvent> = correlation::for_account(&account_id).into_iter().map(AccountEvent::from).collect();
+
+    if ctx.config().transactional_outbox {
+        events.extend(get_pending(&account_id, &ctx).await?);
+        events.sort_by_key(|event| event.timestamp);
+    }
+
+    let events = events.split_off(events.len().saturating_sub(limit as usize));
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK)
+        .header(PAGE_SIZE_HEADER, limit.to_string())
+        .json(events))
+}
+
+///
+/// The notifications staged in the account's outbox which haven't been published yet.
+///
+async fn get_pending(account_id: &str, ctx: &RequestContext) -> Result<Vec<AccountEvent>, InternalError> {
+    let options = FindOneOptions::builder().projection(doc!{ OUTBOX: 1 }).build();
+
+    let entries = match ctx.db().collection(ACCOUNTS).find_one(Account::id_filter(account_id), options).await? {
+        Some(mut account) => account.remove(OUTBOX),
+        None => None,
+    };
+
+    match entries {
+        Some(Bson::Array(entries)) => entries.into_iter()
+            .map(|entry| Ok(bson::from_bson::<OutboxEntry>(entry)?.into()))
+            .collect(),
+        _ => Ok(vec!()),
+    }
+}
+
+impl From<Emitted> for AccountEvent {
+    fn from(emitted: Emitted) -> Self {
+        AccountEvent {
+            topic: emitted.topic,
+            version: emitted.version,
+            timestamp: emitted.timestamp,
+            correlation_id: emitted.correlation_id,
+            pending: false,
+        }
+    }
+}
+
+impl From<OutboxEntry> for AccountEvent {
+    fn from(entry: OutboxEntry) -> Self {
+        AccountEvent {
+            topic: entry.topic,
+            version: 1,
+            timestamp: entry.staged,
+            correlation_id: entry.correlation_id,
+            pending: true,
+        }
+    }
+}
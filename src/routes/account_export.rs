@@ -0,0 +1,39 @@
+use actix_web::{HttpResponse, dev::HttpResponseBuilder, http::StatusCode, web::{Json, Path}};
+use super::{create_account::{create_account, duplicate}, get_account::get_account};
+use crate::{clients::auth::AuthClient, model::account::{AccountExport, NewAccount}, utils::{context::RequestContext, errors::InternalError}};
+
+///
+/// Http handler for exporting an account - to be imported into another environment.
+///
+#[tracing::instrument(name="export_account", level="info")]
+pub async fn handle_export(Path(account_id): Path<String>, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+    match get_account(&account_id, &ctx).await? {
+        Some(account) => Ok(HttpResponseBuilder::new(StatusCode::OK).json(AccountExport::from(account))),
+        None => Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish())
+    }
+}
+
+///
+/// Http handler for importing an exported account.
+///
+/// The account is validated exactly as a new account would be (its profiles must exist, devices are
+/// re-validated) and is attributed to the caller. Importing an accountId which already exists returns that
+/// account with a 200 - so an import can be safely re-run.
+///
+#[tracing::instrument(name="import_account", skip(account), level="info")]
+pub async fn handle_import(account: Json<NewAccount>, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+
+    // Do not allow unless the caller has the import-account permission.
+    let claims = AuthClient::new(&ctx).check_claim("import-account").await?;
+
+    let account_id = match &account.account_id {
+        Some(account_id) => account_id.clone(),
+        None => return Err(InternalError::RequestFormatError { reason: "An imported account must have an accountId".to_string() }),
+    };
+
+    match create_account(account.into_inner(), false, claims.principal(&ctx), &ctx).await {
+        Ok(account) => Ok(HttpResponseBuilder::new(StatusCode::CREATED).json(account)),
+        Err(InternalError::MongoDuplicateError { cause }) => duplicate(cause, Some(account_id), true, &ctx).await,
+        Err(err) => Err(err),
+    }
+}
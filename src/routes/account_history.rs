@@ -0,0 +1,36 @@
+use mongodb::{bson::{self, doc}, options::FindOneOptions};
+use actix_web::{HttpResponse, dev::HttpResponseBuilder, http::StatusCode, web::Path};
+use crate::{model::{account::{prelude::*, Account}, change::{prelude::*, FieldChange}}, utils::{context::RequestContext, errors::InternalError}};
+
+///
+/// Http handler for getting an account's change history.
+///
+#[tracing::instrument(name="get_account_history", level="info")]
+pub async fn handle(Path(account_id): Path<String>, ctx: RequestContext)
+    -> Result<HttpResponse, InternalError> {
+
+    match get_history(&account_id, &ctx).await? {
+        Some(history) => Ok(HttpResponseBuilder::new(StatusCode::OK).json(history)),
+
+        // Note: 204 rather than 404 (the latter indicates the uri isn't present not the content itself)
+        None => Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish())
+    }
+}
+
+///
+/// Return the changes made to the account's fields, oldest first. Only the most recent max_change_history
+/// are kept. None is returned if the account doesn't exist.
+///
+pub async fn get_history(account_id: &str, ctx: &RequestContext) -> Result<Option<Vec<FieldChange>>, InternalError> {
+
+    let options = FindOneOptions::builder().projection(doc!{ CHANGE_HISTORY: 1 }).build();
+    let account = ctx.db().collection(ACCOUNTS).find_one(Account::id_filter(account_id), options).await?;
+
+    match account {
+        None => Ok(None),
+        Some(account) => match account.get(CHANGE_HISTORY) {
+            None => Ok(Some(vec!())),
+            Some(history) => Ok(Some(bson::from_bson(history.clone())?)),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use mongodb::{bson::{self, doc}, options::FindOneOptions};
+use actix_web::{HttpResponse, dev::HttpResponseBuilder, http::StatusCode, web::{Json, Path}};
+use crate::{model::{account::{prelude::*, Account}, note::{prelude::*, NewNote, Note}}, utils::{context::RequestContext, errors::InternalError}};
+
+///
+/// Http handler for adding a note to an account.
+///
+#[tracing::instrument(name="add_account_note", skip(note), level="info")]
+pub async fn handle_add(Path(account_id): Path<String>, note: Json<NewNote>, ctx: RequestContext)
+    -> Result<HttpResponse, InternalError> {
+
+    let note = add_note(&account_id, note.into_inner(), &ctx).await?;
+
+    Ok(HttpResponseBuilder::new(StatusCode::CREATED).json(note))
+}
+
+///
+/// Http handler for getting an account's notes.
+///
+#[tracing::instrument(name="get_account_notes", level="info")]
+pub async fn handle_get(Path(account_id): Path<String>, ctx: RequestContext)
+    -> Result<HttpResponse, InternalError> {
+
+    match get_notes(&account_id, &ctx).await? {
+        Some(notes) => Ok(HttpResponseBuilder::new(StatusCode::OK).json(notes)),
+
+        // Note: 204 rather than 404 (the latter indicates the uri isn't present not the content itself)
+        None => Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish())
+    }
+}
+
+///
+/// Append a note to the account. Notes are append-only - once the account has the configured
+/// maximum, the oldest note is dropped to make room.
+///
+/// Notes are for operators only, so no notification is emitted.
+///
+pub async fn add_note(account_id: &str, note: NewNote, ctx: &RequestContext) -> Result<Note, InternalError> {
+
+    if note.text.trim().is_empty() {
+        return Err(InternalError::RequestFormatError { reason: "A note must have some text".to_string() })
+    }
+
+    let note = Note { text: note.text, author: note.author, at: ctx.now() };
+
+    let _lock = ctx.account_lock(account_id).await;
+    let _permit = ctx.write_permit().await?;
+    let result = ctx.db().collection(ACCOUNTS).update_one(
+        /* Filter  */ Account::id_filter(account_id),
+        /* Update  */ doc!{ "$push": { NOTES: {
+            "$each": [ { TEXT: &note.text, AUTHOR: &note.author, AT: note.at } ],
+            "$slice": -(ctx.config().max_account_notes as i64) } } },
+        /* Options */ None)
+        .await?;
+
+    match result.matched_count {
+        0 => Err(InternalError::AccountNotFound { account_id: account_id.to_string() }),
+        _ => Ok(note)
+    }
+}
+
+///
+/// Return the account's notes, oldest first. None is returned if the account doesn't exist.
+///
+pub async fn get_notes(account_id: &str, ctx: &RequestContext) -> Result<Option<Vec<Note>>, InternalError> {
+
+    let options = FindOneOptions::builder().projection(doc!{ NOTES: 1 }).build();
+    let account = ctx.db().collection(ACCOUNTS).find_one(Account::id_filter(account_id), options).await?;
+
+    match account {
+        None => Ok(None),
+        Some(account) => match account.get(NOTES) {
+            None => Ok(Some(vec!())),
+            Some(notes) => Ok(Some(bson::from_bson(notes.clone())?)),
+        }
+    }
+}
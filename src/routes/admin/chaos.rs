@@ -0,0 +1,114 @@
+use tracing::info;
+use parking_lot::Mutex;
+use std::{collections::{BTreeMap, HashMap}, time::Duration};
+use serde::{Deserialize, Serialize};
+use actix_http::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, dev::HttpResponseBuilder, web::{Json, Path}};
+use crate::utils::{context::RequestContext, errors::InternalError};
+
+/// Faults can't be injected into the chaos endpoints themselves - or they might not be clearable.
+const CHAOS_ENDPOINTS: &str = "admin_chaos";
+
+///
+/// A fault injected into an endpoint - to exercise callers' timeouts and retries end-to-end.
+///
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Fault {
+    #[serde(default)]
+    delay_ms: u64,   // Added before every request to the endpoint is handled.
+
+    #[serde(default)]
+    error_rate: f64, // The fraction of requests to the endpoint refused with a 503, eg. 0.1.
+
+    #[serde(skip)]
+    matched: u64,    // The requests the fault has applied to so far.
+}
+
+///
+/// The faults injected into endpoints, by handler name (eg. create_account). These only exist if
+/// allow_test_endpoints is configured - see the chaos middleware.
+///
+#[derive(Debug, Default)]
+pub struct Chaos {
+    faults: Mutex<HashMap<String, Fault>>,
+}
+
+impl Chaos {
+    ///
+    /// The delay to inject into this request to the endpoint and whether it should then fail - if the
+    /// endpoint has a fault. Failures are every nth request rather than random, so small samples still
+    /// see the configured rate.
+    ///
+    pub fn next(&self, endpoint: &str) -> Option<(Duration, bool)> {
+        let mut faults = self.faults.lock();
+        let fault = faults.get_mut(endpoint)?;
+
+        fault.matched += 1;
+        let fail = (fault.matched as f64 * fault.error_rate).floor() > ((fault.matched - 1) as f64 * fault.error_rate).floor();
+        Some((Duration::from_millis(fault.delay_ms), fail))
+    }
+
+    pub fn set(&self, endpoint: &str, fault: Option<Fault>) {
+        let mut faults = self.faults.lock();
+        match fault {
+            Some(fault) => faults.insert(endpoint.to_string(), fault),
+            None => faults.remove(endpoint),
+        };
+    }
+
+    ///
+    /// The faults - sorted by endpoint.
+    ///
+    pub fn faults(&self) -> BTreeMap<String, Fault> {
+        self.faults.lock().iter().map(|(endpoint, fault)| (endpoint.clone(), fault.clone())).collect()
+    }
+}
+
+///
+/// List the faults currently injected.
+///
+pub async fn handle_get(req: HttpRequest, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+    let chaos = chaos(&req, &ctx)?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(chaos.faults()))
+}
+
+///
+/// Inject a delay and/or error rate into an endpoint (by handler name, as shown in the X-Handler header) -
+/// replacing any fault it already has.
+///
+/// This endpoint only exists if allow_test_endpoints is configured, so it can't be used in production.
+///
+pub async fn handle_set(req: HttpRequest, Path(endpoint): Path<String>, fault: Json<Fault>, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+    let chaos = chaos(&req, &ctx)?;
+
+    if endpoint.starts_with(CHAOS_ENDPOINTS) {
+        return Err(InternalError::RequestFormatError { reason: format!("Faults cannot be injected into the {} endpoint", endpoint) })
+    }
+
+    if !(0.0..=1.0).contains(&fault.error_rate) {
+        return Err(InternalError::RequestFormatError { reason: format!("errorRate {} must be between 0 and 1", fault.error_rate) })
+    }
+
+    info!("Fault injected into endpoint {} by request {}: {:?}", endpoint, ctx.request_id(), fault);
+    chaos.set(&endpoint, Some(fault.into_inner()));
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(chaos.faults()))
+}
+
+///
+/// Stop injecting faults into an endpoint.
+///
+pub async fn handle_clear(req: HttpRequest, Path(endpoint): Path<String>, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+    let chaos = chaos(&req, &ctx)?;
+
+    info!("Faults cleared from endpoint {} by request {}", endpoint, ctx.request_id());
+    chaos.set(&endpoint, None);
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(chaos.faults()))
+}
+
+///
+/// Without allow_test_endpoints there are no faults and these endpoints don't exist.
+///
+fn chaos<'a>(req: &HttpRequest, ctx: &'a RequestContext) -> Result<&'a Chaos, InternalError> {
+    ctx.chaos().ok_or_else(|| InternalError::RouteNotFound { method: req.method().to_string(), path: req.path().to_string() })
+}
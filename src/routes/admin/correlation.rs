@@ -0,0 +1,75 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use parking_lot::RwLock;
+use lazy_static::lazy_static;
+use std::collections::VecDeque;
+use actix_http::http::StatusCode;
+use actix_web::{HttpResponse, dev::HttpResponseBuilder, web::Path};
+
+///
+/// A notification the publisher has sent to RabbitMQ.
+///
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Emitted {
+    pub correlation_id: String,
+    pub topic: String,
+    pub version: u16,
+    pub timestamp: DateTime<Utc>,
+
+    #[serde(skip)]
+    pub account_ids: Vec<String>, // The accounts the notification is about.
+}
+
+lazy_static! {
+    /// The most recently published notifications (oldest first) - written by the RabbitMQ publisher
+    /// thread, which can't reach the request contexts.
+    static ref EMITTED: RwLock<VecDeque<Emitted>> = RwLock::new(VecDeque::new());
+}
+
+///
+/// Remember a published notification, forgetting the oldest once there are more than capacity.
+///
+pub fn record(correlation_id: &str, topic: &str, version: u16, account_ids: Vec<String>, capacity: usize, timestamp: DateTime<Utc>) {
+    if capacity == 0 {
+        return
+    }
+
+    let mut emitted = EMITTED.write();
+    while emitted.len() >= capacity {
+        emitted.pop_front();
+    }
+
+    emitted.push_back(Emitted {
+        correlation_id: correlation_id.to_string(),
+        topic: topic.to_string(),
+        version,
+        timestamp,
+        account_ids,
+    });
+}
+
+///
+/// The recently published notifications about the account - oldest first.
+///
+pub fn for_account(account_id: &str) -> Vec<Emitted> {
+    EMITTED.read()
+        .iter()
+        .filter(|emitted| emitted.account_ids.iter().any(|id| id == account_id))
+        .cloned()
+        .collect()
+}
+
+///
+/// List the recently published notifications for the correlation id - oldest first. Only the last
+/// emitted_history notifications are known, so older ones won't be listed.
+///
+pub async fn handle(Path(correlation_id): Path<String>) -> HttpResponse {
+    let emitted: Vec<Emitted> = EMITTED.read()
+        .iter()
+        .filter(|emitted| emitted.correlation_id == correlation_id)
+        .cloned()
+        .collect();
+
+    HttpResponseBuilder::new(StatusCode::OK).json(emitted)
+}
@@ -0,0 +1,35 @@
+use tracing::info;
+use serde_json::json;
+use actix_http::http::StatusCode;
+use actix_web::{HttpResponse, dev::HttpResponseBuilder, web::Query};
+use crate::utils::{context::RequestContext, dead_letters::{delete_dead_letter, get_dead_letters}, errors::InternalError, paging::{page_size, PageQuery, PAGE_SIZE_HEADER}, rabbit::{FireAndForget, Notification}};
+
+///
+/// List a page of the notifications which couldn't be published to RabbitMQ - oldest first.
+///
+pub async fn handle_list(Query(query): Query<PageQuery>, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+    let limit = page_size(query.limit, ctx.config())?;
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK)
+        .header(PAGE_SIZE_HEADER, limit.to_string())
+        .json(get_dead_letters(ctx.db(), Some(limit)).await?))
+}
+
+///
+/// Re-publish every dead letter with it's original correlation id, removing it from the collection.
+///
+/// If RabbitMQ still can't be reached, a notification is dead-lettered again (with a new id).
+///
+pub async fn handle_redrive(ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+    let dead_letters = get_dead_letters(ctx.db(), None).await?;
+    let redriven = dead_letters.len();
+
+    for dead_letter in dead_letters {
+        let dead_letter_id = dead_letter.dead_letter_id.clone();
+        ctx.publisher().fire_and_forget(Notification::from_dead_letter(dead_letter, ctx.tracer()));
+        delete_dead_letter(ctx.db(), &dead_letter_id).await?;
+    }
+
+    info!("Re-drove {} dead letters", redriven);
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(json!({ "redriven": redriven })))
+}
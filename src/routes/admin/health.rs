@@ -2,23 +2,33 @@ use serde::Serialize;
 use serde_json::json;
 use std::collections::HashMap;
 use actix_web::{HttpResponse, dev::HttpResponseBuilder, http::StatusCode};
-use crate::utils::{context::{RequestContext}, errors::InternalError, http::get, mongo, rabbit};
+use crate::utils::{context::{RequestContext}, errors::InternalError, http::get, mongo};
 
 #[derive(Serialize)]
 struct Health {
     healthy: bool,
 
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    degraded: bool, // Unhealthy, but not critical to the service - so it doesn't fail the overall health.
+
     #[serde(skip_serializing_if = "Option::is_none")]
     message: Option<String>
 }
 
+///
+/// The service is unhealthy (503) if any critical component is. The auth service is only critical if
+/// auth_health_critical is configured - otherwise it's reported as degraded, so a downstream outage doesn't
+/// get this service restarted.
+///
 pub async fn handle(ctx: RequestContext) -> Result<HttpResponse, InternalError> {
     let mut health = HashMap::<&str, Health>::new();
     health.insert("mongodb", mongo_health(&ctx).await);
-    health.insert("rabbitmq", rabbit_health());
-    health.insert("auth", ping_remote(format!("{}/auth/ping", ctx.config().auth_address), &ctx).await);
+    health.insert("schema", schema_health(&ctx).await);
+    health.insert("rabbitmq", rabbit_health(&ctx));
+    health.insert("auth", ping_remote(format!("{}/auth/ping", ctx.config().auth_address), &ctx).await.critical(ctx.config().auth_health_critical));
+    health.insert("maintenance", maintenance_health(&ctx));
 
-    let status = match health.values().any(|health| !health.healthy) {
+    let status = match health.values().any(|health| !health.healthy && !health.degraded) {
         true  => StatusCode::SERVICE_UNAVAILABLE,
         false => StatusCode::OK,
     };
@@ -26,34 +36,74 @@ pub async fn handle(ctx: RequestContext) -> Result<HttpResponse, InternalError>
     Ok(HttpResponseBuilder::new(status).json(json!(
         {
             "MongoDB": health["mongodb"],
+            "Schema": health["schema"],
             "RabbitMQ": health["rabbitmq"],
-            "Auth": health["auth"]
+            "Auth": health["auth"],
+            "Maintenance": health["maintenance"]
         }
     )))
 }
 
+impl Health {
+    fn ok() -> Self {
+        Health { healthy: true, degraded: false, message: None }
+    }
+
+    fn failed(message: String) -> Self {
+        Health { healthy: false, degraded: false, message: Some(message) }
+    }
+
+    ///
+    /// A non-critical component which is unhealthy is only degraded.
+    ///
+    fn critical(mut self, critical: bool) -> Self {
+        self.degraded = !critical && !self.healthy;
+        self
+    }
+}
+
 async fn ping_remote(url: String, ctx: &RequestContext) -> Health {
     match get(url).dont_retry().send(ctx).await {
         Ok(response) => {
             match response.status() {
-                   200 => Health { healthy: true, message: None },
-                status => Health { healthy: false, message: Some(format!("Bad response status {}", status)) }
+                   200 => Health::ok(),
+                status => Health::failed(format!("Bad response status {}", status))
             }
         },
-        Err(err) => Health { healthy: false, message: Some(err.to_string()) },
+        Err(err) => Health::failed(err.to_string()),
+    }
+}
+
+///
+/// Maintenance mode is deliberate - so it's only reported as degraded.
+///
+fn maintenance_health(ctx: &RequestContext) -> Health {
+    match ctx.in_maintenance() {
+        true  => Health::failed("In maintenance - changes are refused".to_string()).critical(false),
+        false => Health::ok(),
     }
 }
 
 async fn mongo_health(ctx: &RequestContext) -> Health {
     match mongo::ping(&ctx.db()).await {
-        Err(err) => Health { healthy: false, message: Some(err.to_string()) },
-        Ok(_) => Health { healthy: true, message: None }
+        Err(err) => Health::failed(err.to_string()),
+        Ok(_) => Health::ok()
+    }
+}
+
+///
+/// Unhealthy if the database hasn't had the schema updates this code relies on applied.
+///
+async fn schema_health(ctx: &RequestContext) -> Health {
+    match mongo::check_schema(ctx.db()).await {
+        Err(err) => Health::failed(err.to_string()),
+        Ok(_) => Health::ok()
     }
 }
 
-fn rabbit_health() -> Health {
-    match *rabbit::RABBIT_CONNECTED.read() {
-        true  => Health { healthy: true, message: None },
-        false => Health { healthy: false, message: Some("Not connected".to_string()) }
+fn rabbit_health(ctx: &RequestContext) -> Health {
+    match ctx.rabbit_connected() {
+        true  => Health::ok(),
+        false => Health::failed("Not connected".to_string())
     }
 }
\ No newline at end of file
@@ -0,0 +1,52 @@
+use serde::Serialize;
+use actix_http::http::StatusCode;
+use std::sync::atomic::{AtomicU64, Ordering};
+use actix_web::{HttpResponse, dev::HttpResponseBuilder};
+
+/// The requests currently being handled and the most there have been at once since start-up.
+static INFLIGHT: AtomicU64 = AtomicU64::new(0);
+static HIGH_WATER: AtomicU64 = AtomicU64::new(0);
+
+///
+/// Counts a request as in-flight for as long as it's held - dropping it (when the request completes,
+/// fails or is abandoned) decrements the gauge.
+///
+pub struct InFlight;
+
+impl InFlight {
+    pub fn start() -> Self {
+        let inflight = INFLIGHT.fetch_add(1, Ordering::Relaxed) + 1;
+        HIGH_WATER.fetch_max(inflight, Ordering::Relaxed);
+        InFlight
+    }
+}
+
+///
+/// The requests currently being handled.
+///
+pub fn current() -> u64 {
+    INFLIGHT.load(Ordering::Relaxed)
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        INFLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InFlightStats {
+    inflight: u64,
+    high_water: u64,
+}
+
+///
+/// Report the number of in-flight requests - this includes the request asking.
+///
+pub async fn handle() -> HttpResponse {
+    HttpResponseBuilder::new(StatusCode::OK).json(InFlightStats {
+        inflight: current(),
+        high_water: HIGH_WATER.load(Ordering::Relaxed),
+    })
+}
@@ -0,0 +1,29 @@
+use tracing::info;
+use serde::Deserialize;
+use serde_json::json;
+use actix_http::http::StatusCode;
+use actix_web::{HttpResponse, dev::HttpResponseBuilder, web::Json};
+use crate::utils::context::RequestContext;
+
+#[derive(Debug, Deserialize)]
+pub struct MaintenanceRequest {
+    enabled: bool,
+}
+
+///
+/// Put the service into (or take it out of) maintenance mode. While in maintenance every write is refused
+/// with a 503 and a Retry-After header - reads, health and the admin endpoints carry on. So operators can
+/// run migrations without changes being made underneath them.
+///
+/// The mode isn't persisted - a restart takes the service out of maintenance.
+///
+pub async fn handle(request: Json<MaintenanceRequest>, ctx: RequestContext) -> HttpResponse {
+    ctx.set_maintenance(request.enabled);
+
+    info!("Maintenance mode {} by {} (request {})",
+        if request.enabled { "entered" } else { "exited" },
+        ctx.principal().as_deref().unwrap_or(&ctx.config().system_identity),
+        ctx.request_id());
+
+    HttpResponseBuilder::new(StatusCode::OK).json(json!({ "maintenance": ctx.in_maintenance() }))
+}
@@ -0,0 +1,22 @@
+use actix_http::http::StatusCode;
+use actix_web::{HttpResponse, dev::HttpResponseBuilder};
+use crate::utils::{context::RequestContext, errors::InternalError, mongo::{recase_account_statuses, update_mongo}};
+
+///
+/// Re-apply the MongoDB schema updates (indexes, default data, etc) without restarting the service.
+///
+/// Safe to call repeatedly - the response lists which updates were applied and which were skipped.
+///
+pub async fn handle(ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+    let report = update_mongo(ctx.db(), ctx.config()).await?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(report))
+}
+
+///
+/// Re-case the stored account statuses to the configured enum_casing. Only call this once every instance using
+/// the database has been configured with the same casing.
+///
+pub async fn handle_recase(ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+    let report = recase_account_statuses(ctx.db(), ctx.config().enum_casing).await?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(report))
+}
\ No newline at end of file
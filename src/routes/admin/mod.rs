@@ -2,7 +2,17 @@
 /// These are endpoints that are used internally by the platform or tests.
 ///
 pub mod ping;
+pub mod chaos;
 pub mod health;
+pub mod correlation;
+pub mod dead_letters;
+pub mod inflight;
+pub mod maintenance;
+pub mod migrate;
+pub mod notification_stats;
+pub mod purge;
+pub mod replay;
 pub mod tracer;
+pub mod toggles;
 pub mod settings;
 pub mod set_time;
\ No newline at end of file
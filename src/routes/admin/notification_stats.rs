@@ -0,0 +1,65 @@
+use tracing::info;
+use serde::Serialize;
+use actix_http::http::StatusCode;
+use std::sync::atomic::{AtomicU64, Ordering};
+use actix_web::{HttpResponse, dev::HttpResponseBuilder};
+
+/// Counts of what's become of the notifications handed to the publisher since start-up (or the last reset).
+static SENT: AtomicU64 = AtomicU64::new(0);
+static DEFERRED: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+///
+/// A notification was confirmed by RabbitMQ.
+///
+pub fn sent() {
+    SENT.fetch_add(1, Ordering::Relaxed);
+}
+
+///
+/// A notification couldn't be published but was kept to be sent later - left in the outbox or written as a
+/// dead letter. It isn't a retry - it's only sent if the outbox relay or a redrive publishes it again.
+///
+pub fn deferred() {
+    DEFERRED.fetch_add(1, Ordering::Relaxed);
+}
+
+///
+/// A notification was lost - it couldn't be queued, serialised or published and wasn't kept.
+///
+pub fn dropped() {
+    DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationStats {
+    sent: u64,
+    deferred: u64,
+    dropped: u64,
+}
+
+///
+/// Report the notification counts. Any dropped notifications mean events have been lost.
+///
+pub async fn handle_get() -> HttpResponse {
+    HttpResponseBuilder::new(StatusCode::OK).json(NotificationStats {
+        sent: SENT.load(Ordering::Relaxed),
+        deferred: DEFERRED.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+    })
+}
+
+///
+/// Zero the counts (eg. once an incident's been dealt with) - returning what they were.
+///
+pub async fn handle_reset() -> HttpResponse {
+    let stats = NotificationStats {
+        sent: SENT.swap(0, Ordering::Relaxed),
+        deferred: DEFERRED.swap(0, Ordering::Relaxed),
+        dropped: DROPPED.swap(0, Ordering::Relaxed),
+    };
+
+    info!("Notification stats reset");
+    HttpResponseBuilder::new(StatusCode::OK).json(stats)
+}
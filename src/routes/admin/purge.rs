@@ -0,0 +1,50 @@
+use tracing::info;
+use serde::Deserialize;
+use serde_json::json;
+use mongodb::bson::doc;
+use actix_http::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, dev::HttpResponseBuilder, web::Query};
+use crate::{model::account::prelude::*, utils::{context::RequestContext, errors::InternalError}};
+
+/// A short prefix could match real accounts - test ids are UUIDs so the first segment is plenty.
+const MIN_PREFIX_LENGTH: usize = 8;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PurgeParams {
+    id_prefix: String,
+}
+
+///
+/// Delete the accounts whose accountId starts with the prefix - to clean up after integration tests.
+///
+/// This endpoint only exists if allow_test_endpoints is configured, so it can't be used in production.
+///
+pub async fn handle(req: HttpRequest, params: Query<PurgeParams>, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+    if !ctx.config().allow_test_endpoints {
+        return Err(InternalError::RouteNotFound { method: req.method().to_string(), path: req.path().to_string() })
+    }
+
+    if params.id_prefix.chars().count() < MIN_PREFIX_LENGTH {
+        return Err(InternalError::RequestFormatError { reason: format!("idPrefix must be at least {} characters", MIN_PREFIX_LENGTH) })
+    }
+
+    // An anchored regex can use the accountId index.
+    let filter = doc!{ ACCOUNT_ID: { "$regex": format!("^{}", escape_regex(&params.id_prefix)) } };
+    let result = ctx.db().collection(ACCOUNTS).delete_many(filter, None).await?;
+
+    info!("Purged {} accounts with the id prefix {} by request {}", result.deleted_count, params.id_prefix, ctx.request_id());
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(json!({ "deleted": result.deleted_count })))
+}
+
+///
+/// Match the prefix literally - so a '.' in it doesn't match any character.
+///
+fn escape_regex(text: &str) -> String {
+    text.chars()
+        .flat_map(|c| match "\\^$.|?*+()[]{}".contains(c) {
+            true  => vec!('\\', c),
+            false => vec!(c),
+        })
+        .collect()
+}
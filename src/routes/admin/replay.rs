@@ -0,0 +1,36 @@
+use tracing::info;
+use serde_json::json;
+use actix_http::http::StatusCode;
+use actix_web::{HttpResponse, dev::HttpResponseBuilder, web::Path};
+use crate::{routes::get_account::get_account, utils::{context::RequestContext, errors::InternalError, rabbit::{notify, prelude::*}}};
+
+///
+/// Re-emit the account's current state to RabbitMQ so a consumer which missed events can rebuild it.
+///
+/// The account is sent as an account.created notification, followed by its latest status. Both are
+/// flagged with a replay header.
+///
+pub async fn handle(Path(account_id): Path<String>, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+    let account = match get_account(&account_id, &ctx).await? {
+        Some(account) => account,
+        None => return Err(InternalError::AccountNotFound { account_id })
+    };
+
+    info!("Replaying events for account {} with correlation id {}", account_id, ctx.request_id());
+
+    notify(TOPIC_ACCOUNT_CREATED)
+        .body(json!(account))
+        .replay()
+        .send(&ctx);
+
+    notify(TOPIC_ACCOUNT_STATUS_UPDATED)
+        .routing_key(ROUTING_ACCOUNT_STATUS_UPDATED)
+        .body(json!({
+            "accountId": &account.account_id,
+            "newStatus": account.status
+        }))
+        .replay()
+        .send(&ctx);
+
+    Ok(HttpResponseBuilder::new(StatusCode::ACCEPTED).finish())
+}
@@ -1,4 +1,6 @@
 use tracing::info;
+use std::sync::Arc;
+use parking_lot::RwLock;
 use chrono::{DateTime, Utc};
 use actix_http::http::StatusCode;
 use actix_web::{Responder, web::Path};
@@ -10,6 +12,12 @@ use crate::utils::context::RequestContext;
 /// Tests can use apis below to fix the time to specific value, this allows data generated by
 /// tests to have a deterministic value from datetimes.
 ///
+///
+/// There's one clock per service, shared by every worker and the RabbitMQ publisher thread - so they all
+/// see the same fixed time.
+///
+pub type Clock = Arc<RwLock<TimeProvider>>;
+
 #[derive(Debug)]
 pub struct TimeProvider {
     fixed: Option<DateTime<Utc>>
@@ -20,6 +28,10 @@ impl TimeProvider {
         TimeProvider { fixed: None }
     }
 
+    pub fn shared() -> Clock {
+        Arc::new(RwLock::new(TimeProvider::default()))
+    }
+
     pub fn now(&self) -> DateTime<Utc> {
         match self.fixed {
             Some(fixed) => fixed,
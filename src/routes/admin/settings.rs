@@ -1,10 +1,36 @@
+use serde_json::{json, Map, Value};
 use actix_http::http::StatusCode;
 use actix_web::{HttpResponse, dev::HttpResponseBuilder};
 use crate::utils::{context::RequestContext, errors::InternalError};
 
 ///
-/// Allow support staff to view the current configuration of the system.
+/// Allow support staff to view the current configuration of the system. Any credentials are redacted.
+///
+/// Whether the service is in maintenance mode is included - it's not a setting but it changes how the
+/// service behaves.
 ///
 pub async fn handle(ctx: RequestContext) -> Result<HttpResponse, InternalError> {
-    Ok(HttpResponseBuilder::new(StatusCode::OK).json(ctx.config()))
-}
\ No newline at end of file
+    let mut settings = serde_json::to_value(ctx.config().redacted())?;
+    settings["maintenance"] = json!(ctx.in_maintenance());
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(settings))
+}
+
+///
+/// As the settings, but with where each setting's value came from (default, file, env or override) - so
+/// support staff can tell which layer of configuration set it.
+///
+pub async fn handle_sources(ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+    let settings = match serde_json::to_value(ctx.config().redacted())? {
+        Value::Object(settings) => settings,
+        _ => Map::new(),
+    };
+
+    let sources: Map<String, Value> = settings.into_iter()
+        .map(|(setting, value)| {
+            let source = ctx.config().source(&setting);
+            (setting, json!({ "value": value, "source": source }))
+        })
+        .collect();
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(sources))
+}
@@ -0,0 +1,88 @@
+use tracing::info;
+use std::collections::HashSet;
+use actix_http::http::StatusCode;
+use actix_web::{HttpResponse, dev::HttpResponseBuilder, web::Path};
+use crate::utils::{context::RequestContext, errors::InternalError};
+
+/// The toggle endpoints themselves can't be disabled - or they couldn't be re-enabled.
+const TOGGLE_ENDPOINTS: &str = "endpoints_";
+
+///
+/// The endpoints which have been switched off, by handler name (eg. create_account). Requests to these
+/// are refused with a 503 - all other endpoints carry on working.
+///
+/// The initial set comes from the disabled_endpoints setting but operators can change it at runtime
+/// with the endpoints below, without a deploy.
+///
+/// Maintenance mode (see maintenance.rs) is toggled here too. It's never persisted - a restart ends it.
+///
+#[derive(Debug)]
+pub struct EndpointToggles {
+    disabled: HashSet<String>,
+    maintenance: bool,
+}
+
+impl EndpointToggles {
+    pub fn new(disabled: &[String]) -> Self {
+        EndpointToggles { disabled: disabled.iter().cloned().collect(), maintenance: false }
+    }
+
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance
+    }
+
+    pub fn set_maintenance(&mut self, maintenance: bool) {
+        self.maintenance = maintenance;
+    }
+
+    pub fn is_disabled(&self, endpoint: &str) -> bool {
+        self.disabled.contains(endpoint)
+    }
+
+    pub fn set_disabled(&mut self, endpoint: &str, disabled: bool) {
+        match disabled {
+            true  => self.disabled.insert(endpoint.to_string()),
+            false => self.disabled.remove(endpoint),
+        };
+    }
+
+    ///
+    /// The disabled endpoints - sorted.
+    ///
+    pub fn disabled(&self) -> Vec<String> {
+        let mut disabled: Vec<String> = self.disabled.iter().cloned().collect();
+        disabled.sort();
+        disabled
+    }
+}
+
+///
+/// List the currently disabled endpoints.
+///
+pub async fn handle_get(ctx: RequestContext) -> HttpResponse {
+    HttpResponseBuilder::new(StatusCode::OK).json(ctx.disabled_endpoints())
+}
+
+///
+/// Switch off an endpoint (by handler name, as shown in the X-Handler header).
+///
+pub async fn handle_disable(Path(endpoint): Path<String>, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+    if endpoint.starts_with(TOGGLE_ENDPOINTS) {
+        return Err(InternalError::RequestFormatError { reason: format!("The {} endpoint cannot be disabled", endpoint) })
+    }
+
+    Ok(set(&endpoint, true, &ctx))
+}
+
+///
+/// Switch an endpoint back on.
+///
+pub async fn handle_enable(Path(endpoint): Path<String>, ctx: RequestContext) -> HttpResponse {
+    set(&endpoint, false, &ctx)
+}
+
+fn set(endpoint: &str, disabled: bool, ctx: &RequestContext) -> HttpResponse {
+    ctx.set_endpoint_disabled(endpoint, disabled);
+    info!("Endpoint {} {} by request {}", endpoint, if disabled { "disabled" } else { "enabled" }, ctx.request_id());
+    HttpResponseBuilder::new(StatusCode::OK).json(ctx.disabled_endpoints())
+}
@@ -1,10 +1,13 @@
 use tracing::info;
 use ansi_term::Colour;
 use serde::Deserialize;
+use itertools::Itertools;
 use parking_lot::RwLock;
 use lazy_static::lazy_static;
-use actix_web::{Responder, web::Query};
+use crate::utils::errors::InternalError;
+use std::sync::atomic::{AtomicU64, Ordering};
 use actix_http::http::{HeaderMap, StatusCode};
+use actix_web::{HttpMessage, Responder, dev::ServiceRequest, web::Query};
 
 pub mod prelude {
     use ansi_term::Colour;
@@ -13,6 +16,9 @@ pub mod prelude {
 
     const GREY: Colour = Colour::RGB(110, 110, 110);
 
+    // Logged in place of the body of an endpoint which handles secrets (see untraced_bodies).
+    pub const UNTRACED_BODY: &str = "<body not traced>";
+
     // Some logging format symbols, potentially with colour.
     lazy_static! {
         pub static ref COLON: String = match *USE_COLOUR {
@@ -57,12 +63,64 @@ pub fn colour_status(status: u16) -> String {
     format!("{}", status)
 }
 
+/// How many bytes of a binary body are shown (in hex) by the tracer.
+const BINARY_PREVIEW_BYTES: usize = 16;
+
+///
+/// Format a request or response body for the tracer based on its content-type. JSON is pretty-printed,
+/// text is shown as-is and anything else (or text which isn't valid UTF-8) is summarised with a hex
+/// preview - so binary bodies don't corrupt the log.
+///
+pub fn format_body(content_type: Option<&str>, body: &[u8]) -> String {
+    let content_type = content_type.unwrap_or_default().to_lowercase();
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+
+    let is_json = mime == "application/json" || mime.ends_with("+json");
+    let is_text = mime.is_empty() // Without a content-type, show it if it's readable.
+        || mime.starts_with("text/")
+        || mime == "application/xml" || mime.ends_with("+xml")
+        || mime == "application/x-www-form-urlencoded";
+
+    if is_json {
+        if let Ok(json) = serde_json::from_slice::<serde_json::Value>(body) {
+            if let Ok(pretty) = serde_json::to_string_pretty(&json) {
+                return pretty
+            }
+        }
+    }
+
+    if is_json || is_text {
+        if let Ok(text) = std::str::from_utf8(body) {
+            return text.to_string()
+        }
+    }
+
+    format!("<binary {} bytes: {}{}>",
+        body.len(),
+        body.iter().take(BINARY_PREVIEW_BYTES).map(|byte| format!("{:02x}", byte)).join(" "),
+        if body.len() > BINARY_PREVIEW_BYTES { " ..." } else { "" })
+}
+
+///
+/// When on, only a sample (fraction) of the matching requests are logged - 1.0 logs them all.
+///
 enum Level {
-    On,
+    On { sample: f64 },
     Off,
-    Bullet { matcher: Option<(String, String)> },
+    Bullet { matcher: Option<(String, String)>, sample: f64 },
 }
 
+/// The requests the tracer has matched (since it was last turned on) and how many of those were logged.
+static MATCHED: AtomicU64 = AtomicU64::new(0);
+static LOGGED: AtomicU64 = AtomicU64::new(0);
+
+///
+/// Whether a request is being traced. It's decided once per request and stored in the request so each
+/// piece of middleware agrees - otherwise sampling could log a request but not it's response.
+///
+#[derive(Clone, Copy)]
+struct Traced(bool);
+
 lazy_static! {
     // In general configuration should be passed in a context struct via Actix .data extractors.
     // Any configuration in a lazy static block exists because there are sections are code where
@@ -80,14 +138,66 @@ lazy_static! {
 }
 
 ///
-/// Uses a RwLock to ascsertain if tracer is on or off.
+/// Marks a request to an endpoint whose bodies must never be traced (see untraced_bodies).
+///
+#[derive(Clone, Copy)]
+struct UntracedBody;
+
+///
+/// Never trace the request's (or it's response's) body - only it's headers.
+///
+pub fn untrace_body(req: &ServiceRequest) {
+    req.extensions_mut().insert(UntracedBody);
+}
+
+///
+/// Has the request been marked so it's (and it's response's) body is never traced?
+///
+pub fn body_untraced<R: HttpMessage>(req: &R) -> bool {
+    req.extensions().get::<UntracedBody>().is_some()
+}
+
+///
+/// Should the request be traced? If the tracer matches the request, it's then sampled.
+///
+pub fn tracer_on(req: &ServiceRequest) -> bool {
+    if let Some(Traced(traced)) = req.extensions().get::<Traced>() {
+        return *traced
+    }
+
+    let traced = match tracer_matches(req.headers()) {
+        Some(sample) => sampled(sample),
+        None => false,
+    };
+
+    req.extensions_mut().insert(Traced(traced));
+    traced
+}
+
+///
+/// Include every nth request where n is determined by the sample ratio. Deterministic rather than
+/// random so small samples are still evenly spread.
+///
+fn sampled(sample: f64) -> bool {
+    let matched = MATCHED.fetch_add(1, Ordering::Relaxed) + 1;
+    let sampled = (matched as f64 * sample).floor() > ((matched - 1) as f64 * sample).floor();
+
+    if sampled {
+        LOGGED.fetch_add(1, Ordering::Relaxed);
+    }
+    sampled
+}
+
+///
+/// Uses a RwLock to ascsertain if tracer is on or off for the request - returning the sample ratio
+/// if it's on.
 ///
-pub fn tracer_on(headers: &HeaderMap) -> bool {
+fn tracer_matches(headers: &HeaderMap) -> Option<f64> {
     let lock = TRACER.read();
     match &*lock {
-        Level::On => true,
-        Level::Off => false,
-        Level::Bullet { matcher } => {
+        Level::On { sample } => Some(*sample),
+        Level::Off => None,
+        Level::Bullet { matcher, sample } => {
             // If the tracer has a key/value which match one of the headers specified, then tracer is
             // on (for this request).
             if let Some((match_key, match_value)) = matcher {
@@ -95,51 +205,77 @@ pub fn tracer_on(headers: &HeaderMap) -> bool {
 
                 if let Some(header_value) = headers.get(match_key) {
                     if let Ok(header_value) = header_value.to_str() {
-                        return header_value.to_lowercase() == match_value
+                        if header_value.to_lowercase() == match_value {
+                            return Some(*sample)
+                        }
                     }
                 }
             }
 
-            return false
+            None
         }
     }
 }
 
-pub async fn handle_on() -> impl Responder {
-    {
-        let mut lock = TRACER.write();
-        *lock = Level::On;
+///
+/// Set the tracer level, resetting the sample counters.
+///
+fn set_level(level: Level) {
+    let mut lock = TRACER.write();
+    *lock = level;
+    MATCHED.store(0, Ordering::Relaxed);
+    LOGGED.store(0, Ordering::Relaxed);
+}
+
+#[derive(Deserialize)]
+pub struct SampleParams {
+    sample: Option<f64>
+}
+
+///
+/// The sample ratio must be more than 0 and no more than 1. If unspecified, everything is logged.
+///
+fn validate_sample(sample: Option<f64>) -> Result<f64, InternalError> {
+    match sample {
+        None => Ok(1.0),
+        Some(sample) if sample > 0.0 && sample <= 1.0 => Ok(sample),
+        Some(sample) => Err(InternalError::RequestFormatError { reason: format!("sample {} must be more than 0 and no more than 1", sample) }),
     }
-    info!("Tracer is on");
-    "on".with_status(StatusCode::OK)
+}
+
+///
+/// HTTP Handler to turn tracer on - optionally for only a sample of requests.
+///
+pub async fn handle_on(params: Query<SampleParams>) -> Result<impl Responder, InternalError> {
+    let sample = validate_sample(params.sample)?;
+    set_level(Level::On { sample });
+    info!("Tracer is on with a sample of {}", sample);
+    Ok("on".with_status(StatusCode::OK))
 }
 
 ///
 /// HTTP Handler to turn tracer .... off.
 ///
 pub async fn handle_off() -> impl Responder {
-    {
-        let mut lock = TRACER.write();
-        *lock = Level::Off;
-    }
-    info!("Tracer is off");
+    info!("Tracer is off - it logged {} of {} matching requests", LOGGED.load(Ordering::Relaxed), MATCHED.load(Ordering::Relaxed));
+    set_level(Level::Off);
     "off".with_status(StatusCode::OK)
 }
 
 #[derive(Deserialize)]
 pub struct Params {
     header: String,
-    value: String
+    value: String,
+    sample: Option<f64>
 }
 
 ///
-/// HTTP Handler to turn tracer on.
+/// HTTP Handler to turn tracer on for requests with a matching header - optionally for only a
+/// sample of them.
 ///
-pub async fn handle_bullet(params: Query<Params>) -> impl Responder {
-    {
-        let mut lock = TRACER.write();
-        *lock = Level::Bullet { matcher: Some((params.header.clone(), params.value.clone())) };
-    }
-    info!("Tracer buller is on where {}={}", params.header, params.value);
-    "bullet".with_status(StatusCode::OK)
+pub async fn handle_bullet(params: Query<Params>) -> Result<impl Responder, InternalError> {
+    let sample = validate_sample(params.sample)?;
+    set_level(Level::Bullet { matcher: Some((params.header.clone(), params.value.clone())), sample });
+    info!("Tracer buller is on where {}={} with a sample of {}", params.header, params.value, sample);
+    Ok("bullet".with_status(StatusCode::OK))
 }
\ No newline at end of file
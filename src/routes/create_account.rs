@@ -1,64 +1,165 @@
 use serde_json::json;
 use mongodb::bson::{self, Document};
-use actix_web::{HttpResponse, dev::HttpResponseBuilder, http::StatusCode, web::Json};
-use super::{get_account_profile::get_account_profile, get_device_profile::get_device_profile};
-use crate::{clients::auth, model::{account::{prelude::*, Account, NewAccount}, device::{prelude::*, NewDevice}, profile::prelude::*}, utils::{context::RequestContext, errors::InternalError, mongo::{Persistable, generate_id}, rabbit::{notify, prelude::*}}};
+use actix_web::{HttpResponse, dev::HttpResponseBuilder, http::StatusCode, web::{Json, Query}};
+use super::{get_account::get_account, get_account_profile::get_account_profile, get_device_profile::get_device_profile};
+use crate::{clients::auth::AuthClient, model::{account::{prelude::*, Account, CreateAccountQuery, CreatedAccount, NewAccount}, device::{prelude::*, NewDevice, RejectedDevice}, external_id::ExternalId, outbox::prelude::OUTBOX, profile::prelude::*}, utils::{context::RequestContext, errors::InternalError, mongo::{Persistable, generate_id}, rabbit::{notify, prelude::*}}};
+
+/// The largest document MongoDB will store.
+const MAX_DOCUMENT_BYTES: usize = 16 * 1024 * 1024;
 
 ///
 /// Http handler for creating an account.
 ///
+/// If the accountId is already taken and returnExistingOnDuplicate is requested, the existing account is
+/// returned with a 200 - so a create can be safely retried.
+///
+/// With partialDevices, any device which fails validation is left out of the account (and reported in
+/// rejectedDevices) rather than failing the create.
+///
 #[tracing::instrument(name="create_account", skip(account), level="info")]
-pub async fn handle(account: Json<NewAccount>, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+pub async fn handle(Query(query): Query<CreateAccountQuery>, account: Json<NewAccount>, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
 
     // Do not allow unless the caller has the create-account permission.
-    let _response = auth::check_claim("create-account", &ctx).await?;
+    let claims = AuthClient::new(&ctx).check_claim("create-account").await?;
 
     // Call the 'business' tier method to do the work.
-    let account = create_account(account.into_inner(), &ctx).await?;
+    let account_id = account.account_id.clone();
+    let account = match create_account(account.into_inner(), query.partial_devices, claims.principal(&ctx), &ctx).await {
+        Ok(account) => account,
+        Err(InternalError::MongoDuplicateError { cause }) => return duplicate(cause, account_id, query.return_existing_on_duplicate, &ctx).await,
+        Err(err) => return Err(err),
+    };
 
     // Create HTTP response for the call.
     Ok(HttpResponseBuilder::new(StatusCode::CREATED).json(account))
 }
 
 ///
-/// Validate and create the account specified.
+/// The response to a create which clashed with an existing account. Unless the existing account is wanted, this
+/// is either the original 400 or, if duplicate_account_conflict is configured, a 409 naming the field.
 ///
-pub async fn create_account(new_account: NewAccount, ctx: &RequestContext) -> Result<Account, InternalError> {
+pub async fn duplicate(cause: String, account_id: Option<String>, return_existing: bool, ctx: &RequestContext) -> Result<HttpResponse, InternalError> {
+    let field = conflicting_field(&cause);
 
-    // Validate and populate defaults.
-    let mut doc = validate_account(&new_account, ctx).await?;
+    if let (true, ACCOUNT_ID, Some(account_id)) = (return_existing, field, account_id) {
+        if let Some(account) = get_account(&account_id, ctx).await? {
+            return Ok(HttpResponseBuilder::new(StatusCode::OK).json(account))
+        }
+    }
+
+    match ctx.config().duplicate_account_conflict {
+        true  => Err(InternalError::AccountConflict { field: field.to_string() }),
+        false => Err(InternalError::MongoDuplicateError { cause }),
+    }
+}
 
-    // Insert into MongoDB.
-    ctx.db().collection(ACCOUNTS).insert_one(doc.clone(), None).await?;
+///
+/// The account field whose unique index (named in MongoDB's duplicate key error) was violated.
+///
+fn conflicting_field(cause: &str) -> &'static str {
+    let index = cause.split("index: ").nth(1)
+        .and_then(|rest| rest.split_whitespace().next())
+        .unwrap_or_default();
+
+    match index {
+        "idx_accountId"         => ACCOUNT_ID,
+        "idx_deviceId"          => "devices.deviceId",
+        "idx_accountExternalId" => "externalIds",
+        "idx_deviceExternalId"  => "devices.externalIds",
+        _                       => "unknown field",
+    }
+}
+
+///
+/// Validate and create the account specified - attributed to the principal given. If partial_devices is
+/// set, invalid devices are rejected individually rather than failing the account.
+///
+pub async fn create_account(new_account: NewAccount, partial_devices: bool, created_by: &str, ctx: &RequestContext) -> Result<CreatedAccount, InternalError> {
+
+    // Validate and populate defaults.
+    let (mut doc, rejected_devices) = match validate_account(&new_account, partial_devices, ctx).await {
+        Ok(doc) => doc,
+        Err(err) => {
+            notify_rejected(&new_account, &err, ctx);
+            return Err(err)
+        },
+    };
+    doc.insert(CREATED_BY, created_by);
 
     // Strip any credentials from the account before we return or notify the account details.
     // (I never actually got as far as adding any in the first place!).
-    doc.remove(CREDENTIALS);
+    let mut public_doc = doc.clone();
+    public_doc.remove(CREDENTIALS);
 
     // Convert the doc into an Account struct and return it to the caller. This avoids a round trip for the
     // caller to get the full account details with all generated values, AND avoids a write-read on the
     // database. So, assuming profiles are cached, a create account (and devices) results in a single write.
-    // let account
-    let account = bson::from_bson(doc.into())?;
+    let account: Account = bson::from_bson(public_doc.into())?;
+    let mut notification = notify(TOPIC_ACCOUNT_CREATED);
+    notification.body(json!(account));
+
+    // If configured, stage the notification in the account's outbox so it's written with the account.
+    let outbox = ctx.config().transactional_outbox;
+    if outbox {
+        doc.insert(OUTBOX, vec!(notification.stage(ctx)?));
+    }
+
+    // Insert into MongoDB - once there's capacity for another write.
+    let _permit = ctx.write_permit().await?;
+    ctx.db().collection(ACCOUNTS).insert_one(doc, None).await?;
 
     // Emit a notification to RabbitMQ (or whatever event system is configured).
-    notify(TOPIC_ACCOUNT_CREATED).body(json!(account)).send(&ctx);
+    if !outbox {
+        notification.send(ctx);
+    }
+
+    Ok(CreatedAccount { account, rejected_devices: partial_devices.then_some(rejected_devices) })
+}
 
-    Ok(account)
+///
+/// If configured, let interested parties (eg. fraud teams) know an account was rejected. The notification
+/// is queued rather than sent so it can't hold up or fail the error response.
+///
+fn notify_rejected(new_account: &NewAccount, err: &InternalError, ctx: &RequestContext) {
+    if ctx.config().notify_rejected_accounts {
+        notify(TOPIC_ACCOUNT_CREATION_REJECTED)
+            .body(json!({
+                "accountId": new_account.account_id,
+                "errorCode": err.error_code(),
+            }))
+            .send(ctx);
+    }
 }
 
 ///
-/// Validate the request and populate additional details - returning a MongoDB Document to insert if all is good.
+/// Validate the request and populate additional details - returning a MongoDB Document to insert if all is good
+/// along with any devices rejected (only when partial_devices is set).
 ///
-async fn validate_account(account: &NewAccount, ctx: &RequestContext) -> Result<Document, InternalError> {
+async fn validate_account(account: &NewAccount, partial_devices: bool, ctx: &RequestContext) -> Result<(Document, Vec<RejectedDevice>), InternalError> {
+
+    // If configured, don't fall back to the default profile.
+    if account.profile_id.is_none() && ctx.config().require_explicit_profile {
+        return Err(InternalError::RequestFormatError { reason: "A profileId is required".to_string() })
+    }
+
+    // Validate that the account profile (the default if one isn't specified) exists - the default could've been deleted.
+    let profile_id = account.profile_id.as_deref().unwrap_or(DEFAULT);
+    if let None = get_account_profile(profile_id, ctx).await? {
+        return Err(InternalError::AccountProfileNotFound { profile_id: profile_id.to_string() })
+    }
 
-    // If specified, validate that the account profile exists.
-    if let Some(profile_id) = &account.profile_id {
-        if let None = get_account_profile(&profile_id, ctx).await? {
-            return Err(InternalError::AccountProfileNotFound { profile_id: profile_id.clone() })
+    // If configured, only allow the known salutations.
+    if let Some(salutation) = &account.salutation {
+        if !ctx.config().salutation_allowed(salutation) {
+            return Err(InternalError::RequestFormatError { reason: format!("The salutation {} is not allowed", salutation) })
         }
     }
 
+    // Guard the indexed external ids from growing too large or clashing with themselves.
+    if let Some(external_ids) = &account.external_ids {
+        validate_external_ids(external_ids, ctx.config().max_external_ids)?;
+    }
+
     // Turn our NewAccount structure into a Bson document. We're going to add defaults which
     // may not have been specified.
     let mut doc = account.to_doc()?;
@@ -70,24 +171,100 @@ async fn validate_account(account: &NewAccount, ctx: &RequestContext) -> Result<
 
     // Set the CREATED field.
     doc.insert(CREATED, ctx.now());
+    doc.insert(VERSION, 1i64);
 
     // Generate an accountId if one isn't specified.
     generate_id(ACCOUNT_ID, &mut doc, &account.account_id);
 
-    // Default the account to active if no status was specified.
+    // Default the account status if none was specified.
     if let None = account.status {
-        doc.insert(STATUS, STATUS_ACTIVE);
+        doc.insert(STATUS, ctx.config().default_account_status);
     }
 
     // Validate any devices specified in the request.
+    let mut rejected_devices = vec!();
     if let Some(devices) = &account.devices {
         for (idx, device) in devices.iter().enumerate() {
             let device_doc = get_sub_doc(DEVICES, idx, &mut doc)?;
-            validate_device(device, device_doc, &ctx).await?;
+            match validate_device(device, device_doc, ctx).await {
+                Ok(()) => (),
+                Err(err) if partial_devices && rejectable(&err) => rejected_devices.push(RejectedDevice {
+                    index: idx,
+                    device_id: device.device_id.clone(),
+                    error_code: err.error_code(),
+                    message: err.client_message(),
+                }),
+                Err(err) => return Err(err),
+            }
         }
     }
 
-    Ok(doc)
+    remove_devices(&rejected_devices, &mut doc)?;
+    validate_size(&doc)?;
+    Ok((doc, rejected_devices))
+}
+
+///
+/// The device validation failures which partialDevices can skip - anything else (eg. MongoDB being
+/// unavailable) still fails the create.
+///
+fn rejectable(err: &InternalError) -> bool {
+    matches!(err,
+        InternalError::DeviceProfileNotFound { profile_id: _ } |
+        InternalError::DeviceTypeNotAllowed { profile_id: _, device_type: _ })
+}
+
+///
+/// Take the rejected devices out of the account - dropping the devices field altogether if none are left.
+///
+fn remove_devices(rejected_devices: &[RejectedDevice], doc: &mut Document) -> Result<(), InternalError> {
+    if rejected_devices.is_empty() {
+        return Ok(())
+    }
+
+    let devices = doc.get_array_mut(DEVICES)?;
+    let mut idx = 0;
+    devices.retain(|_| {
+        let keep = !rejected_devices.iter().any(|rejected| rejected.index == idx);
+        idx += 1;
+        keep
+    });
+
+    if devices.is_empty() {
+        doc.remove(DEVICES);
+    }
+
+    Ok(())
+}
+
+///
+/// Reject an account which MongoDB wouldn't store, rather than waiting for the insert to fail.
+///
+fn validate_size(doc: &Document) -> Result<(), InternalError> {
+    let mut bytes = vec!();
+    doc.to_writer(&mut bytes)?;
+
+    match bytes.len() > MAX_DOCUMENT_BYTES {
+        true  => Err(InternalError::AccountTooLarge { cause: format!("{} bytes exceeds the {} byte limit", bytes.len(), MAX_DOCUMENT_BYTES) }),
+        false => Ok(()),
+    }
+}
+
+///
+/// Ensure there are no more than the maximum number of external ids and that no key is repeated.
+///
+pub fn validate_external_ids(external_ids: &[ExternalId], max_external_ids: usize) -> Result<(), InternalError> {
+    if external_ids.len() > max_external_ids {
+        return Err(InternalError::RequestFormatError { reason: format!("No more than {} externalIds are allowed", max_external_ids) })
+    }
+
+    for (idx, external_id) in external_ids.iter().enumerate() {
+        if external_ids[..idx].iter().any(|other| other.key == external_id.key) {
+            return Err(InternalError::RequestFormatError { reason: format!("The externalId key {} is duplicated", external_id.key) })
+        }
+    }
+
+    Ok(())
 }
 
 ///
@@ -95,13 +272,19 @@ async fn validate_account(account: &NewAccount, ctx: &RequestContext) -> Result<
 ///
 async fn validate_device(device: &NewDevice, doc: &mut Document, ctx: &RequestContext) -> Result<(), InternalError> {
 
-    // If specified, validate that the device profile exists.
-    if let Some(profile_id) = &device.profile_id {
-        if let None = get_device_profile(&profile_id, ctx).await? {
-            return Err(InternalError::DeviceProfileNotFound { profile_id: profile_id.clone() })
-        }
+    // If configured, don't fall back to the default profile.
+    if device.profile_id.is_none() && ctx.config().require_explicit_profile {
+        return Err(InternalError::RequestFormatError { reason: "A profileId is required for every device".to_string() })
     }
 
+    // Validate that the device profile (the default if one isn't specified) exists - and that it allows this type of device.
+    let profile_id = device.profile_id.as_deref().unwrap_or(DEFAULT);
+    match get_device_profile(profile_id, ctx).await? {
+        None => return Err(InternalError::DeviceProfileNotFound { profile_id: profile_id.to_string() }),
+        Some(profile) if !profile.allows(device.device_type) => return Err(InternalError::DeviceTypeNotAllowed { profile_id: profile_id.to_string(), device_type: format!("{:?}", device.device_type) }),
+        _ => (),
+    };
+
     // Set the CREATED field.
     doc.insert(CREATED, ctx.now());
 
@@ -0,0 +1,65 @@
+use mongodb::bson::doc;
+use actix_web::{HttpResponse, dev::HttpResponseBuilder, http::StatusCode, web::Json};
+use crate::{clients::auth::AuthClient, model::profile::{prelude::*, DeviceProfile}, utils::{context::RequestContext, errors::InternalError, mongo::Persistable}};
+
+///
+/// Http handler for creating a device profile.
+///
+#[tracing::instrument(name="create_device_profile", skip(profile), level="info")]
+pub async fn handle_create(profile: Json<DeviceProfile>, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+
+    // Do not allow unless the caller has the manage-profiles permission.
+    let _response = AuthClient::new(&ctx).check_claim("manage-profiles").await?;
+
+    let profile = profile.into_inner();
+    validate_profile(&profile)?;
+
+    // A duplicate profileId is rejected by the unique index.
+    let _permit = ctx.write_permit().await?;
+    ctx.db().collection(DEVICE_PROFILES).insert_one(profile.to_doc()?, None).await?;
+
+    Ok(HttpResponseBuilder::new(StatusCode::CREATED).json(profile))
+}
+
+///
+/// Http handler for replacing the settings of an existing device profile.
+///
+/// Devices already in the profile are not re-validated against the new settings.
+///
+#[tracing::instrument(name="update_device_profile", skip(profile), level="info")]
+pub async fn handle_update(profile: Json<DeviceProfile>, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+
+    // Do not allow unless the caller has the manage-profiles permission.
+    let _response = AuthClient::new(&ctx).check_claim("manage-profiles").await?;
+
+    let profile = profile.into_inner();
+    let profile_id = validate_profile(&profile)?;
+
+    let _permit = ctx.write_permit().await?;
+    let result = ctx.db().collection(DEVICE_PROFILES).replace_one(doc!{ PROFILE_ID: profile_id }, profile.to_doc()?, None).await?;
+    ctx.profile_cache().evict_device_profile(profile_id);
+
+    if result.matched_count == 0 {
+        return Err(InternalError::DeviceProfileNotFound { profile_id: profile_id.to_string() })
+    }
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(profile))
+}
+
+///
+/// A profile must be identified and, if it restricts the device types, allow at least one.
+///
+fn validate_profile(profile: &DeviceProfile) -> Result<&str, InternalError> {
+    let profile_id = match &profile.profile_id {
+        Some(profile_id) => profile_id,
+        None => return Err(InternalError::RequestFormatError { reason: "A profileId is required".to_string() }),
+    };
+
+    if let Some(allowed) = &profile.allowed_device_types {
+        if allowed.is_empty() {
+            return Err(InternalError::RequestFormatError { reason: "allowedDeviceTypes must include at least one device type".to_string() })
+        }
+    }
+
+    Ok(profile_id)
+}
@@ -1,10 +1,23 @@
+use tracing::warn;
 use mongodb::bson::doc;
-use actix_web::{HttpResponse, dev::HttpResponseBuilder, http::StatusCode, web::Path};
+use actix_web::{HttpResponse, dev::HttpResponseBuilder, http::{StatusCode, header::ETAG}, web::Path};
 use crate::{model::account::{prelude::*, Account}, utils::{context::RequestContext, errors::InternalError}};
+use super::get_account_profile::get_account_profile;
+
+/// How many more devices the account's profile allows it - only present if the profile has a device limit.
+pub const DEVICE_SLOTS_REMAINING_HEADER: &str = "x-device-slots-remaining";
 
 ///
 /// Http handler for getting an account.
 ///
+/// The response has an ETag which can be used in an If-Match header to make conditional updates.
+///
+/// If track_last_accessed is configured, the read is recorded in the account's lastAccessedAt - the
+/// response has the time of the previous read.
+///
+/// If the account's profile has a maxDevices limit, the response says how many more devices the account
+/// can have - so clients know before attempting to add one.
+///
 #[tracing::instrument(name="get_account", level="info")]
 pub async fn handle(Path(account_id): Path<String>, ctx: RequestContext)
     -> Result<HttpResponse, InternalError> {
@@ -12,13 +25,76 @@ pub async fn handle(Path(account_id): Path<String>, ctx: RequestContext)
     let account = get_account(&account_id, &ctx).await?;
 
     match account {
-        Some(account) => Ok(HttpResponseBuilder::new(StatusCode::OK).json(account)),
+        Some(account) => {
+            if ctx.config().track_last_accessed {
+                track_last_accessed(&account_id, &ctx);
+            }
+
+            let mut response = HttpResponseBuilder::new(StatusCode::OK);
+            response.header(ETAG, account.etag());
+
+            // The header's only a convenience - so the read still succeeds without it if the profile can't be read.
+            match device_slots_remaining(&account, &ctx).await {
+                Ok(Some(remaining)) => { response.header(DEVICE_SLOTS_REMAINING_HEADER, remaining.to_string()); },
+                Ok(None) => (),
+                Err(err) => warn!("Unable to read the profile of account {} for it's remaining device slots: {}", account.account_id, err),
+            }
+
+            Ok(response.json(account))
+        },
 
         // Note: 204 rather than 404 (the latter indicates the uri isn'y present not the content itself)
         None => Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish())
     }
 }
 
+///
+/// The profile's device limit less the account's devices - None if the profile has no limit.
+///
+async fn device_slots_remaining(account: &Account, ctx: &RequestContext) -> Result<Option<u32>, InternalError> {
+    let max_devices = match get_account_profile(&account.profile_id, ctx).await? {
+        Some(profile) => profile.max_devices,
+        None => None,
+    };
+
+    let devices = account.devices.as_ref().map(Vec::len).unwrap_or_default() as u32;
+    Ok(max_devices.map(|max_devices| max_devices.saturating_sub(devices)))
+}
+
+///
+/// Record the read without waiting for it - it mustn't slow or fail the read, so any error is only logged.
+///
+/// This doesn't touch the modified date, so the account's ETag is unchanged.
+///
+fn track_last_accessed(account_id: &str, ctx: &RequestContext) {
+    let db = ctx.db().clone();
+    let account_id = account_id.to_string();
+    let accessed = ctx.now();
+
+    actix_rt::spawn(async move {
+        if let Err(err) = db.collection(ACCOUNTS).update_one(Account::id_filter(&account_id), doc!{ "$set": { LAST_ACCESSED_AT: accessed } }, None).await {
+            warn!("Failed to record account {} was accessed: {}", account_id, err);
+        }
+    });
+}
+
+///
+/// Http handler for getting the account which owns a device.
+///
+#[tracing::instrument(name="get_account_by_device", level="info")]
+pub async fn handle_by_device(Path(device_id): Path<String>, ctx: RequestContext)
+    -> Result<HttpResponse, InternalError> {
+
+    match get_account_by_device(&device_id, &ctx).await? {
+        Some(account) => Ok(HttpResponseBuilder::new(StatusCode::OK)
+            .header(ETAG, account.etag())
+            .json(account)),
+
+        // No account owns the device.
+        None => Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish())
+    }
+}
+
 ///
 /// Return the specified account.
 ///
@@ -27,5 +103,16 @@ pub async fn get_account(account_id: &str, ctx: &RequestContext)
 
     let collection = ctx.db().collection_with_type(ACCOUNTS);
 
-    Ok(collection.find_one(doc! { "accountId": account_id }, None).await?)
-}
\ No newline at end of file
+    Ok(collection.find_one(Account::id_filter(account_id), None).await?)
+}
+
+///
+/// Return the account which owns the device - device ids are unique across all accounts (idx_deviceId).
+///
+pub async fn get_account_by_device(device_id: &str, ctx: &RequestContext)
+    -> Result<Option<Account>, InternalError> {
+
+    let collection = ctx.db().collection_with_type(ACCOUNTS);
+
+    Ok(collection.find_one(doc! { "devices.deviceId": device_id }, None).await?)
+}
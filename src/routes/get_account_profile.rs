@@ -13,14 +13,24 @@ pub async fn handle(Path(profile_id): Path<String>, ctx: RequestContext)
 
     match profile {
         Some(profile) => Ok(HttpResponseBuilder::new(StatusCode::OK).json(profile)),
+        None if ctx.config().profile_not_found_404 => Ok(InternalError::AccountProfileNotFound { profile_id }.not_found_response()),
         None => Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish())
     }
 }
 
 ///
-/// Return the specified account profile.
+/// Return the specified account profile - from the profile cache if it's there.
 ///
 pub async fn get_account_profile(profile_id: &str, ctx: &RequestContext) -> Result<Option<AccountProfile>, InternalError> {
+    if let Some(profile) = ctx.profile_cache().account_profile(profile_id) {
+        return Ok(Some(profile))
+    }
+
     let collection = ctx.db().collection_with_type(ACCOUNT_PROFILES);
-    Ok(collection.find_one(doc! { "profileId": profile_id }, None).await?)
+    let profile: Option<AccountProfile> = collection.find_one(doc! { "profileId": profile_id }, None).await?;
+
+    if let Some(profile) = &profile {
+        ctx.profile_cache().put_account_profile(profile_id, profile);
+    }
+    Ok(profile)
 }
\ No newline at end of file
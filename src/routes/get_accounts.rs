@@ -1,21 +1,119 @@
-use mongodb::bson::doc;
-use futures::TryStreamExt;
-use actix_web::{HttpResponse, dev::HttpResponseBuilder, http::StatusCode};
-use crate::{model::account::{prelude::*, Account}, utils::{context::RequestContext, errors::InternalError}};
+use tracing::error;
+use mongodb::{Cursor, bson::Document};
+use futures::{StreamExt, TryStreamExt, future::ready, stream};
+use actix_web::{HttpResponse, dev::HttpResponseBuilder, http::{StatusCode, header::LINK}, web::{Bytes, BytesMut, Query}};
+use crate::{model::account::{prelude::*, Account, AccountQuery, ENUM_CASING}, utils::{context::RequestContext, errors::InternalError, paging::{page_size, PAGE_SIZE_HEADER}}};
 
 ///
 /// Http handler for getting multiple accounts.
 ///
+/// The accounts are streamed as a JSON array as they're read from MongoDB, so the whole result is never
+/// held in memory. If MongoDB fails part-way through, the response is cut short.
+///
+/// The page size used is returned in the X-Page-Size header and links to the first, previous and next pages
+/// in the Link header (RFC 5988).
+///
 #[tracing::instrument(name="get_accounts", skip(ctx), level="info")]
-pub async fn handle(ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+pub async fn handle(Query(query): Query<AccountQuery>, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+    list_accounts(query, "/accounts", ctx).await
+}
+
+///
+/// Http handler for searching the accounts - every filter given (status, profileId, createdFrom/To, etc.) must
+/// match. The results are paged and streamed as for GET /accounts.
+///
+#[tracing::instrument(name="search_accounts", skip(ctx), level="info")]
+pub async fn handle_search(Query(query): Query<AccountQuery>, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+    list_accounts(query, "/accounts/search", ctx).await
+}
+
+///
+/// Stream a page of the accounts matching the query - the links to other pages are to the path given.
+///
+async fn list_accounts(query: AccountQuery, path: &str, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+
+    let limit = page_size(query.limit, ctx.config())?;
+    let cursor = get_accounts(&query, limit, &ctx).await?;
+    let links = page_links(&query, limit, path, &ctx).await?;
 
     Ok(HttpResponseBuilder::new(StatusCode::OK)
-        .json(get_accounts(&ctx).await?))
+        .content_type("application/json")
+        .header(PAGE_SIZE_HEADER, limit.to_string())
+        .header(LINK, links)
+        .streaming(json_array(cursor, ctx.config().enum_casing)))
 }
 
-pub async fn get_accounts(ctx: &RequestContext) -> Result<Vec<Account>, InternalError> {
+pub async fn get_accounts(query: &AccountQuery, limit: i64, ctx: &RequestContext) -> Result<Cursor<Account>, InternalError> {
+
+    query.validate()?;
+    let (filter, options) = query.to_find(limit);
 
     let collection = ctx.db().collection_with_type::<Account>(ACCOUNTS);
-    let cursor = collection.find(doc!{}, None).await?; // Yes this would return ALL accounts.
-    Ok(cursor.try_collect().await?)                    // In a real system we'd paginate and limit.
-}
\ No newline at end of file
+    Ok(collection.find(filter, options).await?)
+}
+
+///
+/// The Link header value for the query's other pages - so generic HTTP clients can page without knowing
+/// the query parameters.
+///
+/// Pages requested by skip link to the next (and previous) skip, otherwise the next page is linked by
+/// cursor - which only goes forwards. There's only a next link if more accounts match.
+///
+async fn page_links(query: &AccountQuery, limit: i64, path: &str, ctx: &RequestContext) -> Result<String, InternalError> {
+    let path = format!("{}{}", ctx.config().base_url.trim_end_matches('/'), path);
+    let link = |skip: Option<u64>, cursor: Option<&str>, rel: &str| {
+        format!("<{}?{}>; rel=\"{}\"", path, query.to_query_string(limit, skip, cursor), rel)
+    };
+
+    let mut links = vec!(link(None, None, "first"));
+
+    if let Some(skip) = query.skip.filter(|skip| *skip > 0) {
+        links.push(link(Some(skip.saturating_sub(limit as u64)), None, "prev"));
+    }
+
+    if let Some(last_account_id) = next_cursor(query, limit, ctx).await? {
+        links.push(match query.skip {
+            Some(skip) => link(Some(skip + limit as u64), None, "next"),
+            None => link(None, Some(&last_account_id), "next"),
+        });
+    }
+
+    Ok(links.join(", "))
+}
+
+///
+/// The last accountId on the page - if there are more accounts after it.
+///
+async fn next_cursor(query: &AccountQuery, limit: i64, ctx: &RequestContext) -> Result<Option<String>, InternalError> {
+    let (filter, options) = query.to_lookahead(limit);
+    let ids: Vec<Document> = ctx.db().collection(ACCOUNTS).find(filter, options).await?.try_collect().await?;
+
+    match ids.as_slice() {
+        [last, _next] => Ok(Some(last.get_str(ACCOUNT_ID)?.to_string())),
+        _ => Ok(None),
+    }
+}
+
+///
+/// Serialise each account as it's yielded by the cursor into a chunk of a JSON array.
+///
+/// The body is streamed after the request middleware has returned - so each account is serialised in the
+/// app's enum casing here, rather than relying on the request's.
+///
+fn json_array(cursor: Cursor<Account>, casing: EnumCasing) -> impl futures::Stream<Item = Result<Bytes, InternalError>> {
+    let accounts = cursor.enumerate().then(move |(idx, account)| Box::pin(ENUM_CASING.scope(casing, async move {
+        let mut chunk = BytesMut::new();
+        if idx > 0 {
+            chunk.extend_from_slice(b",");
+        }
+        chunk.extend_from_slice(&serde_json::to_vec(&account?)?);
+        Ok(chunk.freeze())
+    })))
+    .inspect(|chunk: &Result<Bytes, InternalError>| if let Err(err) = chunk {
+        error!("Failed to stream accounts: {}", err);
+    });
+
+    stream::once(ready(Ok(Bytes::from_static(b"["))))
+        .chain(accounts)
+        .chain(stream::once(ready(Ok(Bytes::from_static(b"]")))))
+}
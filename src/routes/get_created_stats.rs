@@ -0,0 +1,65 @@
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use futures::TryStreamExt;
+use mongodb::bson::{self, doc};
+use actix_web::{HttpResponse, dev::HttpResponseBuilder, http::StatusCode, web::Query};
+use crate::{model::account::{prelude::*, CreatedCount, CreatedStatsQuery}, utils::{context::RequestContext, errors::InternalError}};
+
+///
+/// Http handler for counting the accounts created on each day in a date range.
+///
+#[tracing::instrument(name="get_created_stats", skip(ctx), level="info")]
+pub async fn handle(Query(range): Query<CreatedStatsQuery>, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK)
+        .json(get_created_stats(range, &ctx).await?))
+}
+
+///
+/// Bucket the accounts created between the from and to dates (inclusive) by their creation day. Days
+/// without any accounts created are omitted.
+///
+pub async fn get_created_stats(range: CreatedStatsQuery, ctx: &RequestContext) -> Result<Vec<CreatedCount>, InternalError> {
+
+    validate_range(&range, ctx.config().max_stats_span_days)?;
+
+    let from = midnight(range.from);
+    let to = midnight(range.to + Duration::days(1));
+
+    let pipeline = vec!(
+        doc!{ "$match": { CREATED: { "$gte": from, "$lt": to } } },
+        doc!{ "$group": { "_id": { "$dateToString": { "format": "%Y-%m-%d", "date": "$created" } }, "count": { "$sum": 1 } } },
+        doc!{ "$sort": { "_id": 1 } },
+        doc!{ "$project": { "_id": 0, "date": "$_id", "count": 1 } },
+    );
+
+    let cursor = ctx.db().collection(ACCOUNTS).aggregate(pipeline, None).await?;
+    let buckets: Vec<bson::Document> = cursor.try_collect().await?;
+
+    Ok(buckets.into_iter()
+        .map(bson::from_document)
+        .collect::<Result<_, _>>()?)
+}
+
+///
+/// The range must be the right way round and no longer than the configured maximum - this bounds the
+/// cost of the aggregation.
+///
+fn validate_range(range: &CreatedStatsQuery, max_span_days: u32) -> Result<(), InternalError> {
+    if range.from > range.to {
+        return Err(InternalError::RequestFormatError { reason: "from must not be after to".to_string() })
+    }
+
+    if span_days(range.from, range.to) > max_span_days as i64 {
+        return Err(InternalError::RequestFormatError { reason: format!("The date range cannot exceed {} days", max_span_days) })
+    }
+
+    Ok(())
+}
+
+fn span_days(from: NaiveDate, to: NaiveDate) -> i64 {
+    (to - from).num_days() + 1
+}
+
+fn midnight(date: NaiveDate) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).expect("midnight is always valid"))
+}
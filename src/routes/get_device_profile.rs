@@ -15,14 +15,24 @@ pub async fn handle(Path(profile_id): Path<String>, ctx: RequestContext)
         Some(profile) => Ok(HttpResponseBuilder::new(StatusCode::OK).json(profile)),
 
         // Note: 204 rather than 404 (the latter indicates the uri isn't present not the content itself)
+        None if ctx.config().profile_not_found_404 => Ok(InternalError::DeviceProfileNotFound { profile_id }.not_found_response()),
         None => Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish())
     }
 }
 
 ///
-/// Return the specified device profile.
+/// Return the specified device profile - from the profile cache if it's there.
 ///
 pub async fn get_device_profile(profile_id: &str, ctx: &RequestContext) -> Result<Option<DeviceProfile>, InternalError> {
+    if let Some(profile) = ctx.profile_cache().device_profile(profile_id) {
+        return Ok(Some(profile))
+    }
+
     let collection = ctx.db().collection_with_type(DEVICE_PROFILES);
-    Ok(collection.find_one(doc! { "profileId": profile_id }, None).await?)
+    let profile: Option<DeviceProfile> = collection.find_one(doc! { "profileId": profile_id }, None).await?;
+
+    if let Some(profile) = &profile {
+        ctx.profile_cache().put_device_profile(profile_id, profile);
+    }
+    Ok(profile)
 }
\ No newline at end of file
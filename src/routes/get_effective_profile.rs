@@ -0,0 +1,53 @@
+use actix_web::{HttpResponse, dev::HttpResponseBuilder, http::StatusCode, web::Path};
+use super::{get_account::get_account, get_account_profile::get_account_profile, get_device_profile::get_device_profile};
+use crate::{model::profile::EffectiveProfile, utils::{context::RequestContext, errors::InternalError}};
+
+///
+/// Http handler for getting the effective profile of an account.
+///
+#[tracing::instrument(name="get_effective_profile", level="info")]
+pub async fn handle(Path(account_id): Path<String>, ctx: RequestContext)
+    -> Result<HttpResponse, InternalError> {
+
+    let profile = get_effective_profile(&account_id, &ctx).await?;
+
+    match profile {
+        Some(profile) => Ok(HttpResponseBuilder::new(StatusCode::OK).json(profile)),
+
+        // Note: 204 rather than 404 (the latter indicates the uri isn't present not the content itself)
+        None => Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish())
+    }
+}
+
+///
+/// Resolve the account's profile and the profiles of all of its devices. None is returned if the
+/// account doesn't exist.
+///
+pub async fn get_effective_profile(account_id: &str, ctx: &RequestContext)
+    -> Result<Option<EffectiveProfile>, InternalError> {
+
+    let account = match get_account(account_id, ctx).await? {
+        Some(account) => account,
+        None => return Ok(None)
+    };
+
+    let account_profile = match get_account_profile(&account.profile_id, ctx).await? {
+        Some(profile) => profile,
+        None => return Err(InternalError::AccountProfileNotFound { profile_id: account.profile_id })
+    };
+
+    // Many devices will share a profile, so only look each one up once.
+    let mut profile_ids: Vec<&str> = account.devices.iter().flatten().map(|device| device.profile_id.as_str()).collect();
+    profile_ids.sort_unstable();
+    profile_ids.dedup();
+
+    let mut device_profiles = Vec::with_capacity(profile_ids.len());
+    for profile_id in profile_ids {
+        match get_device_profile(profile_id, ctx).await? {
+            Some(profile) => device_profiles.push(profile),
+            None => return Err(InternalError::DeviceProfileNotFound { profile_id: profile_id.to_string() })
+        }
+    }
+
+    Ok(Some(EffectiveProfile { account_id: account.account_id, account_profile, device_profiles }))
+}
\ No newline at end of file
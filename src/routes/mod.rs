@@ -1,7 +1,18 @@
 pub mod admin;
 pub mod get_account;
+pub mod account_notes;
+pub mod account_history;
+pub mod account_export;
+pub mod account_events;
 pub mod get_accounts;
+pub mod get_created_stats;
 pub mod create_account;
 pub mod update_account;
+pub mod patch_account;
+pub mod rotate_account_id;
 pub mod get_device_profile;
-pub mod get_account_profile;
\ No newline at end of file
+pub mod device_profiles;
+pub mod get_effective_profile;
+pub mod get_account_profile;
+pub mod openapi;
+pub mod unknown_route;
\ No newline at end of file
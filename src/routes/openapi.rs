@@ -0,0 +1,50 @@
+use serde_json::{Number, Value};
+use lazy_static::lazy_static;
+use yaml_rust::{Yaml, YamlLoader};
+use actix_web::{HttpResponse, dev::HttpResponseBuilder, http::StatusCode};
+
+///
+/// The service's OpenAPI spec. It's maintained alongside the models (rather than derived from them) so
+/// it can carry descriptions and examples - it's compiled in so it always matches the running version.
+///
+const OPENAPI_YAML: &str = include_str!("../../openapi.yml");
+
+lazy_static! {
+    static ref OPENAPI_JSON: Value = {
+        let docs = YamlLoader::load_from_str(OPENAPI_YAML).expect("openapi.yml is not valid yaml");
+        docs.first().map(to_json).unwrap_or(Value::Null)
+    };
+}
+
+///
+/// Http handler returning the OpenAPI spec as JSON - describing each endpoint, the request/response models
+/// (field names, types and which are required) and the error response.
+///
+pub async fn handle() -> HttpResponse {
+    HttpResponseBuilder::new(StatusCode::OK).json(&*OPENAPI_JSON)
+}
+
+fn to_json(yaml: &Yaml) -> Value {
+    match yaml {
+        Yaml::Real(real)       => real.parse().ok().and_then(Number::from_f64).map(Value::Number).unwrap_or_else(|| Value::String(real.clone())),
+        Yaml::Integer(integer) => Value::from(*integer),
+        Yaml::String(string)   => Value::String(string.clone()),
+        Yaml::Boolean(boolean) => Value::Bool(*boolean),
+        Yaml::Array(array)     => Value::Array(array.iter().map(to_json).collect()),
+        Yaml::Hash(hash)       => Value::Object(hash.iter().map(|(key, value)| (to_key(key), to_json(value))).collect()),
+        Yaml::Alias(_) | Yaml::Null | Yaml::BadValue => Value::Null,
+    }
+}
+
+///
+/// JSON keys must be strings - unquoted yaml keys (eg. response codes) may not be.
+///
+fn to_key(yaml: &Yaml) -> String {
+    match yaml {
+        Yaml::String(string)   => string.clone(),
+        Yaml::Real(real)       => real.clone(),
+        Yaml::Integer(integer) => integer.to_string(),
+        Yaml::Boolean(boolean) => boolean.to_string(),
+        _ => String::new(),
+    }
+}
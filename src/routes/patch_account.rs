@@ -0,0 +1,189 @@
+use itertools::Itertools;
+use serde_json::{json, Value};
+use mongodb::bson::doc;
+use actix_web::{HttpRequest, HttpResponse, dev::HttpResponseBuilder, http::{StatusCode, header::{ETAG, IF_MATCH}}, web::{Json, Path}};
+use super::{create_account::validate_external_ids, get_account::get_account, get_account_profile::get_account_profile};
+use crate::{model::{account::{prelude::*, Account, AccountPatch}, change::{prelude::*, FieldChange}, outbox::prelude::OUTBOX, patch::PatchOperation, profile::prelude::PROFILE_ID}, utils::{audit::redact, context::RequestContext, errors::InternalError, mongo::Persistable, rabbit::{notify, prelude::*}}};
+
+///
+/// The account fields a patch may change (along with anything within them). The rest are either generated
+/// or have endpoints of their own, eg. status and devices.
+///
+const PATCHABLE: [&str; 5] = [PROFILE_ID, SALUTATION, BILLING_ADDRESS, BILLING_DATE, EXTERNAL_IDS];
+
+///
+/// Http handler for applying a JSON-patch (application/json-patch+json) to an account - the patched account is
+/// returned.
+///
+/// If an If-Match header is provided, the patch only proceeds if it matches the account's current ETag (see
+/// get_account) - otherwise a 412 is returned.
+///
+#[tracing::instrument(name="patch_account", skip(req, ops), level="info")]
+pub async fn handle(req: HttpRequest, Path(account_id): Path<String>, ops: Json<Vec<PatchOperation>>, ctx: RequestContext)
+    -> Result<HttpResponse, InternalError> {
+
+    let if_match = match req.headers().get(IF_MATCH) {
+        Some(value) => Some(value.to_str().map_err(|err| InternalError::RequestFormatError { reason: format!("Invalid If-Match header: {}", err) })?),
+        None => None,
+    };
+
+    let account = patch_account(&account_id, ops.into_inner(), if_match, &ctx.config().system_identity, &ctx).await?;
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK)
+        .header(ETAG, account.etag())
+        .json(account))
+}
+
+///
+/// Apply the operations, in order, to the account - attributed to the principal given. Either every operation
+/// is applied or, if any is invalid, none are.
+///
+/// Operations outside the PATCHABLE fields are rejected with a PatchPathNotAllowed error. The patched fields
+/// are validated as they would be on a new account. No operations is a MongoDBUpdateEmpty error unless
+/// empty_update_ok is configured.
+///
+/// Each field changed is recorded in the account's change history, with any audit_redacted_fields masked.
+///
+pub async fn patch_account(account_id: &str, ops: Vec<PatchOperation>, if_match: Option<&str>, modified_by: &str, ctx: &RequestContext)
+    -> Result<Account, InternalError> {
+
+    // If configured, nothing to change isn't an error - the account is returned unchanged.
+    if ops.is_empty() {
+        return match ctx.config().empty_update_ok {
+            true => get_account(account_id, ctx).await?.ok_or_else(|| InternalError::AccountNotFound { account_id: account_id.to_string() }),
+            false => Err(InternalError::MongoDBUpdateEmpty),
+        }
+    }
+
+    if let Some(op) = ops.iter().find(|op| !PATCHABLE.contains(&op.field().as_str())) {
+        return Err(InternalError::PatchPathNotAllowed { path: op.path().to_string() })
+    }
+
+    let _lock = ctx.account_lock(account_id).await;
+    let account = match get_account(account_id, ctx).await? {
+        Some(account) => account,
+        None => return Err(InternalError::AccountNotFound{ account_id: account_id.to_string() })
+    };
+
+    if account.status == AccountStatus::CANCELLED {
+        return Err(InternalError::AccountCancelled { account_id: account.account_id })
+    }
+
+    // Ensure the caller is patching the version of the account they think they are.
+    let mut filter = Account::id_filter(&account.account_id);
+    if let Some(if_match) = if_match {
+        if if_match != "*" && if_match != account.etag() {
+            return Err(InternalError::PreconditionFailed { account_id: account.account_id })
+        }
+
+        filter = account.version_filter();
+    }
+
+    // Apply the operations to the account's JSON and read the patchable fields back from it.
+    let original = serde_json::to_value(&account)?;
+    let mut json = original.clone();
+    for op in &ops {
+        op.apply(&mut json)?;
+    }
+
+    let patched: AccountPatch = serde_json::from_value(json.clone())
+        .map_err(|err| InternalError::RequestFormatError { reason: err.to_string() })?;
+
+    validate_patch(&patched, &account, ctx).await?;
+
+    // Set each field an operation touched - or unset it if it's been removed.
+    let now = ctx.now();
+    let changes = patched.to_doc()?;
+    let mut set = doc!{ MODIFIED: now, MODIFIED_BY: modified_by };
+    let mut unset = doc!{};
+    let mut history = vec!();
+    let redacted = ctx.config().audit_redacted_fields();
+    for field in ops.iter().map(PatchOperation::field).unique() {
+        match changes.get(&field) {
+            Some(value) => set.insert(&field, value.clone()),
+            None => unset.insert(&field, ""),
+        };
+
+        // Record the field's change - unless the operations left it as it was.
+        if original.get(&field) != json.get(&field) {
+            let (old_value, new_value) = (masked(&original, &field, redacted), masked(&json, &field, redacted));
+            history.push(FieldChange::to_doc(&format!("/{}", field), old_value.as_ref(), new_value.as_ref(), modified_by, now)?);
+        }
+    }
+
+    let mut doc = doc!{ "$set": set, "$inc": { VERSION: 1i64 } };
+    if !unset.is_empty() {
+        doc.insert("$unset", unset);
+    }
+
+    // Only the most recent changes are kept.
+    let mut push = doc!{ CHANGE_HISTORY: { "$each": history, "$slice": -(ctx.config().max_change_history as i64) } };
+
+    let mut notification = notify(TOPIC_ACCOUNT_UPDATED);
+    notification.body(json!({
+        "accountId": &account.account_id,
+        "ops": &ops,
+        "modifiedBy": modified_by
+    }));
+
+    // If configured, stage the notification in the account's outbox so it's written with the changes.
+    let outbox = ctx.config().transactional_outbox;
+    if outbox {
+        push.insert(OUTBOX, notification.stage(ctx)?);
+    }
+    doc.insert("$push", push);
+
+    let _permit = ctx.write_permit().await?;
+    let result = ctx.db().collection(ACCOUNTS).update_one(
+        /* Filter  */ filter,
+        /* Update  */ doc,
+        /* Options */ None)
+        .await?;
+
+    if result.matched_count == 0 {
+        return match if_match {
+            Some(_) => Err(InternalError::PreconditionFailed { account_id: account.account_id }),
+            None => Err(InternalError::AccountNotFound { account_id: account.account_id }),
+        }
+    }
+
+    if result.modified_count > 0 && !outbox {
+        notification.send(ctx);
+    }
+
+    match get_account(&account.account_id, ctx).await? {
+        Some(account) => Ok(account),
+        None => Err(InternalError::AccountNotFound { account_id: account.account_id }),
+    }
+}
+
+///
+/// The account's value for the field - with any of the redacted fields in it masked. None if it has no value.
+///
+fn masked(account: &Value, field: &str, redacted: &[String]) -> Option<Value> {
+    let mut value = json!({ field: account.get(field)? });
+    redact(&mut value, redacted);
+    value.get_mut(field).map(Value::take)
+}
+
+///
+/// The patched fields must be as valid as they'd need to be on a new account.
+///
+async fn validate_patch(patched: &AccountPatch, account: &Account, ctx: &RequestContext) -> Result<(), InternalError> {
+
+    if patched.profile_id != account.profile_id && get_account_profile(&patched.profile_id, ctx).await?.is_none() {
+        return Err(InternalError::AccountProfileNotFound { profile_id: patched.profile_id.clone() })
+    }
+
+    if let Some(salutation) = &patched.salutation {
+        if !ctx.config().salutation_allowed(salutation) {
+            return Err(InternalError::RequestFormatError { reason: format!("The salutation {} is not allowed", salutation) })
+        }
+    }
+
+    if let Some(external_ids) = &patched.external_ids {
+        validate_external_ids(external_ids, ctx.config().max_external_ids)?;
+    }
+
+    Ok(())
+}
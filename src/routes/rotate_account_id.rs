@@ -0,0 +1,133 @@
+use tracing::error;
+use serde_json::json;
+use mongodb::bson::{self, Bson, doc};
+use actix_web::{HttpResponse, dev::HttpResponseBuilder, http::StatusCode, web::Path};
+use crate::{clients::auth::AuthClient, model::{account::{prelude::*, Account}, outbox::prelude::OUTBOX}, utils::{context::RequestContext, errors::InternalError, mongo::generate_id, rabbit::{notify, prelude::*, NotificationRequest}}};
+
+///
+/// Http handler for replacing an account's accountId with a freshly generated one.
+///
+/// All the account's other data (devices, externalIds, notes, history, etc.) is kept. The caller needs the
+/// rotate-account-id claim.
+///
+#[tracing::instrument(name="rotate_account_id", skip(ctx), level="info")]
+pub async fn handle(Path(account_id): Path<String>, ctx: RequestContext) -> Result<HttpResponse, InternalError> {
+
+    // Do not allow unless the caller has the rotate-account-id permission.
+    let claims = AuthClient::new(&ctx).check_claim("rotate-account-id").await?;
+
+    let new_account_id = rotate_account_id(&account_id, claims.principal(&ctx), &ctx).await?;
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(json!({ ACCOUNT_ID: new_account_id })))
+}
+
+///
+/// Copy the account to a new generated accountId and delete the original - attributed to the principal
+/// given. The new accountId is returned.
+///
+/// Without multi-document transactions the copy can't be inserted while the original still holds the
+/// uniquely indexed devices and externalIds. So the original is first marked as rotating with those
+/// fields renamed aside (out of the indexes), then the copy is inserted and the original deleted. If the
+/// insert fails, the original is put back. Should the service die part-way, the original keeps it's
+/// devices and externalIds in the renamed fields.
+///
+pub async fn rotate_account_id(account_id: &str, modified_by: &str, ctx: &RequestContext) -> Result<String, InternalError> {
+
+    let _lock = ctx.account_lock(account_id).await;
+    let accounts = ctx.db().collection(ACCOUNTS);
+
+    let mut copy = match accounts.find_one(Account::id_filter(account_id), None).await? {
+        Some(account) => account,
+        None => return Err(InternalError::AccountNotFound { account_id: account_id.to_string() })
+    };
+
+    // The account lock is only per-instance, so the original must still be the version copied when it's renamed.
+    let mut current = Account::id_filter(account_id);
+    current.insert(VERSION, copy.get(VERSION).cloned().unwrap_or(Bson::Null));
+
+    let new_account_id = generate_id(ACCOUNT_ID, &mut copy, &None);
+    let now = ctx.now();
+    copy.remove("_id");
+    copy.insert(MODIFIED, now);
+    copy.insert(MODIFIED_BY, modified_by);
+    copy.insert(VERSION, copy.get_i64(VERSION).unwrap_or_default() + 1);
+
+    // The created notification has the same (credential free) account details as a normal create.
+    let mut public_doc = copy.clone();
+    public_doc.remove(CREDENTIALS);
+    let account: Account = bson::from_bson(public_doc.into())?;
+
+    let mut notifications = vec!(
+        notification(TOPIC_ACCOUNT_ID_ROTATED, json!({ "oldAccountId": account_id, "newAccountId": &new_account_id, "modifiedBy": modified_by })),
+        notification(TOPIC_ACCOUNT_CREATED, json!(account)),
+        notification(TOPIC_ACCOUNT_DELETED, json!({ ACCOUNT_ID: account_id, "modifiedBy": modified_by })));
+
+    // If configured, stage the notifications in the new account's outbox so they're written with it.
+    let outbox = ctx.config().transactional_outbox;
+    if outbox {
+        let mut staged = match copy.remove(OUTBOX) {
+            Some(Bson::Array(entries)) => entries,
+            _ => vec!(),
+        };
+
+        for notification in &notifications {
+            staged.push(notification.stage(ctx)?.into());
+        }
+        copy.insert(OUTBOX, staged);
+    }
+
+    let _permit = ctx.write_permit().await?;
+
+    // Move the original's indexed fields aside - only if it's not already being rotated, or been changed since it was read.
+    let result = accounts.update_one(current, doc!{
+            "$set": { ROTATED_TO: &new_account_id },
+            "$rename": { DEVICES: ROTATING_DEVICES, EXTERNAL_IDS: ROTATING_EXTERNAL_IDS } },
+        None)
+        .await?;
+
+    if result.modified_count == 0 {
+        return match accounts.find_one(Account::id_filter(account_id), None).await? {
+            Some(_) => Err(InternalError::AccountConflict { field: VERSION.to_string() }),
+            None => Err(InternalError::AccountNotFound { account_id: account_id.to_string() }),
+        }
+    }
+
+    if let Err(err) = accounts.insert_one(copy, None).await {
+        restore(account_id, &new_account_id, ctx).await?;
+        return Err(err.into())
+    }
+
+    // The copy has replaced the original now (which reads and writes skip) - so a failure to delete the original
+    // doesn't fail the rotation.
+    if let Err(err) = accounts.delete_one(doc!{ ACCOUNT_ID: account_id, ROTATED_TO: &new_account_id }, None).await {
+        error!("Failed to delete account {} once it was rotated to {}: {}", account_id, new_account_id, err);
+    }
+
+    if !outbox {
+        for notification in &mut notifications {
+            notification.send(ctx);
+        }
+    }
+
+    Ok(new_account_id)
+}
+
+///
+/// Undo the rotating mark on the original account - putting it's devices and externalIds back.
+///
+async fn restore(account_id: &str, new_account_id: &str, ctx: &RequestContext) -> Result<(), InternalError> {
+    ctx.db().collection(ACCOUNTS).update_one(
+        /* Filter  */ doc!{ ACCOUNT_ID: account_id, ROTATED_TO: new_account_id },
+        /* Update  */ doc!{
+            "$unset": { ROTATED_TO: "" },
+            "$rename": { ROTATING_DEVICES: DEVICES, ROTATING_EXTERNAL_IDS: EXTERNAL_IDS } },
+        /* Options */ None)
+        .await?;
+    Ok(())
+}
+
+fn notification(topic: &'static str, body: serde_json::Value) -> NotificationRequest {
+    let mut notification = notify(topic);
+    notification.body(body);
+    notification
+}
@@ -0,0 +1,10 @@
+use actix_web::{HttpRequest, HttpResponse};
+use crate::utils::errors::InternalError;
+
+///
+/// The default service for requests which don't match any endpoint - so they get the same JSON error
+/// body as every other failure, rather than actix's empty 404.
+///
+pub async fn handle(req: HttpRequest) -> Result<HttpResponse, InternalError> {
+    Err(InternalError::RouteNotFound { method: req.method().to_string(), path: req.path().to_string() })
+}
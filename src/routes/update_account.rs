@@ -1,51 +1,142 @@
-use serde_json::json;
+use tracing::warn;
+use serde_json::{json, Value};
+use chrono::{DateTime, Utc};
 use mongodb::bson::{Document, doc};
-use actix_web::{HttpResponse, dev::HttpResponseBuilder, http::StatusCode, web::Json};
-use crate::{model::account::{prelude::*, Account, StatusModification}, routes::get_account::get_account, utils::{context::RequestContext, errors::InternalError, rabbit::{notify, prelude::*}}};
+use actix_web::{HttpRequest, HttpResponse, dev::HttpResponseBuilder, http::{StatusCode, header::IF_MATCH}, web::{Json, Path}};
+use crate::{clients::auth::AuthClient, model::{account::{prelude::*, Account, Reactivation, StatusChange, StatusModification, StatusModificationResult}, outbox::prelude::OUTBOX}, routes::get_account::get_account, utils::{audit::redact, config::Configuration, context::RequestContext, errors::InternalError, rabbit::{notify, prelude::*}}};
 
 ///
 /// Http handler for updating an account's status.
 ///
-#[tracing::instrument(name="update_account_status", level="info")]
-pub async fn handle_status(update: Json<StatusModification>, ctx: RequestContext)
+/// If an If-Match header is provided, the update only proceeds if it matches the account's current
+/// ETag (see get_account) - otherwise a 412 is returned.
+///
+#[tracing::instrument(name="update_account_status", skip(req), level="info")]
+pub async fn handle_status(req: HttpRequest, update: Json<StatusModification>, ctx: RequestContext)
     -> Result<HttpResponse, InternalError> {
 
-    update_account_status(update.into_inner(), &ctx).await?;
+    let if_match = match req.headers().get(IF_MATCH) {
+        Some(value) => Some(value.to_str().map_err(|err| InternalError::RequestFormatError { reason: format!("Invalid If-Match header: {}", err) })?),
+        None => None,
+    };
+
+    update_account_status(update.into_inner(), if_match, &ctx.config().system_identity, &ctx).await?;
 
     Ok(HttpResponseBuilder::new(StatusCode::OK).finish())
 }
 
 ///
-/// Update the account's status. An error is returned if the update cannot proceed.
+/// Http handler for updating the status of many accounts in one request.
+///
+/// Each item is processed independently (unordered) so one invalid item doesn't prevent the
+/// others from being updated. The response contains a result for each item in the request.
+///
+#[tracing::instrument(name="update_account_statuses", skip(updates), level="info")]
+pub async fn handle_statuses(updates: Json<Vec<StatusModification>>, ctx: RequestContext)
+    -> Result<HttpResponse, InternalError> {
+
+    let results = update_account_statuses(updates.into_inner(), &ctx).await;
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(results))
+}
+
+///
+/// Update each account's status in turn, capturing the outcome of each update rather than
+/// failing the entire batch.
+///
+pub async fn update_account_statuses(updates: Vec<StatusModification>, ctx: &RequestContext)
+    -> Vec<StatusModificationResult> {
+
+    let mut results = Vec::with_capacity(updates.len());
+
+    for update in updates {
+        let account_id = update.account_id.clone();
+
+        let result = match update_account_status(update, None, &ctx.config().system_identity, ctx).await {
+            Ok(_) => StatusModificationResult { account_id, updated: true, error_code: None, message: None },
+            Err(err) => {
+                warn!("Batch status update failed for account {}: {}", account_id, err);
+                StatusModificationResult { account_id, updated: false, error_code: Some(err.error_code()), message: err.client_message() }
+            }
+        };
+
+        results.push(result);
+    }
+
+    results
+}
+
+///
+/// Update the account's status - attributed to the principal given. An error is returned if the update
+/// cannot proceed.
+///
+/// If an ETag is expected, the update fails with a PreconditionFailed error if the account doesn't
+/// match it - including if the account is modified by someone else part-way through this update.
 ///
-pub async fn update_account_status(update: StatusModification, ctx: &RequestContext)
+pub async fn update_account_status(update: StatusModification, if_match: Option<&str>, modified_by: &str, ctx: &RequestContext)
     -> Result<(), InternalError> {
 
+    // If configured, wait for any other write to the account through this instance - so they don't interleave.
+    let _lock = ctx.account_lock(&update.account_id).await;
+
     // Find the account.
     let account = match get_account(&update.account_id, ctx).await? {
         Some(account) => account,
         None => return Err(InternalError::AccountNotFound{ account_id: update.account_id })
     };
 
+    // Ensure the caller is updating the version of the account they think they are.
+    let mut filter = Account::id_filter(&account.account_id);
+    if let Some(if_match) = if_match {
+        if if_match != "*" && if_match != account.etag() {
+            return Err(InternalError::PreconditionFailed { account_id: account.account_id })
+        }
+
+        filter = account.version_filter();
+    }
+
     // Validate and populate defaults.
-    let doc = validate_status_update(&update, &account, ctx).await?;
+    let now = ctx.now();
+    let mut doc = validate_status_update(&update, &account, modified_by, now).await?;
+
+    let mut body = json!({
+        "accountId": &account.account_id,
+        "oldStatus": account.status,
+        "newStatus": update.status,
+        "modifiedBy": modified_by
+    });
 
-    // Update the account in MongoDB now.
+    if let Some((before, after)) = snapshots(&account, &update, modified_by, now, ctx.config())? {
+        body["before"] = before;
+        body["after"] = after;
+    }
+
+    let mut notification = notify(TOPIC_ACCOUNT_STATUS_UPDATED);
+    notification
+        .routing_key(ROUTING_ACCOUNT_STATUS_UPDATED)
+        .body(body);
+
+    // If configured, stage the notification in the account's outbox so it's written with the status.
+    let outbox = ctx.config().transactional_outbox;
+    if outbox {
+        doc.get_document_mut("$push")?.insert(OUTBOX, notification.stage(ctx)?);
+    }
+
+    // Update the account in MongoDB now - once there's capacity for another write.
+    let _permit = ctx.write_permit().await?;
     let result = ctx.db().collection(ACCOUNTS).update_one(
-        /* Filter  */ doc!{ ACCOUNT_ID: &account.account_id },
+        /* Filter  */ filter,
         /* Update  */ doc,
         /* Options */ None)
         .await?;
 
+    if if_match.is_some() && result.matched_count == 0 {
+        return Err(InternalError::PreconditionFailed { account_id: account.account_id })
+    }
+
     // Emit a notification to RabbitMQ (or whatever event system is configured).
-    if result.modified_count > 0 {
-        notify(TOPIC_ACCOUNT_STATUS_UPDATED)
-            .body(json!({
-                "accountId": &account.account_id,
-                "oldStatus": account.status,
-                "newStatus": update.status
-            }))
-            .send(&ctx);
+    if result.modified_count > 0 && !outbox {
+        notification.send(ctx);
     }
 
     Ok(())
@@ -54,12 +145,134 @@ pub async fn update_account_status(update: StatusModification, ctx: &RequestCont
 ///
 /// Validate the request and populate additional details - returning a MongoDB Document to insert if all is good.
 ///
-async fn validate_status_update(update: &StatusModification, account: &Account, ctx: &RequestContext)
+async fn validate_status_update(update: &StatusModification, account: &Account, modified_by: &str, now: DateTime<Utc>)
     -> Result<Document, InternalError> {
 
     if account.status == AccountStatus::CANCELLED {
         return Err(InternalError::AccountCancelled {account_id: account.account_id.clone() })
     }
 
-    Ok(doc! { "$set": { STATUS: update.status, MODIFIED: ctx.now() } })
+    Ok(doc! {
+        "$set": { STATUS: update.status, MODIFIED: now, MODIFIED_BY: modified_by },
+        "$inc": { VERSION: 1i64 },
+        "$push": { STATUS_HISTORY: StatusChange::to_doc(account.status, update.status, modified_by, None, now) }
+    })
+}
+
+///
+/// If status_snapshot_fields is configured, the account (or the configured fields of it) before and after the
+/// status change - for event-sourced consumers. Any audit_redacted_fields are masked.
+///
+fn snapshots(account: &Account, update: &StatusModification, modified_by: &str, now: DateTime<Utc>, config: &Configuration)
+    -> Result<Option<(Value, Value)>, InternalError> {
+
+    let fields = config.status_snapshot_fields();
+    if fields.is_empty() {
+        return Ok(None)
+    }
+
+    let mut before = serde_json::to_value(account)?;
+    let mut after = before.clone();
+    after[STATUS] = json!(update.status);
+    after[MODIFIED] = json!(now);
+    after[MODIFIED_BY] = json!(modified_by);
+
+    let change = StatusChange { old_status: account.status, new_status: update.status, modified_by: modified_by.to_string(), reason: None, modified: now };
+    match after.get_mut(STATUS_HISTORY).and_then(Value::as_array_mut) {
+        Some(history) => history.push(json!(change)),
+        None => after[STATUS_HISTORY] = json!([change]),
+    }
+
+    let all = fields.iter().any(|field| field == "*");
+    for snapshot in [&mut before, &mut after] {
+        if let (false, Value::Object(snapshot)) = (all, &mut *snapshot) {
+            snapshot.retain(|field, _| fields.contains(field));
+        }
+        redact(snapshot, config.audit_redacted_fields());
+    }
+
+    Ok(Some((before, after)))
+}
+
+///
+/// Http handler for reactivating a cancelled account.
+///
+/// CANCELLED is otherwise terminal - this is the controlled exception for accounts cancelled in error. The
+/// caller needs the reactivate-account claim and must give a reason, which is kept in the status history.
+///
+#[tracing::instrument(name="reactivate_account", skip(ctx), level="info")]
+pub async fn handle_reactivate(Path(account_id): Path<String>, reactivation: Json<Reactivation>, ctx: RequestContext)
+    -> Result<HttpResponse, InternalError> {
+
+    // Do not allow unless the caller has the reactivate-account permission.
+    let claims = AuthClient::new(&ctx).check_claim("reactivate-account").await?;
+
+    reactivate_account(&account_id, reactivation.into_inner(), claims.principal(&ctx), &ctx).await?;
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).finish())
+}
+
+///
+/// Move a CANCELLED account back to ACTIVE - attributed to the principal given. Any other status is left
+/// alone with an AccountNotCancelled error.
+///
+pub async fn reactivate_account(account_id: &str, reactivation: Reactivation, modified_by: &str, ctx: &RequestContext)
+    -> Result<(), InternalError> {
+
+    if reactivation.reason.trim().is_empty() {
+        return Err(InternalError::RequestFormatError { reason: "A reason is required to reactivate an account".to_string() })
+    }
+
+    let _lock = ctx.account_lock(account_id).await;
+    let account = match get_account(account_id, ctx).await? {
+        Some(account) => account,
+        None => return Err(InternalError::AccountNotFound{ account_id: account_id.to_string() })
+    };
+
+    if account.status != AccountStatus::CANCELLED {
+        return Err(InternalError::AccountNotCancelled { account_id: account.account_id })
+    }
+
+    let now = ctx.now();
+    let mut doc = doc! {
+        "$set": { STATUS: AccountStatus::ACTIVE, MODIFIED: now, MODIFIED_BY: modified_by },
+        "$inc": { VERSION: 1i64 },
+        "$push": { STATUS_HISTORY: StatusChange::to_doc(AccountStatus::CANCELLED, AccountStatus::ACTIVE, modified_by, Some(&reactivation.reason), now) }
+    };
+
+    let mut notification = notify(TOPIC_ACCOUNT_REACTIVATED);
+    notification.body(json!({
+        "accountId": &account.account_id,
+        "oldStatus": AccountStatus::CANCELLED,
+        "newStatus": AccountStatus::ACTIVE,
+        "reason": &reactivation.reason,
+        "modifiedBy": modified_by
+    }));
+
+    // If configured, stage the notification in the account's outbox so it's written with the status.
+    let outbox = ctx.config().transactional_outbox;
+    if outbox {
+        doc.get_document_mut("$push")?.insert(OUTBOX, notification.stage(ctx)?);
+    }
+
+    // Only if it's still cancelled - so a concurrent reactivation doesn't happen twice.
+    let mut filter = Account::id_filter(&account.account_id);
+    filter.insert(STATUS, AccountStatus::CANCELLED.filter());
+
+    let _permit = ctx.write_permit().await?;
+    let result = ctx.db().collection(ACCOUNTS).update_one(
+        /* Filter  */ filter,
+        /* Update  */ doc,
+        /* Options */ None)
+        .await?;
+
+    if result.modified_count == 0 {
+        return Err(InternalError::AccountNotCancelled { account_id: account.account_id })
+    }
+
+    if !outbox {
+        notification.send(ctx);
+    }
+
+    Ok(())
 }
@@ -0,0 +1,66 @@
+use std::sync::Arc;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+///
+/// Serialises the writes handlers make to the same account within this instance - so two concurrent
+/// updates to an account can't interleave their reads and writes. Writes to different accounts aren't
+/// held up.
+///
+/// This only helps within one process - concurrent writes from other instances still rely on the
+/// If-Match (modified) checks. A lock is only kept while it's held or waited for.
+///
+#[derive(Default)]
+pub struct AccountLocks {
+    locks: Arc<Mutex<HashMap<String, Arc<AsyncMutex<()>>>>>,
+}
+
+///
+/// Holds an account's lock until dropped.
+///
+pub struct AccountLock {
+    account_id: String,
+    guard: Option<OwnedMutexGuard<()>>,
+    locks: Arc<Mutex<HashMap<String, Arc<AsyncMutex<()>>>>>,
+}
+
+impl AccountLocks {
+    ///
+    /// Wait for any other write to the account (through this instance) to finish - then hold it's lock.
+    ///
+    pub async fn lock(&self, account_id: &str) -> AccountLock {
+        let lock = {
+            let mut locks = self.locks.lock();
+
+            // Evict any left behind by requests which gave up waiting.
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+
+            locks.entry(account_id.to_string())
+                .or_insert_with(|| Arc::new(AsyncMutex::new(())))
+                .clone()
+        };
+
+        AccountLock {
+            account_id: account_id.to_string(),
+            guard: Some(lock.lock_owned().await),
+            locks: self.locks.clone(),
+        }
+    }
+}
+
+impl Drop for AccountLock {
+    ///
+    /// Release the account's lock - and evict it if no-one else is waiting for it.
+    ///
+    fn drop(&mut self) {
+        drop(self.guard.take());
+
+        let mut locks = self.locks.lock();
+        if let Some(lock) = locks.get(&self.account_id) {
+            if Arc::strong_count(lock) == 1 {
+                locks.remove(&self.account_id);
+            }
+        }
+    }
+}
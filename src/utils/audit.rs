@@ -0,0 +1,77 @@
+use tracing::info;
+use serde_json::Value;
+use actix_web::dev::ServiceRequest;
+use super::config::{Configuration, REDACTED};
+
+//
+// The audit log records who called which write endpoint with what - one structured event per request,
+// without the volume of the tracer. Only the endpoints (by handler name) in the audited_endpoints
+// setting are audited and reads never are.
+//
+// Bodies are logged as JSON with the values of any audit_redacted_fields masked (at any depth). A body
+// which isn't JSON (or was too large to read whole) can't be redacted so only it's size is logged.
+//
+
+///
+/// If the request is to a write endpoint which is configured to be audited.
+///
+pub fn audited(req: &ServiceRequest, endpoint: Option<&str>, config: &Configuration) -> bool {
+    if req.method().is_safe() {
+        return false
+    }
+
+    match endpoint {
+        Some(endpoint) => config.audited_endpoints().iter().any(|audited| audited == endpoint),
+        None => false,
+    }
+}
+
+///
+/// Emit the audit event for a completed request.
+///
+pub fn audit(request_id: &str, principal: &str, method: &str, route: &str, path: &str, status: u16, body: &str) {
+    info!(target: "audit",
+        correlation_id = request_id,
+        principal,
+        method,
+        route,
+        path,
+        status,
+        body,
+        "Audited {} {}", method, route);
+}
+
+///
+/// The body as it may be written to the audit log.
+///
+pub fn redact_body(body: &[u8], complete: bool, fields: &[String]) -> String {
+    if body.is_empty() {
+        return String::new()
+    }
+
+    match serde_json::from_slice::<Value>(body) {
+        Ok(mut json) if complete => {
+            redact(&mut json, fields);
+            json.to_string()
+        },
+        _ => format!("<{} bytes not audited>", body.len()),
+    }
+}
+
+///
+/// Mask the fields (at any depth) in the JSON.
+///
+pub fn redact(json: &mut Value, fields: &[String]) {
+    match json {
+        Value::Object(map) => for (key, value) in map.iter_mut() {
+            match fields.iter().any(|field| field.eq_ignore_ascii_case(key)) {
+                true  => *value = Value::String(REDACTED.to_string()),
+                false => redact(value, fields),
+            }
+        },
+        Value::Array(array) => for value in array.iter_mut() {
+            redact(value, fields);
+        },
+        _ => {},
+    }
+}
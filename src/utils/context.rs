@@ -1,12 +1,14 @@
-use std::sync::Arc;
+use std::{sync::{Arc, atomic::Ordering}, time::Duration};
+use actix_rt::time::timeout;
+use tokio::sync::{Semaphore, SemaphorePermit};
 use mongodb::Database;
 use parking_lot::RwLock;
 use chrono::{DateTime, Utc};
 use futures::future::{err, ok, Ready};
 use actix_http::{Error, error::ErrorBadRequest};
-use crate::routes::admin::set_time::TimeProvider;
-use super::{config::Configuration, http::http_client, rabbit::Publisher};
-use actix_web::{FromRequest, HttpRequest, client::Client, dev, web::Data};
+use crate::routes::admin::{chaos::Chaos, set_time::Clock, toggles::EndpointToggles};
+use super::{account_locks::{AccountLock, AccountLocks}, config::Configuration, errors::InternalError, http::{http_client, warm_up}, profile_cache::ProfileCache, rabbit::{Publisher, RabbitConnected}};
+use actix_web::{FromRequest, HttpRequest, client::Client, dev, http::HeaderName, web::Data};
 
 //
 // The context is used to pass globals around and share them between HTTP handlers.
@@ -30,17 +32,40 @@ use actix_web::{FromRequest, HttpRequest, client::Client, dev, web::Data};
 pub struct InitialisationContext {
     db: Database,
     publisher: Publisher,
+    rabbit_connected: RabbitConnected,
     config: Configuration,
-    time_provider: Arc<RwLock<TimeProvider>>
+    time_provider: Clock,
+    toggles: Arc<RwLock<EndpointToggles>>,
+    write_permits: Option<Semaphore>,
+    profile_cache: ProfileCache,
+    chaos: Option<Chaos>, // Only with allow_test_endpoints.
+    account_locks: Option<AccountLocks>,
 }
 
 impl InitialisationContext {
-    pub fn new(db: Database, config: Configuration, publisher: Publisher) -> Self {
+    pub fn new(db: Database, config: Configuration, publisher: Publisher, rabbit_connected: RabbitConnected, time_provider: Clock) -> Self {
+        let toggles = EndpointToggles::new(config.disabled_endpoints());
+        let write_permits = config.max_concurrent_writes.map(Semaphore::new);
+        let profile_cache = ProfileCache::new(config.profile_cache_size, Duration::from_secs(config.profile_cache_ttl));
+        let account_locks = match config.account_write_lock {
+            true  => Some(AccountLocks::default()),
+            false => None,
+        };
+        let chaos = match config.allow_test_endpoints {
+            true  => Some(Chaos::default()),
+            false => None,
+        };
         InitialisationContext {
             db,
             config,
             publisher,
-            time_provider: Arc::new(RwLock::new(TimeProvider::default()))
+            rabbit_connected,
+            time_provider,
+            toggles: Arc::new(RwLock::new(toggles)),
+            write_permits,
+            profile_cache,
+            chaos,
+            account_locks,
         }
     }
 
@@ -52,6 +77,10 @@ impl InitialisationContext {
         &self.publisher
     }
 
+    pub fn rabbit_connected(&self) -> &RabbitConnected {
+        &self.rabbit_connected
+    }
+
     pub fn now(&self) -> DateTime<Utc> {
         self.time_provider.read().now()
     }
@@ -65,9 +94,57 @@ impl InitialisationContext {
         self.time_provider.write().fix(now);
     }
 
+    pub fn is_endpoint_disabled(&self, endpoint: &str) -> bool {
+        self.toggles.read().is_disabled(endpoint)
+    }
+
+    pub fn set_endpoint_disabled(&self, endpoint: &str, disabled: bool) {
+        self.toggles.write().set_disabled(endpoint, disabled);
+    }
+
+    pub fn disabled_endpoints(&self) -> Vec<String> {
+        self.toggles.read().disabled()
+    }
+
+    pub fn in_maintenance(&self) -> bool {
+        self.toggles.read().in_maintenance()
+    }
+
+    pub fn set_maintenance(&self, maintenance: bool) {
+        self.toggles.write().set_maintenance(maintenance);
+    }
+
     pub fn config(&self) -> &Configuration {
         &self.config
     }
+
+    pub fn profile_cache(&self) -> &ProfileCache {
+        &self.profile_cache
+    }
+
+    pub fn chaos(&self) -> Option<&Chaos> {
+        self.chaos.as_ref()
+    }
+
+    pub async fn write_permit(&self) -> Result<Option<SemaphorePermit<'_>>, InternalError> {
+        if self.in_maintenance() {
+            return Err(InternalError::MaintenanceMode { retry_after: self.config.maintenance_retry_after })
+        }
+
+        match &self.write_permits {
+            None => Ok(None),
+            Some(permits) => timeout(Duration::from_millis(self.config.write_permit_timeout), permits.acquire()).await
+                .map(Some)
+                .map_err(|_| InternalError::MongoWritesBusy),
+        }
+    }
+
+    pub async fn account_lock(&self, account_id: &str) -> Option<AccountLock> {
+        match &self.account_locks {
+            None => None,
+            Some(locks) => Some(locks.lock(account_id).await),
+        }
+    }
 }
 
 ///
@@ -98,6 +175,10 @@ impl PartialRequestContext {
         &self.inner.publisher
     }
 
+    pub fn rabbit_connected(&self) -> bool {
+        self.inner.rabbit_connected.load(Ordering::SeqCst)
+    }
+
     pub fn now(&self) -> DateTime<Utc> {
         self.inner.now()
     }
@@ -106,20 +187,63 @@ impl PartialRequestContext {
         self.inner.set_now(now);
     }
 
+    pub fn is_endpoint_disabled(&self, endpoint: &str) -> bool {
+        self.inner.is_endpoint_disabled(endpoint)
+    }
+
+    pub fn set_endpoint_disabled(&self, endpoint: &str, disabled: bool) {
+        self.inner.set_endpoint_disabled(endpoint, disabled);
+    }
+
+    pub fn disabled_endpoints(&self) -> Vec<String> {
+        self.inner.disabled_endpoints()
+    }
+
+    pub fn in_maintenance(&self) -> bool {
+        self.inner.in_maintenance()
+    }
+
+    pub fn set_maintenance(&self, maintenance: bool) {
+        self.inner.set_maintenance(maintenance);
+    }
+
     pub fn config(&self) -> &Configuration {
         &self.inner.config
     }
+
+    pub fn profile_cache(&self) -> &ProfileCache {
+        self.inner.profile_cache()
+    }
+
+    pub fn chaos(&self) -> Option<&Chaos> {
+        self.inner.chaos()
+    }
+
+    pub async fn write_permit(&self) -> Result<Option<SemaphorePermit<'_>>, InternalError> {
+        self.inner.write_permit().await
+    }
+
+    pub async fn account_lock(&self, account_id: &str) -> Option<AccountLock> {
+        self.inner.account_lock(account_id).await
+    }
 }
 
 ///
-/// Wrap the InitialisationContext and create a HTTP client to boot.
+/// Wrap the InitialisationContext and create a HTTP client to boot - warming-up it's connections if
+/// configured.
 ///
 impl From<Arc<InitialisationContext>> for PartialRequestContext {
     fn from(ctx: Arc<InitialisationContext>) -> Self {
         let cfg = ctx.config().clone();
+        let client = http_client(&cfg);
+
+        if cfg.client_warm_up {
+            actix_rt::spawn(warm_up(client.clone(), cfg.downstream_addresses()));
+        }
+
         PartialRequestContext {
             inner: ctx,
-            client: http_client(&cfg),
+            client,
         }
     }
 }
@@ -140,6 +264,8 @@ pub struct RequestContext {
     inner: Arc<PartialRequestContext>,
     request_id: String,
     tracer: bool,        // If set, tracer will log all request/responses
+    headers: Arc<Vec<(HeaderName, String)>>, // The configured context_headers present on the request.
+    principal: Arc<RwLock<Option<String>>>, // The caller - once a claim check has identified them.
 }
 
 impl RequestContext {
@@ -147,11 +273,13 @@ impl RequestContext {
     /// Convert the thread's PartialRequestContext and request_id into a request-specific
     /// RequestContext.
     ///
-    pub fn from(http_context: Data<PartialRequestContext>, request_id: String, tracer: bool) -> Self {
+    pub fn from(http_context: Data<PartialRequestContext>, request_id: String, tracer: bool, headers: Vec<(HeaderName, String)>) -> Self {
         RequestContext {
             inner: http_context.into_inner(),
             request_id,
             tracer,
+            headers: Arc::new(headers),
+            principal: Arc::new(RwLock::new(None)),
         }
     }
 
@@ -189,6 +317,10 @@ impl RequestContext {
         &self.inner.publisher()
     }
 
+    pub fn rabbit_connected(&self) -> bool {
+        self.inner.rabbit_connected()
+    }
+
     ///
     /// Return the current Utc timezone time. Tests can alter/fix this value.
     ///
@@ -205,6 +337,34 @@ impl RequestContext {
         self.inner.set_now(now);
     }
 
+    ///
+    /// Switch an endpoint (by handler name) off or back on. Takes effect immediately for all workers.
+    ///
+    pub fn set_endpoint_disabled(&self, endpoint: &str, disabled: bool) {
+        self.inner.set_endpoint_disabled(endpoint, disabled);
+    }
+
+    ///
+    /// The endpoints (by handler name) which are currently switched off.
+    ///
+    pub fn disabled_endpoints(&self) -> Vec<String> {
+        self.inner.disabled_endpoints()
+    }
+
+    ///
+    /// Is the service in maintenance mode - refusing writes?
+    ///
+    pub fn in_maintenance(&self) -> bool {
+        self.inner.in_maintenance()
+    }
+
+    ///
+    /// Enter or leave maintenance mode. Takes effect immediately for all workers.
+    ///
+    pub fn set_maintenance(&self, maintenance: bool) {
+        self.inner.set_maintenance(maintenance);
+    }
+
     ///
     /// The service's static configuration, initially loaded through environment variables and
     /// file secrets.
@@ -213,6 +373,40 @@ impl RequestContext {
         &self.inner.config()
     }
 
+    ///
+    /// The recently used account and device profiles - shared by all the workers.
+    ///
+    pub fn profile_cache(&self) -> &ProfileCache {
+        self.inner.profile_cache()
+    }
+
+    ///
+    /// The faults injected into endpoints for chaos testing - None unless allow_test_endpoints is
+    /// configured, so they can never be injected in production.
+    ///
+    pub fn chaos(&self) -> Option<&Chaos> {
+        self.inner.chaos()
+    }
+
+    ///
+    /// Wait (briefly) for permission to write to MongoDB - the permit should be held until the write
+    /// completes. If max_concurrent_writes are already in progress this fails with a 503, so bursts are
+    /// pushed back onto callers rather than piled onto MongoDB. None is returned if there's no limit.
+    ///
+    /// In maintenance mode every write is refused with a 503.
+    ///
+    pub async fn write_permit(&self) -> Result<Option<SemaphorePermit<'_>>, InternalError> {
+        self.inner.write_permit().await
+    }
+
+    ///
+    /// If account_write_lock is configured, wait for any other write to the account (through this instance)
+    /// to finish - the lock should be held until this write completes. None is returned if it's not configured.
+    ///
+    pub async fn account_lock(&self, account_id: &str) -> Option<AccountLock> {
+        self.inner.account_lock(account_id).await
+    }
+
     ///
     /// Indicates if this request should be logged by tracer. Typically this will be if tracer is
     /// turned on, or if the request headers match those required for a tracer bullet (see tracer.rs
@@ -221,6 +415,28 @@ impl RequestContext {
     pub fn tracer(&self) -> bool {
         self.tracer
     }
+
+    ///
+    /// The value of a request header (case-insensitive) - if it was present. Only the headers named in
+    /// the context_headers setting are captured, so handlers don't need the HttpRequest itself.
+    ///
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header.as_str().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    ///
+    /// The authenticated caller, if a claim check has identified them. This is shared with the
+    /// middleware so the request can be audited once it's handled.
+    ///
+    pub fn principal(&self) -> Option<String> {
+        self.principal.read().clone()
+    }
+
+    pub fn set_principal(&self, principal: &str) {
+        *self.principal.write() = Some(principal.to_string());
+    }
 }
 
 ///
@@ -233,7 +449,7 @@ impl FromRequest for RequestContext {
 
     fn from_request(req: &HttpRequest, _payload: &mut dev::Payload) -> Self::Future {
         if let Some(ctx) = req.extensions().get::<RequestContext>() {
-            ok(RequestContext { inner: ctx.inner.clone(), request_id: ctx.request_id.clone(), tracer: ctx.tracer.clone() } )
+            ok(RequestContext { inner: ctx.inner.clone(), request_id: ctx.request_id.clone(), tracer: ctx.tracer.clone(), headers: ctx.headers.clone(), principal: ctx.principal.clone() } )
         } else {
             err(ErrorBadRequest("request context is missing"))
         }
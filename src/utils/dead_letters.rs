@@ -0,0 +1,56 @@
+use tracing::{error, info};
+use mongodb::{Database, bson::{self, Bson, doc}, options::FindOptions};
+use futures::{StreamExt, TryStreamExt, channel::mpsc::{UnboundedReceiver, UnboundedSender}};
+use crate::{model::dead_letter::{prelude::*, DeadLetter}, utils::errors::InternalError};
+
+///
+/// The RabbitMQ publisher thread is not async - so it hands any notifications it couldn't publish
+/// to an async task via this channel, which then writes them to MongoDB.
+///
+pub type DeadLetterSender = UnboundedSender<DeadLetter>;
+
+///
+/// Write dead letters to MongoDB as they arrive, until the sender is dropped.
+///
+pub async fn write_dead_letters(db: Database, mut rx: UnboundedReceiver<DeadLetter>) {
+    while let Some(dead_letter) = rx.next().await {
+        match store_dead_letter(&db, &dead_letter).await {
+            Ok(_) => info!("Notification {} written to {}", dead_letter.dead_letter_id, DEAD_LETTERS),
+            Err(err) => error!("Failed to write dead letter {:?} : {}", dead_letter, err.to_string()),
+        }
+    }
+}
+
+async fn store_dead_letter(db: &Database, dead_letter: &DeadLetter) -> Result<(), InternalError> {
+    let body = bson::to_bson(&dead_letter.body)?;
+
+    db.collection(DEAD_LETTERS).insert_one(doc!{
+        DEAD_LETTER_ID: &dead_letter.dead_letter_id,
+        TOPIC: &dead_letter.topic,
+        ROUTING_KEY: dead_letter.routing_key.as_ref().map(|key| Bson::String(key.clone())).unwrap_or(Bson::Null),
+        HEADERS: {
+            VERSION: dead_letter.headers.version as i32,
+            CORRELATION_ID: &dead_letter.headers.correlation_id,
+            REPLAY: dead_letter.headers.replay,
+        },
+        BODY: body,
+        REASON: &dead_letter.reason,
+        FAILED_AT: dead_letter.failed_at,
+    }, None).await?;
+
+    Ok(())
+}
+
+///
+/// The dead letters - oldest first. Without a limit, all of them.
+///
+pub async fn get_dead_letters(db: &Database, limit: Option<i64>) -> Result<Vec<DeadLetter>, InternalError> {
+    let options = FindOptions::builder().sort(doc!{ FAILED_AT: 1 }).limit(limit).build();
+    let cursor = db.collection_with_type::<DeadLetter>(DEAD_LETTERS).find(None, options).await?;
+    Ok(cursor.try_collect().await?)
+}
+
+pub async fn delete_dead_letter(db: &Database, dead_letter_id: &str) -> Result<(), InternalError> {
+    db.collection(DEAD_LETTERS).delete_one(doc!{ DEAD_LETTER_ID: dead_letter_id }, None).await?;
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use actix_web::http::Method;
+
+///
+/// Every endpoint registered in lib.rs - by method and route pattern (relative to the base_url) - with the handler
+/// name it's known by, eg. in the X-Handler header and in settings such as disabled_endpoints.
+///
+/// Endpoints which share a route have a name each, so one can be disabled, audited, etc. without the other. Path
+/// parameters are never part of a name so ids in the url are never exposed. Keep this in sync with the routes.
+///
+const ENDPOINTS: [(&str, &str, &str); 49] = [
+    // Admin
+    ("GET",    "/ping",                                    "ping"),
+    ("GET",    "/health",                                  "health"),
+    ("GET",    "/settings",                                "settings"),
+    ("GET",    "/settings/sources",                        "settings_sources"),
+    ("GET",    "/endpoints/disabled",                      "endpoints_disabled"),
+    ("POST",   "/endpoints/{endpoint}/disable",            "endpoints_disable"),
+    ("POST",   "/endpoints/{endpoint}/enable",             "endpoints_enable"),
+    ("GET",    "/stats/inflight",                          "stats_inflight"),
+    ("GET",    "/stats/notifications",                     "stats_notifications"),
+    ("POST",   "/stats/notifications/reset",               "stats_notifications_reset"),
+    ("GET",    "/trace/{correlation_id}",                  "trace"),
+    ("POST",   "/tracer/on",                               "tracer_on"),
+    ("POST",   "/tracer/off",                              "tracer_off"),
+    ("POST",   "/tracer-bullet",                           "tracer_bullet"),
+    ("POST",   "/set_time/{fixed_time}",                   "set_time"),
+    ("POST",   "/reset_time",                              "reset_time"),
+    ("POST",   "/admin/maintenance",                       "admin_maintenance"),
+    ("POST",   "/admin/migrate",                           "admin_migrate"),
+    ("POST",   "/admin/recase-statuses",                   "admin_recase_statuses"),
+    ("DELETE", "/admin/accounts",                          "admin_accounts"),
+    ("GET",    "/admin/chaos",                             "admin_chaos"),
+    ("PUT",    "/admin/chaos/{endpoint}",                  "admin_chaos_set"),
+    ("DELETE", "/admin/chaos/{endpoint}",                  "admin_chaos_clear"),
+    ("GET",    "/admin/dead-letters",                      "admin_dead_letters"),
+    ("POST",   "/admin/dead-letters/redrive",              "admin_dead_letters_redrive"),
+    ("POST",   "/account/{account_id}/replay-events",      "account_replay_events"),
+
+    // Account
+    ("GET",    "/account/{account_id}",                    "account"),
+    ("PATCH",  "/account/{account_id}",                    "patch_account"),
+    ("GET",    "/account/{account_id}/events",             "account_events"),
+    ("GET",    "/account/{account_id}/effective-profile",  "account_effective_profile"),
+    ("GET",    "/account/{account_id}/export",             "account_export"),
+    ("GET",    "/account/{account_id}/history",            "account_history"),
+    ("GET",    "/account/{account_id}/notes",              "account_notes"),
+    ("POST",   "/account/{account_id}/notes",              "add_account_note"),
+    ("POST",   "/account/{account_id}/reactivate",         "account_reactivate"),
+    ("POST",   "/account/{account_id}/rotate-id",          "account_rotate_id"),
+    ("GET",    "/accounts",                                "accounts"),
+    ("GET",    "/accounts/search",                         "accounts_search"),
+    ("GET",    "/accounts/created-stats",                  "accounts_created_stats"),
+    ("GET",    "/accounts/by-device/{device_id}",          "accounts_by_device"),
+    ("POST",   "/accounts/import",                         "accounts_import"),
+    ("POST",   "/create-account",                          "create_account"),
+    ("PUT",    "/update-account-status",                   "update_account_status"),
+    ("PUT",    "/update-account-statuses",                 "update_account_statuses"),
+
+    // Profiles
+    ("GET",    "/account-profile/{profile_id}",            "account_profile"),
+    ("GET",    "/device-profile/{profile_id}",             "device_profile"),
+    ("POST",   "/create-device-profile",                   "create_device_profile"),
+    ("PUT",    "/update-device-profile",                   "update_device_profile"),
+
+    // Documentation
+    ("GET",    "/openapi.json",                            "openapi.json"),
+];
+
+///
+/// The handler name of the endpoint the method and matched route pattern are for, eg. PATCH /account/{account_id}
+/// is patch_account. None if it's not a registered endpoint.
+///
+pub fn handler_name(method: &Method, pattern: &str, base_url: &str) -> Option<&'static str> {
+    let route = pattern.trim_start_matches(base_url.trim_end_matches('/'));
+
+    ENDPOINTS.iter()
+        .find(|(endpoint_method, endpoint_route, _)| *endpoint_method == method.as_str() && *endpoint_route == route)
+        .map(|(_, _, name)| *name)
+}
+
+///
+/// Indicates if the name is one of the service's endpoints.
+///
+pub fn is_endpoint(name: &str) -> bool {
+    ENDPOINTS.iter().any(|(_, _, endpoint)| *endpoint == name)
+}
@@ -1,12 +1,13 @@
 use tracing::error;
 use url::ParseError;
+use std::collections::HashMap;
 use serde_json::json;
 use parking_lot::RwLock;
 use lazy_static::lazy_static;
 use crossbeam_channel::SendError;
 use derive_more::{Display, Error};
-use actix_http::{client::SendRequestError, error::PayloadError, http::header::{InvalidHeaderName, InvalidHeaderValue}};
-use actix_web::{HttpResponse, ResponseError, client::JsonPayloadError, dev::HttpResponseBuilder, http::StatusCode, web::JsonConfig};
+use actix_http::{client::SendRequestError, error::PayloadError, http::header::{InvalidHeaderName, InvalidHeaderValue, RETRY_AFTER}};
+use actix_web::{HttpResponse, ResponseError, client::JsonPayloadError, dev::HttpResponseBuilder, http::StatusCode, web::{JsonConfig, QueryConfig}};
 use mongodb::{bson::{self, document::ValueAccessError}, error::{ErrorKind, WriteFailure}};
 
 lazy_static! {
@@ -20,6 +21,16 @@ lazy_static! {
     /// This value is a global as there are areas of the code which can't access the normal config struct.
     /// These tend to be extensions to the actix_web framework.
     pub static ref REDACT_ERROR_MESSAGES: RwLock<bool> = RwLock::new(false);
+
+    /// Localised error messages by language and then error code, eg. {"fr": {"2509": "Compte introuvable"}}.
+    /// Loaded from the error_translations file, if one is configured.
+    pub static ref ERROR_TRANSLATIONS: RwLock<HashMap<String, HashMap<u16, String>>> = RwLock::new(HashMap::new());
+}
+
+tokio::task_local! {
+    /// The languages the client accepts, most preferred first. The request middleware sets these for the
+    /// duration of each request so error responses can be localised.
+    pub static ACCEPT_LANGUAGES: Vec<String>;
 }
 
 ///
@@ -37,15 +48,24 @@ pub enum InternalError {
     #[display(fmt = "Unable to read credentials: {}", cause)]
     UnableToReadCredentials{ cause: String },
 
+    #[display(fmt = "The service configuration is not correct: {}", cause)]
+    InvalidConfiguration{ cause: String },
+
     #[display(fmt = "MongoDB error: {}", cause)]
     MongoDBError{ cause: String },
 
+    #[display(fmt = "MongoDB timed out or is unreachable: {}", cause)]
+    MongoTimeout{ cause: String },
+
     #[display(fmt = "MongoDB schema needs to be v{} but it is v{} - try running with UPDATE_SCHEMA_ENABLED set", code_version, db_version)]
     MongoSchemaError{ code_version: i32, db_version: i32 },
 
     #[display(fmt = "MongoDB is locked for schema updating. Either another instance has locked it and is taking a long time or a previous update crashed and left the lock in place. {}", cause)]
     MongoLockedForUpdate{ cause: String },
 
+    #[display(fmt = "MongoDB is too busy with other writes - try again shortly")]
+    MongoWritesBusy,
+
     #[display(fmt = "The request had no fields to update")]
     MongoDBUpdateEmpty,
 
@@ -58,12 +78,51 @@ pub enum InternalError {
     #[display(fmt = "Request format invalid: {}", reason)]
     RequestFormatError{ reason: String },
 
+    #[display(fmt = "The request URI is {} bytes - no more than {} are allowed", length, limit)]
+    UriTooLong{ length: usize, limit: usize },
+
+    #[display(fmt = "The request headers are {} bytes - no more than {} are allowed", length, limit)]
+    HeadersTooLarge{ length: usize, limit: usize },
+
+    #[display(fmt = "Request bodies with a content-encoding of {} are not accepted", encoding)]
+    UnsupportedEncoding{ encoding: String },
+
+    #[display(fmt = "The request body is larger than {} bytes once decompressed", limit)]
+    BodyTooLarge{ limit: usize },
+
     #[display(fmt = "Failed to make downstream request: {}", cause)]
     SendRequestError{ cause: String },
 
+    #[display(fmt = "The downstream request to {} timed out", url)]
+    DownstreamTimeout{ url: String },
+
     #[display(fmt = "{} claim invalid", claim)]
     InvalidClaim{ claim: String},
 
+    #[display(fmt = "Admin token missing or invalid")]
+    InvalidAdminToken,
+
+    #[display(fmt = "The auth service is unavailable: {}", cause)]
+    AuthUnavailable{ cause: String },
+
+    #[display(fmt = "The auth service did not respond within {}ms", timeout)]
+    AuthTimeout{ timeout: u64 },
+
+    #[display(fmt = "The {} endpoint has been temporarily disabled", endpoint)]
+    EndpointDisabled{ endpoint: String },
+
+    #[display(fmt = "The service is in maintenance - changes can't be made, try again in {} seconds", retry_after)]
+    MaintenanceMode{ retry_after: u64 },
+
+    #[display(fmt = "A fault was injected into the {} endpoint", endpoint)]
+    FaultInjected{ endpoint: String },
+
+    #[display(fmt = "The {} endpoint did not complete within {}ms", endpoint, timeout)]
+    HandlerTimeout{ endpoint: String, timeout: u128 },
+
+    #[display(fmt = "There is no {} {} endpoint", method, path)]
+    RouteNotFound{ method: String, path: String },
+
     #[display(fmt = "Url could not be parsed: {}", cause)]
     InvalidUrl{ cause: String },
 
@@ -88,9 +147,27 @@ pub enum InternalError {
     #[display(fmt = "Device profile {} not found", profile_id)]
     DeviceProfileNotFound{ profile_id: String },
 
+    #[display(fmt = "Device profile {} does not allow {} devices", profile_id, device_type)]
+    DeviceTypeNotAllowed{ profile_id: String, device_type: String },
+
     #[display(fmt = "Account {} cannot be updated: it is cancelled", account_id)]
     AccountCancelled{ account_id: String },
 
+    #[display(fmt = "An account already exists with the same {}", field)]
+    AccountConflict{ field: String },
+
+    #[display(fmt = "Account {} cannot be reactivated: it is not cancelled", account_id)]
+    AccountNotCancelled{ account_id: String },
+
+    #[display(fmt = "The path {} cannot be patched", path)]
+    PatchPathNotAllowed{ path: String },
+
+    #[display(fmt = "Account {} has been modified since it was read", account_id)]
+    PreconditionFailed{ account_id: String },
+
+    #[display(fmt = "The account is too large to store - remove unused devices or externalIds and try again: {}", cause)]
+    AccountTooLarge{ cause: String },
+
     #[display(fmt = "Failed to internally notify: {}", cause)]
     SendNotificationError{ cause: String },
 
@@ -99,13 +176,26 @@ pub enum InternalError {
 }
 
 impl InternalError {
-    fn error_code(&self) -> u16 {
+    pub fn error_code(&self) -> u16 {
         match *self {
             InternalError::InvalidFormatError{ cause: _ }                      => 0400,
             InternalError::UnableToReadCredentials{ cause: _ }                 => 0500,
+            InternalError::InvalidConfiguration{ cause: _ }                    => 501,
             InternalError::InvalidClaim { claim: _ }                           => 1000,
+            InternalError::InvalidAdminToken                                   => 1001,
+            InternalError::AuthUnavailable { cause: _ }                        => 1002,
+            InternalError::EndpointDisabled { endpoint: _ }                    => 1003,
+            InternalError::RouteNotFound { method: _, path: _ }                => 1004,
             InternalError::RemoteRequestError { cause: _, url: _ }             => 1005,
             InternalError::RequestFormatError { reason: _ }                    => 1010,
+            InternalError::UriTooLong { length: _, limit: _ }                  => 1011,
+            InternalError::HeadersTooLarge { length: _, limit: _ }             => 1012,
+            InternalError::UnsupportedEncoding { encoding: _ }                 => 1013,
+            InternalError::BodyTooLarge { limit: _ }                           => 1014,
+            InternalError::AuthTimeout { timeout: _ }                          => 1015,
+            InternalError::MaintenanceMode { retry_after: _ }                  => 1016,
+            InternalError::FaultInjected { endpoint: _ }                       => 1017,
+            InternalError::HandlerTimeout { endpoint: _, timeout: _ }          => 1018,
             InternalError::RabbitMQError { cause: _ }                          => 1990,
             InternalError::MongoDBError { cause: _ }                           => 2001,
             InternalError::MongoSchemaError { code_version: _, db_version: _ } => 2002,
@@ -113,6 +203,8 @@ impl InternalError {
             InternalError::MongoDBUpdateEmpty                                  => 2004,
             InternalError::MongoDuplicateError { cause: _ }                    => 2005,
             InternalError::InvalidBsonError { cause: _ }                       => 2006,
+            InternalError::MongoTimeout { cause: _ }                           => 2007,
+            InternalError::MongoWritesBusy                                     => 2008,
             InternalError::InvalidJsonError { cause: _ }                       => 2105,
             InternalError::InvalidUrl { cause: _ }                             => 2150,
             InternalError::BsonAccessError { cause: _ }                        => 2207,
@@ -120,21 +212,102 @@ impl InternalError {
             InternalError::AccountProfileNotFound { profile_id: _ }            => 2510,
             InternalError::DeviceProfileNotFound { profile_id: _ }             => 2511,
             InternalError::AccountCancelled { account_id: _ }                  => 2512,
+            InternalError::PreconditionFailed { account_id: _ }                => 2513,
+            InternalError::AccountTooLarge { cause: _ }                        => 2514,
+            InternalError::DeviceTypeNotAllowed { profile_id: _, device_type: _ } => 2515,
+            InternalError::AccountNotCancelled { account_id: _ }               => 2516,
+            InternalError::AccountConflict { field: _ }                        => 2517,
+            InternalError::PatchPathNotAllowed { path: _ }                     => 2518,
             InternalError::SendNotificationError { cause: _ }                  => 2920,
             InternalError::SendRequestError { cause: _ }                       => 3000,
+            InternalError::DownstreamTimeout { url: _ }                        => 3001,
         }
     }
 
     ///
-    /// Only 400 (bad request) responses can return an error message field.
-    /// It is then controlled via the global redaction flag.
+    /// Only 400 (bad request) responses can return an error message field - along with disabled
+    /// endpoints, maintenance and injected faults, so the caller knows the 503 is deliberate, and conflicts and rejected
+    /// patches, so the caller knows which field or path was the problem. It is then controlled via the
+    /// global redaction flag.
     ///
     fn redact_message(&self) -> bool {
-        if self.status_code() != StatusCode::BAD_REQUEST {
+        if self.status_code() != StatusCode::BAD_REQUEST && !matches!(self,
+            InternalError::EndpointDisabled { endpoint: _ } | InternalError::MaintenanceMode { retry_after: _ } | InternalError::AccountConflict { field: _ } |
+            InternalError::PatchPathNotAllowed { path: _ } | InternalError::FaultInjected { endpoint: _ }) {
             return true
         }
         *REDACT_ERROR_MESSAGES.read()
     }
+
+    ///
+    /// A 404 response carrying only this error's code - for clients which can't tell an empty 204
+    /// apart from a successful response.
+    ///
+    pub fn not_found_response(&self) -> HttpResponse {
+        HttpResponseBuilder::new(StatusCode::NOT_FOUND).json(json!({ "errorCode": self.error_code() }))
+    }
+
+    ///
+    /// The message that may be returned to a client, subject to the same redaction rules as
+    /// error responses. It's localised for the client's languages if there's a translation.
+    ///
+    pub fn client_message(&self) -> Option<String> {
+        match self.redact_message() {
+            true  => None,
+            false => Some(self.localised_message().unwrap_or_else(|| self.to_string())),
+        }
+    }
+
+    ///
+    /// The translation of this error for the most preferred of the client's languages which has one.
+    ///
+    fn localised_message(&self) -> Option<String> {
+        let translations = ERROR_TRANSLATIONS.read();
+        if translations.is_empty() {
+            return None
+        }
+
+        ACCEPT_LANGUAGES.try_with(|languages| languages.iter()
+            .find_map(|language| translations.get(language)?.get(&self.error_code()).cloned()))
+            .ok()
+            .flatten()
+    }
+}
+
+///
+/// The languages from an Accept-Language header, most preferred first, eg. 'fr-CH, fr;q=0.9, en;q=0.8'
+/// gives fr-ch, fr, en. Each regional language is followed by its primary language as a fallback.
+///
+pub fn accept_languages(header: &str) -> Vec<String> {
+    let mut weighted: Vec<(String, f32)> = header.split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let language = parts.next()?.trim().to_lowercase();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map(|quality| quality.trim().parse::<f32>().unwrap_or(0.0))
+                .unwrap_or(1.0);
+
+            match language.is_empty() || language == "*" || quality <= 0.0 {
+                true  => None,
+                false => Some((language, quality)),
+            }
+        })
+        .collect();
+
+    // A stable sort, so languages of equal quality keep the client's order.
+    weighted.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut languages = vec!();
+    for (language, _) in weighted {
+        let primary = language.split('-').next().unwrap_or_default().to_string();
+        for language in [language, primary] {
+            if !languages.contains(&language) {
+                languages.push(language);
+            }
+        }
+    }
+    languages
 }
 
 impl ResponseError for InternalError {
@@ -142,15 +315,30 @@ impl ResponseError for InternalError {
         match *self {
             InternalError::InvalidFormatError{ cause: _ }           => StatusCode::INTERNAL_SERVER_ERROR,
             InternalError::UnableToReadCredentials{ cause: _ }      => StatusCode::INTERNAL_SERVER_ERROR,
+            InternalError::InvalidConfiguration{ cause: _ }         => StatusCode::INTERNAL_SERVER_ERROR,
             InternalError::InvalidClaim { claim: _ }                => StatusCode::FORBIDDEN,
+            InternalError::InvalidAdminToken                        => StatusCode::UNAUTHORIZED,
+            InternalError::AuthUnavailable { cause: _ }             => StatusCode::SERVICE_UNAVAILABLE,
+            InternalError::AuthTimeout { timeout: _ }               => StatusCode::GATEWAY_TIMEOUT,
+            InternalError::EndpointDisabled { endpoint: _ }         => StatusCode::SERVICE_UNAVAILABLE,
+            InternalError::MaintenanceMode { retry_after: _ }       => StatusCode::SERVICE_UNAVAILABLE,
+            InternalError::FaultInjected { endpoint: _ }            => StatusCode::SERVICE_UNAVAILABLE,
+            InternalError::HandlerTimeout { endpoint: _, timeout: _ } => StatusCode::SERVICE_UNAVAILABLE,
+            InternalError::RouteNotFound { method: _, path: _ }     => StatusCode::NOT_FOUND,
             InternalError::RemoteRequestError { cause: _, url: _ }  => StatusCode::INTERNAL_SERVER_ERROR,
             InternalError::RabbitMQError { cause: _ }               => StatusCode::INTERNAL_SERVER_ERROR,
             InternalError::MongoSchemaError { code_version: _, db_version: _ } => StatusCode::INTERNAL_SERVER_ERROR,
             InternalError::MongoLockedForUpdate { cause: _ }        => StatusCode::INTERNAL_SERVER_ERROR,
             InternalError::MongoDBError { cause: _ }                => StatusCode::INTERNAL_SERVER_ERROR,
+            InternalError::MongoTimeout { cause: _ }                => StatusCode::SERVICE_UNAVAILABLE,
+            InternalError::MongoWritesBusy                          => StatusCode::SERVICE_UNAVAILABLE,
             InternalError::MongoDBUpdateEmpty                       => StatusCode::BAD_REQUEST,
             InternalError::MongoDuplicateError { cause: _ }         => StatusCode::BAD_REQUEST,
             InternalError::RequestFormatError { reason: _ }         => StatusCode::BAD_REQUEST,
+            InternalError::UriTooLong { length: _, limit: _ }       => StatusCode::URI_TOO_LONG,
+            InternalError::HeadersTooLarge { length: _, limit: _ }  => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            InternalError::UnsupportedEncoding { encoding: _ }      => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            InternalError::BodyTooLarge { limit: _ }                => StatusCode::PAYLOAD_TOO_LARGE,
             InternalError::InvalidUrl { cause: _ }                  => StatusCode::INTERNAL_SERVER_ERROR,
             InternalError::InvalidJsonError { cause: _ }            => StatusCode::INTERNAL_SERVER_ERROR,
             InternalError::InvalidBsonError { cause: _ }            => StatusCode::INTERNAL_SERVER_ERROR,
@@ -159,27 +347,41 @@ impl ResponseError for InternalError {
             InternalError::AccountProfileNotFound { profile_id: _ } => StatusCode::BAD_REQUEST,
             InternalError::DeviceProfileNotFound { profile_id: _ }  => StatusCode::BAD_REQUEST,
             InternalError::AccountCancelled { account_id: _ }       => StatusCode::BAD_REQUEST,
+            InternalError::AccountNotCancelled { account_id: _ }    => StatusCode::BAD_REQUEST,
+            InternalError::AccountConflict { field: _ }             => StatusCode::CONFLICT,
+            InternalError::PreconditionFailed { account_id: _ }     => StatusCode::PRECONDITION_FAILED,
+            InternalError::PatchPathNotAllowed { path: _ }          => StatusCode::UNPROCESSABLE_ENTITY,
+            InternalError::AccountTooLarge { cause: _ }             => StatusCode::BAD_REQUEST,
+            InternalError::DeviceTypeNotAllowed { profile_id: _, device_type: _ } => StatusCode::BAD_REQUEST,
             InternalError::SendNotificationError { cause: _ }       => StatusCode::INTERNAL_SERVER_ERROR,
             InternalError::SendRequestError { cause: _ }            => StatusCode::INTERNAL_SERVER_ERROR,
+            InternalError::DownstreamTimeout { url: _ }             => StatusCode::GATEWAY_TIMEOUT,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
         error!("{}", self);
 
-        let body = match self.redact_message() {
-            true => json!(
+        let body = match self.client_message() {
+            None => json!(
                 {
                     "errorCode": self.error_code()
                 }),
-            false => json!(
+            Some(message) => json!(
                 {
                     "errorCode": self.error_code(),
-                    "message": self.to_string()
+                    "message": message
                 }),
         };
 
-        HttpResponseBuilder::new(self.status_code()).json(body)
+        let mut response = HttpResponseBuilder::new(self.status_code());
+
+        // Tell the caller when it's worth trying again.
+        if let InternalError::MaintenanceMode { retry_after } = self {
+            response.header(RETRY_AFTER, retry_after.to_string());
+        }
+
+        response.json(body)
     }
 }
 
@@ -199,10 +401,54 @@ impl From<mongodb::error::Error> for InternalError {
             }
         }
 
+        if is_too_large(&error.kind) {
+            return InternalError::AccountTooLarge { cause: error.to_string() }
+        }
+
+        if is_timeout(&error.kind) {
+            return InternalError::MongoTimeout { cause: error.to_string() }
+        }
+
         InternalError::MongoDBError { cause: error.to_string() }
     }
 }
 
+///
+/// Indicates MongoDB is slow or unreachable rather than the operation itself being wrong.
+///
+fn is_timeout(kind: &ErrorKind) -> bool {
+    const MAX_TIME_MS_EXPIRED: i32 = 50;
+
+    match kind {
+        ErrorKind::Io(_) |
+        ErrorKind::TokioTimeoutElapsed(_) |
+        ErrorKind::ServerSelectionError { .. } |
+        ErrorKind::ConnectionPoolClearedError { .. } => true,
+        ErrorKind::CommandError(command_error) => command_error.code == MAX_TIME_MS_EXPIRED,
+        _ => false,
+    }
+}
+
+///
+/// Indicates a document (or the result of an update) would exceed MongoDB's 16MB document limit.
+///
+fn is_too_large(kind: &ErrorKind) -> bool {
+    const BSON_OBJECT_TOO_LARGE: i32 = 10334;
+    const UPDATED_DOCUMENT_TOO_LARGE: i32 = 17419;
+    const DOCUMENT_TOO_LARGE: i32 = 17420;
+    let too_large = |code| matches!(code, BSON_OBJECT_TOO_LARGE | UPDATED_DOCUMENT_TOO_LARGE | DOCUMENT_TOO_LARGE);
+
+    match kind {
+        ErrorKind::CommandError(command_error) => too_large(command_error.code),
+        ErrorKind::WriteError(WriteFailure::WriteError(write_error)) => too_large(write_error.code),
+        ErrorKind::BulkWriteError(bulk_failure) => bulk_failure.write_errors
+            .as_ref()
+            .map(|write_errors| write_errors.iter().any(|write_error| too_large(write_error.code)))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
 impl From<bson::ser::Error> for InternalError {
     fn from(error: bson::ser::Error) -> Self {
         InternalError::InvalidBsonError { cause: error.to_string() }
@@ -221,6 +467,12 @@ impl From<serde_json::Error> for InternalError {
     }
 }
 
+impl From<config::ConfigError> for InternalError {
+    fn from(error: config::ConfigError) -> Self {
+        InternalError::InvalidConfiguration { cause: error.to_string() }
+    }
+}
+
 impl From<InternalError> for std::io::Error {
     fn from(error: InternalError) -> std::io::Error {
         std::io::Error::new(std::io::ErrorKind::Other, error.to_string() )
@@ -298,4 +550,14 @@ pub fn configure_json_extractor() -> JsonConfig {
         .error_handler(|err, _req| {
             InternalError::RequestFormatError { reason: err.to_string() }.into()
         })
-}
\ No newline at end of file
+}
+
+///
+/// Return query string parse details as an error to the client.
+///
+pub fn configure_query_extractor() -> QueryConfig {
+    QueryConfig::default()
+        .error_handler(|err, _req| {
+            InternalError::RequestFormatError { reason: err.to_string() }.into()
+        })
+}
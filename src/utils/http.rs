@@ -3,14 +3,26 @@ use futures::Stream;
 use serde_json::Value;
 use itertools::Itertools;
 use tracing::{info, warn};
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
+use parking_lot::Mutex;
+use lazy_static::lazy_static;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use serde::de::DeserializeOwned;
 use actix_web_opentelemetry::ClientExt;
 use std::{pin::Pin, str::FromStr, time::Duration};
 use super::{config::Configuration, context::RequestContext, errors::InternalError};
 use actix_web::{client::{Client, ClientRequest, ClientResponse}, dev::Decompress, web::Bytes};
 use crate::{APP_NAME, middleware::request::REQUEST_ID_HEADER, routes::admin::tracer::{prelude::*, colour_status}};
-use actix_http::{Payload, client::Connector, error::PayloadError, http::{Method, HeaderName, HeaderValue, header}};
+use chrono::{DateTime, Utc};
+use actix_http::{Payload, client::{Connector, SendRequestError}, error::PayloadError, http::{Method, HeaderName, HeaderValue, StatusCode, header}};
+
+lazy_static! {
+    ///
+    /// The permits for concurrent requests to each downstream host (and limit) - shared by every worker and
+    /// the webhook sink.
+    ///
+    static ref HOST_PERMITS: Mutex<HashMap<(String, usize), Arc<Semaphore>>> = Mutex::new(HashMap::new());
+}
 
 ///
 /// Construct a configured HTTP client.
@@ -21,10 +33,29 @@ pub fn http_client(config:&Configuration) -> Client {
         .timeout(Duration::from_secs(config.server_timeout))
         .connector(Connector::new()
             .timeout(Duration::from_secs(config.server_timeout))
+            .limit(config.client_pool_limit)
+            .conn_keep_alive(Duration::from_secs(config.client_keep_alive))
             .finish())
         .finish()
 }
 
+///
+/// Open a connection to each downstream service so the first real request doesn't pay for the connection
+/// set-up. The connections are returned to the client's pool - so this must use the worker's own client.
+///
+/// Any response at all means the connection was made - only failing to connect is a warning.
+///
+pub async fn warm_up(client: Client, addresses: Vec<String>) {
+    for address in addresses {
+        let started = std::time::Instant::now();
+
+        match client.head(&address).send().await {
+            Ok(response) => info!("Warmed-up connection to {} in {}ms ({})", address, started.elapsed().as_millis(), response.status()),
+            Err(err) => warn!("Unable to warm-up connection to {}: {}", address, err),
+        }
+    }
+}
+
 ///
 /// Alias onto the Actix Futures response.
 ///
@@ -58,6 +89,9 @@ pub struct HttpRequest {
     headers: HashMap<String, String>,
     query_params: HashMap<String, String>,
     dont_retry: bool,
+    retry_unsafe: bool,
+    retry_limit: Option<u8>,   // Overrides the configured client_retry_limit.
+    timeout: Option<Duration>, // Overrides the client's default timeout.
     body_error: Option<InternalError> // Send when the body is set externally but fails to serialise. This means we can handle errors on send() not body().
 }
 
@@ -70,6 +104,9 @@ impl HttpRequest {
             headers: HashMap::new(),
             query_params: HashMap::new(),
             dont_retry: false,
+            retry_unsafe: false,
+            retry_limit: None,
+            timeout: None,
             body_error: None
         }
     }
@@ -96,22 +133,69 @@ impl HttpRequest {
     }
 
     ///
-    /// By default, errors or 500 status responses will be retried. Use this to supress that behaviour.
+    /// By default, errors or 500 status responses to idempotent requests (GET, PUT, DELETE, etc.) will
+    /// be retried. Use this to supress that behaviour.
     ///
     pub fn dont_retry(&mut self) -> &mut Self {
         self.dont_retry = true;
         self
     }
 
+    ///
+    /// Non-idempotent requests (i.e. POST) are not retried by default as a blind retry could duplicate
+    /// side-effects downstream. Use this to opt-in to retries when the request is known to be safe.
+    ///
+    pub fn retry_unsafe(&mut self) -> &mut Self {
+        self.retry_unsafe = true;
+        self
+    }
+
+    ///
+    /// Attempt this request no more than this many times - rather than the configured client_retry_limit.
+    ///
+    pub fn retry_limit(&mut self, retry_limit: u8) -> &mut Self {
+        self.retry_limit = Some(retry_limit);
+        self
+    }
+
+    ///
+    /// Give up after this long - rather than the client's default timeout. A request which times out is not
+    /// retried, so the caller gets a DownstreamTimeout promptly.
+    ///
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    ///
+    /// Indicates if a failed attempt at this request may be re-attempted.
+    ///
+    fn retryable(&self) -> bool {
+        if self.dont_retry {
+            return false
+        }
+
+        self.retry_unsafe || matches!(self.method, Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE)
+    }
+
     ///
     /// Send the HTTP request - and return a response.
     ///
-    /// Some home-grown retry logic is used if error's or 500 HTTP status are returned.
+    /// Some home-grown retry logic is used if error's, 500 or 429 HTTP status are returned - see retryable().
+    /// A Retry-After on a 429 or 503 is honoured (up to max_retry_after) rather than the client_retry_delay.
     /// Home-grown because all the published crates rely on Tokio 1+ so we're limited.
     /// The entire method (nearly) is in the retry loop because the AWC request is consumed by
     /// send - so it's reconstructed on each re-attempt.
     ///
     pub async fn send(&mut self, ctx: &RequestContext) -> Result<HttpResponse, InternalError> {
+        self.send_with(ctx.client(), ctx.config(), ctx.request_id(), ctx.tracer()).await
+    }
+
+    ///
+    /// As send, but for callers outside of a HTTP request (eg. the notification publisher) which have their own
+    /// client and correlation id.
+    ///
+    pub async fn send_with(&mut self, client: &Client, config: &Configuration, request_id: &str, tracer: bool) -> Result<HttpResponse, InternalError> {
         // If we failed to serailise the body, fail at this point.
         if let Some(body_error) = &self.body_error {
             return Err(body_error.to_owned())
@@ -124,58 +208,76 @@ impl HttpRequest {
             url.query_pairs_mut().append_pair(&query_param.0, &query_param.1);
         }
 
+        // Held until the response has been read - including any retries.
+        let _permit = host_permit(&url, config).await?;
+
+        let retry_limit = self.retry_limit.unwrap_or(config.client_retry_limit);
         let mut attempts: u8 = 1;
         let mut resp = loop {
             // Build an actix web client request.
-            let mut req = ctx.client().request(self.method.clone(), url.as_str());
+            let mut req = client.request(self.method.clone(), url.as_str());
 
             // Append all the specified header.
             for header in &self.headers {
                 append_header(header.0, header.1, &mut req)?;
             }
 
+            if let Some(timeout) = self.timeout {
+                req = req.timeout(timeout);
+            }
+
             // Add the request_id header.
-            append_header(REQUEST_ID_HEADER, ctx.request_id(), &mut req)?;
+            append_header(REQUEST_ID_HEADER, request_id, &mut req)?;
 
-            if ctx.tracer() {
+            if tracer {
                 self.trace(&req);
             }
 
             // Make the request now with the appropriate body type.
             let resp = match &self.body {
                 None => req.trace_request().send().await,
-                Some(body) => req.trace_request().send_body(serde_json::to_string(body)?).await
+                Some(body) => req.trace_request().send_body(body.clone()).await
             };
 
             // Handle the response - re-trying if an error occurs.
             match resp {
-                Ok(resp) if resp.status().as_u16() < 500 => {
+                Ok(resp) if resp.status().as_u16() < 500 && resp.status() != StatusCode::TOO_MANY_REQUESTS => {
                     break Ok(resp);
                 },
                 Ok(resp) => {
-                    // If we have a response but it's a 50x.
+                    // If we have a response but it's a 50x (or we're being rate-limited).
                     attempts += 1;
-                    actix_rt::time::delay_for(Duration::from_secs(ctx.config().client_retry_delay)).await;
 
-                    // If retries exceeded fail.
-                    if self.dont_retry || (attempts > ctx.config().client_retry_limit) {
+                    // If retries exceeded fail - a 429 is left for the caller to handle, as it always has been.
+                    if !self.retryable() || (attempts > retry_limit) {
+                        if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+                            break Ok(resp);
+                        }
                         break Err(InternalError::RemoteRequestError { cause: format!("Remote request returned {}", resp.status()), url: url.to_string() });
                     }
 
+                    let delay = retry_after(&resp, Duration::from_secs(config.max_retry_after))
+                        .unwrap_or_else(|| Duration::from_secs(config.client_retry_delay));
+                    actix_rt::time::delay_for(delay).await;
+
                     // Only warn once.
                     if attempts == 2 {
                         warn!("Request to {} failed with status {}, retrying...", url.to_string(), resp.status());
                     }
                 },
+                Err(SendRequestError::Timeout) if self.timeout.is_some() => {
+                    break Err(InternalError::DownstreamTimeout { url: url.to_string() });
+                },
                 Err(err) => {
                     attempts += 1;
-                    actix_rt::time::delay_for(Duration::from_secs(ctx.config().client_retry_delay)).await;
 
                     // If retries exceeded fail.
-                    if self.dont_retry || (attempts > ctx.config().client_retry_limit) {
+                    if !self.retryable() || (attempts > retry_limit) {
                         break Err(err.into());
                     }
 
+                    actix_rt::time::delay_for(Duration::from_secs(config.client_retry_delay)).await;
+
                     // Only warn once.
                     if attempts == 2 {
                         warn!("Request to {} failed with {}, retrying...", url.to_string(), err.to_string());
@@ -184,14 +286,22 @@ impl HttpRequest {
             };
         }?;
 
+        // Guard against a misbehaving downstream returning a huge payload.
+        let limit = config.max_response_bytes;
+        let body = match resp.body().limit(limit).await {
+            Ok(body) => body,
+            Err(PayloadError::Overflow) => return Err(InternalError::RemoteRequestError { cause: format!("Response body exceeded {} bytes", limit), url: url.to_string() }),
+            Err(err) => return Err(err.into()),
+        };
+
         let resp = HttpResponse {
             url,
             method: self.method.clone(),
-            body: resp.body().await?,
+            body,
             inner: resp
         };
 
-        if ctx.tracer() {
+        if tracer {
             resp.trace();
         }
 
@@ -222,6 +332,50 @@ impl HttpRequest {
     }
 }
 
+///
+/// How long a 429 or 503 response's Retry-After header asks us to wait (either delay-seconds or a HTTP-date) -
+/// capped to the maximum given. None if there's no (valid) header.
+///
+fn retry_after(resp: &ActixHttpResponse, max: Duration) -> Option<Duration> {
+    if !matches!(resp.status(), StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE) {
+        return None
+    }
+
+    let value = resp.headers().get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    let delay = match value.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => {
+            let date = DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc);
+            (date - Utc::now()).to_std().unwrap_or_default() // A date in the past means retry now.
+        },
+    };
+
+    Some(delay.min(max))
+}
+
+///
+/// If max_downstream_requests is configured, wait (for up to the downstream_queue_timeout) until there are
+/// fewer than that many requests in progress to the url's host. None is returned if there's no limit.
+///
+async fn host_permit(url: &Url, config: &Configuration) -> Result<Option<OwnedSemaphorePermit>, InternalError> {
+    let limit = match config.max_downstream_requests {
+        Some(limit) => limit,
+        None => return Ok(None),
+    };
+
+    let host = url.origin().ascii_serialization();
+    let permits = HOST_PERMITS.lock()
+        .entry((host.clone(), limit))
+        .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+        .clone();
+
+    // An available permit is taken even with no timeout.
+    match actix_rt::time::timeout(Duration::from_millis(config.downstream_queue_timeout), permits.acquire_owned()).await {
+        Ok(permit) => Ok(Some(permit)),
+        Err(_) => Err(InternalError::RemoteRequestError { cause: format!("more than {} concurrent requests to {}", limit, host), url: url.to_string() }),
+    }
+}
+
 fn append_header(name: &str, value: &str, req: &mut ClientRequest) -> Result<(), InternalError> {
     req.headers_mut().append(
         HeaderName::from_str(name)?,
@@ -1,6 +1,16 @@
 pub mod http;
+pub mod audit;
+pub mod account_locks;
 pub mod mongo;
 pub mod rabbit;
 pub mod config;
+pub mod dead_letters;
+pub mod endpoints;
 pub mod errors;
-pub mod context;
\ No newline at end of file
+pub mod outbox;
+pub mod paging;
+pub mod profile_cache;
+pub mod context;
+pub mod self_test;
+pub mod shutdown;
+pub mod webhooks;
\ No newline at end of file
@@ -1,63 +1,216 @@
 use uuid::Uuid;
 use tracing::{debug, info};
 use chrono::{DateTime, Utc};
-use std::{collections::HashMap, fs};
-use serde::{Deserialize, Deserializer, Serialize};
-use crate::utils::{config::Configuration, errors::InternalError};
-use mongodb::{Client, Collection, Database, bson::{self, Document, doc}, options::ClientOptions};
+use std::{collections::HashMap, time::{Duration, Instant}};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use crate::{model::account::prelude::*, utils::{config::{resolve_uri, Configuration}, errors::InternalError}};
+use mongodb::{Client, Collection, Database, bson::{self, Document, doc}, options::{ClientOptions, Tls, TlsOptions, UpdateOptions}};
+
+///
+/// The schema version this code expects. Bump this whenever an update is added to update_mongo - so
+/// an instance can tell when it's running against a database which hasn't had that update applied.
+///
+pub const SCHEMA_VERSION: i32 = 3;
+
+/// The collection (and document id) that records the schema version applied to the database.
+const SCHEMA: &str = "Schema";
+const SCHEMA_ID: &str = "version";
+
+///
+/// The outcome of running the schema-like updates. Each update is named and is either applied or
+/// skipped (because it has been applied previously).
+///
+#[derive(Debug, Default, Serialize)]
+pub struct MigrationReport {
+    pub applied: Vec<String>,
+    pub skipped: Vec<String>,
+}
+
+impl MigrationReport {
+    fn record(&mut self, name: &str, applied: bool) {
+        match applied {
+            true  => self.applied.push(name.to_string()),
+            false => self.skipped.push(name.to_string()),
+        }
+    }
+}
 
 ///
 /// Run any schema-like updates against MongoDB that haven't been run yet.
 ///
-pub async fn update_mongo(db: &Database) -> Result<(), InternalError> {
-    create_init_indexes(db).await?;
-    create_default_profiles(db).await?;
+/// Every update is idempotent so this is safe to call repeatedly.
+///
+pub async fn update_mongo(db: &Database, config: &Configuration) -> Result<MigrationReport, InternalError> {
+    let mut report = MigrationReport::default();
+    create_init_indexes(db, config, &mut report).await?;
+    create_default_profiles(db, &mut report).await?;
+    version_accounts(db, &mut report).await?;
+    record_schema_version(db).await?;
+    Ok(report)
+}
+
+///
+/// Record the code's schema version against the database once the updates have been applied. An older
+/// instance running the updates never lowers the version.
+///
+async fn record_schema_version(db: &Database) -> Result<(), InternalError> {
+    let options = UpdateOptions::builder().upsert(true).build();
+    db.collection(SCHEMA).update_one(doc!{ "_id": SCHEMA_ID }, doc!{ "$max": { "version": SCHEMA_VERSION } }, options).await?;
     Ok(())
 }
 
-async fn create_init_indexes(db: &Database) -> Result<(), InternalError> {
+///
+/// The schema version last applied to the database - zero if the updates have never been run.
+///
+pub async fn schema_version(db: &Database) -> Result<i32, InternalError> {
+    let version = db.collection(SCHEMA).find_one(doc!{ "_id": SCHEMA_ID }, None).await?;
+    Ok(version.and_then(|version| version.get_i32("version").ok()).unwrap_or_default())
+}
+
+///
+/// Fail with a MongoSchemaError if the database is behind the code. A database ahead of the code is
+/// fine - that's an older instance during a rolling upgrade.
+///
+pub async fn check_schema(db: &Database) -> Result<(), InternalError> {
+    match schema_version(db).await? {
+        db_version if db_version < SCHEMA_VERSION => Err(InternalError::MongoSchemaError { code_version: SCHEMA_VERSION, db_version }),
+        _ => Ok(())
+    }
+}
+
+async fn create_init_indexes(db: &Database, config: &Configuration, report: &mut MigrationReport) -> Result<(), InternalError> {
     // Note: the current driver doesn't yet support creating indexes on collections, so the dbcommand
     // must be used instead.
     // https://docs.mongodb.com/manual/reference/command/createIndexes/#createindexes
 
     // Note: I've split multiple calls to the same collection to ease readability.
-    db.run_command(doc! { "createIndexes": "Accounts", "indexes": [{ "key": { "accountId": 1 }, "name": "idx_accountId", "unique": true }] }, None).await?;
-    db.run_command(doc! { "createIndexes": "Accounts", "indexes": [{ "key": { "devices.deviceId": 1 }, "name": "idx_deviceId", "unique": true, "sparse": true } ] }, None).await?;
-    db.run_command(doc! { "createIndexes": "Accounts", "indexes": [{ "key": { "externalIds.key": 1, "externalIds.value": 1 }, "name": "idx_accountExternalId", "unique": true, "sparse": true }] }, None).await?;
-    db.run_command(doc! { "createIndexes": "Accounts", "indexes": [{ "key": { "devices.externalIds.key": 1, "devices.externalIds.value": 1 }, "name": "idx_deviceExternalId", "unique": true, "sparse": true }] }, None).await?;
-    db.run_command(doc! { "createIndexes": "AccountProfiles", "indexes": [{ "key": { "profileId": 1 }, "name": "idx_profileId", "unique": true }] }, None).await?;
-    db.run_command(doc! { "createIndexes": "DeviceProfiles", "indexes": [{ "key": { "profileId": 1 }, "name": "idx_profileId", "unique": true }] }, None).await?;
+    let indexes = [
+        ("Accounts", doc! { "key": { "accountId": 1 }, "name": "idx_accountId", "unique": true }),
+        ("Accounts", doc! { "key": { "devices.deviceId": 1 }, "name": "idx_deviceId", "unique": true, "sparse": true }),
+        ("Accounts", doc! { "key": { "externalIds.key": 1, "externalIds.value": 1 }, "name": "idx_accountExternalId", "unique": true, "sparse": true }),
+        ("Accounts", doc! { "key": { "devices.externalIds.key": 1, "devices.externalIds.value": 1 }, "name": "idx_deviceExternalId", "unique": true, "sparse": true }),
+        ("Accounts", doc! { "key": { "outbox.outboxId": 1 }, "name": "idx_outboxId", "sparse": true }),
+        ("AccountProfiles", doc! { "key": { "profileId": 1 }, "name": "idx_profileId", "unique": true }),
+        ("DeviceProfiles", doc! { "key": { "profileId": 1 }, "name": "idx_profileId", "unique": true }),
+    ];
+
+    // Indexes are built one at a time - pausing after each new one so a deploy doesn't swamp the database.
+    let count = indexes.len();
+    for (idx, (collection, index)) in indexes.iter().enumerate() {
+        let applied = create_index(db, collection, index.clone(), idx + 1, count, config, report).await?;
+
+        if applied && config.index_build_delay > 0 && idx + 1 < count {
+            actix_rt::time::delay_for(Duration::from_millis(config.index_build_delay)).await;
+        }
+    }
 
     Ok(())
 }
 
-async fn create_default_profiles(db: &Database) -> Result<(), InternalError> {
-    let col: Collection = db.collection("AccountProfiles");
-    match col.insert_one(doc!{ "profileId": "DEFAULT" }, None).await {
-        _ => () // Insert failures are fine if the profile already exists.
-    };
+///
+/// Create the index if it doesn't already exist and record if it was created in the report. Returns true if it
+/// was created.
+///
+/// If index_build_background is configured, the index is built without blocking the collection (only honoured
+/// by MongoDB versions prior to 4.2 - later versions always build this way).
+///
+async fn create_index(db: &Database, collection: &str, mut index: Document, number: usize, count: usize, config: &Configuration, report: &mut MigrationReport) -> Result<bool, InternalError> {
+    let name = format!("{}.{}", collection, index.get_str("name")?);
+
+    if config.index_build_background {
+        index.insert("background", true);
+    }
+
+    debug!("Ensuring index {} exists ({} of {})", name, number, count);
+    let started = Instant::now();
+    let result = db.run_command(doc! { "createIndexes": collection, "indexes": [index] }, None).await?;
 
-    let col: Collection = db.collection("DeviceProfiles");
-    match col.insert_one(doc!{ "profileId": "DEFAULT" }, None).await {
-        _ => () // Insert failures are fine if the profile already exists.
+    // MongoDB reports the index count before and after - they're the same if the index already existed.
+    let applied = result.get_i32("numIndexesAfter").unwrap_or_default() > result.get_i32("numIndexesBefore").unwrap_or_default();
+    if applied {
+        info!("Built index {} ({} of {}) in {}ms", name, number, count, started.elapsed().as_millis());
+    }
+
+    report.record(&name, applied);
+    Ok(applied)
+}
+
+async fn create_default_profiles(db: &Database, report: &mut MigrationReport) -> Result<(), InternalError> {
+    create_default_profile(db, "AccountProfiles", report).await?;
+    create_default_profile(db, "DeviceProfiles", report).await?;
+    Ok(())
+}
+
+///
+/// Create the collection's DEFAULT profile if it doesn't already exist. Any other failure stops the updates - new
+/// accounts and devices can't be created without it.
+///
+async fn create_default_profile(db: &Database, collection: &str, report: &mut MigrationReport) -> Result<(), InternalError> {
+    let col: Collection = db.collection(collection);
+    let name = format!("{}.DEFAULT", collection);
+
+    match col.insert_one(doc!{ "profileId": "DEFAULT" }, None).await.map_err(InternalError::from) {
+        Ok(_) => report.record(&name, true),
+        Err(InternalError::MongoDuplicateError { cause: _ }) => report.record(&name, false), // The profile already exists.
+        Err(err) => return Err(InternalError::MongoDBError { cause: format!("Unable to create the {} profile: {}", name, err) }),
     };
     Ok(())
 }
 
-pub async fn get_mongo_db(app_name: &str, config: &Configuration) -> Result<Database, InternalError> {
+///
+/// Re-case any account statuses (and their status history) which aren't in the casing given - eg. the original
+/// uppercase once titlecase is configured, or back again if it's switched off. Reads accept either casing so
+/// this can run while the service is live.
+///
+/// This scans every account, so it's a one-off requested once the casing has changed everywhere - not one of
+/// the schema updates, which would have instances with different casings re-casing each other's accounts.
+///
+pub async fn recase_account_statuses(db: &Database, casing: EnumCasing) -> Result<MigrationReport, InternalError> {
+    let mut report = MigrationReport::default();
+    let accounts: Collection = db.collection(ACCOUNTS);
+    let mut modified = 0;
 
-    let uri = match &config.mongo_credentials {
-        Some(filename) => {
-            debug!("Loading MongoDB credentials from secrets file {}", filename);
+    for status in &AccountStatus::ALL {
+        let (legacy, cased) = match casing {
+            EnumCasing::Uppercase => (status.titlecase(), status.uppercase()),
+            EnumCasing::Titlecase => (status.uppercase(), status.titlecase()),
+        };
 
-            // Read username and password from a secrets file.
-            let credentials = fs::read_to_string(filename).map_err(|err| InternalError::UnableToReadCredentials{ cause: err.to_string() })?;
-            let mut credentials = credentials.lines();
-            let uri = config.mongo_uri.replace("$USERNAME", credentials.next().unwrap_or_default());
-            uri.replace("$PASSWORD", credentials.next().unwrap_or_default())
-        },
-        None => config.mongo_uri.clone(),
+        modified += accounts.update_many(doc!{ STATUS: legacy }, doc!{ "$set": { STATUS: cased } }, None).await?.modified_count;
+
+        for field in &["oldStatus", "newStatus"] {
+            let options = UpdateOptions::builder().array_filters(vec!(doc!{ format!("change.{}", field): legacy })).build();
+            modified += accounts.update_many(
+                /* Filter  */ doc!{ format!("{}.{}", STATUS_HISTORY, field): legacy },
+                /* Update  */ doc!{ "$set": { format!("{}.$[change].{}", STATUS_HISTORY, field): cased } },
+                /* Options */ options)
+                .await?
+                .modified_count;
+        }
+    }
+
+    let name = match casing {
+        EnumCasing::Uppercase => "Accounts.status.uppercase",
+        EnumCasing::Titlecase => "Accounts.status.titlecase",
     };
+    report.record(name, modified > 0);
+    Ok(report)
+}
+
+///
+/// Accounts created before versioning have no version - start them at 1, as new accounts are. Their ETags
+/// change once, so callers holding an old ETag must re-read the account.
+///
+async fn version_accounts(db: &Database, report: &mut MigrationReport) -> Result<(), InternalError> {
+    let accounts: Collection = db.collection(ACCOUNTS);
+    let result = accounts.update_many(doc!{ VERSION: { "$exists": false } }, doc!{ "$set": { VERSION: 1i64 } }, None).await?;
+    report.record("Accounts.version", result.modified_count > 0);
+    Ok(())
+}
+
+pub async fn get_mongo_db(app_name: &str, config: &Configuration) -> Result<Database, InternalError> {
+
+    let uri = resolve_uri(&config.mongo_uri, config.mongo_credentials.as_deref())?;
 
     // Parse the uri now.
     let mut client_options = ClientOptions::parse(&uri).await?;
@@ -65,6 +218,24 @@ pub async fn get_mongo_db(app_name: &str, config: &Configuration) -> Result<Data
     // Manually set an option.
     client_options.app_name = Some(app_name.to_string());
 
+    // A custom CA or relaxed certificate checks imply TLS - on top of any TLS options in the uri.
+    if config.tls_ca_file.is_some() || config.tls_allow_invalid_certs {
+        let mut tls = match client_options.tls.take() {
+            Some(Tls::Enabled(tls)) => tls,
+            _ => TlsOptions::default(),
+        };
+
+        if config.tls_ca_file.is_some() {
+            tls.ca_file_path = config.tls_ca_file.clone();
+        }
+
+        if config.tls_allow_invalid_certs {
+            tls.allow_invalid_certificates = Some(true);
+        }
+
+        client_options.tls = Some(Tls::Enabled(tls));
+    }
+
     // Get a handle to the deployment.
     let client = Client::with_options(client_options)?;
 
@@ -143,3 +314,19 @@ pub fn optional_bson_date<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc
 
     Ok(None)
 }
+
+///
+/// Chrono will happily deserialise an ISO8601 string from a JSON request, but when that struct is then
+/// converted with to_doc() the date would be written to MongoDB as a string. Use this fn to serialize
+/// the date as a BSON Date instead.
+///
+/// Note - only use this with structs which deserialise from JSON requests and are persisted with to_doc()!
+///
+pub fn optional_json_date_as_bson<S>(date: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer
+{
+    match date {
+        Some(date) => bson::DateTime::from(*date).serialize(serializer),
+        None => serializer.serialize_none(),
+    }
+}
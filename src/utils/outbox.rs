@@ -0,0 +1,101 @@
+use std::{collections::HashSet, time::Duration};
+use tracing::{error, info};
+use mongodb::{Database, bson::{self, Document, doc}, options::FindOptions};
+use futures::{TryStreamExt, channel::mpsc::{TryRecvError, UnboundedReceiver, UnboundedSender}};
+use crate::{model::{account::prelude::*, outbox::{prelude::*, OutboxEntry}}, utils::{errors::InternalError, rabbit::{FireAndForget, Notification, Publisher}}};
+
+/// The most accounts with unsent notifications read on each poll.
+const POLL_BATCH: i64 = 100;
+
+///
+/// Identifies a notification published from an account's outbox - and whether RabbitMQ confirmed it.
+///
+#[derive(Debug)]
+pub struct Relayed {
+    pub account_id: String,
+    pub outbox_id: String,
+    pub published: bool,
+}
+
+///
+/// The RabbitMQ publisher thread is not async - so it reports the outcome of each outbox notification
+/// to the relay task via this channel.
+///
+pub type OutboxSender = UnboundedSender<Relayed>;
+
+///
+/// Poll the accounts for unsent notifications, pass them to the publisher and remove each from it's
+/// account once RabbitMQ confirms it. This continues until the publisher thread terminates.
+///
+/// Notifications which fail to publish are left in place and re-sent on a later poll, so a notification
+/// is published at least once if (and only if) the write which staged it succeeded.
+///
+pub async fn relay_outbox(db: Database, publisher: Publisher, mut rx: UnboundedReceiver<Relayed>, interval: Duration) {
+    // Notifications passed to the publisher which it hasn't reported back on yet.
+    let mut in_flight = HashSet::<String>::new();
+
+    loop {
+        actix_rt::time::delay_for(interval).await;
+
+        loop {
+            match rx.try_recv() {
+                Ok(relayed) => {
+                    in_flight.remove(&relayed.outbox_id);
+
+                    if relayed.published {
+                        if let Err(err) = remove_entry(&db, &relayed).await {
+                            error!("Failed to remove published notification {:?} from the outbox: {}", relayed, err);
+                        }
+                    }
+                },
+                Err(TryRecvError::Closed) => {
+                    info!("Terminating the outbox relay");
+                    return
+                },
+                Err(TryRecvError::Empty) => break, // Nothing more reported yet.
+            }
+        }
+
+        match unsent(&db).await {
+            Ok(unsent) => {
+                for (account_id, entry) in unsent {
+                    if in_flight.insert(entry.outbox_id.clone()) {
+                        publisher.fire_and_forget(Notification::from_outbox(account_id, entry));
+                    }
+                }
+            },
+            Err(err) => error!("Failed to read the outbox: {}", err),
+        }
+    }
+}
+
+///
+/// The unsent notifications - with the account they're staged on.
+///
+async fn unsent(db: &Database) -> Result<Vec<(String, OutboxEntry)>, InternalError> {
+    let options = FindOptions::builder()
+        .projection(doc!{ ACCOUNT_ID: 1, OUTBOX: 1 })
+        .limit(POLL_BATCH)
+        .build();
+
+    let filter = doc!{ format!("{}.{}", OUTBOX, OUTBOX_ID): { "$exists": true } };
+    let accounts: Vec<Document> = db.collection(ACCOUNTS).find(filter, options).await?.try_collect().await?;
+    let mut unsent = vec!();
+
+    for account in accounts {
+        let account_id = account.get_str(ACCOUNT_ID)?.to_string();
+        for entry in account.get_array(OUTBOX)? {
+            unsent.push((account_id.clone(), bson::from_bson(entry.clone())?));
+        }
+    }
+
+    Ok(unsent)
+}
+
+async fn remove_entry(db: &Database, relayed: &Relayed) -> Result<(), InternalError> {
+    db.collection(ACCOUNTS).update_one(
+        doc!{ ACCOUNT_ID: &relayed.account_id },
+        doc!{ "$pull": { OUTBOX: { OUTBOX_ID: &relayed.outbox_id } } },
+        None).await?;
+    Ok(())
+}
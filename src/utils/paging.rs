@@ -0,0 +1,27 @@
+use serde::Deserialize;
+use crate::utils::{config::Configuration, errors::InternalError};
+
+/// The response header telling the caller the page size a list endpoint actually used.
+pub const PAGE_SIZE_HEADER: &str = "x-page-size";
+
+///
+/// The query parameters for list endpoints which only support a limit.
+///
+#[derive(Debug, Deserialize)]
+pub struct PageQuery {
+    pub limit: Option<i64>,
+}
+
+///
+/// The page size every list endpoint must use - so none can return an unbounded result set.
+///
+/// If no limit is requested the default_page_size is used. Limits over the max_page_size are clamped
+/// to it rather than rejected - callers can tell from the PAGE_SIZE_HEADER.
+///
+pub fn page_size(requested: Option<i64>, config: &Configuration) -> Result<i64, InternalError> {
+    match requested {
+        None => Ok(config.default_page_size),
+        Some(limit) if limit < 1 => Err(InternalError::RequestFormatError { reason: "limit must be at least 1".to_string() }),
+        Some(limit) => Ok(limit.min(config.max_page_size)),
+    }
+}
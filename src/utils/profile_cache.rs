@@ -0,0 +1,89 @@
+use lru_cache::LruCache;
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+use crate::model::profile::{AccountProfile, DeviceProfile};
+
+///
+/// Profiles are read on every account create but rarely change, so the most recently used are kept in
+/// memory for up to the configured TTL. The cache is shared by all the workers.
+///
+/// Only profiles which exist are cached - so a newly created profile is seen straight away. Profile changes
+/// made through this instance evict the profile, changes made elsewhere are seen once the TTL expires.
+///
+/// A size of zero disables the cache.
+///
+pub struct ProfileCache {
+    accounts: Option<Mutex<LruCache<String, (Instant, AccountProfile)>>>,
+    devices: Option<Mutex<LruCache<String, (Instant, DeviceProfile)>>>,
+    ttl: Duration,
+}
+
+impl ProfileCache {
+    pub fn new(size: usize, ttl: Duration) -> Self {
+        match size {
+            0 => ProfileCache { accounts: None, devices: None, ttl },
+            _ => ProfileCache {
+                accounts: Some(Mutex::new(LruCache::new(size))),
+                devices: Some(Mutex::new(LruCache::new(size))),
+                ttl,
+            }
+        }
+    }
+
+    pub fn account_profile(&self, profile_id: &str) -> Option<AccountProfile> {
+        lookup(&self.accounts, profile_id, self.ttl)
+    }
+
+    pub fn put_account_profile(&self, profile_id: &str, profile: &AccountProfile) {
+        store(&self.accounts, profile_id, profile);
+    }
+
+    pub fn device_profile(&self, profile_id: &str) -> Option<DeviceProfile> {
+        lookup(&self.devices, profile_id, self.ttl)
+    }
+
+    pub fn put_device_profile(&self, profile_id: &str, profile: &DeviceProfile) {
+        store(&self.devices, profile_id, profile);
+    }
+
+    pub fn evict_device_profile(&self, profile_id: &str) {
+        if let Some(devices) = &self.devices {
+            devices.lock().remove(profile_id);
+        }
+    }
+
+    ///
+    /// Drop every cached profile - eg. when told profiles have been changed by another service.
+    ///
+    pub fn _clear(&self) {
+        if let Some(accounts) = &self.accounts {
+            accounts.lock().clear();
+        }
+
+        if let Some(devices) = &self.devices {
+            devices.lock().clear();
+        }
+    }
+}
+
+///
+/// The cached profile - if it's not expired.
+///
+fn lookup<P: Clone>(cache: &Option<Mutex<LruCache<String, (Instant, P)>>>, profile_id: &str, ttl: Duration) -> Option<P> {
+    let mut cache = cache.as_ref()?.lock();
+
+    match cache.get_mut(profile_id) {
+        Some((cached, profile)) if cached.elapsed() < ttl => Some(profile.clone()),
+        Some(_) => {
+            cache.remove(profile_id);
+            None
+        },
+        None => None,
+    }
+}
+
+fn store<P: Clone>(cache: &Option<Mutex<LruCache<String, (Instant, P)>>>, profile_id: &str, profile: &P) {
+    if let Some(cache) = cache {
+        cache.lock().insert(profile_id.to_string(), (Instant::now(), profile.clone()));
+    }
+}
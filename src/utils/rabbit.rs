@@ -1,14 +1,17 @@
 use uuid::Uuid;
 use serde_json::Value;
-use parking_lot::RwLock;
-use lazy_static::lazy_static;
-use std::{fs, time::Duration};
+use native_tls::Certificate;
+use futures::task::{self, ArcWake};
+use std::{future::Future, io::{self, Write}, pin::Pin, sync::{Arc, atomic::{AtomicBool, Ordering}}, task::{Context, Poll}, time::{Duration, Instant}};
 use tracing::{debug, error, info, warn};
-use crate::{routes::admin::tracer::prelude::*, utils::config::Configuration};
+use chrono::{DateTime, Utc};
+use mongodb::bson::{self, Bson, Document, doc};
+use crate::{model::{account::prelude::ACCOUNT_ID, dead_letter::{DeadLetter, DeadLetterHeaders}, outbox::{prelude::*, OutboxEntry}}, routes::admin::{correlation, notification_stats, set_time::Clock, tracer::prelude::*}, utils::config::{resolve_uri, Configuration}};
 use backoff::{ExponentialBackoff, retry_notify};
-use super::{context::RequestContext, errors::InternalError};
+use flate2::{Compression, write::GzEncoder};
+use super::{context::RequestContext, dead_letters::DeadLetterSender, errors::InternalError, outbox::{OutboxSender, Relayed}, webhooks::WebhookSink};
 use crossbeam_channel::{Receiver, RecvTimeoutError::Timeout, Sender};
-use lapin::{BasicProperties, Channel, Connection, ConnectionProperties, ExchangeKind, options::{BasicPublishOptions, ExchangeDeclareOptions}, types::{AMQPValue, FieldTable, ShortString}};
+use lapin::{BasicProperties, Channel, Connection, ConnectionProperties, ExchangeKind, publisher_confirm::{Confirmation, PublisherConfirm}, options::{BasicPublishOptions, ConfirmSelectOptions, ExchangeDeclareOptions}, tcp::{NativeTlsConnector, TcpStream}, types::{AMQPValue, FieldTable, ShortString}, uri::{AMQPScheme, AMQPUri}};
 
 //
 // This file contains all the rabbit publishing code. Each HTTP handler is given a crossbeam
@@ -18,26 +21,68 @@ use lapin::{BasicProperties, Channel, Connection, ConnectionProperties, Exchange
 //
 // The RabbitMQ publisher runs in it's own thread and will pick these notifications up and
 // send to the RabbitMQ exchange. At present, if the transmission fails to reach RabbitMQ, the
-// original handler cannot respond to the error.
+// original handler cannot respond to the error - but if dead_letters is configured, the notification
+// is written to MongoDB to be re-driven later.
+//
+// Alternatively, if transactional_outbox is configured, handlers stage notifications on the account in
+// the same write as the change, and the outbox relay (see outbox.rs) publishes them from there.
+//
+// Topics with a configured webhook are also POSTed there by this thread (see webhooks.rs). A failure of
+// either is treated the same - the notification is dead-lettered (or left in the outbox) and a re-drive
+// delivers it to both again.
 //
 
 pub mod prelude {
     pub const TOPIC_ACCOUNT_CREATED: &str = "account.created";
     pub const TOPIC_ACCOUNT_STATUS_UPDATED: &str = "account.status.updated";
+    pub const TOPIC_ACCOUNT_CREATION_REJECTED: &str = "account.creation.rejected";
+    pub const TOPIC_ACCOUNT_REACTIVATED: &str = "account.reactivated";
+    pub const TOPIC_ACCOUNT_ID_ROTATED: &str = "account.id.rotated";
+    pub const TOPIC_ACCOUNT_DELETED: &str = "account.deleted";
+    pub const TOPIC_ACCOUNT_UPDATED: &str = "account.updated";
+
+    // Routing key templates - {field} placeholders are substituted from the notification body.
+    pub const ROUTING_ACCOUNT_STATUS_UPDATED: &str = "account.status.updated.{newStatus}";
 }
 
-lazy_static! {
-    ///
-    /// The RabbitMQ publisher runs in a single thread and part of it's event loop is to check the
-    /// connection status (and re-connect if not open). This flag tracks the known state of the connection
-    /// and can be used by the health check to indicate if the RabbitMQ connection is healthy or not.
-    ///
-    pub static ref RABBIT_CONNECTED: RwLock<bool> = RwLock::new(false);
+/// The content-encoding of compressed notification bodies.
+pub const GZIP: &str = "gzip";
+
+/// How long the publisher tries to connect at start-up before the process is stopped.
+pub const STARTUP_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+///
+/// The RabbitMQ publisher runs in a single thread and part of it's event loop is to check the
+/// connection status (and re-connect if not open). This flag tracks the known state of the connection
+/// and can be used by the health check to indicate if the RabbitMQ connection is healthy or not.
+///
+pub type RabbitConnected = Arc<AtomicBool>;
+
+/// Set on shutdown - the publisher thread exits once it's published everything already queued.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+///
+/// The RabbitMQ publisher connects in its own thread, so give it up to the timeout to do so. Returns false
+/// if it's still not connected.
+///
+pub async fn await_connection(connected: &RabbitConnected, timeout: Duration) -> bool {
+    let started = Instant::now();
+
+    while started.elapsed() < timeout {
+        if connected.load(Ordering::SeqCst) {
+            return true
+        }
+        actix_rt::time::delay_for(Duration::from_millis(200)).await;
+    }
+
+    connected.load(Ordering::SeqCst)
 }
 
 pub struct NotificationRequest {
     topic: &'static str,
-    body: Option<Value>
+    body: Option<Value>,
+    routing_key: Option<&'static str>,
+    replay: bool,
 }
 
 impl NotificationRequest {
@@ -46,21 +91,96 @@ impl NotificationRequest {
         self
     }
 
+    ///
+    /// Publish with a routing key built from the template rather than the topic, eg.
+    /// 'account.status.updated.{newStatus}'. Any {field} placeholders are substituted with the
+    /// top-level field from the body.
+    ///
+    /// Templates are only applied when templated_routing_keys is configured - so existing bindings to
+    /// the plain topic are not broken.
+    ///
+    pub fn routing_key(&mut self, template: &'static str) -> &mut Self {
+        self.routing_key = Some(template);
+        self
+    }
+
+    ///
+    /// Flag the notification as a replay of an earlier event, so consumers rebuilding state can tell it
+    /// apart from a genuine change.
+    ///
+    pub fn replay(&mut self) -> &mut Self {
+        self.replay = true;
+        self
+    }
+
     ///
     /// Asynchronously send the message to RabbitMQ. The caller cannot action any failure (currently).
     ///
     pub fn send(&mut self, ctx: &RequestContext) {
-        ctx.publisher()
-            .fire_and_forget(Notification::new(
-                self.topic,
-                self.body.clone().unwrap_or_default(),
-                ctx.request_id(),
-                ctx.tracer()));
+        ctx.publisher().fire_and_forget(self.notification(ctx));
+    }
+
+    ///
+    /// The outbox entry to write with the change the notification describes (rather than sending it) - the
+    /// outbox relay publishes it once the write has succeeded.
+    ///
+    pub fn stage(&self, ctx: &RequestContext) -> Result<Document, InternalError> {
+        let notification = self.notification(ctx);
+
+        // Fail the change now if the body can't be serialised - the relay could never publish it.
+        serde_json::to_vec(&notification.body)?;
+
+        Ok(doc!{
+            OUTBOX_ID: Uuid::new_v4().to_hyphenated().to_string(),
+            TOPIC: notification.topic,
+            ROUTING_KEY: notification.routing_key.map(Bson::String).unwrap_or(Bson::Null),
+            CORRELATION_ID: notification.request_id,
+            BODY: bson::to_bson(&notification.body)?,
+            STAGED: ctx.now(),
+        })
+    }
+
+    fn notification(&self, ctx: &RequestContext) -> Notification {
+        let body = self.body.clone().unwrap_or_default();
+
+        let routing_key = match (ctx.config().templated_routing_keys, self.routing_key) {
+            (true, Some(template)) => render_routing_key(template, &body),
+            _ => None,
+        };
+
+        Notification::new(self.topic, body, ctx.request_id(), ctx.tracer())
+            .with_routing_key(routing_key)
+            .with_replay(self.replay)
     }
 }
 
 pub fn notify(topic: &'static str) -> NotificationRequest {
-    NotificationRequest { topic, body: None }
+    NotificationRequest { topic, body: None, routing_key: None, replay: false }
+}
+
+///
+/// Substitute each {field} placeholder in the template with the value of that (top-level) field in the
+/// body. If any field is missing the template cannot be rendered and None is returned.
+///
+fn render_routing_key(template: &str, body: &Value) -> Option<String> {
+    let mut routing_key = String::new();
+    let mut remaining = template;
+
+    while let Some(start) = remaining.find('{') {
+        let end = start + remaining[start..].find('}')?;
+        let value = match body.get(&remaining[start + 1..end])? {
+            Value::String(value) => value.clone(),
+            Value::Null => return None,
+            value => value.to_string(),
+        };
+
+        routing_key.push_str(&remaining[..start]);
+        routing_key.push_str(&value);
+        remaining = &remaining[end + 1..];
+    }
+
+    routing_key.push_str(remaining);
+    Some(routing_key)
 }
 
 ///
@@ -74,16 +194,99 @@ pub type Publisher = Sender<Notification>;
 ///
 #[derive(Debug)]
 pub struct Notification {
-    topic: &'static str, // The routing key to send the message via.
+    topic: String,       // The logical message type - and the routing key unless one is specified.
+    routing_key: Option<String>, // A routing key to use instead of the topic.
     version: u16,        // The body schema version - allows for breaking mutation of message structure.
     request_id: String,  // The correlation-id of the initiating request.
     body: Value,         // The JSON representation of the message body.
     tracer: bool,        // Indicates the notification should be traced by tracer.
+    replay: bool,        // The notification re-emits earlier state rather than a new change.
+    outboxed: Option<(String, String)>, // The account and outbox entry the notification was staged in - if any.
 }
 
 impl Notification {
-    pub fn new(topic: &'static str, body: Value, request_id: &str, tracer: bool) -> Self {
-        Notification { topic, body, request_id: request_id.to_string(), version: 1, tracer }
+    pub fn new(topic: &str, body: Value, request_id: &str, tracer: bool) -> Self {
+        Notification { topic: topic.to_string(), routing_key: None, body, request_id: request_id.to_string(), version: 1, tracer, replay: false, outboxed: None }
+    }
+
+    pub fn with_routing_key(mut self, routing_key: Option<String>) -> Self {
+        self.routing_key = routing_key;
+        self
+    }
+
+    pub fn with_replay(mut self, replay: bool) -> Self {
+        self.replay = replay;
+        self
+    }
+
+    ///
+    /// Re-create a notification which couldn't originally be published - with it's original correlation id.
+    ///
+    pub fn from_dead_letter(dead_letter: DeadLetter, tracer: bool) -> Self {
+        Notification {
+            topic: dead_letter.topic,
+            routing_key: dead_letter.routing_key,
+            version: dead_letter.headers.version,
+            request_id: dead_letter.headers.correlation_id,
+            body: dead_letter.body,
+            tracer,
+            replay: dead_letter.headers.replay,
+            outboxed: None,
+        }
+    }
+
+    ///
+    /// Create the notification staged in an account's outbox - the publisher reports back once it's sent.
+    ///
+    pub fn from_outbox(account_id: String, entry: OutboxEntry) -> Self {
+        Notification {
+            topic: entry.topic,
+            routing_key: entry.routing_key,
+            version: 1,
+            request_id: entry.correlation_id,
+            body: entry.body,
+            tracer: false,
+            replay: false,
+            outboxed: Some((account_id, entry.outbox_id)),
+        }
+    }
+
+    ///
+    /// The accounts the notification is about - from the body (a rotation is about both the old and new
+    /// accountId) or the outbox it was staged in.
+    ///
+    fn account_ids(&self) -> Vec<String> {
+        let mut account_ids: Vec<String> = [ACCOUNT_ID, "oldAccountId", "newAccountId"].iter()
+            .filter_map(|field| self.body.get(field).and_then(Value::as_str))
+            .map(String::from)
+            .collect();
+
+        if let Some((account_id, _)) = &self.outboxed {
+            if !account_ids.contains(account_id) {
+                account_ids.push(account_id.clone());
+            }
+        }
+
+        account_ids
+    }
+
+    ///
+    /// The routing key the message is published with.
+    ///
+    fn routing_key(&self) -> &str {
+        self.routing_key.as_deref().unwrap_or(&self.topic)
+    }
+
+    fn into_dead_letter(self, reason: String, failed_at: DateTime<Utc>) -> DeadLetter {
+        DeadLetter {
+            dead_letter_id: Uuid::new_v4().to_hyphenated().to_string(),
+            topic: self.topic,
+            routing_key: self.routing_key,
+            headers: DeadLetterHeaders { version: self.version, correlation_id: self.request_id, replay: self.replay },
+            body: self.body,
+            reason,
+            failed_at,
+        }
     }
 }
 
@@ -104,6 +307,7 @@ impl FireAndForget for Publisher {
     fn fire_and_forget(&self, notification: Notification) {
         if let Err(err) = self.send(notification) {
             error!("Failed to send notification {}", err);
+            notification_stats::dropped();
         }
     }
 }
@@ -133,36 +337,30 @@ fn backoff(timeout: Option<Duration>) -> ExponentialBackoff {
 ///
 /// Attempt to connect to RabbitMQ, retrying on any failure.
 ///
-fn connect(config: &Configuration, timeout: Option<Duration>) -> Result<(Connection, Channel), InternalError> {
+fn connect(config: &Configuration, connected: &RabbitConnected, timeout: Option<Duration>) -> Result<(Connection, Channel), InternalError> {
     info!("Connecting to RabbitMQ...");
 
-    let uri = match &config.rabbit_credentials {
-        Some(filename) => {
-            debug!("Loading RabbitMQ credentials from secrets file {}", filename);
-
-            // Read username and password from a secrets file.
-            let credentials = fs::read_to_string(filename).map_err(|err| InternalError::UnableToReadCredentials{ cause: err.to_string() })?;
-            let mut credentials = credentials.lines();
-            let uri = config.rabbit_uri.replace("$USERNAME", credentials.next().unwrap_or_default());
-            uri.replace("$PASSWORD", credentials.next().unwrap_or_default())
-        },
-        None => config.rabbit_uri.clone(),
-    };
+    let uri = resolve_uri(&config.rabbit_uri, config.rabbit_credentials.as_deref())?;
 
     let log_warn = |err, _dur| warn!("Failed to re-connect to RabbitMQ {}", err);
     let op = || {
-        let conn = Connection::connect(&uri, ConnectionProperties::default()).wait()?;
+        let conn = open_connection(&uri, config)?;
         let channel = conn.create_channel().wait()?;
 
-        info!("Connected to RabbitMQ");
-        *RABBIT_CONNECTED.write() = true;
+        // Have RabbitMQ confirm each publish - so a send which isn't routed to the exchange is known to have failed.
+        channel.confirm_select(ConfirmSelectOptions::default()).wait()?;
 
-        // Create the exchange if it doesn't already exist.
-        channel.exchange_declare(
-            &config.rabbit_exchange,
-            ExchangeKind::Topic,
-            ExchangeDeclareOptions { durable: true, ..ExchangeDeclareOptions::default() },
-            FieldTable::default()).wait()?;
+        info!("Connected to RabbitMQ");
+        connected.store(true, Ordering::SeqCst);
+
+        // Create the exchanges if they don't already exist.
+        for exchange in config.exchanges() {
+            channel.exchange_declare(
+                exchange,
+                ExchangeKind::Topic,
+                ExchangeDeclareOptions { durable: true, ..ExchangeDeclareOptions::default() },
+                FieldTable::default()).wait()?;
+        }
 
         Ok((conn, channel))
     };
@@ -170,14 +368,45 @@ fn connect(config: &Configuration, timeout: Option<Duration>) -> Result<(Connect
     retry_notify(backoff(timeout), op, log_warn).map_err(|err|err.into())
 }
 
+///
+/// Open a connection to RabbitMQ. For amqps uris, any custom CA is trusted (alongside the system's roots)
+/// and certificate checks are relaxed if configured.
+///
+fn open_connection(uri: &str, config: &Configuration) -> lapin::Result<Connection> {
+    let uri: AMQPUri = uri.parse().map_err(io::Error::other)?;
+
+    if let AMQPScheme::AMQP = uri.scheme {
+        return Connection::connect_uri(uri, ConnectionProperties::default()).wait()
+    }
+
+    let mut tls = NativeTlsConnector::builder();
+    tls.danger_accept_invalid_certs(config.tls_allow_invalid_certs);
+
+    if let Some(pem) = config.tls_ca_pem() {
+        let ca = Certificate::from_pem(pem.as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        tls.add_root_certificate(ca);
+    }
+
+    let tls = tls.build().map_err(io::Error::other)?;
+
+    let address = format!("{}:{}", uri.authority.host, uri.authority.port);
+    let stream = match uri.query.connection_timeout {
+        Some(timeout) => TcpStream::connect_timeout(address, Duration::from_millis(timeout)),
+        None => TcpStream::connect(address),
+    }?;
+
+    let host = uri.authority.host.clone();
+    Connection::connector(ConnectionProperties::default())(uri, stream.into_native_tls(tls, &host)).wait()
+}
+
 ///
 /// Check the connection. If it's not open - re-connect.
 ///
-fn check_connection(rabbit_connection: &mut RabbitConnection, config: &Configuration) {
+fn check_connection(rabbit_connection: &mut RabbitConnection, config: &Configuration, connected: &RabbitConnected) {
     if !rabbit_connection.channel.status().connected() {
-        *RABBIT_CONNECTED.write() = false;
+        connected.store(false, Ordering::SeqCst);
 
-        match connect(&config, None) {
+        match connect(&config, connected, None) {
             Ok((connection, channel)) => {
                 rabbit_connection.connection = connection;
                 rabbit_connection.channel = channel;
@@ -190,15 +419,25 @@ fn check_connection(rabbit_connection: &mut RabbitConnection, config: &Configura
 ///
 /// Dedicated rabbit publishing thread.
 ///
-pub fn rabbit_publisher(rx: Receiver::<Notification>, app_name: &str, config: Configuration) {
-    let mut connection = match connect(&config, Some(Duration::from_secs(30))) {
+pub fn rabbit_publisher(rx: Receiver::<Notification>, app_name: &str, config: Configuration, dead_letters: DeadLetterSender, outbox: OutboxSender, clock: Clock, connected: RabbitConnected) {
+    let mut connection = match connect(&config, &connected, Some(STARTUP_CONNECT_TIMEOUT)) {
         Ok((connection, channel)) => RabbitConnection { connection, channel },
+        Err(err) if config.self_test => {
+            // Leave the self-test to report the failure.
+            error!("Unable to connect to RabbitMQ at start-up: {}", err);
+            return
+        },
         Err(err) => {
             error!("Stopping process. Unable to connect to RabbitMQ at start-up: {}", err);
             std::process::exit(1)
         }
     };
 
+    let mut webhooks = match config.webhooks_enabled() {
+        true => Some(WebhookSink::new(config.clone())),
+        false => None,
+    };
+
     let mut running = true;
 
     // Main thread loop - publish to the RabbitMQ exchange anything send to this thread.
@@ -207,11 +446,37 @@ pub fn rabbit_publisher(rx: Receiver::<Notification>, app_name: &str, config: Co
         // of the RabbitMQ connection and repair it if it's closed.
         match rx.recv_timeout(Duration::from_secs(1)) {
             Ok(notification) => {
-                if let Some((bytes, props)) = to_rabbit_message(&notification, app_name) {
-                    send(props, bytes, notification, &connection, &config);
+                match to_rabbit_message(&notification, app_name, &config) {
+                    Ok((bytes, props)) => {
+                        let headers = webhook_headers(&notification, &props, &config);
+                        let result = send(props, bytes, &notification, &connection, &config, &clock);
+                        let result = match &mut webhooks {
+                            Some(webhooks) => either_failed(result, deliver_webhook(webhooks, &notification, &headers, &config)),
+                            None => result,
+                        };
+
+                        if result.is_ok() {
+                            notification_stats::sent();
+                        }
+
+                        // Outbox notifications stay in the outbox until they're sent, so they're never dead-lettered.
+                        match (result, notification.outboxed.clone()) {
+                            (result, Some((account_id, outbox_id))) => relayed(Relayed { account_id, outbox_id, published: result.is_ok() }, &outbox),
+                            (Err(reason), None) => dead_letter(notification, reason, &config, &dead_letters, &clock),
+                            (Ok(_), None) => (),
+                        }
+                    },
+                    Err(err) => {
+                        error!(topic = %notification.topic, correlation_id = %notification.request_id, error = %err, "Failed to serialise notification - it's been dropped");
+                        notification_stats::dropped();
+                    },
                 }
             },
-            Err(Timeout) => check_connection(&mut connection, &config),
+            Err(Timeout) if STOPPING.load(Ordering::Relaxed) => {
+                running = false;
+                info!("Terminating RabbitMQ thread - all queued notifications have been published");
+            },
+            Err(Timeout) => check_connection(&mut connection, &config, &connected),
             Err(err) => {
                 running = false;
                 debug!("Expected error in RabbitMQ thread: {}", err);
@@ -221,73 +486,233 @@ pub fn rabbit_publisher(rx: Receiver::<Notification>, app_name: &str, config: Co
     }
 }
 
+///
+/// Ask the publisher thread to exit once the notifications already queued have been published - it stops
+/// by itself when every sender has been dropped, but the HTTP server's app factories may outlive it.
+///
+pub fn stop_publisher() {
+    STOPPING.store(true, Ordering::Relaxed);
+}
+
 ///
 /// Convert the Notification into the headers and payload for sending to RabbitMQ.
 ///
-fn to_rabbit_message(notification: &Notification, app_name: &str) -> Option<(Vec<u8>, BasicProperties)> {
-    match serde_json::to_vec(&notification.body) {
-        Ok(bytes) => {
-            let mut headers = FieldTable::default();
-            headers.insert("version".to_string().into(), AMQPValue::ShortInt(notification.version as i16));
-            headers.insert("messageType".to_string().into(), AMQPValue::LongString(notification.topic.to_string().into()));
-
-            let props = BasicProperties::default()
-                .with_app_id(app_name.to_string().into())
-                .with_content_type("application/json".to_string().into())
-                .with_correlation_id(notification.request_id.clone().into())
-                .with_message_id(Uuid::new_v4().to_hyphenated().to_string().into())
-                .with_headers(headers);
-
-            Some((bytes, props))
+/// If compression is enabled, bodies over the threshold are gzipped and the content-encoding is set
+/// so consumers know to decompress them.
+///
+fn to_rabbit_message(notification: &Notification, app_name: &str, config: &Configuration) -> Result<(Vec<u8>, BasicProperties), InternalError> {
+    let bytes = serde_json::to_vec(&notification.body)?;
+
+    let mut headers = FieldTable::default();
+    headers.insert("version".to_string().into(), AMQPValue::ShortInt(notification.version as i16));
+    headers.insert("messageType".to_string().into(), AMQPValue::LongString(notification.topic.clone().into()));
+    headers.insert("schemaBundleVersion".to_string().into(), AMQPValue::LongString(config.event_schema_version.clone().into()));
+
+    if notification.replay {
+        headers.insert("replay".to_string().into(), AMQPValue::Boolean(true));
+    }
+
+    let mut props = BasicProperties::default()
+        .with_app_id(app_name.to_string().into())
+        .with_content_type("application/json".to_string().into())
+        .with_correlation_id(notification.request_id.clone().into())
+        .with_message_id(Uuid::new_v4().to_hyphenated().to_string().into())
+        .with_headers(headers);
+
+    if !config.compress_notifications || bytes.len() <= config.compression_threshold {
+        return Ok((bytes, props))
+    }
+
+    match gzip(&bytes) {
+        Ok(compressed) => {
+            props = props.with_content_encoding(GZIP.to_string().into());
+            Ok((compressed, props))
         },
         Err(err) => {
-            error!("Failed to serialise notification {:?} : {}", notification, err.to_string());
-            None
+            warn!("Failed to compress notification {:?}, sending uncompressed : {}", notification, err.to_string());
+            Ok((bytes, props))
         }
     }
 }
 
 ///
-/// Send the RabbitMQ message - any errors are logged but ignored.
+/// The notification's headers as they're sent to a webhook - the same as the RabbitMQ message's.
+///
+fn webhook_headers(notification: &Notification, props: &BasicProperties, config: &Configuration) -> Vec<(&'static str, String)> {
+    let mut headers = vec!(
+        ("content-type", "application/json".to_string()),
+        ("app-id", props.app_id().format().to_string()),
+        ("message-id", props.message_id().format().to_string()),
+        ("version", notification.version.to_string()),
+        ("messageType", notification.topic.clone()),
+        ("schemaBundleVersion", config.event_schema_version.clone()));
+
+    if notification.replay {
+        headers.push(("replay", true.to_string()));
+    }
+
+    headers
+}
+
+///
+/// POST the notification to the webhook configured for it's topic - if there is one.
+///
+fn deliver_webhook(webhooks: &mut WebhookSink, notification: &Notification, headers: &[(&str, String)], config: &Configuration) -> Result<(), String> {
+    let url = match config.webhook_for(&notification.topic) {
+        Some(url) => url,
+        None => return Ok(()),
+    };
+
+    webhooks.deliver(url, headers, &notification.body, &notification.request_id, notification.tracer)
+        .map_err(|reason| {
+            error!("Failed to deliver notification {:?} : {}", notification, reason);
+            reason
+        })
+}
+
+///
+/// Combine the results of publishing to RabbitMQ and delivering to a webhook - a failure of either fails both.
+///
+fn either_failed(published: Result<(), String>, delivered: Result<(), String>) -> Result<(), String> {
+    match (published, delivered) {
+        (Err(published), Err(delivered)) => Err(format!("{}; {}", published, delivered)),
+        (Err(reason), Ok(_)) | (Ok(_), Err(reason)) => Err(reason),
+        (Ok(_), Ok(_)) => Ok(()),
+    }
+}
+
+fn gzip(bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes)?;
+    encoder.finish()
+}
+
+///
+/// Send the RabbitMQ message - any errors are logged and the reason returned.
 ///
-#[tracing::instrument(name="send_rabbitmq", skip(props, bytes, notification, cc, config), level="info")]
-fn send(props: BasicProperties, bytes: Vec<u8>, notification: Notification, cc: &RabbitConnection, config: &Configuration) {
+#[tracing::instrument(name="send_rabbitmq", skip(props, bytes, notification, cc, config, clock), level="info")]
+fn send(props: BasicProperties, bytes: Vec<u8>, notification: &Notification, cc: &RabbitConnection, config: &Configuration, clock: &Clock) -> Result<(), String> {
     match cc.channel.basic_publish(
-        &config.rabbit_exchange,
-        notification.topic,
+        config.exchange_for(&notification.topic),
+        notification.routing_key(),
         BasicPublishOptions::default(),
         bytes,
         props.clone()).wait() {
             Ok(mut confirm) => {
-                // Ensure the exchange confirms the send.
-                match confirm.wait() {
-                    Err(err) => error!("Failed to ack send for notification {:?}: {}", notification, err.to_string()),
-                    _ => trace(&props, &notification)
+                // Ensure the exchange confirms the send - a lost confirm mustn't stall every notification after it.
+                let timeout = Duration::from_millis(config.rabbit_confirm_timeout);
+                match wait_for_confirm(&mut confirm, timeout) {
+                    None => {
+                        error!("Timed-out after {}ms waiting for RabbitMQ to ack the {} notification with correlation id {}",
+                            config.rabbit_confirm_timeout, notification.topic, notification.request_id);
+                        Err(format!("Timed-out after {}ms waiting for the send to be acked", config.rabbit_confirm_timeout))
+                    },
+                    Some(Err(err)) => {
+                        error!("Failed to ack send for notification {:?}: {}", notification, err.to_string());
+                        Err(format!("Failed to ack send: {}", err))
+                    },
+                    Some(Ok(Confirmation::Nack(_))) => {
+                        error!("RabbitMQ nacked the send for notification {:?}", notification);
+                        Err("The send was nacked".to_string())
+                    },
+                    _ => {
+                        trace(&props, notification, config);
+                        correlation::record(&notification.request_id, &notification.topic, notification.version, notification.account_ids(), config.emitted_history, clock.read().now());
+                        Ok(())
+                    }
                 }
             },
-            Err(err) => error!("Failed to send notification {:?} : {}", notification, err.to_string())
-    };
+            Err(err) => {
+                error!("Failed to send notification {:?} : {}", notification, err.to_string());
+                Err(format!("Failed to send: {}", err))
+            }
+    }
+}
+
+///
+/// Block until RabbitMQ confirms the publish - or the timeout passes, in which case None is returned.
+///
+fn wait_for_confirm(confirm: &mut PublisherConfirm, timeout: Duration) -> Option<lapin::Result<Confirmation>> {
+    let (tx, rx) = crossbeam_channel::bounded(1);
+    let waker = task::waker(Arc::new(ConfirmWaker(tx)));
+    let mut cx = Context::from_waker(&waker);
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if let Poll::Ready(result) = Pin::new(&mut *confirm).poll(&mut cx) {
+            return Some(result)
+        }
+
+        // Sleep until lapin wakes us (the confirm has arrived) or we run out of time.
+        let remaining = deadline.checked_duration_since(Instant::now())?;
+        let _ = rx.recv_timeout(remaining);
+    }
+}
+
+///
+/// Wakes the publisher thread when a confirm it's waiting for arrives.
+///
+struct ConfirmWaker(Sender<()>);
+
+impl ArcWake for ConfirmWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        let _ = arc_self.0.try_send(());
+    }
+}
+
+///
+/// If configured, hand the un-publishable notification over to be written to the DeadLetters
+/// collection. Otherwise it's dropped.
+///
+fn dead_letter(notification: Notification, reason: String, config: &Configuration, dead_letters: &DeadLetterSender, clock: &Clock) {
+    if !config.dead_letters {
+        notification_stats::dropped();
+        return
+    }
+
+    match dead_letters.unbounded_send(notification.into_dead_letter(reason, clock.read().now())) {
+        Ok(_) => notification_stats::deferred(),
+        Err(err) => {
+            error!("Failed to dead letter notification {:?}", err.into_inner());
+            notification_stats::dropped();
+        },
+    }
 }
 
+///
+/// Tell the outbox relay whether an outbox notification was published.
+///
+fn relayed(relayed: Relayed, outbox: &OutboxSender) {
+    // An unpublished notification stays in the outbox for the relay to publish later.
+    if !relayed.published {
+        notification_stats::deferred();
+    }
+
+    if let Err(err) = outbox.unbounded_send(relayed) {
+        error!("Failed to report outbox notification {:?}", err.into_inner());
+    }
+}
 
-fn trace(props: &BasicProperties, notification: &Notification) {
+fn trace(props: &BasicProperties, notification: &Notification, config: &Configuration) {
     if notification.tracer {
         let headers = format!("\n\
             {content_type}\n\
             {app_id}\n\
             {message_type}\n\
             {version}\n\
+            {schema_bundle_version}\n\
             {correlation_id}\n\
             {message_id}",
             version      = format_header("version", &format!("{}, ", notification.version)),
-            message_type = format_header("messageType", notification.topic),
+            message_type = format_header("messageType", &notification.topic),
+            schema_bundle_version = format_header("schemaBundleVersion", &config.event_schema_version),
             app_id       = format_header("App-Id", props.app_id().format()),
             content_type = format_header("Content-Type", props.content_type().format()),
             correlation_id = format_header("X-Correlation-Id", props.correlation_id().format()),
             message_id   = format_header("Message-Id", props.message_id().format()));
 
         info!("Emitting message to {}{}\n{}\n",
-            notification.topic,
+            notification.routing_key(),
             headers,
             notification.body);
     }
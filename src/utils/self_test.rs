@@ -0,0 +1,92 @@
+use serde::Serialize;
+use super::{context::InitialisationContext, errors::InternalError, mongo, rabbit};
+
+///
+/// The outcome of a self-test - a service that passes is ready to serve requests.
+///
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelfTestReport {
+    pub passed: bool,
+    initialisation: Check,
+    mongodb: Check,
+    rabbitmq: Check,
+    schema: Check,
+}
+
+#[derive(Debug, Serialize)]
+struct Check {
+    passed: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>
+}
+
+impl Check {
+    fn passed(message: Option<String>) -> Self {
+        Check { passed: true, message }
+    }
+
+    fn failed(message: String) -> Self {
+        Check { passed: false, message: Some(message) }
+    }
+}
+
+impl SelfTestReport {
+    ///
+    /// The report when the service couldn't be initialised - eg. the configuration is invalid or MongoDB
+    /// couldn't be connected to. The dependencies can't be checked without it.
+    ///
+    pub fn initialisation_failed(err: &InternalError) -> Self {
+        let not_checked = || Check::failed("Not checked - initialisation failed".to_string());
+
+        SelfTestReport {
+            passed: false,
+            initialisation: Check::failed(err.to_string()),
+            mongodb: not_checked(),
+            rabbitmq: not_checked(),
+            schema: not_checked(),
+        }
+    }
+}
+
+///
+/// If the self-test was asked for on the command line or in the environment. This is checked without the
+/// configuration, so a report can be given if it fails to load.
+///
+pub fn requested() -> bool {
+    std::env::args().any(|arg| arg == "--self-test")
+        || std::env::var("SELF_TEST").map(|value| value.eq_ignore_ascii_case("true")).unwrap_or_default()
+}
+
+///
+/// Verify the service's dependencies once it has been initialised. This reuses the start-up code paths -
+/// so by the time it's called, Mongo has been connected to and the RabbitMQ publisher is connecting.
+///
+/// Nothing is written - the schema is checked, not updated (start-up skips the updates in self-test mode).
+///
+pub async fn self_test(ctx: &InitialisationContext) -> SelfTestReport {
+    let mongodb = match mongo::ping(ctx.db()).await {
+        Ok(_) => Check::passed(None),
+        Err(err) => Check::failed(err.to_string()),
+    };
+
+    let schema = match mongo::check_schema(ctx.db()).await {
+        Ok(_) => Check::passed(None),
+        Err(err) => Check::failed(err.to_string()),
+    };
+
+    // The publisher doesn't stop the process in self-test mode if it can't connect.
+    let rabbitmq = match rabbit::await_connection(ctx.rabbit_connected(), rabbit::STARTUP_CONNECT_TIMEOUT).await {
+        true  => Check::passed(None),
+        false => Check::failed("Not connected".to_string()),
+    };
+
+    SelfTestReport {
+        passed: mongodb.passed && rabbitmq.passed && schema.passed,
+        initialisation: Check::passed(None),
+        mongodb,
+        rabbitmq,
+        schema
+    }
+}
@@ -0,0 +1,53 @@
+use tracing::{info, warn};
+use actix_web::dev::Server;
+use std::time::{Duration, Instant};
+use futures::future::{join_all, select};
+use crate::routes::admin::inflight;
+
+///
+/// Resolves when the process is asked to stop - a SIGTERM (eg. from the orchestrator during a rolling deploy)
+/// or a ctrl-c.
+///
+pub async fn signalled() {
+    #[cfg(unix)]
+    {
+        use actix_rt::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                select(Box::pin(terminate.recv()), Box::pin(actix_rt::signal::ctrl_c())).await;
+                return
+            },
+            Err(err) => warn!("Unable to listen for SIGTERM, only ctrl-c will shut down gracefully: {}", err),
+        }
+    }
+
+    let _ = actix_rt::signal::ctrl_c().await;
+}
+
+///
+/// Stop the servers accepting connections and give the requests already in-flight up to the drain timeout
+/// to complete. The servers' workers (and any requests still running) are dropped at the deadline.
+///
+pub async fn drain(servers: &[Server], drain_timeout: Duration) {
+    let draining = inflight::current();
+    info!("Shutting down - draining {} in-flight requests for up to {}s", draining, drain_timeout.as_secs());
+
+    let stopped = join_all(servers.iter().map(|server| server.stop(true)));
+
+    let deadline = Instant::now() + drain_timeout;
+    while inflight::current() > 0 && Instant::now() < deadline {
+        actix_rt::time::delay_for(Duration::from_millis(100)).await;
+    }
+
+    let aborted = inflight::current();
+    stopped.await;
+
+    match aborted {
+        0 => info!("Drained {} in-flight requests", draining),
+        _ => warn!("Drained {} in-flight requests, {} were aborted at the {}s deadline",
+            draining.saturating_sub(aborted),
+            aborted,
+            drain_timeout.as_secs()),
+    }
+}
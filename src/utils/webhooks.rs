@@ -0,0 +1,53 @@
+use std::rc::Rc;
+use serde_json::Value;
+use actix_web::client::Client;
+use actix_rt::{System, SystemRunner};
+use super::{config::Configuration, http::{http_client, post}};
+
+///
+/// Delivers notifications to the webhooks configured for their topic - alongside RabbitMQ, for integrators
+/// who can't consume from the broker.
+///
+/// The RabbitMQ publisher thread isn't async, so the sink has a small actix system of it's own and blocks
+/// on each delivery until it succeeds or it's retries (webhook_retry_limit) are exhausted.
+///
+pub struct WebhookSink {
+    system: SystemRunner,
+    client: Client,
+    config: Rc<Configuration>,
+}
+
+impl WebhookSink {
+    pub fn new(config: Configuration) -> Self {
+        let config = Rc::new(config);
+        let mut system = System::new("webhook-sink");
+        let client = system.block_on({
+            let config = config.clone();
+            async move { http_client(&config) }
+        });
+        WebhookSink { system, client, config }
+    }
+
+    ///
+    /// POST the notification body to the url with the headers given. Anything other than a 2xx response is
+    /// a failure - the reason is returned.
+    ///
+    pub fn deliver(&mut self, url: &str, headers: &[(&str, String)], body: &Value, request_id: &str, tracer: bool) -> Result<(), String> {
+        let mut request = post(url.to_string());
+        request.json(body)
+            .retry_unsafe() // Consumers can de-duplicate on the message-id header.
+            .retry_limit(self.config.webhook_retry_limit);
+
+        for (name, value) in headers {
+            request.header(name, value);
+        }
+
+        // The system only runs futures which own everything they use.
+        let (client, config, request_id) = (self.client.clone(), self.config.clone(), request_id.to_string());
+        match self.system.block_on(async move { request.send_with(&client, &config, &request_id, tracer).await }) {
+            Ok(response) if (200..300).contains(&response.status()) => Ok(()),
+            Ok(response) => Err(format!("Webhook {} returned {}", url, response.status())),
+            Err(err) => Err(format!("Failed to deliver to webhook {}: {}", url, err)),
+        }
+    }
+}
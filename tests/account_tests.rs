@@ -2,11 +2,15 @@ mod common;
 
 #[cfg(test)]
 mod tests {
-    use actix_web::test;
+    use actix_web::{HttpServer, client::Client, test};
+    use futures::{FutureExt, channel::oneshot, future::join3};
+    use std::{io::Write, panic::AssertUnwindSafe, sync::{Arc, atomic::Ordering}, time::{Duration, Instant}};
     use mockito::{Matcher, mock};
+    use mongodb::bson::doc;
+    use flate2::{Compression, write::GzEncoder};
     use serde_json::{Value, json};
     use assert_json_diff::assert_json_eq;
-    use crate::common::{freeze_time, http::{get, post, put}, new_uuid, rabbit::listen_to_topic, run_test, start_app};
+    use crate::common::{assert_matches_schema, capture_logs, database, freeze_time, http::{delete, get, options, patch, post, put}, new_uuid, rabbit::{listen_to_exchange, listen_to_topic}, run_test, start_admin_app_with, start_app, start_app_with, store_account_profile, stored_account};
 
     // TODO: Mock to match on correlation-id, test response and rabbit have same id.
 
@@ -40,7 +44,8 @@ mod tests {
                     "profileId": "DEFAULT",
                     "status": "ACTIVE",
                     "salutation": "Mr Blobby",
-                    "created": "2021-07-03T04:52:49.830Z"
+                    "created": "2021-07-03T04:52:49.830Z",
+                    "createdBy": "jbloggs"
                 });
             assert_json_eq!(actual, expected.clone());
 
@@ -53,6 +58,7 @@ mod tests {
                 .await;
 
             assert_eq!(resp.status(), 200);
+            assert_eq!(resp.header("x-device-slots-remaining"), None); // The DEFAULT profile has no device limit.
             let actual: Value = resp.read_body().await;
             assert_json_eq!(actual, expected.clone());
 
@@ -61,90 +67,3623 @@ mod tests {
         }).await;
     }
 
+    #[actix_rt::test]
+    async fn test_create_account_with_transactional_outbox() {
+        run_test(async {
+            // Given notifications are staged in the account outbox.
+            let mut service = test::init_service(start_app_with(&[("transactional_outbox", "true")]).await).await;
+            let rabbit = listen_to_topic("account.created").await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+            freeze_time(&mut service, "2021-07-03T04:52:49.830Z").await;
+
+            // When an account is created.
+            let mut resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id }))
+                .send(&mut service)
+                .await;
+
+            // Then the account is returned without it's outbox.
+            assert_eq!(resp.status(), 201);
+            let actual: Value = resp.read_body().await;
+            let expected = json!({
+                    "accountId": account_id,
+                    "profileId": "DEFAULT",
+                    "status": "ACTIVE",
+                    "created": "2021-07-03T04:52:49.830Z",
+                    "createdBy": "jbloggs"
+                });
+            assert_json_eq!(actual, expected.clone());
+
+            // And the notification is relayed from the outbox to RabbitMQ.
+            rabbit.assert_payload_received(expected).await;
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_create_account_published_to_topic_exchange() {
+        run_test(async {
+            // Given account.created notifications are published to their own exchange.
+            let mut service = test::init_service(start_app_with(&[("topic_exchanges", "account.created=security.events")]).await).await;
+            let rabbit = listen_to_exchange("security.events", "account.created").await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+            freeze_time(&mut service, "2021-07-03T04:52:49.830Z").await;
+
+            // When an account is created.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // Then the notification is published to that exchange.
+            rabbit.assert_payload_received(json!({
+                "accountId": account_id,
+                "profileId": "DEFAULT",
+                "status": "ACTIVE",
+                "created": "2021-07-03T04:52:49.830Z",
+                "createdBy": "jbloggs"
+            })).await;
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_created_account_is_delivered_to_webhook() {
+        run_test(async {
+            // Given account.created notifications are also delivered to a webhook.
+            let path = format!("/hooks/{}", new_uuid());
+            let webhooks = format!("account.created={}{}", mockito::server_url(), path);
+            let mut service = test::init_service(start_app_with(&[("webhooks", webhooks.as_str())]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+            let webhook_mock = mock("POST", path.as_str())
+                .match_header("messageType", "account.created")
+                .match_header("x-correlation-id", Matcher::Any)
+                .match_header("message-id", Matcher::Any)
+                .match_body(Matcher::PartialJson(json!({ "accountId": account_id })))
+                .with_status(204)
+                .create();
+
+            // When an account is created.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // Then the notification is POSTed to the webhook.
+            let started = std::time::Instant::now();
+            while !webhook_mock.matched() && started.elapsed() < std::time::Duration::from_secs(10) {
+                actix_rt::time::delay_for(std::time::Duration::from_millis(200)).await;
+            }
+            webhook_mock.assert();
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_rate_limited_webhook_is_retried_after_delay() {
+        run_test(async {
+            // Given a webhook which rate-limits every request - asking for a second's wait.
+            let path = format!("/hooks/{}", new_uuid());
+            let webhooks = format!("account.created={}{}", mockito::server_url(), path);
+            let mut service = test::init_service(start_app_with(&[("webhooks", webhooks.as_str()), ("webhook_retry_limit", "2"), ("client_retry_delay", "0")]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let webhook_mock = mock("POST", path.as_str())
+                .with_status(429)
+                .with_header("retry-after", "1")
+                .expect(2)
+                .create();
+
+            // When an account is created.
+            let started = std::time::Instant::now();
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": new_uuid() }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // Then the delivery is retried once the requested time has passed.
+            while !webhook_mock.matched() && started.elapsed() < std::time::Duration::from_secs(10) {
+                actix_rt::time::delay_for(std::time::Duration::from_millis(200)).await;
+            }
+            webhook_mock.assert();
+            assert!(started.elapsed() >= std::time::Duration::from_secs(1));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_create_account_with_write_limit() {
+        run_test(async {
+            // Given only one MongoDB write is allowed at a time.
+            let mut service = test::init_service(start_app_with(&[("max_concurrent_writes", "1")]).await).await;
+            let _auth_mock = mock_auth_ok();
+
+            // When accounts are created one after the other.
+            for _ in 0..3 {
+                let resp = post("/create-account")
+                    .header("content-type", "application/json")
+                    .body(json!({ "accountId": new_uuid() }))
+                    .send(&mut service)
+                    .await;
+
+                // Then each write's permit is released for the next.
+                assert_eq!(resp.status(), 201);
+            }
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_rejected_account_creation_is_notified() {
+        run_test(async {
+            // Given rejected accounts are notified.
+            let mut service = test::init_service(start_app_with(&[("notify_rejected_accounts", "true")]).await).await;
+            let rabbit = listen_to_topic("account.creation.rejected").await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            // When an account is created with an unknown profile.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "profileId": new_uuid() }))
+                .send(&mut service)
+                .await;
+
+            // Then the request is still rejected.
+            assert_eq!(resp.status(), 400);
+
+            // And the rejection is notified with the reason code.
+            rabbit.assert_payload_received(json!({ "accountId": account_id, "errorCode": 2510 })).await;
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_create_account_rejected_if_the_default_profile_is_missing() {
+        run_test(async {
+            // Given a database whose DEFAULT account profile has been deleted.
+            let db_name = format!("MissingDefault-{}", new_uuid());
+            let mut service = test::init_service(start_app_with(&[("db_name", &db_name)]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let db = database(&db_name).await;
+            db.collection("AccountProfiles").delete_one(doc!{ "profileId": "DEFAULT" }, None).await.expect("Unable to delete the profile");
+
+            // When an account is created without a profile.
+            let mut resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": new_uuid() }))
+                .send(&mut service)
+                .await;
+
+            // Then it's rejected rather than referencing the missing profile.
+            assert_eq!(resp.status(), 400);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["errorCode"], json!(2510));
+
+            db.drop(None).await.expect("Unable to drop the database");
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_duplicate_account_creation() {
+        run_test(async {
+            // Given duplicates are conflicts.
+            let mut service = test::init_service(start_app_with(&[("duplicate_account_conflict", "true")]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            // And an account already exists.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "salutation": "Mr Blobby" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When it's created again.
+            let mut resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "salutation": "Mr Whippy" }))
+                .send(&mut service)
+                .await;
+
+            // Then there's a conflict on the accountId.
+            assert_eq!(resp.status(), 409);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["errorCode"], json!(2517));
+            assert_eq!(actual["message"], json!("An account already exists with the same accountId"));
+
+            // When it's created again asking for the existing account.
+            let mut resp = post("/create-account?returnExistingOnDuplicate=true")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "salutation": "Mr Whippy" }))
+                .send(&mut service)
+                .await;
+
+            // Then the original account is returned.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["salutation"], json!("Mr Blobby"));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_create_account_with_partial_devices() {
+        run_test(async {
+            // Given the environment is set-up.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let (account_id, good_device_id, bad_device_id) = (new_uuid(), new_uuid(), new_uuid());
+            let body = json!({
+                "accountId": account_id,
+                "devices": [
+                    { "deviceId": good_device_id, "deviceType": "PC" },
+                    { "deviceId": bad_device_id, "deviceType": "PC", "profileId": "NOT_A_PROFILE" }
+                ]
+            });
+
+            // When an account is created with an invalid device.
+            let mut resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(body.clone())
+                .send(&mut service)
+                .await;
+
+            // Then the whole create fails.
+            assert_eq!(resp.status(), 400);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["errorCode"], json!(2511));
+
+            // When it's created with partialDevices.
+            let mut resp = post("/create-account?partialDevices=true")
+                .header("content-type", "application/json")
+                .body(body)
+                .send(&mut service)
+                .await;
+
+            // Then the account is created with only the valid device and the other is reported.
+            assert_eq!(resp.status(), 201);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["devices"].as_array().unwrap().len(), 1);
+            assert_eq!(actual["devices"][0]["deviceId"], json!(good_device_id));
+            assert_eq!(actual["rejectedDevices"], json!([{ "index": 1, "deviceId": bad_device_id, "errorCode": 2511 }]));
+
+            // And the stored account has only the valid device.
+            let mut resp = get(&format!("/account/{}", account_id))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["devices"].as_array().unwrap().len(), 1);
+            assert_eq!(actual.get("rejectedDevices"), None);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_device_profile_restricts_device_types() {
+        run_test(async {
+            // Given a device profile which only allows PCs.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let profile_id = new_uuid();
+
+            let mut resp = post("/create-device-profile")
+                .header("content-type", "application/json")
+                .body(json!({ "profileId": profile_id, "allowedDeviceTypes": [ "PC" ], "maxSessions": 2 }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual, json!({ "profileId": profile_id, "allowedDeviceTypes": [ "PC" ], "maxSessions": 2 }));
+
+            // When an account is created with a PC in the profile.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "devices": [{ "deviceType": "PC", "profileId": profile_id }] }))
+                .send(&mut service)
+                .await;
+
+            // Then the device is allowed.
+            assert_eq!(resp.status(), 201);
+
+            // When an account is created with a smartphone in the profile.
+            let mut resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "devices": [{ "deviceType": "SMARTPHONE", "profileId": profile_id }] }))
+                .send(&mut service)
+                .await;
+
+            // Then the device is refused.
+            assert_eq!(resp.status(), 400);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["errorCode"], json!(2515));
+
+            // And once the profile allows smartphones, it can be created.
+            let resp = put("/update-device-profile")
+                .header("content-type", "application/json")
+                .body(json!({ "profileId": profile_id, "allowedDeviceTypes": [ "PC", "SMARTPHONE" ] }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "devices": [{ "deviceType": "SMARTPHONE", "profileId": profile_id }] }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_cached_device_profile_is_evicted_when_updated() {
+        run_test(async {
+            // Given a device profile has been read (and so cached).
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let profile_id = new_uuid();
+
+            let resp = post("/create-device-profile")
+                .header("content-type", "application/json")
+                .body(json!({ "profileId": profile_id, "maxSessions": 2 }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            let mut resp = get(&format!("/device-profile/{}", profile_id)).send(&mut service).await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["maxSessions"], json!(2));
+
+            // When the profile is updated.
+            let resp = put("/update-device-profile")
+                .header("content-type", "application/json")
+                .body(json!({ "profileId": profile_id, "maxSessions": 5 }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+
+            // Then the update is seen straight away.
+            let mut resp = get(&format!("/device-profile/{}", profile_id)).send(&mut service).await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["maxSessions"], json!(5));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_get_account_tracks_last_accessed() {
+        run_test(async {
+            // Given reads are tracked and an account exists.
+            let mut service = test::init_service(start_app_with(&[("track_last_accessed", "true")]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+            freeze_time(&mut service, "2021-07-03T04:52:49.830Z").await;
+
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When the account is read for the first time.
+            let mut resp = get(&format!("/account/{}", account_id)).send(&mut service).await;
+
+            // Then it has no previous access.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["lastAccessedAt"], Value::Null);
+
+            // And subsequent reads show when it was read - the access is recorded in the background.
+            let mut actual = Value::Null;
+            for _ in 0..50 {
+                let mut resp = get(&format!("/account/{}", account_id)).send(&mut service).await;
+                assert_eq!(resp.status(), 200);
+                actual = resp.read_body().await;
+                if actual["lastAccessedAt"] != Value::Null {
+                    break
+                }
+                tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+            }
+            assert_eq!(actual["lastAccessedAt"], json!("2021-07-03T04:52:49.830Z"));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_create_account_billing_date_round_trip() {
+        run_test(async {
+            // Given the environment is set-up.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+            freeze_time(&mut service, "2021-07-03T04:52:49.830Z").await;
+
+            // When an account is created with a billing date.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({
+                    "accountId": account_id,
+                    "billingDate": "2021-08-01T00:00:00.000Z"
+                }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // Then the account can be read back with the same billing date. The read will only succeed
+            // if the date was stored as a BSON Date rather than a string.
+            let mut resp = get(&format!("/account/{}", account_id))
+                .send(&mut service)
+                .await;
+
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_json_eq!(actual, json!({
+                "accountId": account_id,
+                "profileId": "DEFAULT",
+                "status": "ACTIVE",
+                "billingDate": "2021-08-01T00:00:00Z",
+                "created": "2021-07-03T04:52:49.830Z",
+                "createdBy": "jbloggs"
+            }));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_titlecase_statuses_are_accepted() {
+        run_test(async {
+            // Given the environment is set-up.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            // When an account is created with a titlecase status.
+            let mut resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "status": "Restricted" }))
+                .send(&mut service)
+                .await;
+
+            // Then it's accepted and returned in the configured (upper) casing.
+            assert_eq!(resp.status(), 201);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["status"], "RESTRICTED");
+
+            // And the status can be updated with a titlecase status too.
+            let resp = put("/update-account-status")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "status": "Suspended" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+
+            let mut resp = get(&format!("/account/{}", account_id)).send(&mut service).await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["status"], "SUSPENDED");
+
+            // But an unknown status is still refused.
+            let resp = put("/update-account-status")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "status": "Snoozing" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 400);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_enum_casing_is_per_app_and_migrated_both_ways() {
+        run_test(async {
+            // Given an app configured for titlecase statuses.
+            let mut titlecase = test::init_service(start_app_with(&[("enum_casing", "titlecase")]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let prefix = new_uuid();
+            let account_id = format!("{}-1", prefix);
+
+            // And an account it's created.
+            let mut resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "status": "ACTIVE" }))
+                .send(&mut titlecase)
+                .await;
+            assert_eq!(resp.status(), 201);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["status"], "Active");
+
+            // When an app with the default (upper) casing starts alongside it.
+            let mut uppercase = test::init_service(start_app().await).await;
+
+            // Then each app serves the account in it's own casing.
+            let mut resp = get(&format!("/account/{}", account_id)).send(&mut titlecase).await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["status"], "Active");
+
+            let mut resp = get(&format!("/account/{}", account_id)).send(&mut uppercase).await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["status"], "ACTIVE");
+
+            // And lists the account in it's own casing too - the list is streamed after the request has been handled.
+            let mut resp = get(&format!("/accounts?cursor={}&limit=1", prefix)).send(&mut titlecase).await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual[0]["accountId"], json!(account_id));
+            assert_eq!(actual[0]["status"], "Active");
+
+            let mut resp = get(&format!("/accounts/search?cursor={}&limit=1", prefix)).send(&mut uppercase).await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual[0]["status"], "ACTIVE");
+
+            // And the uppercase app's start-up left the stored status alone.
+            let stored = stored_account(&account_id).await.expect("Account not stored");
+            assert_eq!(stored.get_str("status").ok(), Some("Active"));
+
+            // When the uppercase app is asked to re-case the statuses.
+            let mut resp = post("/admin/recase-statuses").send(&mut uppercase).await;
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["applied"], json!(["Accounts.status.uppercase"]));
+
+            // Then the stored status is migrated back to uppercase.
+            let stored = stored_account(&account_id).await.expect("Account not stored");
+            assert_eq!(stored.get_str("status").ok(), Some("ACTIVE"));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_create_account_uses_configured_default_status() {
+        run_test(async {
+            // Given the service is configured to create accounts as PENDING.
+            let mut service = test::init_service(start_app_with(&[("default_account_status", "PENDING")]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+            let other_id = new_uuid();
+
+            // When an account is created without a status.
+            let mut resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id }))
+                .send(&mut service)
+                .await;
+
+            // Then the account has the configured default status.
+            assert_eq!(resp.status(), 201);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["status"], "PENDING");
+
+            // And a status in the request still takes precedence.
+            let mut resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": other_id, "status": "ACTIVE" }))
+                .send(&mut service)
+                .await;
+
+            assert_eq!(resp.status(), 201);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["status"], "ACTIVE");
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_create_account_validates_external_ids() {
+        run_test(async {
+            // Given accounts may have at most two external ids.
+            let mut service = test::init_service(start_app_with(&[("max_external_ids", "2")]).await).await;
+            let _auth_mock = mock_auth_ok();
+
+            // When an account is created with too many.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({
+                    "accountId": new_uuid(),
+                    "externalIds": [
+                        { "key": "a", "value": new_uuid() },
+                        { "key": "b", "value": new_uuid() },
+                        { "key": "c", "value": new_uuid() }
+                    ]
+                }))
+                .send(&mut service)
+                .await;
+
+            // Then it's rejected.
+            assert_eq!(resp.status(), 400);
+
+            // And so is an account with a repeated key.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({
+                    "accountId": new_uuid(),
+                    "externalIds": [
+                        { "key": "a", "value": new_uuid() },
+                        { "key": "a", "value": new_uuid() }
+                    ]
+                }))
+                .send(&mut service)
+                .await;
+
+            assert_eq!(resp.status(), 400);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_create_account_defaults_profiles() {
+        run_test(async {
+            // Given profiles aren't required.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            // When an account is created without account or device profiles.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "devices": [{ "deviceType": "PC" }] }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // Then both are given the DEFAULT profile.
+            let mut resp = get(&format!("/account/{}", account_id)).send(&mut service).await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["profileId"], json!("DEFAULT"));
+            assert_eq!(actual["devices"][0]["profileId"], json!("DEFAULT"));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_create_account_requires_explicit_profiles() {
+        run_test(async {
+            // Given profiles must be explicit.
+            let mut service = test::init_service(start_app_with(&[("require_explicit_profile", "true")]).await).await;
+            let _auth_mock = mock_auth_ok();
+
+            // When an account is created without a profile.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": new_uuid() }))
+                .send(&mut service)
+                .await;
+
+            // Then it's rejected.
+            assert_eq!(resp.status(), 400);
+
+            // And so is an account with a device without a profile.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": new_uuid(), "profileId": "DEFAULT", "devices": [{ "deviceType": "PC" }] }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 400);
+
+            // But naming the profiles (even DEFAULT) is fine.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": new_uuid(), "profileId": "DEFAULT", "devices": [{ "deviceType": "PC", "profileId": "DEFAULT" }] }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_get_account_reports_remaining_device_slots() {
+        run_test(async {
+            // Given an account with one device in a profile which allows three.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+            let profile_id = new_uuid();
+            store_account_profile(doc!{ "profileId": &profile_id, "maxDevices": 3 }).await;
+
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "profileId": profile_id, "devices": [{ "deviceType": "PC", "profileId": "DEFAULT" }] }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When the account is read.
+            let resp = get(&format!("/account/{}", account_id))
+                .send(&mut service)
+                .await;
+
+            // Then the response says two more devices can be added.
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.header("x-device-slots-remaining"), Some("2".to_string()));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_create_account_validates_salutation() {
+        run_test(async {
+            // Given only some salutations are allowed.
+            let mut service = test::init_service(start_app_with(&[("allowed_salutations", "Mr,Mrs,Ms,Dr,Mx")]).await).await;
+            let _auth_mock = mock_auth_ok();
+
+            // When an account is created with an allowed salutation (in any case).
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": new_uuid(), "salutation": "dr" }))
+                .send(&mut service)
+                .await;
+
+            // Then it's created.
+            assert_eq!(resp.status(), 201);
+
+            // But an account with any other salutation is rejected, naming it.
+            let mut resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": new_uuid(), "salutation": "Mr Blobby" }))
+                .send(&mut service)
+                .await;
+
+            assert_eq!(resp.status(), 400);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["errorCode"], json!(1010));
+            assert_eq!(actual["message"], json!("Request format invalid: The salutation Mr Blobby is not allowed"));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_create_account_validates_salutation_case() {
+        run_test(async {
+            // Given salutations must match the allowed ones exactly.
+            let overrides = [("allowed_salutations", "Mr,Mrs,Ms,Dr,Mx"), ("salutation_case_sensitive", "true")];
+            let mut service = test::init_service(start_app_with(&overrides).await).await;
+            let _auth_mock = mock_auth_ok();
+
+            // When an account is created with an allowed salutation in the wrong case.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": new_uuid(), "salutation": "dr" }))
+                .send(&mut service)
+                .await;
+
+            // Then it's rejected.
+            assert_eq!(resp.status(), 400);
+
+            // But it's created with the exact salutation.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": new_uuid(), "salutation": "Dr" }))
+                .send(&mut service)
+                .await;
+
+            assert_eq!(resp.status(), 201);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_get_account_by_device() {
+        run_test(async {
+            // Given an account exists with a device.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+            let device_id = new_uuid();
+
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({
+                    "accountId": account_id,
+                    "devices": [{ "deviceId": device_id, "deviceType": "PC" }]
+                }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When the account is looked up by the device.
+            let mut resp = get(&format!("/accounts/by-device/{}", device_id))
+                .send(&mut service)
+                .await;
+
+            // Then the owning account is returned.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["accountId"], json!(account_id));
+
+            // And an unknown device has no account.
+            let resp = get(&format!("/accounts/by-device/{}", new_uuid()))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 204);
+        }).await;
+    }
+
     #[actix_rt::test]
     async fn test_update_account_status_happy_path() {
         run_test(async {
             // Given the environment is set-up.
             let mut service = test::init_service(start_app().await).await;
-            let rabbit = listen_to_topic("account.status.updated").await;
-            let auth_mock = mock_auth_ok();
+            let rabbit = listen_to_topic("account.status.updated").await;
+            let auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+            freeze_time(&mut service, "2021-07-04T04:52:49.830Z").await;
+
+            // And an account already exists.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({
+                    "accountId": account_id,
+                    "salutation": "Mr Blobby",
+                    "profileId": "DEFAULT"
+                }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+            auth_mock.assert();
+
+            // When the status is updated.
+            let resp = put("/update-account-status")
+                .header("content-type", "application/json")
+                .body(json!({
+                    "accountId": account_id,
+                    "status": "RESTRICTED"
+                }))
+                .send(&mut service)
+                .await;
+
+            // Then the response is successful.
+            assert_eq!(resp.status(), 200);
+
+            // And a RabbitMQ notification was generated.
+            rabbit.assert_payload_received(json!({
+                "accountId": account_id,
+                "oldStatus": "ACTIVE",
+                "newStatus": "RESTRICTED",
+                "modifiedBy": "system"
+            })).await;
+
+            // And the change is attributed on the account - there was no principal, so to the system.
+            let mut resp = get(&format!("/account/{}", account_id)).send(&mut service).await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["createdBy"], json!("jbloggs"));
+            assert_eq!(actual["modifiedBy"], json!("system"));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_update_account_status_with_templated_routing_key() {
+        run_test(async {
+            // Given status notifications are routed by their new status.
+            let mut service = test::init_service(start_app_with(&[("templated_routing_keys", "true")]).await).await;
+            let rabbit = listen_to_topic("account.status.updated.SUSPENDED").await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            // And an account already exists.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When the account is suspended.
+            let resp = put("/update-account-status")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "status": "SUSPENDED" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+
+            // Then a consumer bound to just suspensions gets the notification.
+            rabbit.assert_payload_received(json!({
+                "accountId": account_id,
+                "oldStatus": "ACTIVE",
+                "newStatus": "SUSPENDED",
+                "modifiedBy": "system"
+            })).await;
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_changes_without_a_principal_are_attributed_to_the_system_identity() {
+        run_test(async {
+            // Given a configured system identity and an auth service which doesn't say who the caller is.
+            let mut service = test::init_service(start_app_with(&[("system_identity", "nightly-batch")]).await).await;
+            let rabbit = listen_to_topic("account.status.updated").await;
+            let _auth_mock = mock("POST", "/auth/get-claims")
+                .match_query(Matcher::Any)
+                .with_header("content-type", "application/json")
+                .with_status(200)
+                .with_body(r#"{ "claims": [ "create-account" ] }"#)
+                .create();
+            let account_id = new_uuid();
+
+            // When an account is created and it's status updated.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            let resp = put("/update-account-status")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "status": "RESTRICTED" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+
+            // Then both changes are attributed to the system identity.
+            let mut resp = get(&format!("/account/{}", account_id)).send(&mut service).await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["createdBy"], json!("nightly-batch"));
+            assert_eq!(actual["modifiedBy"], json!("nightly-batch"));
+
+            // And so is the notification.
+            rabbit.assert_payload_received(json!({
+                "accountId": account_id,
+                "oldStatus": "ACTIVE",
+                "newStatus": "RESTRICTED",
+                "modifiedBy": "nightly-batch"
+            })).await;
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_update_account_status_with_snapshots() {
+        run_test(async {
+            // Given status notifications have snapshots of some (redacted) fields.
+            let overrides = [("status_snapshot_fields", "status,salutation"), ("audit_redacted_fields", "salutation")];
+            let mut service = test::init_service(start_app_with(&overrides).await).await;
+            let rabbit = listen_to_topic("account.status.updated").await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            // And an account already exists.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "salutation": "Mr Blobby" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When the status is updated.
+            let resp = put("/update-account-status")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "status": "RESTRICTED" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+
+            // Then the notification has the account's fields before and after.
+            rabbit.assert_payload_received(json!({
+                "accountId": account_id,
+                "oldStatus": "ACTIVE",
+                "newStatus": "RESTRICTED",
+                "modifiedBy": "system",
+                "before": { "status": "ACTIVE", "salutation": "********" },
+                "after": { "status": "RESTRICTED", "salutation": "********" }
+            })).await;
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_compressed_notifications_are_received() {
+        run_test(async {
+            // Given the service is configured to compress every notification.
+            let mut service = test::init_service(start_app_with(&[
+                ("compress_notifications", "true"),
+                ("compression_threshold", "0")]).await).await;
+            let rabbit = listen_to_topic("account.status.updated").await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            // And an account already exists.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "status": "ACTIVE" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When the status is updated.
+            let resp = put("/update-account-status")
+                .header("content-type", "application/json")
+                .body(json!({
+                    "accountId": account_id,
+                    "status": "SUSPENDED"
+                }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+
+            // Then the gzipped notification can be decompressed by consumers.
+            rabbit.assert_payload_received(json!({
+                "accountId": account_id,
+                "oldStatus": "ACTIVE",
+                "newStatus": "SUSPENDED",
+                "modifiedBy": "system"
+            })).await;
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_reactivate_cancelled_account() {
+        run_test(async {
+            // Given the environment is set-up.
+            let mut service = test::init_service(start_app().await).await;
+            let rabbit = listen_to_topic("account.reactivated").await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+            freeze_time(&mut service, "2021-07-04T04:52:49.830Z").await;
+
+            // And an account has been cancelled.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            let resp = put("/update-account-status")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "status": "CANCELLED" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+
+            // When it's reactivated.
+            let resp = post(&format!("/account/{}/reactivate", account_id))
+                .header("content-type", "application/json")
+                .body(json!({ "reason": "Cancelled in error" }))
+                .send(&mut service)
+                .await;
+
+            // Then the response is successful.
+            assert_eq!(resp.status(), 200);
+
+            // And a reactivation notification was generated.
+            rabbit.assert_payload_received(json!({
+                "accountId": account_id,
+                "oldStatus": "CANCELLED",
+                "newStatus": "ACTIVE",
+                "reason": "Cancelled in error",
+                "modifiedBy": "jbloggs"
+            })).await;
+
+            // And the account is active with the reason in it's status history.
+            let mut resp = get(&format!("/account/{}", account_id)).send(&mut service).await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["status"], json!("ACTIVE"));
+            assert_eq!(actual["statusHistory"], json!([
+                { "oldStatus": "ACTIVE", "newStatus": "CANCELLED", "modifiedBy": "system", "modified": "2021-07-04T04:52:49.830Z" },
+                { "oldStatus": "CANCELLED", "newStatus": "ACTIVE", "modifiedBy": "jbloggs", "reason": "Cancelled in error", "modified": "2021-07-04T04:52:49.830Z" }
+            ]));
+
+            // And it can't be reactivated again.
+            let mut resp = post(&format!("/account/{}/reactivate", account_id))
+                .header("content-type", "application/json")
+                .body(json!({ "reason": "Cancelled in error" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 400);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["errorCode"], json!(2516));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_patched_fields_are_kept_in_change_history() {
+        run_test(async {
+            // Given only two changes are kept and salutations are sensitive.
+            let overrides = [("max_change_history", "2"), ("audit_redacted_fields", "salutation")];
+            let mut service = test::init_service(start_app_with(&overrides).await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "salutation": "Mr" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When the account is patched twice.
+            for ops in &[
+                json!([{ "op": "add", "path": "/billingAddress", "value": [{ "key": "line1", "value": "1 High Street" }] }]),
+                json!([{ "op": "replace", "path": "/salutation", "value": "Dr" }, { "op": "remove", "path": "/billingAddress/0" }])] {
+
+                let resp = patch(&format!("/account/{}", account_id))
+                    .header("content-type", "application/json-patch+json")
+                    .body(ops.clone())
+                    .send(&mut service)
+                    .await;
+                assert_eq!(resp.status(), 200);
+            }
+
+            // Then only the last two changes are kept - with the salutations masked.
+            let mut resp = get(&format!("/account/{}/history", account_id)).send(&mut service).await;
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            let changes: Vec<Value> = actual.as_array().expect("history should be an array").iter()
+                .map(|change| json!({ "path": change["path"], "oldValue": change["oldValue"], "newValue": change["newValue"], "modifiedBy": change["modifiedBy"] }))
+                .collect();
+
+            assert_eq!(changes, vec!(
+                json!({ "path": "/salutation", "oldValue": "********", "newValue": "********", "modifiedBy": "system" }),
+                json!({ "path": "/billingAddress", "oldValue": [{ "key": "line1", "value": "1 High Street" }], "newValue": [], "modifiedBy": "system" }),
+            ));
+
+            // And there's no history for an unknown account.
+            let resp = get(&format!("/account/{}/history", new_uuid())).send(&mut service).await;
+            assert_eq!(resp.status(), 204);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_writes_with_account_write_lock() {
+        run_test(async {
+            // Given writes to the same account are serialised.
+            let mut service = test::init_service(start_app_with(&[("account_write_lock", "true")]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When the account is written to repeatedly.
+            let resp = patch(&format!("/account/{}", account_id))
+                .header("content-type", "application/json-patch+json")
+                .body(json!([{ "op": "add", "path": "/salutation", "value": "Dr" }]))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+
+            for status in &["SUSPENDED", "ACTIVE"] {
+                let resp = put("/update-account-status")
+                    .header("content-type", "application/json")
+                    .body(json!({ "accountId": account_id, "status": status }))
+                    .send(&mut service)
+                    .await;
+                assert_eq!(resp.status(), 200);
+            }
+
+            // Then each write's lock was released for the next and every change was made.
+            let mut resp = get(&format!("/account/{}", account_id)).send(&mut service).await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["salutation"], json!("Dr"));
+            assert_eq!(actual["status"], json!("ACTIVE"));
+            assert_eq!(actual["statusHistory"].as_array().map(Vec::len), Some(2));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_patch_account() {
+        run_test(async {
+            // Given the environment is set-up.
+            let mut service = test::init_service(start_app().await).await;
+            let rabbit = listen_to_topic("account.updated").await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            // And an account exists.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "salutation": "Mr" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When it's patched.
+            let ops = json!([
+                { "op": "replace", "path": "/salutation", "value": "Dr" },
+                { "op": "add", "path": "/billingAddress", "value": [{ "key": "line1", "value": "1 High Street" }] }
+            ]);
+            let mut resp = patch(&format!("/account/{}", account_id))
+                .header("content-type", "application/json-patch+json")
+                .body(ops.clone())
+                .send(&mut service)
+                .await;
+
+            // Then the patched account is returned.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["salutation"], json!("Dr"));
+            assert_eq!(actual["billingAddress"], json!([{ "key": "line1", "value": "1 High Street" }]));
+
+            // And an update notification was generated with the operations applied.
+            rabbit.assert_payload_received(json!({
+                "accountId": account_id,
+                "ops": ops,
+                "modifiedBy": "system"
+            })).await;
+
+            // And operations on other fields are rejected.
+            let mut resp = patch(&format!("/account/{}", account_id))
+                .header("content-type", "application/json-patch+json")
+                .body(json!([{ "op": "replace", "path": "/accountId", "value": "stolen" }]))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 422);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["errorCode"], json!(2518));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_account_too_large_to_store_is_a_client_error() {
+        run_test(async {
+            // Given an account exists.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "billingAddress": [] }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When large address lines keep being added - each change also being kept in it's history.
+            let line = "x".repeat(30000);
+            let mut refused = None;
+            for _ in 0..200 {
+                let resp = patch(&format!("/account/{}", account_id))
+                    .header("content-type", "application/json-patch+json")
+                    .body(json!([{ "op": "add", "path": "/billingAddress/-", "value": { "key": "line", "value": line } }]))
+                    .send(&mut service)
+                    .await;
+
+                if resp.status() != 200 {
+                    refused = Some(resp);
+                    break;
+                }
+            }
+
+            // Then, once it's too large for MongoDB to store, the caller is told so.
+            let mut resp = refused.expect("the account was never too large to store");
+            assert_eq!(resp.status(), 400);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["errorCode"], json!(2514));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_empty_patch_is_ok_when_configured() {
+        run_test(async {
+            // Given empty updates are configured as a no-op.
+            let mut service = test::init_service(start_app_with(&[("empty_update_ok", "true")]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            // And an account exists.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "salutation": "Mr" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When it's patched with no operations.
+            let mut resp = patch(&format!("/account/{}", account_id))
+                .header("content-type", "application/json-patch+json")
+                .body(json!([]))
+                .send(&mut service)
+                .await;
+
+            // Then the unchanged account is returned.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["accountId"], json!(account_id));
+            assert_eq!(actual["salutation"], json!("Mr"));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_rotate_account_id() {
+        run_test(async {
+            // Given the environment is set-up.
+            let mut service = test::init_service(start_app().await).await;
+            let rabbit = listen_to_topic("account.deleted").await;
+            let _auth_mock = mock_auth_ok();
+            let (account_id, device_id) = (new_uuid(), new_uuid());
+
+            // And an account exists with a device and externalId.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({
+                    "accountId": account_id,
+                    "salutation": "Mr Blobby",
+                    "externalIds": [ { "key": "accountNumber", "value": account_id } ],
+                    "devices": [ { "deviceId": device_id, "deviceType": "PC" } ]
+                }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When it's accountId is rotated.
+            let mut resp = post(&format!("/account/{}/rotate-id", account_id))
+                .send(&mut service)
+                .await;
+
+            // Then a new accountId is returned.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            let new_account_id = actual["accountId"].as_str().unwrap().to_string();
+            assert_ne!(new_account_id, account_id);
+
+            // And the old account was deleted.
+            rabbit.assert_payload_received(json!({ "accountId": account_id, "modifiedBy": "jbloggs" })).await;
+
+            let resp = get(&format!("/account/{}", account_id)).send(&mut service).await;
+            assert_eq!(resp.status(), 204);
+
+            // And the new account has everything else - including the uniquely indexed device and externalId.
+            let mut resp = get(&format!("/account/{}", new_account_id)).send(&mut service).await;
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["salutation"], json!("Mr Blobby"));
+            assert_eq!(actual["externalIds"], json!([ { "key": "accountNumber", "value": account_id } ]));
+            assert_eq!(actual["devices"][0]["deviceId"], json!(device_id));
+            assert_eq!(actual["modifiedBy"], json!("jbloggs"));
+
+            // And the old accountId can't be rotated again.
+            let mut resp = post(&format!("/account/{}/rotate-id", account_id))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 400);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["errorCode"], json!(2509));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_part_rotated_account_is_not_served_or_changed() {
+        run_test(async {
+            // Given an account whose rotation didn't complete - the copy replaced it but it wasn't deleted.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            database("Accounts").await
+                .collection("Accounts")
+                .update_one(doc!{ "accountId": &account_id }, doc!{ "$set": { "rotatedTo": new_uuid() } }, None)
+                .await
+                .expect("Unable to mark the account as rotated");
+
+            // When it's read.
+            let resp = get(&format!("/account/{}", account_id)).send(&mut service).await;
+
+            // Then it's not found.
+            assert_eq!(resp.status(), 204);
+
+            // And it can't be patched or have it's status changed.
+            let mut resp = patch(&format!("/account/{}", account_id))
+                .header("content-type", "application/json-patch+json")
+                .body(json!([ { "op": "replace", "path": "/salutation", "value": "Mr Blobby" } ]))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 400);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["errorCode"], json!(2509));
+
+            let mut resp = put("/update-account-status")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "status": "SUSPENDED" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 400);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["errorCode"], json!(2509));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_export_and_import_account() {
+        run_test(async {
+            // Given an account exists with a device and externalId.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let (account_id, device_id) = (new_uuid(), new_uuid());
+
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({
+                    "accountId": account_id,
+                    "status": "SUSPENDED",
+                    "externalIds": [ { "key": "accountNumber", "value": account_id } ],
+                    "devices": [ { "deviceId": device_id, "deviceType": "PC" } ]
+                }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When it's exported.
+            let mut resp = get(&format!("/account/{}/export", account_id)).send(&mut service).await;
+
+            // Then the export has everything but the audit fields.
+            assert_eq!(resp.status(), 200);
+            let mut export: Value = resp.read_body().await;
+            assert_json_eq!(export, json!({
+                "accountId": account_id,
+                "status": "SUSPENDED",
+                "profileId": "DEFAULT",
+                "externalIds": [ { "key": "accountNumber", "value": account_id } ],
+                "devices": [ { "deviceId": device_id, "profileId": "DEFAULT", "deviceType": "PC", "enabled": true } ]
+            }));
+
+            // And re-importing it returns the existing account.
+            let resp = post("/accounts/import")
+                .header("content-type", "application/json")
+                .body(export.clone())
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+
+            // And it can be imported with new ids (as if into another environment).
+            let (new_account_id, new_device_id) = (new_uuid(), new_uuid());
+            export["accountId"] = json!(new_account_id);
+            export["externalIds"][0]["value"] = json!(new_account_id);
+            export["devices"][0]["deviceId"] = json!(new_device_id);
+
+            let mut resp = post("/accounts/import")
+                .header("content-type", "application/json")
+                .body(export)
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["status"], json!("SUSPENDED"));
+            assert_eq!(actual["devices"][0]["deviceId"], json!(new_device_id));
+            assert_eq!(actual["createdBy"], json!("jbloggs"));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_update_account_status_if_match() {
+        run_test(async {
+            // Given an account exists.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // And the caller has read its ETag.
+            let resp = get(&format!("/account/{}", account_id))
+                .send(&mut service)
+                .await;
+            let etag = resp.header("etag").expect("No ETag on account");
+
+            // When the status is updated with a stale ETag.
+            let resp = put("/update-account-status")
+                .header("content-type", "application/json")
+                .header("if-match", "\"0\"")
+                .body(json!({ "accountId": account_id, "status": "RESTRICTED" }))
+                .send(&mut service)
+                .await;
+
+            // Then the update is refused.
+            assert_eq!(resp.status(), 412);
+
+            // But the current ETag is accepted.
+            let resp = put("/update-account-status")
+                .header("content-type", "application/json")
+                .header("if-match", &etag)
+                .body(json!({ "accountId": account_id, "status": "RESTRICTED" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+
+            // And it can't be used again now the account has been modified.
+            let resp = put("/update-account-status")
+                .header("content-type", "application/json")
+                .header("if-match", &etag)
+                .body(json!({ "accountId": account_id, "status": "SUSPENDED" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 412);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_if_match_detects_writes_at_the_same_time() {
+        run_test(async {
+            // Given the clock is frozen - so every write has the same modified time.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+            freeze_time(&mut service, "2021-07-03T04:52:49.830Z").await;
+
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // And two callers have read the account's ETag.
+            let resp = get(&format!("/account/{}", account_id))
+                .send(&mut service)
+                .await;
+            let etag = resp.header("etag").expect("No ETag on account");
+
+            // When the first patches the account.
+            let resp = patch(&format!("/account/{}", account_id))
+                .header("content-type", "application/json-patch+json")
+                .header("if-match", &etag)
+                .body(json!([{ "op": "add", "path": "/salutation", "value": "Dr" }]))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+            assert_ne!(resp.header("etag"), Some(etag.clone()));
+
+            // Then the second's write, in the same instant, is refused rather than lost.
+            let resp = put("/update-account-status")
+                .header("content-type", "application/json")
+                .header("if-match", &etag)
+                .body(json!({ "accountId": account_id, "status": "RESTRICTED" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 412);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_update_account_statuses_reports_each_item() {
+        run_test(async {
+            // Given the environment is set-up.
+            let mut service = test::init_service(start_app().await).await;
+            let rabbit = listen_to_topic("account.status.updated").await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+            let missing_id = new_uuid();
+
+            // And an account already exists.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({
+                    "accountId": account_id,
+                    "salutation": "Mr Blobby"
+                }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When a batch of status updates is made, including an unknown account.
+            let mut resp = put("/update-account-statuses")
+                .header("content-type", "application/json")
+                .body(json!([
+                    { "accountId": missing_id, "status": "SUSPENDED" },
+                    { "accountId": account_id, "status": "SUSPENDED" }
+                ]))
+                .send(&mut service)
+                .await;
+
+            // Then the response reports the outcome of each item.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_json_eq!(actual, json!([
+                { "accountId": missing_id, "updated": false, "errorCode": 2509, "message": format!("Account {} not found", missing_id) },
+                { "accountId": account_id, "updated": true }
+            ]));
+
+            // And a RabbitMQ notification was generated for the updated account.
+            rabbit.assert_payload_received(json!({
+                "accountId": account_id,
+                "oldStatus": "ACTIVE",
+                "newStatus": "SUSPENDED",
+                "modifiedBy": "system"
+            })).await;
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_get_effective_profile() {
+        run_test(async {
+            // Given the environment is set-up.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            // And an account exists with devices.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({
+                    "accountId": account_id,
+                    "devices": [
+                        { "deviceType": "PC" },
+                        { "deviceType": "STB", "profileId": "DEFAULT" }
+                    ]
+                }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When the account's effective profile is requested.
+            let mut resp = get(&format!("/account/{}/effective-profile", account_id))
+                .send(&mut service)
+                .await;
+
+            // Then the response contains the account profile and the distinct device profiles.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_json_eq!(actual, json!({
+                "accountId": account_id,
+                "accountProfile": { "profileId": "DEFAULT" },
+                "deviceProfiles": [ { "profileId": "DEFAULT" } ]
+            }));
+
+            // And an unknown account has no effective profile.
+            let resp = get(&format!("/account/{}/effective-profile", new_uuid()))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 204);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_account_notes() {
+        run_test(async {
+            // Given the service keeps at most two notes per account.
+            let mut service = test::init_service(start_app_with(&[("max_account_notes", "2")]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+            freeze_time(&mut service, "2021-07-04T04:52:49.830Z").await;
+
+            // And an account already exists.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When three notes are added.
+            for text in &["first", "second", "third"] {
+                let resp = post(&format!("/account/{}/notes", account_id))
+                    .header("content-type", "application/json")
+                    .body(json!({ "text": text, "author": "ops" }))
+                    .send(&mut service)
+                    .await;
+                assert_eq!(resp.status(), 201);
+            }
+
+            // Then only the newest two are kept.
+            let mut resp = get(&format!("/account/{}/notes", account_id))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual, json!([
+                { "text": "second", "author": "ops", "at": "2021-07-04T04:52:49.830Z" },
+                { "text": "third", "author": "ops", "at": "2021-07-04T04:52:49.830Z" }
+            ]));
+
+            // And the notes are not part of the account itself.
+            let mut resp = get(&format!("/account/{}", account_id))
+                .send(&mut service)
+                .await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual.get("notes"), None);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_replay_events() {
+        run_test(async {
+            // Given the environment is set-up.
+            let mut service = test::init_service(start_app().await).await;
+            let rabbit = listen_to_topic("account.status.updated").await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            // And an account already exists.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "status": "RESTRICTED" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When the account's events are replayed.
+            let resp = post(&format!("/account/{}/replay-events", account_id))
+                .send(&mut service)
+                .await;
+
+            // Then the replay is accepted.
+            assert_eq!(resp.status(), 202);
+
+            // And the latest status is re-emitted.
+            rabbit.assert_payload_received(json!({
+                "accountId": account_id,
+                "newStatus": "RESTRICTED"
+            })).await;
+
+            // And unknown accounts are rejected.
+            let resp = post("/account/not-an-account/replay-events")
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 400);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_get_accounts_with_query() {
+        run_test(async {
+            // Given some accounts exist with consecutive ids.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let prefix = new_uuid();
+
+            for n in 1..=3 {
+                let resp = post("/create-account")
+                    .header("content-type", "application/json")
+                    .body(json!({ "accountId": format!("{}-{}", prefix, n) }))
+                    .send(&mut service)
+                    .await;
+                assert_eq!(resp.status(), 201);
+            }
+
+            // When the page after the first account is requested.
+            let mut resp = get(&format!("/accounts?cursor={}-1&limit=2", prefix))
+                .send(&mut service)
+                .await;
+
+            // Then the next two accounts are returned in order.
+            assert_eq!(resp.status(), 200);
+            let actual: Vec<Value> = resp.read_body().await;
+            let ids: Vec<&Value> = actual.iter().map(|account| &account["accountId"]).collect();
+            assert_eq!(ids, vec!(&json!(format!("{}-2", prefix)), &json!(format!("{}-3", prefix))));
+
+            // And the page size used is returned.
+            assert_eq!(resp.header("x-page-size"), Some("2".to_string()));
+
+            // And invalid queries are rejected.
+            for query in &["limit=0", "skip=1&cursor=abc", "status=BOGUS"] {
+                let resp = get(&format!("/accounts?{}", query))
+                    .send(&mut service)
+                    .await;
+                assert_eq!(resp.status(), 400, "query {}", query);
+            }
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_get_accounts_is_streamed() {
+        run_test(async {
+            // Given some accounts exist with consecutive ids.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let prefix = new_uuid();
+
+            for n in 1..=3 {
+                let resp = post("/create-account")
+                    .header("content-type", "application/json")
+                    .body(json!({ "accountId": format!("{}-{}", prefix, n) }))
+                    .send(&mut service)
+                    .await;
+                assert_eq!(resp.status(), 201);
+            }
+
+            // When the accounts are requested.
+            let mut resp = get(&format!("/accounts?cursor={}-1&limit=2", prefix))
+                .send(&mut service)
+                .await;
+
+            // Then they're streamed rather than sent in one go.
+            assert_eq!(resp.status(), 200);
+            assert!(resp.streamed());
+
+            // And the chunks make up a JSON array of the accounts.
+            let actual: Vec<Value> = resp.read_body().await;
+            let ids: Vec<&Value> = actual.iter().map(|account| &account["accountId"]).collect();
+            assert_eq!(ids, vec!(&json!(format!("{}-2", prefix)), &json!(format!("{}-3", prefix))));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_get_accounts_links_next_page() {
+        run_test(async {
+            // Given some accounts exist with consecutive ids.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let prefix = new_uuid();
+
+            for n in 1..=3 {
+                let resp = post("/create-account")
+                    .header("content-type", "application/json")
+                    .body(json!({ "accountId": format!("{}-{}", prefix, n) }))
+                    .send(&mut service)
+                    .await;
+                assert_eq!(resp.status(), 201);
+            }
+
+            // When a page of the first two is requested.
+            let resp = get(&format!("/accounts?cursor={}&limit=2&status=ACTIVE", prefix))
+                .send(&mut service)
+                .await;
+
+            // Then the first and next pages are linked - keeping the filters.
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.header("link"), Some(format!(
+                "</accounts?limit=2&status=ACTIVE>; rel=\"first\", </accounts?limit=2&cursor={}-2&status=ACTIVE>; rel=\"next\"", prefix)));
+
+            // And pages requested by skip link to the previous and next skip.
+            let resp = get("/accounts?skip=3&limit=2").send(&mut service).await;
+            assert_eq!(resp.status(), 200);
+            let link = resp.header("link").unwrap();
+            assert!(link.starts_with("</accounts?limit=2>; rel=\"first\", </accounts?limit=2&skip=1>; rel=\"prev\""), "link {}", link);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_search_accounts_with_combined_filters() {
+        run_test(async {
+            // Given some accounts are created on different days with different statuses.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let prefix = new_uuid();
+
+            let accounts = [("1", "ACTIVE", "1998-06-01T09:00:00.000Z"), ("2", "SUSPENDED", "1998-06-01T10:00:00.000Z"),
+                ("3", "ACTIVE", "1998-06-02T09:00:00.000Z"), ("4", "ACTIVE", "1998-06-01T11:00:00.000Z")];
+
+            for (n, status, created) in &accounts {
+                freeze_time(&mut service, created).await;
+                let resp = post("/create-account")
+                    .header("content-type", "application/json")
+                    .body(json!({ "accountId": format!("{}-{}", prefix, n), "status": status }))
+                    .send(&mut service)
+                    .await;
+                assert_eq!(resp.status(), 201);
+            }
+
+            // When the active DEFAULT profile accounts created on the first day are searched for.
+            let mut resp = get(&format!("/accounts/search?cursor={}&limit=2&status=ACTIVE&profileId=DEFAULT\
+                &createdFrom=1998-06-01T00:00:00Z&createdTo=1998-06-01T23:59:59Z", prefix))
+                .send(&mut service)
+                .await;
+
+            // Then only the accounts matching every filter are returned.
+            assert_eq!(resp.status(), 200);
+            let actual: Vec<Value> = resp.read_body().await;
+            let ids: Vec<&Value> = actual.iter().map(|account| &account["accountId"]).collect();
+            assert_eq!(ids, vec!(&json!(format!("{}-1", prefix)), &json!(format!("{}-4", prefix))));
+
+            // And none are returned for another profile.
+            let mut resp = get(&format!("/accounts/search?cursor={}&limit=2&profileId={}", prefix, new_uuid()))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+            let actual: Vec<Value> = resp.read_body().await;
+            assert!(actual.is_empty());
+
+            // And an inverted created range is rejected.
+            let resp = get("/accounts/search?createdFrom=1998-06-02T00:00:00Z&createdTo=1998-06-01T00:00:00Z")
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 400);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_trailing_slashes_are_ignored() {
+        run_test(async {
+            // Given the environment is set-up.
+            let mut service = test::init_service(start_app().await).await;
+
+            // When endpoints are requested with and without trailing slashes.
+            for url in &["/accounts", "/accounts/", "/accounts//", "/ping", "/ping/"] {
+                let resp = get(url).send(&mut service).await;
+
+                // Then both forms reach the same handler.
+                assert_eq!(resp.status(), 200, "url {}", url);
+            }
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_purge_accounts_by_prefix() {
+        run_test(async {
+            // Given test endpoints are enabled and some accounts share a prefix.
+            let mut service = test::init_service(start_app_with(&[("allow_test_endpoints", "true")]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let prefix = new_uuid();
+            let other_id = new_uuid();
+
+            for account_id in &[format!("{}-1", prefix), format!("{}-2", prefix), other_id.clone()] {
+                let resp = post("/create-account")
+                    .header("content-type", "application/json")
+                    .body(json!({ "accountId": account_id }))
+                    .send(&mut service)
+                    .await;
+                assert_eq!(resp.status(), 201);
+            }
+
+            // When the accounts with the prefix are purged.
+            let mut resp = delete(&format!("/admin/accounts?idPrefix={}", prefix)).send(&mut service).await;
+
+            // Then only they are deleted.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual, json!({ "deleted": 2 }));
+
+            let resp = get(&format!("/account/{}-1", prefix)).send(&mut service).await;
+            assert_eq!(resp.status(), 204);
+            let resp = get(&format!("/account/{}", other_id)).send(&mut service).await;
+            assert_eq!(resp.status(), 200);
+
+            // And a short prefix is refused.
+            let resp = delete("/admin/accounts?idPrefix=a").send(&mut service).await;
+            assert_eq!(resp.status(), 400);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_purge_accounts_disabled_by_default() {
+        run_test(async {
+            // Given test endpoints aren't enabled.
+            let mut service = test::init_service(start_app().await).await;
+
+            // When accounts are purged.
+            let resp = delete(&format!("/admin/accounts?idPrefix={}", new_uuid())).send(&mut service).await;
+
+            // Then the endpoint doesn't exist.
+            assert_eq!(resp.status(), 404);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_downstream_request_permits_are_released() {
+        run_test(async {
+            // Given only one request at a time may be made to the auth service - and none may wait.
+            let overrides = [("max_downstream_requests", "1"), ("downstream_queue_timeout", "0")];
+            let mut service = test::init_service(start_app_with(&overrides).await).await;
+            let _auth_mock = mock_auth_ok();
+
+            // When accounts are created one after another.
+            for _ in 0..2 {
+                let resp = post("/create-account")
+                    .header("content-type", "application/json")
+                    .body(json!({ "accountId": new_uuid() }))
+                    .send(&mut service)
+                    .await;
+
+                // Then each claim check gets the permit the last one released.
+                assert_eq!(resp.status(), 201);
+            }
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_chaos_faults_are_injected_and_cleared() {
+        run_test(async {
+            // Given test endpoints are enabled.
+            let mut service = test::init_service(start_app_with(&[("allow_test_endpoints", "true")]).await).await;
+
+            // When every ping is made to fail.
+            let resp = put("/admin/chaos/ping")
+                .header("content-type", "application/json")
+                .body(json!({ "errorRate": 1.0 }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+
+            // Then pings are refused with a 503.
+            let mut resp = get("/ping").send(&mut service).await;
+            assert_eq!(resp.status(), 503);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["errorCode"], json!(1017));
+
+            // And once the fault is cleared they succeed again.
+            let resp = delete("/admin/chaos/ping").send(&mut service).await;
+            assert_eq!(resp.status(), 200);
+            let resp = get("/ping").send(&mut service).await;
+            assert_eq!(resp.status(), 200);
+
+            // And an error rate above 1 is refused.
+            let resp = put("/admin/chaos/ping")
+                .header("content-type", "application/json")
+                .body(json!({ "errorRate": 1.5 }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 400);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_endpoint_timeouts_override_the_handler_timeout() {
+        run_test(async {
+            // Given a generous default handler timeout but a tight one for ping (and a longer one for patches).
+            let mut service = test::init_service(start_app_with(&[
+                ("allow_test_endpoints", "true"),
+                ("handler_timeout", "5000"),
+                ("endpoint_timeouts", "ping=100,patch_account=60000")]).await).await;
+
+            // When ping and health are both slowed down.
+            for endpoint in ["ping", "health"] {
+                let resp = put(&format!("/admin/chaos/{}", endpoint))
+                    .header("content-type", "application/json")
+                    .body(json!({ "delayMs": 300 }))
+                    .send(&mut service)
+                    .await;
+                assert_eq!(resp.status(), 200);
+            }
+
+            // Then pings are refused with a 503.
+            let mut resp = get("/ping").send(&mut service).await;
+            assert_eq!(resp.status(), 503);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["errorCode"], json!(1018));
+
+            // And health checks still complete within the default.
+            let resp = get("/health").send(&mut service).await;
+            assert_eq!(resp.status(), 200);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_unknown_endpoint_timeout_fails_startup() {
+        run_test(async {
+            // Given a timeout for an endpoint the service doesn't have.
+            let overrides = [("endpoint_timeouts", "accounts_import=60000,no_such_endpoint=100")];
+
+            // When the service is initialised.
+            let result = nails::init_everything_with(&overrides).await;
+
+            // Then start-up fails naming the endpoint.
+            let err = result.err().expect("init_everything should have failed");
+            assert!(err.to_string().contains("no_such_endpoint"), "unexpected error: {}", err);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_in_flight_requests_are_drained_on_shutdown() {
+        run_test(async {
+            // Given a server whose pings take a second.
+            let ctx = Arc::new(nails::init_everything_with(&[("allow_test_endpoints", "true")]).await.expect("init_everything failed").0);
+            let app_ctx = ctx.clone();
+            let server = HttpServer::new(move || nails::app(app_ctx.clone()))
+                .workers(1)
+                .bind("127.0.0.1:0")
+                .expect("Unable to bind the server");
+            let url = format!("http://{}", server.addrs()[0]);
+            let server = server.disable_signals().run();
+
+            let client = Client::default();
+            let resp = client.put(format!("{}/admin/chaos/ping", url))
+                .send_json(&json!({ "delayMs": 1000 }))
+                .await
+                .expect("Unable to slow the pings");
+            assert_eq!(resp.status(), 200);
+
+            // When the server is shut down while a ping is in-flight.
+            let (stop, stopped) = oneshot::channel::<()>();
+            let ping = async {
+                client.get(format!("{}/ping", url)).timeout(Duration::from_secs(10)).send().await
+            };
+            let shutdown = async {
+                tokio::time::delay_for(Duration::from_millis(300)).await;
+                let _ = stop.send(());
+            };
+            let (served, ping, _) = join3(nails::serve_until(vec!(server), stopped, Duration::from_secs(5)), ping, shutdown).await;
+
+            // Then the ping still completes before the server stops.
+            assert!(served.is_ok());
+            assert_eq!(ping.expect("the in-flight ping was aborted").status(), 200);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_chaos_disabled_by_default() {
+        run_test(async {
+            // Given test endpoints aren't enabled.
+            let mut service = test::init_service(start_app().await).await;
+
+            // When a fault is injected.
+            let resp = put("/admin/chaos/ping")
+                .header("content-type", "application/json")
+                .body(json!({ "errorRate": 1.0 }))
+                .send(&mut service)
+                .await;
+
+            // Then the endpoint doesn't exist and pings are unaffected.
+            assert_eq!(resp.status(), 404);
+            let resp = get("/ping").send(&mut service).await;
+            assert_eq!(resp.status(), 200);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_unknown_route() {
+        run_test(async {
+            // Given the environment is set-up.
+            let mut service = test::init_service(start_app().await).await;
+
+            // When a path with no endpoint is requested.
+            let mut resp = get("/no-such-endpoint")
+                .header("x-correlation-id", "lost-request")
+                .send(&mut service)
+                .await;
+
+            // Then a JSON error is returned with the correlation id.
+            assert_eq!(resp.status(), 404);
+            assert_eq!(resp.header("x-correlation-id"), Some("lost-request".to_string()));
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual, json!({ "errorCode": 1004 }));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_get_accounts_page_size() {
+        run_test(async {
+            // Given a configured default and maximum page size.
+            let mut service = test::init_service(start_app_with(&[("default_page_size", "1"), ("max_page_size", "2")]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let prefix = new_uuid();
+
+            for n in 1..=3 {
+                let resp = post("/create-account")
+                    .header("content-type", "application/json")
+                    .body(json!({ "accountId": format!("{}-{}", prefix, n) }))
+                    .send(&mut service)
+                    .await;
+                assert_eq!(resp.status(), 201);
+            }
+
+            // When accounts are requested without a limit.
+            let mut resp = get(&format!("/accounts?cursor={}", prefix)).send(&mut service).await;
+
+            // Then the default page size is used.
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.header("x-page-size"), Some("1".to_string()));
+            let actual: Vec<Value> = resp.read_body().await;
+            assert_eq!(actual.len(), 1);
+
+            // And a limit over the maximum is clamped rather than rejected.
+            let mut resp = get(&format!("/accounts?cursor={}&limit=50", prefix)).send(&mut service).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.header("x-page-size"), Some("2".to_string()));
+            let actual: Vec<Value> = resp.read_body().await;
+            assert_eq!(actual.len(), 2);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_get_created_stats() {
+        run_test(async {
+            // Given accounts are created over two days (long ago so other tests' accounts don't count).
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+
+            for created in &["1999-01-01T09:00:00.000Z", "1999-01-01T23:59:59.000Z", "1999-01-03T00:00:00.000Z"] {
+                freeze_time(&mut service, created).await;
+                let resp = post("/create-account")
+                    .header("content-type", "application/json")
+                    .body(json!({ "accountId": new_uuid() }))
+                    .send(&mut service)
+                    .await;
+                assert_eq!(resp.status(), 201);
+            }
+
+            // When the stats are requested.
+            let mut resp = get("/accounts/created-stats?from=1999-01-01&to=1999-01-03")
+                .send(&mut service)
+                .await;
+
+            // Then the accounts are counted by the day they were created.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual, json!([
+                { "date": "1999-01-01", "count": 2 },
+                { "date": "1999-01-03", "count": 1 }
+            ]));
+
+            // And a backwards range is rejected.
+            let resp = get("/accounts/created-stats?from=1999-01-03&to=1999-01-01")
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 400);
+
+            // And so is a range exceeding the maximum span.
+            let resp = get("/accounts/created-stats?from=1999-01-01&to=2001-01-01")
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 400);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_unknown_profiles_can_return_404() {
+        run_test(async {
+            // Given the service is configured to return 404s for unknown profiles.
+            let mut service = test::init_service(start_app_with(&[("profile_not_found_404", "true")]).await).await;
+
+            // When an unknown account profile is requested.
+            let mut resp = get("/account-profile/NOT_A_PROFILE")
+                .send(&mut service)
+                .await;
+
+            // Then a 404 is returned with the profile-not-found code.
+            assert_eq!(resp.status(), 404);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual, json!({ "errorCode": 2510 }));
+
+            // And the same is true for device profiles.
+            let mut resp = get("/device-profile/NOT_A_PROFILE")
+                .send(&mut service)
+                .await;
+
+            assert_eq!(resp.status(), 404);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual, json!({ "errorCode": 2511 }));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_ensure_default_account_profile_exists() {
+        run_test(async {
+            // Given the environment is set-up.
+            let mut service = test::init_service(start_app().await).await;
+
+            // When a request to retrieve the DEFAULT account profile is made.
+            let mut resp = get("/account-profile/DEFAULT")
+                .send(&mut service)
+                .await;
+
+            // Then the response looks correct.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            let expected = json!({
+                "profileId": "DEFAULT"
+            });
+            assert_json_eq!(actual, expected.clone());
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_ensure_default_device_profile_exists() {
+        run_test(async {
+            // Given the environment is set-up.
+            let mut service = test::init_service(start_app().await).await;
+
+            // When a request to retrieve the DEFAULT device profile is made.
+            let mut resp = get("/device-profile/DEFAULT")
+                .send(&mut service)
+                .await;
+
+            // Then the response looks correct.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            let expected = json!({
+                "profileId": "DEFAULT"
+            });
+            assert_json_eq!(actual, expected.clone());
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_migrate_with_background_index_builds() {
+        run_test(async {
+            // Given indexes are built in the background with a pause between them.
+            let mut service = test::init_service(start_app_with(&[("index_build_background", "true"), ("index_build_delay", "10")]).await).await;
+
+            // When the schema updates are re-applied.
+            let mut resp = post("/admin/migrate")
+                .send(&mut service)
+                .await;
+
+            // Then the existing indexes are skipped as normal.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["applied"], json!([]));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_migrate_is_idempotent() {
+        run_test(async {
+            // Given the environment is set-up (which applies the schema updates).
+            let mut service = test::init_service(start_app().await).await;
+
+            // When the schema updates are re-applied.
+            let mut resp = post("/admin/migrate")
+                .send(&mut service)
+                .await;
+
+            // Then every update is skipped.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_json_eq!(actual, json!({
+                "applied": [],
+                "skipped": [
+                    "Accounts.idx_accountId",
+                    "Accounts.idx_deviceId",
+                    "Accounts.idx_accountExternalId",
+                    "Accounts.idx_deviceExternalId",
+                    "AccountProfiles.idx_profileId",
+                    "DeviceProfiles.idx_profileId",
+                    "AccountProfiles.DEFAULT",
+                    "DeviceProfiles.DEFAULT"
+                ]
+            }));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_get_inflight_stats() {
+        run_test(async {
+            // Given the environment is set-up.
+            let mut service = test::init_service(start_app().await).await;
+
+            // When the in-flight stats are requested.
+            let mut resp = get("/stats/inflight")
+                .send(&mut service)
+                .await;
+
+            // Then the request itself is in-flight and the high-water mark is at least as high.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            let inflight = actual["inflight"].as_u64().expect("no inflight count");
+            assert!(inflight >= 1);
+            assert!(actual["highWater"].as_u64().expect("no high-water mark") >= inflight);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_dead_letters() {
+        run_test(async {
+            // Given notifications are published without fault.
+            let mut service = test::init_service(start_app_with(&[("dead_letters", "true")]).await).await;
+
+            // When the dead letters are listed.
+            let mut resp = get("/admin/dead-letters")
+                .send(&mut service)
+                .await;
+
+            // Then there aren't any.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual, json!([]));
+
+            // And there's nothing to re-drive.
+            let mut resp = post("/admin/dead-letters/redrive")
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual, json!({ "redriven": 0 }));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_unconfirmed_notifications_are_dead_lettered() {
+        run_test(async {
+            // Given RabbitMQ never confirms a send in time.
+            let mut service = test::init_service(start_app_with(&[("dead_letters", "true"), ("rabbit_confirm_timeout", "0")]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            // When an account is created.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // Then the notification is dead-lettered as a failed send - rather than blocking the publisher.
+            let mut dead_letter = None;
+            for _ in 0..50 {
+                let mut resp = get("/admin/dead-letters").send(&mut service).await;
+                assert_eq!(resp.status(), 200);
+                let actual: Value = resp.read_body().await;
+                dead_letter = actual.as_array().expect("not a list").iter().find(|dl| dl["body"]["accountId"] == json!(account_id)).cloned();
+                if dead_letter.is_some() {
+                    break
+                }
+                tokio::time::delay_for(Duration::from_millis(100)).await;
+            }
+            let dead_letter = dead_letter.expect("the notification wasn't dead-lettered");
+            assert_eq!(dead_letter["reason"], json!("Timed-out after 0ms waiting for the send to be acked"));
+
+            // Tidy-up by re-driving it with the default confirm timeout.
+            let mut service = test::init_service(start_app_with(&[("dead_letters", "true")]).await).await;
+            let resp = post("/admin/dead-letters/redrive").send(&mut service).await;
+            assert_eq!(resp.status(), 200);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_admin_endpoints_require_the_admin_token() {
+        run_test(async {
+            // Given an admin token is configured.
+            let mut service = test::init_service(start_app_with(&[("admin_token", "let-me-in")]).await).await;
+
+            // When an admin endpoint is called without the token.
+            let mut resp = get("/settings").send(&mut service).await;
+
+            // Then it's refused.
+            assert_eq!(resp.status(), 401);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["errorCode"], json!(1001));
+
+            // And so is the wrong token.
+            let resp = get("/settings").header("x-admin-token", "let-me-in-please").send(&mut service).await;
+            assert_eq!(resp.status(), 401);
+
+            // But the right token is accepted.
+            let resp = get("/settings").header("x-admin-token", "let-me-in").send(&mut service).await;
+            assert_eq!(resp.status(), 200);
+
+            // And business endpoints don't need it.
+            let resp = get("/openapi.json").send(&mut service).await;
+            assert_eq!(resp.status(), 200);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_admin_endpoints_served_separately_with_an_admin_port() {
+        run_test(async {
+            // Given the admin endpoints are served from their own port.
+            let overrides = [("admin_port", "8089")];
+            let mut service = test::init_service(start_app_with(&overrides).await).await;
+            let mut admin_service = test::init_service(start_admin_app_with(&overrides).await).await;
+            let _auth_mock = mock_auth_ok();
+
+            // When the settings are requested from the main port.
+            let resp = get("/settings").send(&mut service).await;
+
+            // Then they're not found.
+            assert_eq!(resp.status(), 404);
+
+            // But business endpoints are still served.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": new_uuid() }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // And the admin port serves the settings but not the business endpoints.
+            let resp = get("/settings").send(&mut admin_service).await;
+            assert_eq!(resp.status(), 200);
+
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": new_uuid() }))
+                .send(&mut admin_service)
+                .await;
+            assert_eq!(resp.status(), 404);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_endpoint_toggles() {
+        run_test(async {
+            // Given the service starts with an endpoint disabled.
+            let mut service = test::init_service(start_app_with(&[("disabled_endpoints", "account_profile")]).await).await;
+
+            // When the endpoint is called.
+            let mut resp = get("/account-profile/DEFAULT")
+                .send(&mut service)
+                .await;
+
+            // Then it's refused.
+            assert_eq!(resp.status(), 503);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual, json!({ "errorCode": 1003, "message": "The account_profile endpoint has been temporarily disabled" }));
+
+            // And other endpoints still work.
+            let resp = get("/device-profile/DEFAULT")
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+
+            // When the endpoint is re-enabled.
+            let mut resp = post("/endpoints/account_profile/enable")
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual, json!([]));
+
+            // Then it's served immediately.
+            let resp = get("/account-profile/DEFAULT")
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+
+            // And the toggle endpoints can't disable themselves.
+            let resp = post("/endpoints/endpoints_enable/disable")
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 400);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_endpoints_sharing_a_route_are_disabled_separately() {
+        run_test(async {
+            // Given the service starts with account patches disabled - and names handlers on responses.
+            let mut service = test::init_service(start_app_with(&[("disabled_endpoints", "patch_account"), ("handler_header", "true")]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            // And an account exists.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "salutation": "Mr" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When the account is patched.
+            let mut resp = patch(&format!("/account/{}", account_id))
+                .header("content-type", "application/json-patch+json")
+                .body(json!([{ "op": "replace", "path": "/salutation", "value": "Dr" }]))
+                .send(&mut service)
+                .await;
+
+            // Then it's refused.
+            assert_eq!(resp.status(), 503);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["errorCode"], json!(1003));
+
+            // But the account can still be read from the same route.
+            let resp = get(&format!("/account/{}", account_id))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.header("x-handler"), Some("account".to_string()));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_localised_error_messages() {
+        run_test(async {
+            // Given the service has error translations.
+            let mut service = test::init_service(start_app_with(&[("error_translations", "utils/error_translations.json")]).await).await;
+
+            // When a request fails for a client preferring a translated language.
+            let mut resp = post(&format!("/account/{}/notes", new_uuid()))
+                .header("content-type", "application/json")
+                .header("accept-language", "fr-CH, fr;q=0.9, en;q=0.8")
+                .body(json!({ "text": "A note", "author": "support" }))
+                .send(&mut service)
+                .await;
+
+            // Then the message is localised.
+            assert_eq!(resp.status(), 400);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual, json!({ "errorCode": 2509, "message": "Compte introuvable" }));
+
+            // And clients without a translated language get English.
+            let account_id = new_uuid();
+            let mut resp = post(&format!("/account/{}/notes", account_id))
+                .header("content-type", "application/json")
+                .header("accept-language", "es")
+                .body(json!({ "text": "A note", "author": "support" }))
+                .send(&mut service)
+                .await;
+
+            assert_eq!(resp.status(), 400);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual, json!({ "errorCode": 2509, "message": format!("Account {} not found", account_id) }));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_handler_header() {
+        run_test(async {
+            // Given the service is configured to name handlers on responses.
+            let mut service = test::init_service(start_app_with(&[("handler_header", "true")]).await).await;
+
+            // When a request with a path parameter is made.
+            let resp = get("/account-profile/DEFAULT")
+                .send(&mut service)
+                .await;
+
+            // Then the response names the handler without the path parameter.
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.header("x-handler"), Some("account_profile".to_string()));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_echo_headers() {
+        run_test(async {
+            // Given the service is configured to echo a header.
+            let mut service = test::init_service(start_app_with(&[("echo_headers", "x-tenant-id")]).await).await;
+
+            // When a request is made with the header (and one which isn't echoed).
+            let resp = get("/account-profile/DEFAULT")
+                .header("x-tenant-id", "tenant-1")
+                .header("x-other-id", "other-1")
+                .send(&mut service)
+                .await;
+
+            // Then only the configured header is copied to the response.
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.header("x-tenant-id"), Some("tenant-1".to_string()));
+            assert_eq!(resp.header("x-other-id"), None);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_cors() {
+        run_test(async {
+            // Given the service allows a browser origin.
+            let mut service = test::init_service(start_app_with(&[("cors_allowed_origins", "https://admin.example.com")]).await).await;
+
+            // When a preflight request is made from the origin.
+            let resp = options("/accounts")
+                .header("origin", "https://admin.example.com")
+                .header("access-control-request-method", "GET")
+                .header("access-control-request-headers", "content-type")
+                .send(&mut service)
+                .await;
+
+            // Then it's answered with what's allowed.
+            assert_eq!(resp.status(), 204);
+            assert_eq!(resp.header("access-control-allow-origin"), Some("https://admin.example.com".to_string()));
+            assert_eq!(resp.header("access-control-allow-methods"), Some("GET,POST,PUT".to_string()));
+            assert_eq!(resp.header("access-control-allow-headers"), Some("content-type,if-match,x-correlation-id".to_string()));
+
+            // And a preflight for a method which isn't allowed is refused.
+            let resp = options("/accounts")
+                .header("origin", "https://admin.example.com")
+                .header("access-control-request-method", "DELETE")
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 403);
+            assert_eq!(resp.header("access-control-allow-origin"), None);
+
+            // And actual requests from the origin are allowed.
+            let resp = get("/account-profile/DEFAULT")
+                .header("origin", "https://admin.example.com")
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.header("access-control-allow-origin"), Some("https://admin.example.com".to_string()));
+
+            // But requests from other origins are not.
+            let resp = get("/account-profile/DEFAULT")
+                .header("origin", "https://elsewhere.example.com")
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.header("access-control-allow-origin"), None);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_create_account_when_auth_unavailable() {
+        run_test(async {
+            // Given the auth service is down (and requests to it aren't retried).
+            let mut service = test::init_service(start_app_with(&[("client_retry_limit", "1")]).await).await;
+            let _auth_mock = mock("POST", "/auth/get-claims")
+                .match_query(Matcher::Any)
+                .with_status(503)
+                .create();
+
+            // When an account is created.
+            let mut resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": new_uuid() }))
+                .send(&mut service)
+                .await;
+
+            // Then the caller is told to try again later rather than forbidden.
+            assert_eq!(resp.status(), 503);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual, json!({ "errorCode": 1002 }));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_oversized_auth_response_is_refused() {
+        run_test(async {
+            // Given the auth service responds with more than the service accepts from a downstream.
+            let mut service = test::init_service(start_app_with(&[("max_response_bytes", "1024")]).await).await;
+            let _auth_mock = mock("POST", "/auth/get-claims")
+                .match_query(Matcher::Any)
+                .with_header("content-type", "application/json")
+                .with_status(200)
+                .with_body(json!({ "claims": [ "create-account", "x".repeat(2048) ], "principalId": "jbloggs" }).to_string())
+                .create();
+
+            // When an account is created.
+            let mut resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": new_uuid() }))
+                .send(&mut service)
+                .await;
+
+            // Then the response isn't read in full and the claim isn't granted.
+            assert_eq!(resp.status(), 503);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual, json!({ "errorCode": 1002 }));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_claim_check_is_retried_without_waiting_after_the_last_attempt() {
+        run_test(async {
+            // Given the auth service is down and requests to it are attempted twice, two seconds apart.
+            let mut service = test::init_service(start_app_with(&[("client_retry_limit", "2"), ("client_retry_delay", "2")]).await).await;
+            let auth_mock = mock("POST", "/auth/get-claims")
+                .match_query(Matcher::Any)
+                .with_status(503)
+                .expect(2)
+                .create();
+            let started = Instant::now();
+
+            // When an account is created.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": new_uuid() }))
+                .send(&mut service)
+                .await;
+
+            // Then the claim check - a POST, but one which opts-in to retries - was re-attempted.
+            assert_eq!(resp.status(), 503);
+            auth_mock.assert();
+
+            // And the caller wasn't kept waiting once the last attempt had failed.
+            assert!(started.elapsed() < Duration::from_secs(4), "took {:?}", started.elapsed());
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_create_account_when_auth_times_out() {
+        run_test(async {
+            // Given the auth service accepts connections but never responds.
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let auth_address = format!("http://{}", listener.local_addr().unwrap());
+            let mut service = test::init_service(start_app_with(&[("auth_address", auth_address.as_str()), ("auth_timeout", "200")]).await).await;
+
+            // When an account is created.
+            let mut resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": new_uuid() }))
+                .send(&mut service)
+                .await;
+
+            // Then the claim check gives up after the auth timeout.
+            assert_eq!(resp.status(), 504);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual, json!({ "errorCode": 1015 }));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_context_headers_are_passed_to_auth() {
+        run_test(async {
+            // Given the authorization header is captured into the request context.
+            let mut service = test::init_service(start_app_with(&[("context_headers", "authorization")]).await).await;
+            let auth_mock = mock("POST", "/auth/get-claims")
+                .match_query(Matcher::Any)
+                .match_body(Matcher::Json(json!({ "token": "Bearer abc123" })))
+                .with_header("content-type", "application/json")
+                .with_status(200)
+                .with_body(r#"{ "claims": [ "create-account" ] }"#)
+                .create();
+
+            // When an account is created with a bearer token.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .header("authorization", "Bearer abc123")
+                .body(json!({ "accountId": new_uuid() }))
+                .send(&mut service)
+                .await;
+
+            // Then the caller's token was checked by the auth service.
+            assert_eq!(resp.status(), 201);
+            auth_mock.assert();
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_audited_request_body_reaches_handler() {
+        run_test(async {
+            // Given account creation is audited.
+            let mut service = test::init_service(start_app_with(&[("audited_endpoints", "create_account")]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            // When an account is created.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "salutation": "Mr Blobby" }))
+                .send(&mut service)
+                .await;
+
+            // Then the body captured for the audit log was still passed on in full.
+            assert_eq!(resp.status(), 201);
+            let mut resp = get(&format!("/account/{}", account_id)).send(&mut service).await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["salutation"], json!("Mr Blobby"));
+            assert_eq!(actual["createdBy"], json!("jbloggs"));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_account_patches_are_audited_by_their_own_name() {
+        run_test(async {
+            // Given account reads are configured to be audited - but patches aren't.
+            let mut service = test::init_service(start_app_with(&[("audited_endpoints", "account")]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let (_guard, logs) = capture_logs();
+            let account_id = new_uuid();
+
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "salutation": "Mr" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When the account is patched.
+            let resp = patch(&format!("/account/{}", account_id))
+                .header("content-type", "application/json-patch+json")
+                .body(json!([{ "op": "replace", "path": "/salutation", "value": "Dr" }]))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+
+            // Then the patch isn't audited.
+            assert!(!logs.contains("Audited PATCH"));
+
+            // But once patches are audited, it is.
+            let mut service = test::init_service(start_app_with(&[("audited_endpoints", "patch_account")]).await).await;
+            let resp = patch(&format!("/account/{}", account_id))
+                .header("content-type", "application/json-patch+json")
+                .body(json!([{ "op": "replace", "path": "/salutation", "value": "Ms" }]))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+            assert!(logs.contains("Audited PATCH /account/{account_id}"));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_client_connections_are_warmed_up() {
+        run_test(async {
+            // Given the auth service is up.
+            let warm_up_mock = mock("HEAD", "/").with_status(200).create();
+
+            // When the service starts with warm-up enabled.
+            let _service = test::init_service(start_app_with(&[("client_warm_up", "true")]).await).await;
+
+            // Then the worker connects to the auth service before any requests are made.
+            for _ in 0..50 {
+                if warm_up_mock.matched() {
+                    break
+                }
+                tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+            }
+            warm_up_mock.assert();
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_health_reports_schema_version() {
+        run_test(async {
+            // Given the service has started - which applies the schema updates.
+            let mut service = test::init_service(start_app().await).await;
+
+            // When the health is checked.
+            let mut resp = get("/health").send(&mut service).await;
+
+            // Then the database schema isn't behind the code.
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["Schema"], json!({ "healthy": true }));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_health_reports_non_critical_auth_as_degraded() {
+        run_test(async {
+            // Given the auth service is unreachable but not critical.
+            let mut service = test::init_service(start_app_with(&[("auth_address", "http://127.0.0.1:1"), ("auth_health_critical", "false")]).await).await;
+
+            // When the health is checked.
+            let mut resp = get("/health").send(&mut service).await;
+
+            // Then auth is reported as degraded.
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["Auth"]["healthy"], json!(false));
+            assert_eq!(actual["Auth"]["degraded"], json!(true));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_maintenance_mode_refuses_writes() {
+        run_test(async {
+            // Given an account exists.
+            let mut service = test::init_service(start_app_with(&[("maintenance_retry_after", "30")]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // When the service is put into maintenance.
+            let mut resp = post("/admin/maintenance")
+                .header("content-type", "application/json")
+                .body(json!({ "enabled": true }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual, json!({ "maintenance": true }));
+
+            // Then changes are refused - with when to try again.
+            let mut resp = put("/update-account-status")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "status": "SUSPENDED" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 503);
+            assert_eq!(resp.header("retry-after"), Some("30".to_string()));
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["errorCode"], json!(1016));
+
+            // But the account can still be read.
+            let resp = get(&format!("/account/{}", account_id)).send(&mut service).await;
+            assert_eq!(resp.status(), 200);
+
+            // And the maintenance is shown in the health and settings.
+            let mut resp = get("/health").send(&mut service).await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["Maintenance"]["degraded"], json!(true));
+
+            let mut resp = get("/settings").send(&mut service).await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["maintenance"], json!(true));
+
+            // And once maintenance ends, changes can be made again.
+            let resp = post("/admin/maintenance")
+                .header("content-type", "application/json")
+                .body(json!({ "enabled": false }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+
+            let resp = put("/update-account-status")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": account_id, "status": "SUSPENDED" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_openapi_describes_models() {
+        run_test(async {
+            // Given the service has started.
+            let mut service = test::init_service(start_app().await).await;
+
+            // When the spec is requested.
+            let mut resp = get("/openapi.json").send(&mut service).await;
+
+            // Then the account models and the error response are described.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            let schemas = &actual["components"]["schemas"];
+            assert_eq!(schemas["Account"]["properties"]["accountId"]["type"], json!("string"));
+            assert_eq!(schemas["StatusModification"]["required"], json!([ "accountId", "status" ]));
+            assert!(schemas["NewAccount"].is_object());
+            assert!(schemas["ErrorResponse"].is_object());
+
+            // And response codes are keyed as strings.
+            assert!(actual["paths"]["/openapi.json"]["get"]["responses"]["200"].is_object());
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_models_match_their_documented_schemas() {
+        run_test(async {
+            // Given the service's spec.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let mut resp = get("/openapi.json").send(&mut service).await;
+            let spec: Value = resp.read_body().await;
+            let account_id = new_uuid();
+
+            // When an account is created with every documented field.
+            let new_account = json!({
+                "accountId": account_id,
+                "status": "ACTIVE",
+                "profileId": "DEFAULT",
+                "salutation": "Mr Blobby",
+                "billingAddress": [ { "key": "postcode", "value": "NP20 1AA" } ],
+                "externalIds": [ { "key": "passportId", "value": new_uuid() } ],
+                "devices": [{
+                    "deviceId": new_uuid(),
+                    "profileId": "DEFAULT",
+                    "deviceType": "PC",
+                    "enabled": true,
+                    "externalIds": [ { "key": "serialNumber", "value": new_uuid() } ]
+                }],
+                "billingDate": "2021-08-01T00:00:00.000Z"
+            });
+            assert_matches_schema(&spec, "NewAccount", &new_account);
+
+            let mut resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(new_account)
+                .send(&mut service)
+                .await;
+
+            // Then the created account is as documented.
+            assert_eq!(resp.status(), 201);
+            let actual: Value = resp.read_body().await;
+            assert_matches_schema(&spec, "CreatedAccount", &actual);
+
+            // And so is the account once it's status has been modified.
+            let modification = json!({ "accountId": account_id, "status": "SUSPENDED" });
+            assert_matches_schema(&spec, "StatusModification", &modification);
+
+            let resp = put("/update-account-status")
+                .header("content-type", "application/json")
+                .body(modification)
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 200);
+
+            let mut resp = get(&format!("/account/{}", account_id)).send(&mut service).await;
+            let actual: Value = resp.read_body().await;
+            assert_matches_schema(&spec, "Account", &actual);
+            assert_matches_schema(&spec, "Device", &actual["devices"][0]);
+
+            // And so are the profiles.
+            let mut resp = get("/account-profile/DEFAULT").send(&mut service).await;
+            let actual: Value = resp.read_body().await;
+            assert_matches_schema(&spec, "AccountProfile", &actual);
+
+            let mut resp = get("/device-profile/DEFAULT").send(&mut service).await;
+            let actual: Value = resp.read_body().await;
+            assert_matches_schema(&spec, "DeviceProfile", &actual);
+
+            // And so is an error.
+            let mut resp = get(&format!("/account/{}", new_uuid())).send(&mut service).await;
+            assert_eq!(resp.status(), 404);
+            let actual: Value = resp.read_body().await;
+            assert_matches_schema(&spec, "ErrorResponse", &actual);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_traced_request_body_is_capped() {
+        run_test(async {
+            // Given a tracer bullet is on and the traced body is capped at a few bytes.
+            let mut service = test::init_service(start_app_with(&[("trace_max_body_bytes", "64")]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let bullet = new_uuid();
+            let resp = post(&format!("/tracer-bullet?header=x-bullet&value={}", bullet)).send(&mut service).await;
+            assert_eq!(resp.status(), 200);
+
+            // When an account with a much larger body is created under the bullet.
+            let account_id = new_uuid();
+            let salutation = "x".repeat(20000);
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .header("x-bullet", &bullet)
+                .body(json!({ "accountId": account_id, "salutation": salutation }))
+                .send(&mut service)
+                .await;
+
+            // Then the handler still got the whole body.
+            assert_eq!(resp.status(), 201);
+            let mut resp = get(&format!("/account/{}", account_id)).send(&mut service).await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["salutation"], json!(salutation));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_tracer_bullet_logs_a_sample_of_requests() {
+        run_test(async {
+            // Given a tracer bullet is on for half of the matching requests.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let (_guard, logs) = capture_logs();
+            let bullet = new_uuid();
+            let resp = post(&format!("/tracer-bullet?header=x-bullet&value={}&sample=0.5", bullet)).send(&mut service).await;
+            assert_eq!(resp.status(), 200);
+
+            // When several accounts are created under the bullet.
+            let mut salutations = vec!();
+            for _ in 0..4 {
+                let salutation = new_uuid();
+                let resp = post("/create-account")
+                    .header("content-type", "application/json")
+                    .header("x-bullet", &bullet)
+                    .body(json!({ "salutation": salutation }))
+                    .send(&mut service)
+                    .await;
+                assert_eq!(resp.status(), 201);
+                salutations.push(salutation);
+            }
+
+            // Then only half of them were logged.
+            assert_eq!(salutations.iter().filter(|salutation| logs.contains(salutation)).count(), 2);
+
+            // And a sample which isn't more than 0 and no more than 1 is refused.
+            for sample in &["0", "1.5"] {
+                let resp = post(&format!("/tracer-bullet?header=x-bullet&value={}&sample={}", bullet, sample)).send(&mut service).await;
+                assert_eq!(resp.status(), 400);
+            }
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_traced_bodies_are_formatted_by_content_type() {
+        run_test(async {
+            // Given a tracer bullet is on.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let (_guard, logs) = capture_logs();
+            let bullet = new_uuid();
+            let resp = post(&format!("/tracer-bullet?header=x-bullet&value={}", bullet)).send(&mut service).await;
+            assert_eq!(resp.status(), 200);
+
+            // When an account is created under the bullet.
+            let salutation = new_uuid();
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .header("x-bullet", &bullet)
+                .body(json!({ "salutation": salutation }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // Then it's JSON body is logged pretty-printed.
+            assert!(logs.contains(&format!("\"salutation\": \"{}\"", salutation)));
+
+            // And when a binary body is posted under the bullet.
+            let _resp = post("/create-account")
+                .header("content-type", "application/octet-stream")
+                .header("x-bullet", &bullet)
+                .raw_body(vec!(0xde, 0xad, 0xbe, 0xef, 0x00, 0xff))
+                .send(&mut service)
+                .await;
+
+            // Then it's summarised rather than logged as-is.
+            assert!(logs.contains("<binary 6 bytes: de ad be ef 00 ff>"));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_untraced_bodies_are_not_logged() {
+        run_test(async {
+            // Given account creation is configured to never have it's bodies traced.
+            let mut service = test::init_service(start_app_with(&[("untraced_bodies", "create_account")]).await).await;
+            let _auth_mock = mock_auth_ok();
+            let (_guard, logs) = capture_logs();
+
+            // And a tracer bullet is on.
+            let bullet = new_uuid();
+            let resp = post(&format!("/tracer-bullet?header=x-bullet&value={}", bullet)).send(&mut service).await;
+            assert_eq!(resp.status(), 200);
+
+            // When an account is created and a note added to it under the bullet.
+            let account_id = new_uuid();
+            let secret = new_uuid();
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .header("x-bullet", &bullet)
+                .body(json!({ "accountId": account_id, "salutation": secret }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            let text = new_uuid();
+            let resp = post(&format!("/account/{}/notes", account_id))
+                .header("content-type", "application/json")
+                .header("x-bullet", &bullet)
+                .body(json!({ "text": text, "author": "jbloggs" }))
+                .send(&mut service)
+                .await;
+            assert_eq!(resp.status(), 201);
+
+            // Then the account's body wasn't traced but it's headers were.
+            assert!(!logs.contains(&secret));
+            assert!(logs.contains("POST /create-account"));
+            assert!(logs.contains("<body not traced>"));
+
+            // And the note's body was.
+            assert!(logs.contains(&text));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_trace_lists_emitted_notifications() {
+        run_test(async {
+            // Given the environment is set-up.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
             let account_id = new_uuid();
-            freeze_time(&mut service, "2021-07-04T04:52:49.830Z").await;
+            let correlation_id = new_uuid();
 
-            // And an account already exists.
+            // When an account is created with a known correlation id.
             let resp = post("/create-account")
                 .header("content-type", "application/json")
-                .body(json!({
-                    "accountId": account_id,
-                    "salutation": "Mr Blobby",
-                    "profileId": "DEFAULT"
-                }))
+                .header("x-correlation-id", &correlation_id)
+                .body(json!({ "accountId": account_id }))
                 .send(&mut service)
                 .await;
             assert_eq!(resp.status(), 201);
-            auth_mock.assert();
 
-            // When the status is updated.
-            let resp = put("/update-account-status")
+            // Then the account.created notification is listed against the correlation id - the publisher
+            // records it once RabbitMQ confirms, so allow it a moment.
+            let mut actual = Value::Null;
+            for _ in 0..50 {
+                let mut resp = get(&format!("/trace/{}", correlation_id)).send(&mut service).await;
+                assert_eq!(resp.status(), 200);
+                actual = resp.read_body().await;
+                if actual.as_array().map(|emitted| !emitted.is_empty()).unwrap_or_default() {
+                    break
+                }
+                tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+            }
+            assert_eq!(actual[0]["topic"], json!("account.created"));
+            assert_eq!(actual[0]["correlationId"], json!(correlation_id));
+
+            // And nothing is listed for an unknown correlation id.
+            let mut resp = get(&format!("/trace/{}", new_uuid())).send(&mut service).await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual, json!([]));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_account_events_are_listed() {
+        run_test(async {
+            // Given an account is created then has it's status changed.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
+            let correlation_id = new_uuid();
+
+            let resp = post("/create-account")
                 .header("content-type", "application/json")
-                .body(json!({
-                    "accountId": account_id,
-                    "status": "RESTRICTED"
-                }))
+                .header("x-correlation-id", &correlation_id)
+                .body(json!({ "accountId": account_id }))
                 .send(&mut service)
                 .await;
+            assert_eq!(resp.status(), 201);
 
-            // Then the response is successful.
+            let resp = put("/update-account-status")
+                .header("content-type", "application/json")
+                .header("x-correlation-id", &correlation_id)
+                .body(json!({ "accountId": account_id, "status": "SUSPENDED" }))
+                .send(&mut service)
+                .await;
             assert_eq!(resp.status(), 200);
 
-            // And a RabbitMQ notification was generated.
-            rabbit.assert_payload_received(json!({
-                "accountId": account_id,
-                "oldStatus": "ACTIVE",
-                "newStatus": "RESTRICTED"
-            })).await;
+            // When the account's events are requested - allowing the publisher a moment to send them.
+            let mut actual = Value::Null;
+            for _ in 0..50 {
+                let mut resp = get(&format!("/account/{}/events", account_id)).send(&mut service).await;
+                assert_eq!(resp.status(), 200);
+                actual = resp.read_body().await;
+                if actual.as_array().map(|events| events.len() == 2).unwrap_or_default() {
+                    break
+                }
+                tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+            }
+
+            // Then both notifications are listed in order.
+            let topics: Vec<&Value> = actual.as_array().expect("no events").iter().map(|event| &event["topic"]).collect();
+            assert_eq!(topics, vec!(&json!("account.created"), &json!("account.status.updated")));
+            assert_eq!(actual[1]["version"], json!(1));
+            assert_eq!(actual[1]["correlationId"], json!(correlation_id));
+            assert!(actual[1]["pending"].is_null());
+
+            // And only the most recent are listed with a limit.
+            let mut resp = get(&format!("/account/{}/events?limit=1", account_id)).send(&mut service).await;
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual.as_array().map(|events| events.len()), Some(1));
+            assert_eq!(actual[0]["topic"], json!("account.status.updated"));
         }).await;
     }
 
     #[actix_rt::test]
-    async fn test_ensure_default_account_profile_exists() {
+    async fn test_notification_stats() {
         run_test(async {
             // Given the environment is set-up.
             let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
 
-            // When a request to retrieve the DEFAULT account profile is made.
-            let mut resp = get("/account-profile/DEFAULT")
+            // When an account is created.
+            let resp = post("/create-account")
+                .header("content-type", "application/json")
+                .body(json!({ "accountId": new_uuid() }))
                 .send(&mut service)
                 .await;
+            assert_eq!(resp.status(), 201);
 
-            // Then the response looks correct.
+            // Then it's notification is counted as sent once RabbitMQ confirms it.
+            let mut actual = Value::Null;
+            for _ in 0..50 {
+                let mut resp = get("/stats/notifications").send(&mut service).await;
+                assert_eq!(resp.status(), 200);
+                actual = resp.read_body().await;
+                if actual["sent"].as_u64().unwrap_or_default() > 0 {
+                    break
+                }
+                tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+            }
+            assert!(actual["sent"].as_u64().unwrap_or_default() > 0);
+            assert!(actual["deferred"].is_u64());
+            assert!(actual["dropped"].is_u64());
+
+            // And the counts can be reset - returning what they were.
+            let mut resp = post("/stats/notifications/reset").send(&mut service).await;
             assert_eq!(resp.status(), 200);
             let actual: Value = resp.read_body().await;
-            let expected = json!({
-                "profileId": "DEFAULT"
-            });
-            assert_json_eq!(actual, expected.clone());
+            assert!(actual["sent"].as_u64().unwrap_or_default() > 0);
         }).await;
     }
 
     #[actix_rt::test]
-    async fn test_ensure_default_device_profile_exists() {
+    async fn test_fixed_time_is_shared_with_publisher() {
+        run_test(async {
+            // Given the time is fixed.
+            let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let correlation_id = new_uuid();
+            freeze_time(&mut service, "2021-07-03T04:52:49.830Z").await;
+
+            // When accounts are created - whichever worker they're served by.
+            for _ in 0..3 {
+                let mut resp = post("/create-account")
+                    .header("content-type", "application/json")
+                    .header("x-correlation-id", &correlation_id)
+                    .body(json!({ "accountId": new_uuid() }))
+                    .send(&mut service)
+                    .await;
+
+                // Then they're created at the fixed time.
+                assert_eq!(resp.status(), 201);
+                let actual: Value = resp.read_body().await;
+                assert_eq!(actual["created"], json!("2021-07-03T04:52:49.830Z"));
+            }
+
+            // And the publisher thread records their notifications at the fixed time too.
+            let mut actual = Value::Null;
+            for _ in 0..50 {
+                let mut resp = get(&format!("/trace/{}", correlation_id)).send(&mut service).await;
+                actual = resp.read_body().await;
+                if actual.as_array().map(|emitted| emitted.len() == 3).unwrap_or_default() {
+                    break
+                }
+                tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+            }
+
+            for emitted in actual.as_array().expect("no notifications emitted") {
+                assert_eq!(emitted["timestamp"], json!("2021-07-03T04:52:49.830Z"));
+            }
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_oversized_query_string_is_refused() {
+        run_test(async {
+            // Given a small URI limit.
+            let mut service = test::init_service(start_app_with(&[("max_uri_bytes", "64")]).await).await;
+
+            // When accounts are listed with a huge query string.
+            let mut resp = get(&format!("/accounts?cursor={}", "A".repeat(100))).send(&mut service).await;
+
+            // Then the request is refused with a 414 before it's routed.
+            assert_eq!(resp.status(), 414);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["errorCode"], json!(1011));
+
+            // And a short query string is still served.
+            let resp = get("/accounts?limit=1").send(&mut service).await;
+            assert_eq!(resp.status(), 200);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_create_account_with_gzipped_body() {
         run_test(async {
             // Given the environment is set-up.
             let mut service = test::init_service(start_app().await).await;
+            let _auth_mock = mock_auth_ok();
+            let account_id = new_uuid();
 
-            // When a request to retrieve the DEFAULT device profile is made.
-            let mut resp = get("/device-profile/DEFAULT")
+            // When an account is created with a gzipped body.
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(json!({ "accountId": account_id, "salutation": "Mr Blobby" }).to_string().as_bytes()).unwrap();
+
+            let mut resp = post("/create-account")
+                .header("content-type", "application/json")
+                .header("content-encoding", "gzip")
+                .raw_body(encoder.finish().unwrap())
                 .send(&mut service)
                 .await;
 
-            // Then the response looks correct.
+            // Then it's decompressed and the account created.
+            assert_eq!(resp.status(), 201);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["salutation"], json!("Mr Blobby"));
+
+            // When a body is sent with an unsupported encoding.
+            let mut resp = post("/create-account")
+                .header("content-type", "application/json")
+                .header("content-encoding", "br")
+                .raw_body(vec!(1, 2, 3))
+                .send(&mut service)
+                .await;
+
+            // Then it's refused.
+            assert_eq!(resp.status(), 415);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["errorCode"], json!(1013));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_event_schema_version_is_shown_in_settings() {
+        run_test(async {
+            // Given a service with a configured event schema bundle version.
+            let mut service = test::init_service(start_app_with(&[("event_schema_version", "2021.3")]).await).await;
+
+            // When the settings are requested.
+            let mut resp = get("/settings")
+                .send(&mut service)
+                .await;
+
+            // Then the version stamped on notifications is shown.
             assert_eq!(resp.status(), 200);
             let actual: Value = resp.read_body().await;
-            let expected = json!({
-                "profileId": "DEFAULT"
-            });
-            assert_json_eq!(actual, expected.clone());
+            assert_eq!(actual["event_schema_version"], json!("2021.3"));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_settings_sources_show_where_values_came_from() {
+        run_test(async {
+            // Given a service with an overridden setting.
+            let mut service = test::init_service(start_app_with(&[("auth_timeout", "1500")]).await).await;
+
+            // When the settings sources are requested.
+            let mut resp = get("/settings/sources")
+                .send(&mut service)
+                .await;
+
+            // Then the overridden and defaulted settings are shown as such.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["auth_timeout"], json!({ "value": 1500, "source": "override" }));
+            assert_eq!(actual["event_schema_version"]["source"], json!("default"));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_settings_mask_connection_credentials() {
+        run_test(async {
+            // Given a service connected with a username and password in each connection URI.
+            let mut service = test::init_service(start_app().await).await;
+
+            // When the settings are requested.
+            let mut resp = get("/settings")
+                .send(&mut service)
+                .await;
+
+            // Then the credentials are masked but the rest of each URI is shown.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert!(actual["mongo_uri"].as_str().unwrap().starts_with("mongodb://********@localhost:"));
+            assert!(actual["rabbit_uri"].as_str().unwrap().starts_with("amqp://********@localhost:"));
+            assert!(!actual.to_string().contains("changeme"));
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_http_server_limits_are_configurable() {
+        run_test(async {
+            // Given a service with it's own worker, connection and backlog limits.
+            let overrides = [("workers", "3"), ("max_connections", "500"), ("backlog", "64")];
+            let mut service = test::init_service(start_app_with(&overrides).await).await;
+
+            // When the settings are requested.
+            let mut resp = get("/settings")
+                .send(&mut service)
+                .await;
+
+            // Then the limits the HTTP server is started with are shown.
+            assert_eq!(resp.status(), 200);
+            let actual: Value = resp.read_body().await;
+            assert_eq!(actual["workers"], json!(3));
+            assert_eq!(actual["max_connections"], json!(500));
+            assert_eq!(actual["backlog"], json!(64));
+
+            // And a limit which isn't positive fails start-up.
+            for setting in &["workers", "max_connections", "backlog"] {
+                let overrides = [(*setting, "0")];
+                let result = AssertUnwindSafe(nails::init_everything_with(&overrides)).catch_unwind().await;
+                assert!(result.is_err(), "{}=0 should have failed start-up", setting);
+            }
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_malformed_jaeger_endpoint_is_a_config_error() {
+        run_test(async {
+            // Given a jaeger endpoint with no port.
+            let overrides = [("jaeger_endpoint", "not-an-endpoint")];
+
+            // When the service is initialised.
+            let result = nails::init_everything_with(&overrides).await;
+
+            // Then start-up fails with a config error rather than panicking.
+            let err = result.err().expect("init_everything should have failed");
+            assert!(err.to_string().contains("jaeger_endpoint not-an-endpoint must be a host:port"), "unexpected error: {}", err);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_unset_uri_env_reference_fails_startup() {
+        run_test(async {
+            // Given a MongoDB URI referencing an environment variable which isn't set.
+            let overrides = [("mongo_uri", "mongodb://$ENV:NAILS_UNSET_USER:$ENV:NAILS_UNSET_PASSWORD@localhost:27017")];
+
+            // When the service is initialised.
+            let result = nails::init_everything_with(&overrides).await;
+
+            // Then start-up fails naming the missing variable.
+            let err = result.err().expect("init_everything should have failed");
+            assert!(err.to_string().contains("NAILS_UNSET_USER"), "unexpected error: {}", err);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_unreachable_mongo_is_a_timeout() {
+        run_test(async {
+            // Given MongoDB can't be reached.
+            let overrides = [("mongo_uri", "mongodb://localhost:1/?serverSelectionTimeoutMS=500")];
+
+            // When the service is initialised.
+            let result = nails::init_everything_with(&overrides).await;
+
+            // Then start-up fails with a timeout rather than a generic MongoDB error.
+            let err = result.err().expect("init_everything should have failed");
+            assert!(err.to_string().starts_with("MongoDB timed out or is unreachable"), "unexpected error: {}", err);
+        }).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_await_rabbit_gates_start_up_until_connected() {
+        run_test(async {
+            // Given start-up is configured to wait for RabbitMQ.
+            let overrides = [("await_rabbit", "true")];
+
+            // When the service is initialised.
+            let (ctx, _uninstall, _publisher) = nails::init_everything_with(&overrides).await
+                .expect("init_everything should have succeeded");
+
+            // Then the publisher is already connected - so notifications can be published to the first request.
+            assert!(ctx.rabbit_connected().load(Ordering::SeqCst));
         }).await;
     }
 
@@ -165,7 +3704,8 @@ mod tests {
                     "create-account",
                     "read-own-account",
                     "etc"
-                ]
+                ],
+                "principalId": "jbloggs"
             }"#)
             .create()
     }